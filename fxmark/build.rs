@@ -1,4 +1,8 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("./protos/syscalls.proto")?;
+    // Decode `bytes` fields as `bytes::Bytes` so pages are sliced out of the
+    // receive buffer instead of being copied into a fresh `Vec<u8>`.
+    tonic_build::configure()
+        .bytes(["."])
+        .compile(&["./protos/syscalls.proto"], &["./protos"])?;
     Ok(())
 }
@@ -0,0 +1,23 @@
+//! Feeds arbitrary bytes to the DRPC handlers: the first byte picks the opcode, the rest
+//! is the request payload.
+
+#![no_main]
+
+use fxrpc::fxrpc::backend::MemoryBackend;
+use fxrpc::fxrpc::drpc::{init_raw, serve_raw};
+use libfuzzer_sys::fuzz_target;
+use std::sync::{Arc, Once};
+
+// A small payload buffer, which no response may overrun
+const CAPACITY: usize = 8192;
+
+static INIT: Once = Once::new();
+
+fuzz_target!(|data: &[u8]| {
+    INIT.call_once(|| init_raw(Arc::new(MemoryBackend::new())));
+    if let Some((&opcode, request)) = data.split_first() {
+        if let Some(response) = serve_raw(opcode, request, CAPACITY) {
+            assert!(response.len() <= CAPACITY.max(request.len()));
+        }
+    }
+});
@@ -8,7 +8,9 @@ use alloc::vec::Vec;
 use alloc::{format, vec};
 use core::cell::RefCell;
 use core::sync::atomic::{AtomicUsize, Ordering};
-use libc::{O_CREAT, O_RDWR, S_IRWXU};
+use futures::future::join_all;
+use libc::{EINTR, O_CREAT, O_RDWR, S_IRWXU};
+use tokio::runtime::Builder;
 use x86::random::rdrand16;
 
 use crate::fxrpc::grpc::*;
@@ -43,8 +45,8 @@ impl Default for MIX {
 }
 
 impl Bench for MIX {
-    fn init(&self, cores: Vec<u64>, open_files: usize, conn_type: ConnType, rpc_type: RPCType) {
-        let mut client = init_client(conn_type, rpc_type);
+    fn init(&self, cores: Vec<u64>, open_files: usize, config: &ClientConfig) {
+        let mut client = connected(config, init_client(config));
 
         *self.cores.borrow_mut() = cores.len();
         *self.min_core.borrow_mut() = *cores.iter().min().unwrap() as usize;
@@ -52,14 +54,11 @@ impl Bench for MIX {
         for file_num in 0..open_files {
             let filename = format!("file{}.txt", file_num);
             let fd = { client.rpc_open(&filename, O_RDWR | O_CREAT, S_IRWXU.into()) }
-                .expect("FileOpen syscall failed");
+                .unwrap_or_else(|e| panic!("FileOpen syscall failed: {}", e));
 
-            let ret = {
-                client
-                    .rpc_pwrite(fd, &self.page, PAGE_SIZE, self.size)
-                    .expect("FileWriteAt syscall failed")
-            };
-            assert_eq!(ret, PAGE_SIZE as i32);
+            let ret = pwrite_all(&mut *client, fd, &self.page, self.size)
+                .unwrap_or_else(|e| panic!("FileWriteAt syscall failed: {}", e));
+            assert_eq!(ret, PAGE_SIZE);
             self.fds.borrow_mut()[file_num] = fd as u64;
         }
     }
@@ -70,10 +69,11 @@ impl Bench for MIX {
         duration: u64,
         core: usize,
         write_ratio: usize,
-        conn_type: ConnType,
-        rpc_type: RPCType,
+        config: &ClientConfig,
+        qdepth: usize,
+        batch: usize,
     ) -> Vec<usize> {
-        let mut client = init_client(conn_type, rpc_type);
+        let mut client = connected(config, init_client(config));
 
         let mut iops_per_second = Vec::with_capacity(duration as usize);
 
@@ -86,11 +86,17 @@ impl Bench for MIX {
         // let page: &mut [u8; PAGE_SIZE as usize] = &mut [0; PAGE_SIZE as usize];
         let mut page: Vec<u8> = vec![0; PAGE_SIZE as usize];
 
-        {
-            client
-                .rpc_pwrite(fd as i32, &page, PAGE_SIZE, self.size)
-                .expect("can't write_at");
-        }
+        pwrite_all(&mut *client, fd as i32, &page, self.size)
+            .unwrap_or_else(|e| panic!("can't write_at: {}", e));
+
+        // Deeper queues are driven over a separate, pipelined connection
+        let pipelined = if qdepth > 1 {
+            let rt = Builder::new_current_thread().enable_all().build().unwrap();
+            let async_client = connected(config, rt.block_on(init_async_client(config, qdepth)));
+            Some((rt, async_client))
+        } else {
+            None
+        };
 
         // Synchronize with all cores
         poor_mans_barrier.fetch_sub(1, Ordering::Release);
@@ -98,42 +104,73 @@ impl Bench for MIX {
             core::hint::spin_loop();
         }
 
-        let mut iops = 0;
-        let mut iterations = 0;
-        let mut random_num: u16 = 0;
-
-        while iterations <= duration {
-            let start = std::time::Instant::now();
-            while start.elapsed().as_secs() < 1 {
-                for _i in 0..4 {
-                    unsafe { rdrand16(&mut random_num) };
-                    let rand = random_num as usize % total_pages;
-                    let offset = rand * 4096;
+        // Operations that failed with an error, they do not count towards the IOPS
+        let mut failed = 0;
+        if let Some((rt, async_client)) = &pipelined {
+            iops_per_second = rt.block_on(self.run_pipelined(
+                &**async_client,
+                fd as i32,
+                duration,
+                write_ratio,
+                total_pages,
+                qdepth,
+                batch,
+                &mut failed,
+            ));
+        } else {
+            let mut iops = 0;
+            let mut iterations = 0;
+            let mut random_num: u16 = 0;
+            let mut pages: Vec<Vec<u8>> = vec![vec![0; PAGE_SIZE as usize]; batch];
 
-                    if random_num as usize % 100 < write_ratio {
-                        if client
-                            .rpc_pwrite(fd as i32, &page, PAGE_SIZE, offset as i64)
-                            .expect("FileWriteAt syscall failed")
-                            != PAGE_SIZE as i32
-                        {
-                            panic!("MIX: write_at() failed");
+            while iterations <= duration {
+                let start = std::time::Instant::now();
+                while start.elapsed().as_secs() < 1 {
+                    for _i in 0..4 {
+                        if batch > 1 {
+                            let (is_write, offsets) = random_batch(batch, total_pages, write_ratio);
+                            let (op, ret) = if is_write {
+                                (
+                                    "pwritev()",
+                                    pwritev_all(&mut *client, fd as i32, &offsets, &pages),
+                                )
+                            } else {
+                                (
+                                    "preadv()",
+                                    preadv_all(&mut *client, fd as i32, &offsets, &mut pages),
+                                )
+                            };
+                            if check_io(op, ret, batch * PAGE_SIZE, &mut failed) {
+                                iops += batch;
+                            }
+                            continue;
                         }
-                    } else {
-                        if client
-                            .rpc_pread(fd as i32, &mut page, PAGE_SIZE, offset as i64)
-                            .expect("FileReadAt syscall failed")
-                            != PAGE_SIZE as i32
-                        {
-                            panic!("MIX: read_at() failed");
+
+                        unsafe { rdrand16(&mut random_num) };
+                        let rand = random_num as usize % total_pages;
+                        let offset = rand * 4096;
+
+                        let (op, ret) = if random_num as usize % 100 < write_ratio {
+                            let ret = pwrite_all(&mut *client, fd as i32, &page, offset as i64);
+                            ("write_at()", ret)
+                        } else {
+                            let ret = pread_all(&mut *client, fd as i32, &mut page, offset as i64);
+                            ("read_at()", ret)
+                        };
+                        if check_io(op, ret, PAGE_SIZE, &mut failed) {
+                            iops += 1;
                         }
                     }
-                    iops += 1;
                 }
+
+                iops_per_second.push(iops);
+                iterations += 1;
+                iops = 0;
             }
+        }
 
-            iops_per_second.push(iops);
-            iterations += 1;
-            iops = 0;
+        if failed > 0 {
+            println!("MIX: {} operations failed on core {}", failed, core);
         }
 
         poor_mans_barrier.fetch_add(1, Ordering::Release);
@@ -149,11 +186,314 @@ impl Bench for MIX {
                 let fd = self.fds.borrow()[i];
                 client
                     .rpc_close(fd as i32)
-                    .expect("FileClose syscall failed");
+                    .unwrap_or_else(|e| panic!("FileClose syscall failed: {}", e));
             }
         }
         iops_per_second.clone()
     }
 }
 
+impl MIX {
+    // Keeps `qdepth` requests in flight by issuing them together and waiting for the batch
+    async fn run_pipelined(
+        &self,
+        client: &dyn AsyncFxRPC,
+        fd: i32,
+        duration: u64,
+        write_ratio: usize,
+        total_pages: usize,
+        qdepth: usize,
+        batch: usize,
+        failed: &mut usize,
+    ) -> Vec<usize> {
+        let mut iops_per_second = Vec::with_capacity(duration as usize);
+        let mut pages: Vec<Vec<u8>> = vec![vec![0; PAGE_SIZE as usize]; qdepth * batch];
+
+        let mut iops = 0;
+        let mut iterations = 0;
+        let mut random_num: u16 = 0;
+
+        while iterations <= duration {
+            let start = std::time::Instant::now();
+            while start.elapsed().as_secs() < 1 {
+                let mut writes = Vec::with_capacity(qdepth);
+                if batch > 1 {
+                    // Every request in flight is a vectored read or write of `batch` pages
+                    let ops = pages.chunks_mut(batch).map(|chunk| {
+                        let (is_write, offsets) = random_batch(batch, total_pages, write_ratio);
+                        writes.push(is_write);
+                        async move {
+                            if is_write {
+                                async_pwritev_all(client, fd, &offsets, chunk).await
+                            } else {
+                                async_preadv_all(client, fd, &offsets, chunk).await
+                            }
+                        }
+                    });
+                    let results = join_all(ops).await;
+
+                    for (ret, is_write) in results.into_iter().zip(writes) {
+                        let op = if is_write { "pwritev()" } else { "preadv()" };
+                        if check_io(op, ret, batch * PAGE_SIZE, failed) {
+                            iops += batch;
+                        }
+                    }
+                    continue;
+                }
+
+                let ops = pages.iter_mut().map(|page| {
+                    unsafe { rdrand16(&mut random_num) };
+                    let rand = random_num as usize % total_pages;
+                    let offset = rand * 4096;
+
+                    let is_write = random_num as usize % 100 < write_ratio;
+                    writes.push(is_write);
+                    async move {
+                        if is_write {
+                            async_pwrite_all(client, fd, page, offset as i64).await
+                        } else {
+                            async_pread_all(client, fd, page, offset as i64).await
+                        }
+                    }
+                });
+                let results = join_all(ops).await;
+
+                for (ret, is_write) in results.into_iter().zip(writes) {
+                    let op = if is_write { "write_at()" } else { "read_at()" };
+                    if check_io(op, ret, PAGE_SIZE, failed) {
+                        iops += 1;
+                    }
+                }
+            }
+
+            iops_per_second.push(iops);
+            iterations += 1;
+            iops = 0;
+        }
+
+        iops_per_second
+    }
+}
+
+// Picks whether the next batch is written or read, and the random pages it covers
+fn random_batch(batch: usize, total_pages: usize, write_ratio: usize) -> (bool, Vec<i64>) {
+    let mut random_num: u16 = 0;
+    unsafe { rdrand16(&mut random_num) };
+    let is_write = random_num as usize % 100 < write_ratio;
+
+    let offsets = (0..batch)
+        .map(|_| {
+            unsafe { rdrand16(&mut random_num) };
+            let rand = random_num as usize % total_pages;
+            (rand * 4096) as i64
+        })
+        .collect();
+    (is_write, offsets)
+}
+
+// A benchmark cannot run without its server, so failing to reach it ends the process
+fn connected<T>(config: &ClientConfig, client: Result<T, FxRpcError>) -> T {
+    client.unwrap_or_else(|e| {
+        eprintln!("Cannot connect to {}: {}", config.addr, e);
+        std::process::exit(1)
+    })
+}
+
+// Whether an operation moved all `len` bytes. Errors the server reports only fail the
+// operation, a transfer that stops short or a broken connection ends the benchmark.
+fn check_io(op: &str, ret: Result<usize, FxRpcError>, len: usize, failed: &mut usize) -> bool {
+    match ret {
+        Ok(ret) if ret == len => true,
+        Ok(ret) => panic!("MIX: {} was short: {} of {} bytes", op, ret, len),
+        Err(e) if e.errno().is_some() => {
+            *failed += 1;
+            false
+        }
+        Err(e) => panic!("MIX: {} failed: {}", op, e),
+    }
+}
+
+// Accounts for one attempt at a transfer of `len` bytes, `done` of which have moved
+// already, and returns whether the transfer is over. Interrupted calls are retried and
+// short ones continue where they stopped, a call that moves nothing ends the transfer.
+fn progress(
+    done: &mut usize,
+    len: usize,
+    ret: Result<i32, FxRpcError>,
+) -> Result<bool, FxRpcError> {
+    match ret {
+        Ok(0) => Ok(true),
+        Ok(n) => {
+            *done += n as usize;
+            Ok(*done >= len)
+        }
+        Err(e) if e.errno() == Some(EINTR) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// The part of the pages at `offsets` that is left after the first `done` bytes
+fn remaining<'a>(offsets: &[i64], pages: &'a [Vec<u8>], done: usize) -> Vec<(i64, &'a [u8])> {
+    let mut skip = done;
+    let mut segments = Vec::with_capacity(offsets.len());
+    for (offset, page) in offsets.iter().zip(pages.iter()) {
+        if skip >= page.len() {
+            skip -= page.len();
+            continue;
+        }
+        segments.push((offset + skip as i64, &page[skip..]));
+        skip = 0;
+    }
+    segments
+}
+
+fn remaining_mut<'a>(
+    offsets: &[i64],
+    pages: &'a mut [Vec<u8>],
+    done: usize,
+) -> Vec<(i64, &'a mut [u8])> {
+    let mut skip = done;
+    let mut segments = Vec::with_capacity(offsets.len());
+    for (offset, page) in offsets.iter().zip(pages.iter_mut()) {
+        if skip >= page.len() {
+            skip -= page.len();
+            continue;
+        }
+        segments.push((offset + skip as i64, &mut page[skip..]));
+        skip = 0;
+    }
+    segments
+}
+
+fn pwrite_all(
+    client: &mut dyn FxRPC,
+    fd: i32,
+    page: &[u8],
+    offset: i64,
+) -> Result<usize, FxRpcError> {
+    let mut done = 0;
+    loop {
+        let ret = client.rpc_pwrite(fd, &page[done..], offset + done as i64);
+        if progress(&mut done, page.len(), ret)? {
+            return Ok(done);
+        }
+    }
+}
+
+fn pread_all(
+    client: &mut dyn FxRPC,
+    fd: i32,
+    page: &mut [u8],
+    offset: i64,
+) -> Result<usize, FxRpcError> {
+    let mut done = 0;
+    loop {
+        let ret = client.rpc_pread(fd, &mut page[done..], offset + done as i64);
+        if progress(&mut done, page.len(), ret)? {
+            return Ok(done);
+        }
+    }
+}
+
+fn pwritev_all(
+    client: &mut dyn FxRPC,
+    fd: i32,
+    offsets: &[i64],
+    pages: &[Vec<u8>],
+) -> Result<usize, FxRpcError> {
+    let len = offsets.len() * PAGE_SIZE;
+    let mut done = 0;
+    loop {
+        let ret = client.rpc_pwritev(fd, &remaining(offsets, pages, done));
+        if progress(&mut done, len, ret)? {
+            return Ok(done);
+        }
+    }
+}
+
+fn preadv_all(
+    client: &mut dyn FxRPC,
+    fd: i32,
+    offsets: &[i64],
+    pages: &mut [Vec<u8>],
+) -> Result<usize, FxRpcError> {
+    let len = offsets.len() * PAGE_SIZE;
+    let mut done = 0;
+    loop {
+        let ret = client.rpc_preadv(fd, &mut remaining_mut(offsets, pages, done));
+        if progress(&mut done, len, ret)? {
+            return Ok(done);
+        }
+    }
+}
+
+async fn async_pwrite_all(
+    client: &dyn AsyncFxRPC,
+    fd: i32,
+    page: &[u8],
+    offset: i64,
+) -> Result<usize, FxRpcError> {
+    let mut done = 0;
+    loop {
+        let ret = client
+            .rpc_pwrite(fd, &page[done..], offset + done as i64)
+            .await;
+        if progress(&mut done, page.len(), ret)? {
+            return Ok(done);
+        }
+    }
+}
+
+async fn async_pread_all(
+    client: &dyn AsyncFxRPC,
+    fd: i32,
+    page: &mut [u8],
+    offset: i64,
+) -> Result<usize, FxRpcError> {
+    let mut done = 0;
+    loop {
+        let ret = client
+            .rpc_pread(fd, &mut page[done..], offset + done as i64)
+            .await;
+        if progress(&mut done, page.len(), ret)? {
+            return Ok(done);
+        }
+    }
+}
+
+async fn async_pwritev_all(
+    client: &dyn AsyncFxRPC,
+    fd: i32,
+    offsets: &[i64],
+    pages: &[Vec<u8>],
+) -> Result<usize, FxRpcError> {
+    let len = offsets.len() * PAGE_SIZE;
+    let mut done = 0;
+    loop {
+        let ret = client
+            .rpc_pwritev(fd, &remaining(offsets, pages, done))
+            .await;
+        if progress(&mut done, len, ret)? {
+            return Ok(done);
+        }
+    }
+}
+
+async fn async_preadv_all(
+    client: &dyn AsyncFxRPC,
+    fd: i32,
+    offsets: &[i64],
+    pages: &mut [Vec<u8>],
+) -> Result<usize, FxRpcError> {
+    let len = offsets.len() * PAGE_SIZE;
+    let mut done = 0;
+    loop {
+        let mut segments = remaining_mut(offsets, pages, done);
+        let ret = client.rpc_preadv(fd, &mut segments).await;
+        if progress(&mut done, len, ret)? {
+            return Ok(done);
+        }
+    }
+}
+
 unsafe impl Sync for MIX {}
@@ -28,7 +28,7 @@ use utils::topology::*;
 mod mix;
 use crate::fxmark::mix::MIX;
 
-use crate::fxrpc::{ClientParams, ConnType, LogMode, RPCType};
+use crate::fxrpc::{ClientConfig, ClientParams, LogMode};
 
 pub const PAGE_SIZE: usize = 1024;
 
@@ -78,15 +78,16 @@ impl FromStr for ARGs {
 }
 
 pub trait Bench {
-    fn init(&self, cores: Vec<u64>, open_files: usize, conn_type: ConnType, rpc_type: RPCType);
+    fn init(&self, cores: Vec<u64>, open_files: usize, config: &ClientConfig);
     fn run(
         &self,
         barrier: &AtomicUsize,
         duration: u64,
         core: usize,
         write_ratio: usize,
-        conn_type: ConnType,
-        rpc_type: RPCType,
+        config: &ClientConfig,
+        qdepth: usize,
+        batch: usize,
     ) -> Vec<usize>;
 }
 
@@ -176,8 +177,9 @@ where
             bench_duration_secs,
             core_id,
             write_ratio,
-            client_params.conn_type,
-            client_params.rpc_type,
+            &client_params.config,
+            client_params.qdepth,
+            client_params.batch,
         );
 
         let mut csv_file = if client_params.log_mode == LogMode::CSV {
@@ -206,7 +208,7 @@ where
                 client_params.cid,
                 client_params.ccores,
                 client_params.nclients,
-                client_params.rpc_type,
+                client_params.config.rpc_type,
             );
 
             match client_params.log_mode {
@@ -275,12 +277,8 @@ pub fn bench(
 
                 for core_id in cores.clone() {
                     let mb = Arc::new(microbench.clone());
-                    mb.bench.init(
-                        cores.clone(),
-                        open_files,
-                        client_params.conn_type,
-                        client_params.rpc_type,
-                    );
+                    mb.bench
+                        .init(cores.clone(), open_files, &client_params.config);
 
                     let bench_duration = duration.clone();
                     let params = (*client_params).clone();
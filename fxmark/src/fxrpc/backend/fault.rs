@@ -0,0 +1,469 @@
+use libc::*;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use yaml_rust::{Yaml, YamlLoader};
+
+use crate::fxrpc::backend::FsBackend;
+use crate::fxrpc::iov::segments_len;
+use crate::fxrpc::{DirPage, FileStat};
+
+/// Operations faults can be configured for. Vectored reads and writes count as reads and
+/// writes, positional or not.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FaultOp {
+    Open,
+    Close,
+    Read,
+    Write,
+    Fsync,
+    Fstat,
+    Ftruncate,
+    Remove,
+    Unlink,
+    Mkdir,
+    Rmdir,
+    Rename,
+    Link,
+    Truncate,
+    Stat,
+    Readdir,
+}
+
+const FAULT_OPS: [(&str, FaultOp); 16] = [
+    ("open", FaultOp::Open),
+    ("close", FaultOp::Close),
+    ("read", FaultOp::Read),
+    ("write", FaultOp::Write),
+    ("fsync", FaultOp::Fsync),
+    ("fstat", FaultOp::Fstat),
+    ("ftruncate", FaultOp::Ftruncate),
+    ("remove", FaultOp::Remove),
+    ("unlink", FaultOp::Unlink),
+    ("mkdir", FaultOp::Mkdir),
+    ("rmdir", FaultOp::Rmdir),
+    ("rename", FaultOp::Rename),
+    ("link", FaultOp::Link),
+    ("truncate", FaultOp::Truncate),
+    ("stat", FaultOp::Stat),
+    ("readdir", FaultOp::Readdir),
+];
+
+/// Distribution the delay added to an operation is drawn from.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Latency {
+    None,
+    Fixed(Duration),
+    Uniform(Duration, Duration),
+    Exponential(Duration),
+}
+
+impl Latency {
+    fn sample(&self, rng: &mut XorShift) -> Duration {
+        match *self {
+            Latency::None => Duration::ZERO,
+            Latency::Fixed(delay) => delay,
+            Latency::Uniform(min, max) => min + (max - min).mul_f64(rng.next_f64()),
+            Latency::Exponential(mean) => mean.mul_f64(-(1.0 - rng.next_f64()).ln()),
+        }
+    }
+}
+
+/// Faults injected into a single kind of operation. Rates are probabilities per call.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct OpFaults {
+    pub latency: Latency,
+    pub eio: f64,
+    pub enospc: f64,
+    pub eintr: f64,
+    // Reads and writes only: the call transfers a random part of what was asked for
+    pub short: f64,
+    // The connection the request arrived on is dropped instead of answering it
+    pub drop: f64,
+}
+
+impl Default for OpFaults {
+    fn default() -> Self {
+        OpFaults {
+            latency: Latency::None,
+            eio: 0.0,
+            enospc: 0.0,
+            eintr: 0.0,
+            short: 0.0,
+            drop: 0.0,
+        }
+    }
+}
+
+/// Faults a `FaultBackend` injects, read from a YAML file such as
+///
+/// ```yaml
+/// seed: 42
+/// default:
+///   latency: { dist: uniform, min_us: 10, max_us: 100 }
+///   eintr: 0.01
+/// ops:
+///   read: { short: 0.05, eio: 0.001 }
+///   write: { short: 0.05, enospc: 0.001 }
+///   fsync:
+///     latency: { dist: exponential, mean_us: 2000 }
+///     drop: 0.0001
+/// ```
+///
+/// Every operation starts out with the `default` faults, which the keys under `ops`
+/// override. Latencies are `fixed` (`us`), `uniform` (`min_us`, `max_us`) or
+/// `exponential` (`mean_us`), a bare number is a fixed latency in microseconds.
+#[derive(Clone, PartialEq, Debug)]
+pub struct FaultConfig {
+    // The same seed injects the same faults into the same sequence of calls
+    pub seed: Option<u64>,
+    ops: Vec<OpFaults>,
+}
+
+impl FaultConfig {
+    pub fn from_file(path: &str) -> Result<FaultConfig, String> {
+        let yaml = std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read fault config {}: {}", path, e))?;
+        FaultConfig::from_yaml(&yaml)
+    }
+
+    pub fn from_yaml(yaml: &str) -> Result<FaultConfig, String> {
+        let docs = YamlLoader::load_from_str(yaml).map_err(|e| e.to_string())?;
+        let doc = match docs.into_iter().next() {
+            Some(doc) => doc,
+            None => return Ok(FaultConfig::new(OpFaults::default())),
+        };
+        let doc = match doc {
+            Yaml::Hash(doc) => doc,
+            Yaml::Null => return Ok(FaultConfig::new(OpFaults::default())),
+            _ => return Err(String::from("Fault config must be a mapping")),
+        };
+        for key in doc.keys() {
+            match key.as_str() {
+                Some("seed") | Some("default") | Some("ops") => {}
+                _ => return Err(format!("Unknown fault config key {:?}", key)),
+            }
+        }
+        let doc = Yaml::Hash(doc);
+
+        let mut config = FaultConfig::new(parse_faults(&doc["default"], OpFaults::default())?);
+        match &doc["seed"] {
+            Yaml::Integer(seed) if *seed >= 0 => config.seed = Some(*seed as u64),
+            Yaml::BadValue => {}
+            _ => return Err(String::from("seed must be a non-negative integer")),
+        }
+        match &doc["ops"] {
+            Yaml::Hash(ops) => {
+                for (name, value) in ops.iter() {
+                    let op = name
+                        .as_str()
+                        .and_then(fault_op)
+                        .ok_or_else(|| format!("Unknown operation {:?}", name))?;
+                    config.ops[op as usize] = parse_faults(value, *config.faults(op))?;
+                }
+            }
+            Yaml::BadValue | Yaml::Null => {}
+            _ => return Err(String::from("ops must map operations to faults")),
+        }
+        Ok(config)
+    }
+
+    /// Injects the same faults into every operation.
+    pub fn new(faults: OpFaults) -> FaultConfig {
+        FaultConfig {
+            seed: None,
+            ops: vec![faults; FAULT_OPS.len()],
+        }
+    }
+
+    pub fn faults(&self, op: FaultOp) -> &OpFaults {
+        &self.ops[op as usize]
+    }
+}
+
+fn fault_op(name: &str) -> Option<FaultOp> {
+    FAULT_OPS
+        .iter()
+        .find(|(op_name, _)| *op_name == name)
+        .map(|(_, op)| *op)
+}
+
+// Duration of `value` microseconds
+fn micros(value: &Yaml, key: &str) -> Result<Duration, String> {
+    let us = match value {
+        Yaml::Integer(us) => *us as f64,
+        Yaml::BadValue => return Err(format!("Latency is missing {}", key)),
+        _ => value.as_f64().unwrap_or(-1.0),
+    };
+    if us < 0.0 {
+        return Err(format!("{} must be a non-negative number", key));
+    }
+    Ok(Duration::from_secs_f64(us / 1e6))
+}
+
+fn parse_latency(yaml: &Yaml) -> Result<Latency, String> {
+    if let Yaml::Integer(_) | Yaml::Real(_) = yaml {
+        return Ok(Latency::Fixed(micros(yaml, "latency")?));
+    }
+    match yaml["dist"].as_str() {
+        Some("none") => Ok(Latency::None),
+        Some("fixed") => Ok(Latency::Fixed(micros(&yaml["us"], "us")?)),
+        Some("uniform") => {
+            let min = micros(&yaml["min_us"], "min_us")?;
+            let max = micros(&yaml["max_us"], "max_us")?;
+            if min > max {
+                return Err(String::from("min_us exceeds max_us"));
+            }
+            Ok(Latency::Uniform(min, max))
+        }
+        Some("exponential") => Ok(Latency::Exponential(micros(&yaml["mean_us"], "mean_us")?)),
+        _ => Err(format!("Unknown latency distribution {:?}", yaml["dist"])),
+    }
+}
+
+fn parse_rate(yaml: &Yaml, key: &str) -> Result<f64, String> {
+    let rate = match yaml {
+        Yaml::Integer(rate) => *rate as f64,
+        _ => yaml
+            .as_f64()
+            .ok_or_else(|| format!("{} must be a number", key))?,
+    };
+    if !(0.0..=1.0).contains(&rate) {
+        return Err(format!("{} must be between 0 and 1", key));
+    }
+    Ok(rate)
+}
+
+// Reads the faults of an operation, keys that are not given keep their value in `faults`
+fn parse_faults(yaml: &Yaml, mut faults: OpFaults) -> Result<OpFaults, String> {
+    let hash = match yaml {
+        Yaml::Hash(hash) => hash,
+        Yaml::Null | Yaml::BadValue => return Ok(faults),
+        _ => return Err(String::from("Faults must be a mapping")),
+    };
+    for (key, value) in hash.iter() {
+        let key = key.as_str().unwrap_or_default();
+        match key {
+            "latency" => faults.latency = parse_latency(value)?,
+            "eio" => faults.eio = parse_rate(value, key)?,
+            "enospc" => faults.enospc = parse_rate(value, key)?,
+            "eintr" => faults.eintr = parse_rate(value, key)?,
+            "short" => faults.short = parse_rate(value, key)?,
+            "drop" => faults.drop = parse_rate(value, key)?,
+            _ => return Err(format!("Unknown fault {:?}", key)),
+        }
+    }
+    if faults.eio + faults.enospc + faults.eintr + faults.drop > 1.0 {
+        return Err(String::from("Error and drop rates add up to more than 1"));
+    }
+    Ok(faults)
+}
+
+// xorshift64*, faults only need to be cheap and reproducible
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> XorShift {
+        // The state must never be zero
+        XorShift(seed ^ 0x9e37_79b9_7f4a_7c15)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Wraps another backend and injects latency, errors, short reads and writes and
+/// connection drops into the calls passed through to it, for testing how clients cope.
+///
+/// A drop is reported as ECONNABORTED, which the frontends answer by dropping the
+/// connection of the request. Injected errors fail a call before it reaches the wrapped
+/// backend, so it has no effect. `close` is only delayed, failing it would leak the
+/// descriptor.
+pub struct FaultBackend {
+    inner: Arc<dyn FsBackend>,
+    config: FaultConfig,
+    rng: Mutex<XorShift>,
+}
+
+impl FaultBackend {
+    pub fn new(inner: Arc<dyn FsBackend>, config: FaultConfig) -> FaultBackend {
+        let seed = config.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|t| t.as_nanos() as u64)
+                .unwrap_or(0)
+        });
+        FaultBackend {
+            inner: inner,
+            config: config,
+            rng: Mutex::new(XorShift::new(seed)),
+        }
+    }
+
+    // Delays a call and decides whether it fails, the lock is not held while sleeping
+    fn inject(&self, op: FaultOp) -> Result<(), i32> {
+        let faults = self.config.faults(op);
+        let (delay, roll) = {
+            let mut rng = self.rng.lock().unwrap();
+            (faults.latency.sample(&mut rng), rng.next_f64())
+        };
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+        }
+        if op == FaultOp::Close {
+            return Ok(());
+        }
+
+        let mut threshold = 0.0;
+        for (rate, errno) in [
+            (faults.drop, ECONNABORTED),
+            (faults.eio, EIO),
+            (faults.enospc, ENOSPC),
+            (faults.eintr, EINTR),
+        ] {
+            threshold += rate;
+            if roll < threshold {
+                return Err(errno);
+            }
+        }
+        Ok(())
+    }
+
+    // Number of bytes a transfer of `len` bytes is cut down to, at least one
+    fn shorten(&self, op: FaultOp, len: usize) -> usize {
+        let mut rng = self.rng.lock().unwrap();
+        if len < 2 || rng.next_f64() >= self.config.faults(op).short {
+            return len;
+        }
+        1 + (rng.next_u64() % (len as u64 - 1)) as usize
+    }
+}
+
+// First `limit` bytes of the (offset, len) segments
+fn trim(segments: &[(i64, usize)], mut limit: usize) -> Vec<(i64, usize)> {
+    let mut trimmed = Vec::with_capacity(segments.len());
+    for &(offset, len) in segments {
+        if limit == 0 {
+            break;
+        }
+        trimmed.push((offset, len.min(limit)));
+        limit -= len.min(limit);
+    }
+    trimmed
+}
+
+impl FsBackend for FaultBackend {
+    fn open(&self, path: &str, flags: i32, mode: u32) -> Result<i32, i32> {
+        self.inject(FaultOp::Open)?;
+        self.inner.open(path, flags, mode)
+    }
+
+    fn close(&self, fd: i32) -> Result<(), i32> {
+        self.inject(FaultOp::Close)?;
+        self.inner.close(fd)
+    }
+
+    fn read(&self, fd: i32, buf: &mut [u8]) -> Result<usize, i32> {
+        self.inject(FaultOp::Read)?;
+        let len = self.shorten(FaultOp::Read, buf.len());
+        self.inner.read(fd, &mut buf[..len])
+    }
+
+    fn pread(&self, fd: i32, buf: &mut [u8], offset: i64) -> Result<usize, i32> {
+        self.inject(FaultOp::Read)?;
+        let len = self.shorten(FaultOp::Read, buf.len());
+        self.inner.pread(fd, &mut buf[..len], offset)
+    }
+
+    fn write(&self, fd: i32, buf: &[u8]) -> Result<usize, i32> {
+        self.inject(FaultOp::Write)?;
+        let len = self.shorten(FaultOp::Write, buf.len());
+        self.inner.write(fd, &buf[..len])
+    }
+
+    fn pwrite(&self, fd: i32, buf: &[u8], offset: i64) -> Result<usize, i32> {
+        self.inject(FaultOp::Write)?;
+        let len = self.shorten(FaultOp::Write, buf.len());
+        self.inner.pwrite(fd, &buf[..len], offset)
+    }
+
+    fn preadv(&self, fd: i32, segments: &[(i64, usize)], buf: &mut [u8]) -> Result<usize, i32> {
+        self.inject(FaultOp::Read)?;
+        let len = self.shorten(FaultOp::Read, segments_len(segments));
+        self.inner.preadv(fd, &trim(segments, len), buf)
+    }
+
+    fn pwritev(&self, fd: i32, segments: &[(i64, usize)], data: &[u8]) -> Result<usize, i32> {
+        self.inject(FaultOp::Write)?;
+        let len = self.shorten(FaultOp::Write, segments_len(segments));
+        self.inner.pwritev(fd, &trim(segments, len), data)
+    }
+
+    fn fsync(&self, fd: i32) -> Result<(), i32> {
+        self.inject(FaultOp::Fsync)?;
+        self.inner.fsync(fd)
+    }
+
+    fn fstat(&self, fd: i32) -> Result<FileStat, i32> {
+        self.inject(FaultOp::Fstat)?;
+        self.inner.fstat(fd)
+    }
+
+    fn ftruncate(&self, fd: i32, len: i64) -> Result<(), i32> {
+        self.inject(FaultOp::Ftruncate)?;
+        self.inner.ftruncate(fd, len)
+    }
+
+    fn remove(&self, path: &str) -> Result<(), i32> {
+        self.inject(FaultOp::Remove)?;
+        self.inner.remove(path)
+    }
+
+    fn unlink(&self, path: &str) -> Result<(), i32> {
+        self.inject(FaultOp::Unlink)?;
+        self.inner.unlink(path)
+    }
+
+    fn mkdir(&self, path: &str, mode: u32) -> Result<(), i32> {
+        self.inject(FaultOp::Mkdir)?;
+        self.inner.mkdir(path, mode)
+    }
+
+    fn rmdir(&self, path: &str) -> Result<(), i32> {
+        self.inject(FaultOp::Rmdir)?;
+        self.inner.rmdir(path)
+    }
+
+    fn rename(&self, oldpath: &str, newpath: &str, flags: u32) -> Result<(), i32> {
+        self.inject(FaultOp::Rename)?;
+        self.inner.rename(oldpath, newpath, flags)
+    }
+
+    fn link(&self, oldpath: &str, newpath: &str) -> Result<(), i32> {
+        self.inject(FaultOp::Link)?;
+        self.inner.link(oldpath, newpath)
+    }
+
+    fn truncate(&self, path: &str, len: i64) -> Result<(), i32> {
+        self.inject(FaultOp::Truncate)?;
+        self.inner.truncate(path, len)
+    }
+
+    fn stat(&self, path: &str, follow: bool) -> Result<FileStat, i32> {
+        self.inject(FaultOp::Stat)?;
+        self.inner.stat(path, follow)
+    }
+
+    fn readdir(&self, path: &str, cookie: u64, count: usize) -> Result<DirPage, i32> {
+        self.inject(FaultOp::Readdir)?;
+        self.inner.readdir(path, cookie, count)
+    }
+}
@@ -0,0 +1,579 @@
+use libc::*;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::fxrpc::backend::{components, FsBackend};
+use crate::fxrpc::dir::READDIR_MAX_COUNT;
+use crate::fxrpc::{DirEntry, DirPage, FileStat};
+
+const ROOT_INO: u64 = 1;
+// Unit stat() reports the space a file takes in
+const BLOCK_SIZE: i64 = 512;
+// Largest file the backend holds, writes and truncates beyond fail with EFBIG so that a
+// client cannot make the server allocate arbitrary amounts of memory with one offset
+const MAX_FILE_LEN: usize = 1 << 30;
+
+enum Node {
+    File(Vec<u8>),
+    // Entries are kept sorted, so a readdir cookie is simply the position in the listing
+    Dir(BTreeMap<String, u64>),
+}
+
+struct Inode {
+    node: Node,
+    // Permission bits only, the file type follows from the node
+    mode: u32,
+    nlink: u64,
+    atime: i64,
+    mtime: i64,
+    ctime: i64,
+    // Open descriptors, an unlinked inode lives on until the last one is closed
+    open: usize,
+}
+
+#[derive(Clone, Copy)]
+struct OpenFile {
+    ino: u64,
+    flags: i32,
+    offset: i64,
+}
+
+struct MemFs {
+    inodes: HashMap<u64, Inode>,
+    next_ino: u64,
+    files: HashMap<i32, OpenFile>,
+    next_fd: i32,
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_nanos() as i64)
+        .unwrap_or(0)
+}
+
+impl MemFs {
+    fn inode(&self, ino: u64) -> &Inode {
+        self.inodes.get(&ino).expect("Dangling inode number")
+    }
+
+    fn inode_mut(&mut self, ino: u64) -> &mut Inode {
+        self.inodes.get_mut(&ino).expect("Dangling inode number")
+    }
+
+    fn entries(&self, dir: u64) -> Result<&BTreeMap<String, u64>, i32> {
+        match &self.inode(dir).node {
+            Node::Dir(entries) => Ok(entries),
+            Node::File(_) => Err(ENOTDIR),
+        }
+    }
+
+    fn entries_mut(&mut self, dir: u64) -> &mut BTreeMap<String, u64> {
+        let inode = self.inode_mut(dir);
+        inode.mtime = now();
+        inode.ctime = inode.mtime;
+        match &mut inode.node {
+            Node::Dir(entries) => entries,
+            Node::File(_) => panic!("Inode {} is not a directory", dir),
+        }
+    }
+
+    fn is_dir(&self, ino: u64) -> bool {
+        matches!(self.inode(ino).node, Node::Dir(_))
+    }
+
+    // Directory holding the last component of `path`, and that component. The root
+    // itself resolves to (root, ".").
+    fn parent<'a>(&self, path: &'a str) -> Result<(u64, &'a str), i32> {
+        let mut parts = components(path)?;
+        let name = parts.pop().unwrap_or(".");
+        let mut dir = ROOT_INO;
+        for part in parts {
+            dir = *self.entries(dir)?.get(part).ok_or(ENOENT)?;
+        }
+        self.entries(dir)?;
+        Ok((dir, name))
+    }
+
+    fn child(&self, dir: u64, name: &str) -> Option<u64> {
+        if name == "." {
+            return Some(dir);
+        }
+        self.entries(dir).ok()?.get(name).copied()
+    }
+
+    fn lookup(&self, path: &str) -> Result<u64, i32> {
+        let (dir, name) = self.parent(path)?;
+        self.child(dir, name).ok_or(ENOENT)
+    }
+
+    fn alloc(&mut self, node: Node, mode: u32) -> u64 {
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        let time = now();
+        let nlink = match node {
+            Node::File(_) => 1,
+            Node::Dir(_) => 2,
+        };
+        self.inodes.insert(
+            ino,
+            Inode {
+                node: node,
+                mode: mode & 0o7777,
+                nlink: nlink,
+                atime: time,
+                mtime: time,
+                ctime: time,
+                open: 0,
+            },
+        );
+        ino
+    }
+
+    // Drops an inode that is neither linked nor open anymore
+    fn release(&mut self, ino: u64) {
+        let inode = self.inode(ino);
+        if inode.nlink == 0 && inode.open == 0 {
+            self.inodes.remove(&ino);
+        }
+    }
+
+    // Removes the entry `name` of `dir` that points to `ino`
+    fn drop_link(&mut self, dir: u64, name: &str, ino: u64) {
+        self.entries_mut(dir).remove(name);
+        let is_dir = self.is_dir(ino);
+        let inode = self.inode_mut(ino);
+        inode.ctime = now();
+        if is_dir {
+            inode.nlink = 0;
+            self.inode_mut(dir).nlink -= 1;
+        } else {
+            inode.nlink -= 1;
+        }
+        self.release(ino);
+    }
+
+    fn file(&self, fd: i32) -> Result<OpenFile, i32> {
+        self.files.get(&fd).copied().ok_or(EBADF)
+    }
+
+    fn read_at(&mut self, ino: u64, buf: &mut [u8], offset: i64) -> Result<usize, i32> {
+        if offset < 0 {
+            return Err(EINVAL);
+        }
+        let inode = self.inode_mut(ino);
+        inode.atime = now();
+        let data = match &inode.node {
+            Node::File(data) => data,
+            Node::Dir(_) => return Err(EISDIR),
+        };
+        let start = (offset as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    // Writes at `offset`, or at the end of the file if it was opened with O_APPEND.
+    // Returns the bytes written and the offset right after them.
+    fn write_at(&mut self, file: OpenFile, buf: &[u8], offset: i64) -> Result<(usize, i64), i32> {
+        if file.flags & O_ACCMODE == O_RDONLY {
+            return Err(EBADF);
+        }
+        if offset < 0 {
+            return Err(EINVAL);
+        }
+        let inode = self.inode_mut(file.ino);
+        let data = match &mut inode.node {
+            Node::File(data) => data,
+            Node::Dir(_) => return Err(EISDIR),
+        };
+        let start = if file.flags & O_APPEND != 0 {
+            data.len()
+        } else {
+            offset as usize
+        };
+        let end = start.saturating_add(buf.len());
+        if end > MAX_FILE_LEN {
+            return Err(EFBIG);
+        }
+        if end > data.len() {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(buf);
+        inode.mtime = now();
+        inode.ctime = inode.mtime;
+        Ok((buf.len(), end as i64))
+    }
+
+    fn resize(&mut self, ino: u64, len: i64) -> Result<(), i32> {
+        if len < 0 {
+            return Err(EINVAL);
+        }
+        if len as usize > MAX_FILE_LEN {
+            return Err(EFBIG);
+        }
+        let inode = self.inode_mut(ino);
+        inode.mtime = now();
+        inode.ctime = inode.mtime;
+        match &mut inode.node {
+            Node::File(data) => {
+                data.resize(len as usize, 0);
+                Ok(())
+            }
+            Node::Dir(_) => Err(EISDIR),
+        }
+    }
+
+    // Whether `ino` is `dir` or one of its descendants
+    fn contains(&self, dir: u64, ino: u64) -> bool {
+        if dir == ino {
+            return true;
+        }
+        match &self.inode(dir).node {
+            Node::Dir(entries) => entries
+                .values()
+                .any(|&child| self.is_dir(child) && self.contains(child, ino)),
+            Node::File(_) => false,
+        }
+    }
+
+    // Moves the entry `name` of `from`, pointing to `ino`, to `to`
+    fn move_link(&mut self, from: u64, name: &str, to: u64, newname: &str, ino: u64) {
+        self.entries_mut(from).remove(name);
+        self.entries_mut(to).insert(newname.to_string(), ino);
+        self.inode_mut(ino).ctime = now();
+        if self.is_dir(ino) && from != to {
+            self.inode_mut(from).nlink -= 1;
+            self.inode_mut(to).nlink += 1;
+        }
+    }
+
+    fn rename(&mut self, oldpath: &str, newpath: &str, flags: u32) -> Result<(), i32> {
+        let exchange = flags & RENAME_EXCHANGE != 0;
+        let noreplace = flags & RENAME_NOREPLACE != 0;
+        if flags & !(RENAME_EXCHANGE | RENAME_NOREPLACE) != 0 || (exchange && noreplace) {
+            return Err(EINVAL);
+        }
+
+        let (olddir, oldname) = self.parent(oldpath)?;
+        let (newdir, newname) = self.parent(newpath)?;
+        if oldname == "." || newname == "." {
+            return Err(EBUSY);
+        }
+        let src = self.child(olddir, oldname).ok_or(ENOENT)?;
+        let dst = self.child(newdir, newname);
+
+        // A directory cannot be moved into itself
+        if self.is_dir(src) && self.contains(src, newdir) {
+            return Err(EINVAL);
+        }
+
+        if exchange {
+            let dst = dst.ok_or(ENOENT)?;
+            if dst == src {
+                return Ok(());
+            }
+            if self.is_dir(dst) && self.contains(dst, olddir) {
+                return Err(EINVAL);
+            }
+            self.entries_mut(olddir).insert(oldname.to_string(), dst);
+            self.entries_mut(newdir).insert(newname.to_string(), src);
+            for (ino, from, to) in [(src, olddir, newdir), (dst, newdir, olddir)] {
+                self.inode_mut(ino).ctime = now();
+                if self.is_dir(ino) && from != to {
+                    self.inode_mut(from).nlink -= 1;
+                    self.inode_mut(to).nlink += 1;
+                }
+            }
+            return Ok(());
+        }
+
+        if let Some(dst) = dst {
+            if noreplace {
+                return Err(EEXIST);
+            }
+            if dst == src {
+                return Ok(());
+            }
+            match (self.is_dir(src), self.is_dir(dst)) {
+                (true, false) => return Err(ENOTDIR),
+                (false, true) => return Err(EISDIR),
+                (true, true) if !self.entries(dst)?.is_empty() => return Err(ENOTEMPTY),
+                _ => {}
+            }
+            self.drop_link(newdir, newname, dst);
+        }
+        self.move_link(olddir, oldname, newdir, newname, src);
+        Ok(())
+    }
+
+    fn unlink(&mut self, path: &str) -> Result<(), i32> {
+        let (dir, name) = self.parent(path)?;
+        let ino = self.child(dir, name).ok_or(ENOENT)?;
+        if self.is_dir(ino) {
+            return Err(EISDIR);
+        }
+        self.drop_link(dir, name, ino);
+        Ok(())
+    }
+
+    fn rmdir(&mut self, path: &str) -> Result<(), i32> {
+        let (dir, name) = self.parent(path)?;
+        if name == "." {
+            return Err(EINVAL);
+        }
+        let ino = self.child(dir, name).ok_or(ENOENT)?;
+        if !self.entries(ino)?.is_empty() {
+            return Err(ENOTEMPTY);
+        }
+        self.drop_link(dir, name, ino);
+        Ok(())
+    }
+
+    fn stat(&self, ino: u64) -> FileStat {
+        let inode = self.inode(ino);
+        let (kind, size) = match &inode.node {
+            Node::File(data) => (S_IFREG, data.len() as i64),
+            Node::Dir(_) => (S_IFDIR, 0),
+        };
+        FileStat {
+            dev: 0,
+            ino: ino,
+            mode: kind | inode.mode,
+            nlink: inode.nlink,
+            uid: unsafe { getuid() },
+            gid: unsafe { getgid() },
+            size: size,
+            blocks: (size + BLOCK_SIZE - 1) / BLOCK_SIZE,
+            atime: inode.atime,
+            mtime: inode.mtime,
+            ctime: inode.ctime,
+        }
+    }
+}
+
+/// Keeps the whole file system in memory, so frameworks can be compared without any
+/// storage underneath and tests leave no trace on the host. It starts out as an empty
+/// root directory and has no symlinks or permission checks.
+pub struct MemoryBackend {
+    fs: Mutex<MemFs>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> MemoryBackend {
+        let mut fs = MemFs {
+            inodes: HashMap::new(),
+            next_ino: ROOT_INO,
+            files: HashMap::new(),
+            next_fd: 0,
+        };
+        fs.alloc(Node::Dir(BTreeMap::new()), 0o755);
+        MemoryBackend { fs: Mutex::new(fs) }
+    }
+}
+
+impl Default for MemoryBackend {
+    fn default() -> Self {
+        MemoryBackend::new()
+    }
+}
+
+impl FsBackend for MemoryBackend {
+    fn open(&self, path: &str, flags: i32, mode: u32) -> Result<i32, i32> {
+        let mut fs = self.fs.lock().unwrap();
+        let (dir, name) = fs.parent(path)?;
+        let ino = match fs.child(dir, name) {
+            Some(_) if flags & O_CREAT != 0 && flags & O_EXCL != 0 => return Err(EEXIST),
+            Some(ino) => ino,
+            None if flags & O_CREAT == 0 => return Err(ENOENT),
+            None => {
+                let ino = fs.alloc(Node::File(Vec::new()), mode);
+                fs.entries_mut(dir).insert(name.to_string(), ino);
+                ino
+            }
+        };
+
+        let writable = flags & O_ACCMODE != O_RDONLY;
+        if fs.is_dir(ino) {
+            if writable {
+                return Err(EISDIR);
+            }
+        } else if flags & O_DIRECTORY != 0 {
+            return Err(ENOTDIR);
+        } else if writable && flags & O_TRUNC != 0 {
+            fs.resize(ino, 0)?;
+        }
+
+        let mut fd = fs.next_fd;
+        while fs.files.contains_key(&fd) {
+            fd = fd.checked_add(1).unwrap_or(0);
+        }
+        fs.next_fd = fd.checked_add(1).unwrap_or(0);
+        fs.files.insert(
+            fd,
+            OpenFile {
+                ino: ino,
+                flags: flags,
+                offset: 0,
+            },
+        );
+        fs.inode_mut(ino).open += 1;
+        Ok(fd)
+    }
+
+    fn close(&self, fd: i32) -> Result<(), i32> {
+        let mut fs = self.fs.lock().unwrap();
+        let file = fs.files.remove(&fd).ok_or(EBADF)?;
+        fs.inode_mut(file.ino).open -= 1;
+        fs.release(file.ino);
+        Ok(())
+    }
+
+    fn read(&self, fd: i32, buf: &mut [u8]) -> Result<usize, i32> {
+        let mut fs = self.fs.lock().unwrap();
+        let file = fs.file(fd)?;
+        if file.flags & O_ACCMODE == O_WRONLY {
+            return Err(EBADF);
+        }
+        let n = fs.read_at(file.ino, buf, file.offset)?;
+        fs.files.get_mut(&fd).unwrap().offset += n as i64;
+        Ok(n)
+    }
+
+    fn pread(&self, fd: i32, buf: &mut [u8], offset: i64) -> Result<usize, i32> {
+        let mut fs = self.fs.lock().unwrap();
+        let file = fs.file(fd)?;
+        if file.flags & O_ACCMODE == O_WRONLY {
+            return Err(EBADF);
+        }
+        fs.read_at(file.ino, buf, offset)
+    }
+
+    fn write(&self, fd: i32, buf: &[u8]) -> Result<usize, i32> {
+        let mut fs = self.fs.lock().unwrap();
+        let file = fs.file(fd)?;
+        let (n, end) = fs.write_at(file, buf, file.offset)?;
+        fs.files.get_mut(&fd).unwrap().offset = end;
+        Ok(n)
+    }
+
+    fn pwrite(&self, fd: i32, buf: &[u8], offset: i64) -> Result<usize, i32> {
+        let mut fs = self.fs.lock().unwrap();
+        let file = fs.file(fd)?;
+        fs.write_at(file, buf, offset).map(|(n, _)| n)
+    }
+
+    fn fsync(&self, fd: i32) -> Result<(), i32> {
+        self.fs.lock().unwrap().file(fd).map(|_| ())
+    }
+
+    fn fstat(&self, fd: i32) -> Result<FileStat, i32> {
+        let fs = self.fs.lock().unwrap();
+        let file = fs.file(fd)?;
+        Ok(fs.stat(file.ino))
+    }
+
+    fn ftruncate(&self, fd: i32, len: i64) -> Result<(), i32> {
+        let mut fs = self.fs.lock().unwrap();
+        let file = fs.file(fd)?;
+        if file.flags & O_ACCMODE == O_RDONLY || fs.is_dir(file.ino) {
+            return Err(EINVAL);
+        }
+        fs.resize(file.ino, len)
+    }
+
+    fn remove(&self, path: &str) -> Result<(), i32> {
+        let mut fs = self.fs.lock().unwrap();
+        let ino = fs.lookup(path)?;
+        if fs.is_dir(ino) {
+            fs.rmdir(path)
+        } else {
+            fs.unlink(path)
+        }
+    }
+
+    fn unlink(&self, path: &str) -> Result<(), i32> {
+        self.fs.lock().unwrap().unlink(path)
+    }
+
+    fn mkdir(&self, path: &str, mode: u32) -> Result<(), i32> {
+        let mut fs = self.fs.lock().unwrap();
+        let (dir, name) = fs.parent(path)?;
+        if fs.child(dir, name).is_some() {
+            return Err(EEXIST);
+        }
+        let ino = fs.alloc(Node::Dir(BTreeMap::new()), mode);
+        fs.entries_mut(dir).insert(name.to_string(), ino);
+        fs.inode_mut(dir).nlink += 1;
+        Ok(())
+    }
+
+    fn rmdir(&self, path: &str) -> Result<(), i32> {
+        self.fs.lock().unwrap().rmdir(path)
+    }
+
+    fn rename(&self, oldpath: &str, newpath: &str, flags: u32) -> Result<(), i32> {
+        self.fs.lock().unwrap().rename(oldpath, newpath, flags)
+    }
+
+    fn link(&self, oldpath: &str, newpath: &str) -> Result<(), i32> {
+        let mut fs = self.fs.lock().unwrap();
+        let ino = fs.lookup(oldpath)?;
+        if fs.is_dir(ino) {
+            return Err(EPERM);
+        }
+        let (dir, name) = fs.parent(newpath)?;
+        if fs.child(dir, name).is_some() {
+            return Err(EEXIST);
+        }
+        fs.entries_mut(dir).insert(name.to_string(), ino);
+        let inode = fs.inode_mut(ino);
+        inode.nlink += 1;
+        inode.ctime = now();
+        Ok(())
+    }
+
+    fn truncate(&self, path: &str, len: i64) -> Result<(), i32> {
+        let mut fs = self.fs.lock().unwrap();
+        let ino = fs.lookup(path)?;
+        fs.resize(ino, len)
+    }
+
+    // There are no symlinks, so stat() and lstat() are the same
+    fn stat(&self, path: &str, _follow: bool) -> Result<FileStat, i32> {
+        let fs = self.fs.lock().unwrap();
+        let ino = fs.lookup(path)?;
+        Ok(fs.stat(ino))
+    }
+
+    fn readdir(&self, path: &str, cookie: u64, count: usize) -> Result<DirPage, i32> {
+        let fs = self.fs.lock().unwrap();
+        let ino = fs.lookup(path)?;
+        let entries = fs.entries(ino)?;
+        if count == 0 {
+            return Err(EINVAL);
+        }
+
+        let count = count.min(READDIR_MAX_COUNT);
+        let mut page = DirPage {
+            entries: Vec::new(),
+            cookie: cookie,
+            eof: false,
+        };
+        for (position, (name, &child)) in entries.iter().enumerate().skip(cookie as usize) {
+            if page.entries.len() == count {
+                return Ok(page);
+            }
+            page.cookie = position as u64 + 1;
+            page.entries.push(DirEntry {
+                name: name.clone(),
+                file_type: if fs.is_dir(child) { DT_DIR } else { DT_REG },
+                ino: child,
+                cookie: page.cookie,
+            });
+        }
+        page.eof = true;
+        Ok(page)
+    }
+}
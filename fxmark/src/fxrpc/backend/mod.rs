@@ -0,0 +1,172 @@
+use libc::{EACCES, EINVAL};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::fxrpc::{DirPage, FileStat, ServerConfig};
+
+pub mod fault;
+pub use crate::fxrpc::backend::fault::{FaultBackend, FaultConfig};
+
+pub mod memory;
+pub use crate::fxrpc::backend::memory::MemoryBackend;
+
+pub mod posix;
+pub use crate::fxrpc::backend::posix::PosixBackend;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(C)]
+pub enum BackendType {
+    Posix,
+    Memory,
+}
+
+impl std::fmt::Display for BackendType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BackendType::Posix => write!(f, "posix"),
+            BackendType::Memory => write!(f, "memory"),
+        }
+    }
+}
+
+impl std::str::FromStr for BackendType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "posix" => Ok(BackendType::Posix),
+            "memory" => Ok(BackendType::Memory),
+            _ => Err(format!("Unknown backend {}", s)),
+        }
+    }
+}
+
+/// The file system a server exports. Both RPC frontends only decode requests and hand
+/// them to the backend, so gRPC and DRPC serve identical semantics whatever the backend.
+///
+/// Calls mirror the POSIX calls of the same name and fail with the errno those would
+/// set. Descriptors are the backend's own; frontends never hand them to clients
+/// directly but through a per-connection `HandleTable`. Paths are relative to the root
+/// of the backend and may not leave it: absolute paths and `..` fail with EACCES, paths
+/// with NUL bytes with EINVAL. A call failing with ECONNABORTED makes the frontend drop
+/// the connection the request arrived on instead of answering it.
+pub trait FsBackend: Send + Sync {
+    fn open(&self, path: &str, flags: i32, mode: u32) -> Result<i32, i32>;
+    fn close(&self, fd: i32) -> Result<(), i32>;
+    fn read(&self, fd: i32, buf: &mut [u8]) -> Result<usize, i32>;
+    fn pread(&self, fd: i32, buf: &mut [u8], offset: i64) -> Result<usize, i32>;
+    fn write(&self, fd: i32, buf: &[u8]) -> Result<usize, i32>;
+    fn pwrite(&self, fd: i32, buf: &[u8], offset: i64) -> Result<usize, i32>;
+    fn fsync(&self, fd: i32) -> Result<(), i32>;
+    fn fstat(&self, fd: i32) -> Result<FileStat, i32>;
+    fn ftruncate(&self, fd: i32, len: i64) -> Result<(), i32>;
+    // Removes files and empty directories alike, like remove(3)
+    fn remove(&self, path: &str) -> Result<(), i32>;
+    fn unlink(&self, path: &str) -> Result<(), i32>;
+    fn mkdir(&self, path: &str, mode: u32) -> Result<(), i32>;
+    fn rmdir(&self, path: &str) -> Result<(), i32>;
+    // `flags` takes the renameat2() RENAME_* flags
+    fn rename(&self, oldpath: &str, newpath: &str, flags: u32) -> Result<(), i32>;
+    fn link(&self, oldpath: &str, newpath: &str) -> Result<(), i32>;
+    fn truncate(&self, path: &str, len: i64) -> Result<(), i32>;
+    // stat() if `follow` is set, lstat() otherwise
+    fn stat(&self, path: &str, follow: bool) -> Result<FileStat, i32>;
+    // Lists up to `count` entries following `cookie`, 0 starts at the beginning. A
+    // `count` of 0 fails with EINVAL.
+    fn readdir(&self, path: &str, cookie: u64, count: usize) -> Result<DirPage, i32>;
+
+    // Reads the (offset, len) segments back to back into `buf`. Stops at the first short
+    // read and returns the bytes read so far, an error is only reported if nothing was.
+    fn preadv(&self, fd: i32, segments: &[(i64, usize)], buf: &mut [u8]) -> Result<usize, i32> {
+        let mut done = 0;
+        for &(offset, len) in segments {
+            let dst = buf.get_mut(done..).and_then(|rest| rest.get_mut(..len));
+            let dst = dst.ok_or(EINVAL)?;
+            match self.pread(fd, dst, offset) {
+                Ok(n) => {
+                    done += n;
+                    if n < len {
+                        break;
+                    }
+                }
+                Err(errno) if done == 0 => return Err(errno),
+                Err(_) => break,
+            }
+        }
+        Ok(done)
+    }
+
+    // Writes the (offset, len) segments, which `data` holds back to back
+    fn pwritev(&self, fd: i32, segments: &[(i64, usize)], data: &[u8]) -> Result<usize, i32> {
+        let mut done = 0;
+        for &(offset, len) in segments {
+            let src = data.get(done..).and_then(|rest| rest.get(..len));
+            let src = src.ok_or(EINVAL)?;
+            match self.pwrite(fd, src, offset) {
+                Ok(n) => {
+                    done += n;
+                    if n < len {
+                        break;
+                    }
+                }
+                Err(errno) if done == 0 => return Err(errno),
+                Err(_) => break,
+            }
+        }
+        Ok(done)
+    }
+}
+
+// Splits a client path into its components. Nothing may lead out of the root, so
+// absolute paths and ".." are refused.
+pub(crate) fn components(path: &str) -> Result<Vec<&str>, i32> {
+    if path.as_bytes().contains(&0) {
+        return Err(EINVAL);
+    }
+    if path.starts_with('/') {
+        return Err(EACCES);
+    }
+    let mut parts = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => continue,
+            ".." => return Err(EACCES),
+            part => parts.push(part),
+        }
+    }
+    Ok(parts)
+}
+
+// (result, errno) pair RPC responses carry for a call without a return value
+pub(crate) fn status(res: Result<(), i32>) -> (i32, i32) {
+    match res {
+        Ok(()) => (0, 0),
+        Err(errno) => (-1, errno),
+    }
+}
+
+// (bytes, errno) pair RPC responses carry for a call that transfers data
+pub(crate) fn transferred(res: Result<usize, i32>) -> (i64, i32) {
+    match res {
+        Ok(n) => (n as i64, 0),
+        Err(errno) => (-1, errno),
+    }
+}
+
+/// Creates the backend a server exports. Only the POSIX backend uses `root`, the
+/// in-memory one starts out empty. Either is wrapped in a `FaultBackend` if the
+/// configuration asks for faults.
+pub fn init_backend(config: &ServerConfig) -> Arc<dyn FsBackend> {
+    let root: &Path = &config.root;
+    let backend: Arc<dyn FsBackend> = match config.backend {
+        BackendType::Posix => Arc::new(
+            PosixBackend::new(root)
+                .unwrap_or_else(|e| panic!("Failed to open export root {}: {}", root.display(), e)),
+        ),
+        BackendType::Memory => Arc::new(MemoryBackend::new()),
+    };
+    match &config.faults {
+        Some(faults) => Arc::new(FaultBackend::new(backend, faults.clone())),
+        None => backend,
+    }
+}
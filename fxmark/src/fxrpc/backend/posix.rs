@@ -0,0 +1,231 @@
+use libc::*;
+use std::ffi::{CStr, CString};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+
+use crate::fxrpc::backend::{components, FsBackend};
+use crate::fxrpc::dir::read_dir_page;
+use crate::fxrpc::error::errno_for;
+use crate::fxrpc::filestat::stat_at;
+use crate::fxrpc::iov::{preadv_segments, pwritev_segments, segments_len};
+use crate::fxrpc::{DirPage, FileStat};
+
+// Flags directories are walked with. O_NOFOLLOW makes a symlinked directory fail
+// instead of leading out of the root.
+const WALK_FLAGS: i32 = O_PATH | O_DIRECTORY | O_NOFOLLOW | O_CLOEXEC;
+
+/// Passes requests through to the host file system below an exported directory. Client
+/// paths are resolved relative to it, one component at a time with `openat()`, and
+/// symlinks are never followed, so no request can reach a file outside of it. Paths with
+/// NUL bytes fail with EINVAL, and a symlink in the way fails with ELOOP or ENOTDIR.
+/// `lstat` reports symlinks themselves.
+#[derive(Debug)]
+pub struct PosixBackend {
+    path: PathBuf,
+    dir: OwnedFd,
+}
+
+// Result of a syscall that just returned `res`
+fn check(res: i64) -> Result<i64, i32> {
+    if res < 0 {
+        Err(errno_for(res))
+    } else {
+        Ok(res)
+    }
+}
+
+fn check_unit(res: i32) -> Result<(), i32> {
+    check(res as i64).map(|_| ())
+}
+
+impl PosixBackend {
+    pub fn new(path: &Path) -> std::io::Result<PosixBackend> {
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        let fd = unsafe { open(c_path.as_ptr(), O_PATH | O_DIRECTORY | O_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(PosixBackend {
+            path: path.to_path_buf(),
+            dir: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Walks to the directory holding the last component of `path` and calls `f` with
+    // that directory and the component. The root itself resolves to (root, ".").
+    fn at<T, F>(&self, path: &str, f: F) -> Result<T, i32>
+    where
+        F: FnOnce(i32, &CStr) -> Result<T, i32>,
+    {
+        let mut parts = components(path)?;
+        let name = CString::new(parts.pop().unwrap_or(".")).map_err(|_| EINVAL)?;
+
+        let mut dir: Option<OwnedFd> = None;
+        for part in parts {
+            let c_part = CString::new(part).map_err(|_| EINVAL)?;
+            let parent = dir.as_ref().unwrap_or(&self.dir).as_raw_fd();
+            let fd = check(unsafe { openat(parent, c_part.as_ptr(), WALK_FLAGS) } as i64)?;
+            dir = Some(unsafe { OwnedFd::from_raw_fd(fd as i32) });
+        }
+        f(dir.as_ref().unwrap_or(&self.dir).as_raw_fd(), &name)
+    }
+}
+
+impl FsBackend for PosixBackend {
+    fn open(&self, path: &str, flags: i32, mode: u32) -> Result<i32, i32> {
+        self.at(path, |dir, name| {
+            let fd = unsafe { openat(dir, name.as_ptr(), flags | O_NOFOLLOW | O_CLOEXEC, mode) };
+            check(fd as i64).map(|fd| fd as i32)
+        })
+    }
+
+    fn close(&self, fd: i32) -> Result<(), i32> {
+        check_unit(unsafe { close(fd) })
+    }
+
+    fn read(&self, fd: i32, buf: &mut [u8]) -> Result<usize, i32> {
+        let res = unsafe { read(fd, buf.as_mut_ptr() as *mut c_void, buf.len()) };
+        check(res as i64).map(|n| n as usize)
+    }
+
+    fn pread(&self, fd: i32, buf: &mut [u8], offset: i64) -> Result<usize, i32> {
+        let res = unsafe { pread(fd, buf.as_mut_ptr() as *mut c_void, buf.len(), offset) };
+        check(res as i64).map(|n| n as usize)
+    }
+
+    fn write(&self, fd: i32, buf: &[u8]) -> Result<usize, i32> {
+        let res = unsafe { write(fd, buf.as_ptr() as *const c_void, buf.len()) };
+        check(res as i64).map(|n| n as usize)
+    }
+
+    fn pwrite(&self, fd: i32, buf: &[u8], offset: i64) -> Result<usize, i32> {
+        let res = unsafe { pwrite(fd, buf.as_ptr() as *const c_void, buf.len(), offset) };
+        check(res as i64).map(|n| n as usize)
+    }
+
+    // Contiguous segments go to the kernel as a single preadv()
+    fn preadv(&self, fd: i32, segments: &[(i64, usize)], buf: &mut [u8]) -> Result<usize, i32> {
+        if segments_len(segments) > buf.len() {
+            return Err(EINVAL);
+        }
+        let (res, errno) = unsafe { preadv_segments(fd, segments, buf.as_mut_ptr()) };
+        if res < 0 {
+            return Err(errno);
+        }
+        Ok(res as usize)
+    }
+
+    fn pwritev(&self, fd: i32, segments: &[(i64, usize)], data: &[u8]) -> Result<usize, i32> {
+        let (res, errno) = pwritev_segments(fd, segments, data);
+        if res < 0 {
+            return Err(errno);
+        }
+        Ok(res as usize)
+    }
+
+    fn fsync(&self, fd: i32) -> Result<(), i32> {
+        check_unit(unsafe { fsync(fd) })
+    }
+
+    fn fstat(&self, fd: i32) -> Result<FileStat, i32> {
+        let mut info = std::mem::MaybeUninit::<stat>::uninit();
+        unsafe {
+            check_unit(fstat(fd, info.as_mut_ptr()))?;
+            Ok(FileStat::from(&info.assume_init()))
+        }
+    }
+
+    fn ftruncate(&self, fd: i32, len: i64) -> Result<(), i32> {
+        check_unit(unsafe { ftruncate(fd, len) })
+    }
+
+    fn remove(&self, path: &str) -> Result<(), i32> {
+        self.at(path, |dir, name| unsafe {
+            match check_unit(unlinkat(dir, name.as_ptr(), 0)) {
+                Err(EISDIR) => check_unit(unlinkat(dir, name.as_ptr(), AT_REMOVEDIR)),
+                res => res,
+            }
+        })
+    }
+
+    fn unlink(&self, path: &str) -> Result<(), i32> {
+        self.at(path, |dir, name| unsafe {
+            check_unit(unlinkat(dir, name.as_ptr(), 0))
+        })
+    }
+
+    fn mkdir(&self, path: &str, mode: u32) -> Result<(), i32> {
+        self.at(path, |dir, name| unsafe {
+            check_unit(mkdirat(dir, name.as_ptr(), mode))
+        })
+    }
+
+    fn rmdir(&self, path: &str) -> Result<(), i32> {
+        self.at(path, |dir, name| unsafe {
+            check_unit(unlinkat(dir, name.as_ptr(), AT_REMOVEDIR))
+        })
+    }
+
+    fn rename(&self, oldpath: &str, newpath: &str, flags: u32) -> Result<(), i32> {
+        self.at(oldpath, |olddir, oldname| {
+            self.at(newpath, |newdir, newname| unsafe {
+                check(syscall(
+                    SYS_renameat2,
+                    olddir,
+                    oldname.as_ptr(),
+                    newdir,
+                    newname.as_ptr(),
+                    flags,
+                ))
+                .map(|_| ())
+            })
+        })
+    }
+
+    fn link(&self, oldpath: &str, newpath: &str) -> Result<(), i32> {
+        self.at(oldpath, |olddir, oldname| {
+            self.at(newpath, |newdir, newname| unsafe {
+                check_unit(linkat(
+                    olddir,
+                    oldname.as_ptr(),
+                    newdir,
+                    newname.as_ptr(),
+                    0,
+                ))
+            })
+        })
+    }
+
+    // Opened without blocking, so a FIFO nobody reads does not hold up the server. Only
+    // regular files are truncated, anything else fails with EINVAL like truncate() does.
+    fn truncate(&self, path: &str, len: i64) -> Result<(), i32> {
+        let fd = match self.open(path, O_WRONLY | O_NONBLOCK, 0) {
+            Err(ENXIO) => return Err(EINVAL),
+            res => res?,
+        };
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        if self.fstat(fd.as_raw_fd())?.mode & S_IFMT != S_IFREG {
+            return Err(EINVAL);
+        }
+        self.ftruncate(fd.as_raw_fd(), len)
+    }
+
+    // Symlinks are not followed, so stat() of one fails with ELOOP
+    fn stat(&self, path: &str, follow: bool) -> Result<FileStat, i32> {
+        let st = self.at(path, |dir, name| stat_at(dir, name, AT_SYMLINK_NOFOLLOW))?;
+        if follow && st.mode & S_IFMT == S_IFLNK {
+            return Err(ELOOP);
+        }
+        Ok(st)
+    }
+
+    fn readdir(&self, path: &str, cookie: u64, count: usize) -> Result<DirPage, i32> {
+        let fd = self.open(path, O_RDONLY | O_DIRECTORY, 0)?;
+        read_dir_page(fd, cookie, count)
+    }
+}
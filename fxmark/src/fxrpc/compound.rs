@@ -0,0 +1,293 @@
+use libc::*;
+use std::sync::Arc;
+
+use crate::fxrpc::backend::FsBackend;
+use crate::fxrpc::handles::{HandleTable, OpenFile};
+use crate::fxrpc::FxRpcError;
+
+/// File descriptor an operation of a compound works on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FdRef {
+    /// A descriptor the client already holds.
+    Fd(i32),
+    /// The descriptor returned by the operation at this index of the same compound.
+    Op(usize),
+}
+
+/// A single operation of a compound. Paths are relative to the root of the server's backend.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompoundOp {
+    Open {
+        path: String,
+        flags: i32,
+        mode: u32,
+    },
+    PRead {
+        fd: FdRef,
+        len: usize,
+        offset: i64,
+    },
+    PWrite {
+        fd: FdRef,
+        data: Vec<u8>,
+        offset: i64,
+    },
+    Fsync {
+        fd: FdRef,
+    },
+    /// Result is the file size.
+    Fstat {
+        fd: FdRef,
+    },
+    FTruncate {
+        fd: FdRef,
+        len: i64,
+    },
+    Close {
+        fd: FdRef,
+    },
+    Unlink {
+        path: String,
+    },
+    Mkdir {
+        path: String,
+        mode: u32,
+    },
+}
+
+/// Outcome of one executed operation.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OpResult {
+    pub result: i64,
+    pub errno: i32,
+    /// Data returned by a read.
+    pub data: Vec<u8>,
+}
+
+impl OpResult {
+    pub fn check(&self) -> Result<i64, FxRpcError> {
+        if self.result < 0 {
+            Err(FxRpcError::Errno(self.errno))
+        } else {
+            Ok(self.result)
+        }
+    }
+
+    fn succeeded(res: i64) -> OpResult {
+        OpResult {
+            result: res,
+            errno: 0,
+            data: Vec::new(),
+        }
+    }
+
+    fn failed(errno: i32) -> OpResult {
+        OpResult {
+            result: -1,
+            errno: errno,
+            data: Vec::new(),
+        }
+    }
+}
+
+/// Builds a list of operations that the server runs in order within one round trip.
+/// It stops at the first operation that fails, so a compound returns one result per
+/// operation up to and including the failed one. Descriptors opened by a compound are
+/// ordinary handles of the connection and stay open until they are closed, even if a
+/// later operation fails.
+///
+/// ```ignore
+/// let mut compound = Compound::new();
+/// let fd = compound.open("file.txt", O_RDWR | O_CREAT, S_IRWXU);
+/// compound.pwrite(fd, page, 0).fsync(fd).close(fd);
+/// let results = client.rpc_compound(&compound)?;
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Compound {
+    pub ops: Vec<CompoundOp>,
+}
+
+impl Compound {
+    pub fn new() -> Compound {
+        Compound::default()
+    }
+
+    /// Appends an arbitrary operation and returns the reference to its result.
+    pub fn push(&mut self, op: CompoundOp) -> FdRef {
+        self.ops.push(op);
+        FdRef::Op(self.ops.len() - 1)
+    }
+
+    /// Opens a file, the returned reference stands for the new descriptor.
+    pub fn open(&mut self, path: &str, flags: i32, mode: u32) -> FdRef {
+        self.push(CompoundOp::Open {
+            path: path.to_string(),
+            flags: flags,
+            mode: mode,
+        })
+    }
+
+    pub fn pread(&mut self, fd: FdRef, len: usize, offset: i64) -> &mut Self {
+        self.push(CompoundOp::PRead {
+            fd: fd,
+            len: len,
+            offset: offset,
+        });
+        self
+    }
+
+    pub fn pwrite(&mut self, fd: FdRef, data: &[u8], offset: i64) -> &mut Self {
+        self.push(CompoundOp::PWrite {
+            fd: fd,
+            data: data.to_vec(),
+            offset: offset,
+        });
+        self
+    }
+
+    pub fn fsync(&mut self, fd: FdRef) -> &mut Self {
+        self.push(CompoundOp::Fsync { fd: fd });
+        self
+    }
+
+    pub fn fstat(&mut self, fd: FdRef) -> &mut Self {
+        self.push(CompoundOp::Fstat { fd: fd });
+        self
+    }
+
+    pub fn ftruncate(&mut self, fd: FdRef, len: i64) -> &mut Self {
+        self.push(CompoundOp::FTruncate { fd: fd, len: len });
+        self
+    }
+
+    pub fn close(&mut self, fd: FdRef) -> &mut Self {
+        self.push(CompoundOp::Close { fd: fd });
+        self
+    }
+
+    pub fn unlink(&mut self, path: &str) -> &mut Self {
+        self.push(CompoundOp::Unlink {
+            path: path.to_string(),
+        });
+        self
+    }
+
+    pub fn mkdir(&mut self, path: &str, mode: u32) -> &mut Self {
+        self.push(CompoundOp::Mkdir {
+            path: path.to_string(),
+            mode: mode,
+        });
+        self
+    }
+
+    // Upper bound on the read data a response to this compound carries
+    pub(crate) fn read_len(&self) -> usize {
+        self.ops
+            .iter()
+            .map(|op| match op {
+                CompoundOp::PRead { len, .. } => *len,
+                _ => 0,
+            })
+            .sum()
+    }
+}
+
+// Resolves a descriptor reference to the handle it stands for. Only an earlier,
+// successful open yields a handle.
+fn resolve_handle(fd: FdRef, ops: &[CompoundOp], results: &[OpResult]) -> Result<i32, i32> {
+    match fd {
+        FdRef::Fd(handle) => Ok(handle),
+        FdRef::Op(index) => match (ops.get(index), results.get(index)) {
+            (Some(CompoundOp::Open { .. }), Some(res)) if res.result >= 0 => Ok(res.result as i32),
+            _ => Err(EBADF),
+        },
+    }
+}
+
+// Resolves a descriptor reference against the results of the operations run so far and
+// the handles of the connection
+fn resolve(
+    fd: FdRef,
+    ops: &[CompoundOp],
+    results: &[OpResult],
+    handles: &HandleTable,
+) -> Result<Arc<OpenFile>, i32> {
+    handles.get(resolve_handle(fd, ops, results)?)
+}
+
+fn run_op(
+    op: &CompoundOp,
+    ops: &[CompoundOp],
+    backend: &dyn FsBackend,
+    results: &[OpResult],
+    handles: &HandleTable,
+) -> Result<OpResult, i32> {
+    let res = match op {
+        CompoundOp::Open { path, flags, mode } => {
+            handles.insert(backend.open(path, *flags, *mode)?) as i64
+        }
+        CompoundOp::PRead { fd, len, offset } => {
+            let fd = resolve(*fd, ops, results, handles)?;
+            let mut data = vec![0u8; *len];
+            let n = backend.pread(fd.fd(), &mut data, *offset)?;
+            data.truncate(n);
+            return Ok(OpResult {
+                result: n as i64,
+                errno: 0,
+                data: data,
+            });
+        }
+        CompoundOp::PWrite { fd, data, offset } => {
+            let fd = resolve(*fd, ops, results, handles)?;
+            backend.pwrite(fd.fd(), data, *offset)? as i64
+        }
+        CompoundOp::Fsync { fd } => {
+            backend.fsync(resolve(*fd, ops, results, handles)?.fd())?;
+            0
+        }
+        CompoundOp::Fstat { fd } => {
+            backend
+                .fstat(resolve(*fd, ops, results, handles)?.fd())?
+                .size
+        }
+        CompoundOp::FTruncate { fd, len } => {
+            backend.ftruncate(resolve(*fd, ops, results, handles)?.fd(), *len)?;
+            0
+        }
+        CompoundOp::Close { fd } => {
+            let (res, errno) = handles.close(resolve_handle(*fd, ops, results)?);
+            if res < 0 {
+                return Err(errno);
+            }
+            0
+        }
+        CompoundOp::Unlink { path } => {
+            backend.unlink(path)?;
+            0
+        }
+        CompoundOp::Mkdir { path, mode } => {
+            backend.mkdir(path, *mode)?;
+            0
+        }
+    };
+    Ok(OpResult::succeeded(res))
+}
+
+// Runs the operations in order on `backend` and stops after the first one that fails.
+// Descriptors are opened and looked up through the handles of the client's connection.
+pub(crate) fn run_compound(
+    ops: &[CompoundOp],
+    backend: &dyn FsBackend,
+    handles: &HandleTable,
+) -> Vec<OpResult> {
+    let mut results: Vec<OpResult> = Vec::with_capacity(ops.len());
+    for op in ops {
+        let result = run_op(op, ops, backend, &results, handles).unwrap_or_else(OpResult::failed);
+        let failed = result.result < 0;
+        results.push(result);
+        if failed {
+            break;
+        }
+    }
+    results
+}
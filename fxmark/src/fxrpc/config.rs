@@ -0,0 +1,294 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::fxmark::utils::topology::{Cpu, MachineTopology, ThreadMapping};
+use crate::fxrpc::drpc::Codec;
+use crate::fxrpc::{BackendType, ConnType, FaultConfig, RPCType, UDS_PATH};
+
+/// Address a server listens on and clients connect to.
+///
+/// Parsed from `tcp://host:port`, `unix:///path/to/socket`, a bare `host:port`
+/// or a bare absolute socket path.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ServerAddr {
+    Tcp(String),
+    Uds(PathBuf),
+}
+
+impl ServerAddr {
+    /// Address implied by the `--transport` and `--port` flags when none is given.
+    pub fn default_for(conn_type: ConnType, rpc_type: RPCType, port: u16) -> ServerAddr {
+        match conn_type {
+            ConnType::TcpLocal => match rpc_type {
+                RPCType::GRPC => ServerAddr::Tcp(format!("[::1]:{}", port)),
+                RPCType::DRPC => ServerAddr::Tcp(format!("127.0.0.1:{}", port)),
+            },
+            ConnType::TcpRemote => ServerAddr::Tcp(format!("172.31.0.1:{}", port)),
+            ConnType::UDS => ServerAddr::Uds(PathBuf::from(UDS_PATH)),
+        }
+    }
+}
+
+impl FromStr for ServerAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix://") {
+            if path.is_empty() {
+                return Err(format!("Missing socket path in '{}'", s));
+            }
+            Ok(ServerAddr::Uds(PathBuf::from(path)))
+        } else if let Some(addr) = s.strip_prefix("tcp://") {
+            parse_tcp(addr)
+        } else if s.starts_with('/') {
+            Ok(ServerAddr::Uds(PathBuf::from(s)))
+        } else if s.contains("://") {
+            Err(format!("Unsupported address scheme in '{}'", s))
+        } else {
+            parse_tcp(s)
+        }
+    }
+}
+
+fn parse_tcp(addr: &str) -> Result<ServerAddr, String> {
+    match addr.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+            Ok(ServerAddr::Tcp(addr.to_string()))
+        }
+        _ => Err(format!("Expected host:port, got '{}'", addr)),
+    }
+}
+
+impl std::fmt::Display for ServerAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ServerAddr::Tcp(addr) => write!(f, "tcp://{}", addr),
+            ServerAddr::Uds(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+/// How clients reach the server.
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    pub rpc_type: RPCType,
+    pub addr: ServerAddr,
+    /// How DRPC messages are serialised, the server answers in whichever a client picks.
+    pub codec: Codec,
+    /// Message sizes of gRPC clients, which should match the server's.
+    pub grpc_limits: GrpcLimits,
+    /// Secures TCP connections to the server, which has to use TLS as well.
+    pub tls: Option<TlsConfig>,
+}
+
+/// Where and how the server is run.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub rpc_type: RPCType,
+    pub addr: ServerAddr,
+    /// Directory exported to clients, no request reaches outside of it.
+    pub root: PathBuf,
+    /// File system requests are served from, `root` only applies to the POSIX one.
+    pub backend: BackendType,
+    /// Faults injected into the backend, for testing clients against a misbehaving server.
+    pub faults: Option<FaultConfig>,
+    /// File the server writes its metrics to as CSV when it exits.
+    pub metrics_out: Option<PathBuf>,
+    /// Token clients have to present to stop the server, which they cannot without one.
+    pub shutdown_token: Option<String>,
+    /// Server threads and the cores they are pinned to. DRPC serves connections from an
+    /// event-driven worker pool, gRPC runs its runtime on them as `runtime` says. Without
+    /// a pool, DRPC serves every connection on a thread of its own and gRPC uses tokio's
+    /// default runtime.
+    pub pool: Option<WorkerPool>,
+    /// Flavour of the gRPC runtime when there is a pool.
+    pub runtime: RuntimeFlavor,
+    /// Where the gRPC server runs its calls into the backend.
+    pub io_exec: IoExec,
+    /// Largest read or write DRPC serves in one request. Clients learn it when they
+    /// connect and split larger plain reads and writes to fit.
+    pub max_io: usize,
+    /// Message sizes of the gRPC server.
+    pub grpc_limits: GrpcLimits,
+    /// Secures TCP connections, clients have to use TLS as well.
+    pub tls: Option<TlsConfig>,
+}
+
+/// PEM files that secure TCP connections with TLS.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsConfig {
+    /// Certificate chain presented to the peer. Servers need one, clients only present
+    /// one for mutual TLS.
+    pub cert: Option<PathBuf>,
+    /// Private key of `cert`.
+    pub key: Option<PathBuf>,
+    /// CA certificates the peer has to present a certificate of. Clients need them to
+    /// check the server; servers given them require a certificate of every client.
+    pub ca: Option<PathBuf>,
+    /// Name clients expect the server's certificate to be issued for.
+    pub server_name: String,
+}
+
+/// Message sizes of the gRPC service. Client and server should agree on them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GrpcLimits {
+    /// Largest message sent or received, larger ones fail the call.
+    pub max_message: usize,
+    /// Reads and writes of more bytes go over ReadStream and WriteStream, in messages
+    /// carrying this many bytes of data each.
+    pub stream_chunk: usize,
+}
+
+// Room a message needs beyond the data it carries
+const MESSAGE_HEADROOM: usize = 1024;
+
+// Most bytes a segment of a vectored read or write takes up in a message
+const SEGMENT_LEN: usize = 24;
+
+impl GrpcLimits {
+    /// Refuses chunks that would not fit a message.
+    pub fn check(&self) -> Result<(), String> {
+        if self.stream_chunk == 0 {
+            return Err(String::from("Stream chunks need at least one byte"));
+        }
+        if self.stream_chunk.saturating_add(MESSAGE_HEADROOM) > self.max_message {
+            return Err(format!(
+                "Stream chunks of {} bytes do not fit messages of {} bytes",
+                self.stream_chunk, self.max_message
+            ));
+        }
+        Ok(())
+    }
+
+    /// Most bytes of data a single message carries, reads that would return more in one
+    /// message fail with EMSGSIZE.
+    pub fn max_data(&self) -> usize {
+        self.max_message.saturating_sub(MESSAGE_HEADROOM)
+    }
+
+    // Most segments a vectored read or write of `stream_chunk` bytes lists in a message,
+    // in the room the data leaves besides what the other fields take
+    pub(crate) fn max_segments(&self) -> usize {
+        self.max_message
+            .saturating_sub(self.stream_chunk)
+            .saturating_sub(MESSAGE_HEADROOM / 2)
+            / SEGMENT_LEN
+    }
+}
+
+impl Default for GrpcLimits {
+    /// tonic's default message size, with streams in chunks of a quarter of it.
+    fn default() -> GrpcLimits {
+        GrpcLimits {
+            max_message: 4 * 1024 * 1024,
+            stream_chunk: 1024 * 1024,
+        }
+    }
+}
+
+/// Where the gRPC server runs the calls it makes into the backend, all of which block.
+#[derive(Clone, Debug, PartialEq)]
+pub enum IoExec {
+    /// On the runtime thread serving the request, which stalls the other requests of
+    /// that thread until the call returns.
+    Inline,
+    /// On tokio's blocking thread pool.
+    Blocking,
+    /// On a dedicated pool of I/O threads, each pinned to its core.
+    Pool(WorkerPool),
+}
+
+impl std::fmt::Display for IoExec {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            IoExec::Inline => write!(f, "inline"),
+            IoExec::Blocking => write!(f, "blocking"),
+            IoExec::Pool(_) => write!(f, "pool"),
+        }
+    }
+}
+
+/// How the gRPC server runs on a worker pool.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RuntimeFlavor {
+    /// One multi-threaded runtime with a worker thread per worker of the pool.
+    MultiThread,
+    /// One current-thread runtime per worker, each with its own listener.
+    PerCore,
+}
+
+impl std::fmt::Display for RuntimeFlavor {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RuntimeFlavor::MultiThread => write!(f, "multi"),
+            RuntimeFlavor::PerCore => write!(f, "per-core"),
+        }
+    }
+}
+
+impl FromStr for RuntimeFlavor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "multi" => Ok(RuntimeFlavor::MultiThread),
+            "per-core" => Ok(RuntimeFlavor::PerCore),
+            _ => Err(format!("Unknown runtime flavour {}", s)),
+        }
+    }
+}
+
+/// Fixed set of server threads, each pinned to a core.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WorkerPool {
+    pub workers: usize,
+    /// Core of every worker, in order.
+    pub cores: Vec<Cpu>,
+}
+
+impl WorkerPool {
+    /// Pins the workers to `cores`, round-robin if there are fewer cores than workers.
+    /// Without cores, the workers get the first cores of the machine, one per core and
+    /// filling a socket before moving on to the next.
+    pub fn new(workers: usize, cores: Option<Vec<Cpu>>) -> Result<WorkerPool, String> {
+        if workers == 0 {
+            return Err(String::from("A worker pool needs at least one worker"));
+        }
+        let cores = match cores {
+            Some(cores) if cores.is_empty() => return Err(String::from("No cores given")),
+            Some(cores) => cores,
+            None => MachineTopology::new()
+                .allocate(ThreadMapping::Sequential, workers, false)
+                .iter()
+                .map(|c| c.cpu)
+                .collect(),
+        };
+        Ok(WorkerPool {
+            workers: workers,
+            cores: (0..workers).map(|i| cores[i % cores.len()]).collect(),
+        })
+    }
+}
+
+/// Parses a list of cores like `0-3,8,10-11`.
+pub fn parse_cores(list: &str) -> Result<Vec<Cpu>, String> {
+    let mut cores = Vec::new();
+    for range in list.split(',') {
+        let core = |s: &str| {
+            s.trim()
+                .parse::<Cpu>()
+                .map_err(|_| format!("Invalid core '{}' in '{}'", s, list))
+        };
+        match range.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (core(first)?, core(last)?);
+                if first > last {
+                    return Err(format!("Empty core range '{}' in '{}'", range, list));
+                }
+                cores.extend(first..=last);
+            }
+            None => cores.push(core(range)?),
+        }
+    }
+    Ok(cores)
+}
@@ -0,0 +1,108 @@
+use libc::*;
+use std::ffi::CStr;
+
+use crate::fxrpc::error::errno_for;
+
+/// Most entries a server returns per `rpc_readdir` call, whatever the client asks for.
+pub const READDIR_MAX_COUNT: usize = 1024;
+
+/// A directory entry returned by `rpc_readdir`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DirEntry {
+    pub name: String,
+    /// One of the `libc::DT_*` constants, `DT_UNKNOWN` if the file system does not say.
+    pub file_type: u8,
+    pub ino: u64,
+    /// Position right after this entry, listing resumes here when passed back as cookie.
+    pub cookie: u64,
+}
+
+/// One page of a directory listing.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DirPage {
+    pub entries: Vec<DirEntry>,
+    /// Cookie to pass to the next `rpc_readdir` call to continue the listing.
+    pub cookie: u64,
+    /// Set once the end of the directory has been reached.
+    pub eof: bool,
+}
+
+impl DirPage {
+    // Drops all entries from `len` on, resuming the listing after the last one kept
+    pub(crate) fn truncate(&mut self, len: usize) {
+        if len < self.entries.len() {
+            self.entries.truncate(len);
+            self.eof = false;
+            if let Some(last) = self.entries.last() {
+                self.cookie = last.cookie;
+            }
+        }
+    }
+}
+
+// Reads up to `count` entries of the open directory `fd`, starting after `cookie`
+// (0 starts from the beginning), and closes it. "." and ".." are skipped. Cookies are the
+// d_off values of the entries, which stay valid across opens of the same directory.
+// Names are sent as strings, so a name that is not UTF-8 fails the page with EILSEQ.
+pub(crate) fn read_dir_page(fd: i32, cookie: u64, count: usize) -> Result<DirPage, i32> {
+    // An empty page leaves the cookie where it was, paging would never get anywhere
+    if count == 0 {
+        unsafe { close(fd) };
+        return Err(EINVAL);
+    }
+    let count = count.min(READDIR_MAX_COUNT);
+    let mut page = DirPage {
+        entries: Vec::new(),
+        cookie: cookie,
+        eof: false,
+    };
+
+    unsafe {
+        let dir = fdopendir(fd);
+        if dir.is_null() {
+            let errno = errno_for(-1);
+            close(fd);
+            return Err(errno);
+        }
+        if cookie != 0 {
+            seekdir(dir, cookie as c_long);
+        }
+
+        while page.entries.len() < count {
+            // readdir() only sets errno on failure, not at the end of the directory
+            *__errno_location() = 0;
+            let ent = readdir(dir);
+            if ent.is_null() {
+                let errno = *__errno_location();
+                closedir(dir);
+                if errno != 0 {
+                    return Err(errno);
+                }
+                page.eof = true;
+                return Ok(page);
+            }
+
+            let ent = &*ent;
+            page.cookie = ent.d_off as u64;
+            let name = CStr::from_ptr(ent.d_name.as_ptr());
+            if name.to_bytes() == b"." || name.to_bytes() == b".." {
+                continue;
+            }
+            let name = match name.to_str() {
+                Ok(name) => name.to_owned(),
+                Err(_) => {
+                    closedir(dir);
+                    return Err(EILSEQ);
+                }
+            };
+            page.entries.push(DirEntry {
+                name: name,
+                file_type: ent.d_type,
+                ino: ent.d_ino,
+                cookie: page.cookie,
+            });
+        }
+        closedir(dir);
+    }
+    Ok(page)
+}
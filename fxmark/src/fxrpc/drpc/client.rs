@@ -3,293 +3,438 @@ use rpc::client::Client;
 use rpc::rpc::*;
 use rpc::transport::stdtcp::*;
 use rpc::transport::uds::*;
+use rpc::transport::Transport;
 use std::net::TcpStream;
+use std::ops::Range;
 use std::os::unix::net::UnixStream;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 
-use abomonation::{decode, encode};
-
 use crate::fxrpc::drpc::*;
-use crate::fxrpc::FxRPC;
-use crate::fxrpc::PAGE_SIZE;
+use crate::fxrpc::iov::{advance, batches, segments_in, segments_in_mut, IOV_MAX};
+use crate::fxrpc::{
+    Compound, DirPage, FileStat, FxRPC, FxRpcError, OpResult, ServerStats, TlsConfig,
+};
 
 ////////////////////////////////// CLIENT //////////////////////////////////
 
-fn decode_response(payload: &mut [u8]) -> (i32, usize, Vec<u8>) {
-    match unsafe { decode::<Response>(payload) } {
-        Some((req, _)) => (req.result, req.size, req.page.clone()),
-        None => panic!("Cannot decode response!"),
-    }
+/// DRPC client that sends one request at a time, in the codec picked at connect time.
+pub struct DrpcClient {
+    client: Client,
+    codec: Codec,
+    // Largest read or write the server takes in one request
+    max_io: usize,
 }
 
-// TODO: ERROR HANDLING
+impl DrpcClient {
+    // Wire header and encoded request, ready to send
+    fn request<T: Message>(&self, request: &T) -> Vec<u8> {
+        let mut bytes = self.codec.header().to_vec();
+        self.codec.encode(request, &mut bytes);
+        bytes
+    }
 
-impl FxRPC for Client {
-    fn rpc_open(
+    // Sends a request made of `data_in` and receives the data following the response
+    // header into `data_out`, in order, so it lands directly in the caller's buffers.
+    // Returns the result and the size of that data.
+    fn call(
         &mut self,
-        path: &str,
-        flags: i32,
-        mode: u32,
-    ) -> Result<i32, Box<dyn std::error::Error>> {
-        let request = OpenReq {
-            path: path.as_bytes().to_vec(),
-            flags: flags,
-            mode: mode,
-        };
-
-        let mut bytes = Vec::new();
-        unsafe { encode(&request, &mut bytes) }.expect("Failed to encode open request");
-        let mut data_out = [0u8; std::mem::size_of::<Response>()];
-
-        match self.call(DRPC::Open as RPCType, &[&bytes], &mut [&mut data_out]) {
-            Ok(_) => {
-                let (result, size, page) = decode_response(&mut data_out);
-                debug!(
-                    "Received - result: {:?}, size: {:?}, page: {:?}",
-                    result, size, page
-                );
-                Ok(result)
-            }
-            Err(_) => Err(Box::from("Open RPC failed")),
+        rpc: DRPC,
+        data_in: &[&[u8]],
+        data_out: &mut [&mut [u8]],
+    ) -> Result<(i32, usize), FxRpcError> {
+        let mut response = [0u8; RESPONSE_LEN];
+        let mut bufs: Vec<&mut [u8]> = Vec::with_capacity(data_out.len() + 1);
+        bufs.push(&mut response);
+        bufs.extend(data_out.iter_mut().map(|buf| &mut **buf));
+
+        if let Err(e) = self.client.call(rpc as RPCType, data_in, &mut bufs) {
+            return Err(FxRpcError::Transport(format!(
+                "{:?} RPC failed: {:?}",
+                rpc, e
+            )));
         }
+        let (result, errno, size) = read_response(&mut response)?;
+        debug!(
+            "Received - result: {:?}, errno: {:?}, size: {:?}",
+            result, errno, size
+        );
+        Ok((FxRpcError::check(result, errno)?, size))
     }
 
-    fn rpc_read(
+    // Reads into `page` in a single request, `offset` only applies to PRead
+    fn read_chunk(
         &mut self,
+        rpc: DRPC,
         fd: i32,
-        page: &mut Vec<u8>,
-        size: usize,
-    ) -> Result<i32, Box<dyn std::error::Error>> {
+        page: &mut [u8],
+        offset: i64,
+    ) -> Result<i32, FxRpcError> {
         let request = ReadReq {
             fd: fd,
-            size: size,
-            offset: 0,
+            size: page.len(),
+            offset: offset,
+            segments: Vec::new(),
         };
-
-        let mut bytes = Vec::new();
-        unsafe { encode(&request, &mut bytes) }.expect("Failed to encode open request");
-
-        // probably a bit conservative
-        let mut data_out = [0u8; 2 * PAGE_SIZE];
-
-        match self.call(DRPC::Read as RPCType, &[&bytes], &mut [&mut data_out]) {
-            Ok(_) => {
-                let (result, size, ret_page) = decode_response(&mut data_out);
-                debug!(
-                    "Received - result: {:?}, size: {:?}, page: {:?}",
-                    result, size, ret_page
-                );
-                *page = ret_page;
-
-                Ok(result)
-            }
-            Err(_) => Err(Box::from("Read RPC failed")),
-        }
+        let bytes = self.request(&request);
+        let (result, _) = self.call(rpc, &[&bytes], &mut [page])?;
+        Ok(result)
     }
 
-    fn rpc_pread(
+    // Writes `page` in a single request, `offset` only applies to PWrite. The page is
+    // sent straight from the caller's buffer after the request header.
+    fn write_chunk(
         &mut self,
+        rpc: DRPC,
         fd: i32,
-        page: &mut Vec<u8>,
-        size: usize,
+        page: &[u8],
         offset: i64,
-    ) -> Result<i32, Box<dyn std::error::Error>> {
-        let request = ReadReq {
+    ) -> Result<i32, FxRpcError> {
+        let request = WriteReq {
             fd: fd,
-            size: size,
+            size: page.len(),
             offset: offset,
+            segments: Vec::new(),
         };
-
-        let mut bytes = Vec::new();
-        unsafe { encode(&request, &mut bytes) }.expect("Failed to encode open request");
-
-        // probably a bit conservative
-        let mut data_out = [0u8; 2 * PAGE_SIZE];
-
-        match self.call(DRPC::PRead as RPCType, &[&bytes], &mut [&mut data_out]) {
-            Ok(_) => {
-                let (result, size, ret_page) = decode_response(&mut data_out);
-                debug!(
-                    "Received - result: {:?}, size: {:?}, page: {:?}",
-                    result, size, ret_page
-                );
-                *page = ret_page;
-
-                Ok(result)
-            }
-            Err(_) => Err(Box::from("PRead RPC failed")),
-        }
+        let bytes = self.request(&request);
+        let (result, _) = self.call(rpc, &[&bytes, page], &mut [])?;
+        Ok(result)
     }
 
-    fn rpc_write(
+    // Vectored reads and writes of at most `max_io` bytes, which go in a single request
+    fn preadv_batch(
         &mut self,
         fd: i32,
-        page: &Vec<u8>,
-        size: usize,
-    ) -> Result<i32, Box<dyn std::error::Error>> {
-        let request = WriteReq {
+        segments: &mut [(i64, &mut [u8])],
+    ) -> Result<i32, FxRpcError> {
+        let request = ReadReq {
             fd: fd,
-            page: page.to_vec(),
-            size: size,
+            size: segments.iter().map(|(_, buf)| buf.len()).sum(),
             offset: 0,
+            segments: segments
+                .iter()
+                .map(|(offset, buf)| (*offset, buf.len()))
+                .collect(),
         };
+        let bytes = self.request(&request);
 
-        let mut bytes = Vec::new();
-        unsafe { encode(&request, &mut bytes) }.expect("Failed to encode open request");
-        let mut data_out = [0u8; std::mem::size_of::<Response>()];
-
-        match self.call(DRPC::Write as RPCType, &[&bytes], &mut [&mut data_out]) {
-            Ok(_) => {
-                let (result, size, page) = decode_response(&mut data_out);
-                debug!(
-                    "Received - result: {:?}, size: {:?}, page: {:?}",
-                    result, size, page
-                );
-
-                Ok(result)
-            }
-            Err(_) => Err(Box::from("Write RPC failed")),
-        }
+        // The segments land directly in the caller's buffers, in order
+        let mut data_out: Vec<&mut [u8]> = segments.iter_mut().map(|(_, buf)| &mut **buf).collect();
+        let (result, _) = self.call(DRPC::PReadV, &[&bytes], &mut data_out)?;
+        Ok(result)
     }
 
-    fn rpc_pwrite(
-        &mut self,
-        fd: i32,
-        page: &Vec<u8>,
-        size: usize,
-        offset: i64,
-    ) -> Result<i32, Box<dyn std::error::Error>> {
+    fn pwritev_batch(&mut self, fd: i32, segments: &[(i64, &[u8])]) -> Result<i32, FxRpcError> {
         let request = WriteReq {
             fd: fd,
-            page: page.to_vec(),
-            size: size,
-            offset: offset,
+            size: segments.iter().map(|(_, buf)| buf.len()).sum(),
+            offset: 0,
+            segments: segments
+                .iter()
+                .map(|(offset, buf)| (*offset, buf.len()))
+                .collect(),
         };
+        let bytes = self.request(&request);
+
+        // The segments are sent straight from the caller's buffers after the request
+        let mut data_in: Vec<&[u8]> = Vec::with_capacity(segments.len() + 1);
+        data_in.push(&bytes);
+        data_in.extend(segments.iter().map(|(_, buf)| *buf));
+        let (result, _) = self.call(DRPC::PWriteV, &data_in, &mut [])?;
+        Ok(result)
+    }
+}
 
-        let mut bytes = Vec::new();
-        unsafe { encode(&request, &mut bytes) }.expect("Failed to encode open request");
-        let mut data_out = [0u8; std::mem::size_of::<Response>()];
-
-        match self.call(DRPC::PWrite as RPCType, &[&bytes], &mut [&mut data_out]) {
-            Ok(_) => {
-                let (result, size, page) = decode_response(&mut data_out);
-                debug!(
-                    "Received - result: {:?}, size: {:?}, page: {:?}",
-                    result, size, page
-                );
+// Ranges of a transfer of `len` bytes that each fit a request of at most `max_io` bytes.
+// An empty transfer is still a single, empty request.
+pub(crate) fn io_chunks(len: usize, max_io: usize) -> impl Iterator<Item = Range<usize>> {
+    let step = max_io.max(1);
+    (0..len.max(1))
+        .step_by(step)
+        .map(move |start| start..start.saturating_add(step).min(len))
+}
 
-                Ok(result)
-            }
-            Err(_) => Err(Box::from("PWrite RPC failed")),
+// Moves `len` bytes a chunk at a time, `transfer` moving the range it is given
+fn chunked<F>(len: usize, max_io: usize, mut transfer: F) -> Result<i32, FxRpcError>
+where
+    F: FnMut(Range<usize>) -> Result<i32, FxRpcError>,
+{
+    let mut done = 0;
+    for chunk in io_chunks(len, max_io) {
+        let want = chunk.len();
+        if !advance(&mut done, want, transfer(chunk))? {
+            break;
         }
     }
+    Ok(done as i32)
+}
 
-    fn rpc_close(&mut self, fd: i32) -> Result<i32, Box<dyn std::error::Error>> {
-        let request = CloseReq { fd: fd };
+// Issues a request whose response carries no data beyond the response header
+fn call_fixed<T: Message>(
+    client: &mut DrpcClient,
+    rpc: DRPC,
+    request: &T,
+) -> Result<i32, FxRpcError> {
+    let bytes = client.request(request);
+    let (result, _) = client.call(rpc, &[&bytes], &mut [])?;
+    Ok(result)
+}
 
-        let mut bytes = Vec::new();
-        unsafe { encode(&request, &mut bytes) }.expect("Failed to encode open request");
-        let mut data_out = [0u8; std::mem::size_of::<Response>()];
+fn call_stat(client: &mut DrpcClient, rpc: DRPC, path: &str) -> Result<FileStat, FxRpcError> {
+    let request = StatReq {
+        path: path.as_bytes().to_vec(),
+    };
+    let bytes = client.request(&request);
+    // The little-endian codec never takes more room than abomonation
+    let mut stat_out = [0u8; std::mem::size_of::<FileStat>()];
+    let (_, size) = client.call(rpc, &[&bytes], &mut [&mut stat_out])?;
+    decode_stat(client.codec, &mut stat_out[..size.min(stat_out.len())])
+}
 
-        match self.call(DRPC::Close as RPCType, &[&bytes], &mut [&mut data_out]) {
-            Ok(_) => {
-                let (result, size, page) = decode_response(&mut data_out);
-                debug!(
-                    "Received - result: {:?}, size: {:?}, page: {:?}",
-                    result, size, page
-                );
+impl FxRPC for DrpcClient {
+    fn rpc_open(&mut self, path: &str, flags: i32, mode: u32) -> Result<i32, FxRpcError> {
+        let request = OpenReq {
+            path: path.as_bytes().to_vec(),
+            flags: flags,
+            mode: mode,
+        };
+        call_fixed(self, DRPC::Open, &request)
+    }
 
-                Ok(result)
-            }
-            Err(_) => Err(Box::from("Close RPC failed")),
-        }
+    fn rpc_read(&mut self, fd: i32, page: &mut [u8]) -> Result<i32, FxRpcError> {
+        let max_io = self.max_io;
+        chunked(page.len(), max_io, |chunk| {
+            self.read_chunk(DRPC::Read, fd, &mut page[chunk], 0)
+        })
     }
 
-    fn rpc_remove(&mut self, path: &str) -> Result<i32, Box<dyn std::error::Error>> {
-        let request = RemoveReq {
-            path: path.as_bytes().to_vec(),
-        };
+    fn rpc_pread(&mut self, fd: i32, page: &mut [u8], offset: i64) -> Result<i32, FxRpcError> {
+        let max_io = self.max_io;
+        chunked(page.len(), max_io, |chunk| {
+            let offset = offset + chunk.start as i64;
+            self.read_chunk(DRPC::PRead, fd, &mut page[chunk], offset)
+        })
+    }
 
-        let mut bytes = Vec::new();
-        unsafe { encode(&request, &mut bytes) }.expect("Failed to encode open request");
-        let mut data_out = [0u8; std::mem::size_of::<Response>()];
+    fn rpc_write(&mut self, fd: i32, page: &[u8]) -> Result<i32, FxRpcError> {
+        let max_io = self.max_io;
+        chunked(page.len(), max_io, |chunk| {
+            self.write_chunk(DRPC::Write, fd, &page[chunk], 0)
+        })
+    }
 
-        match self.call(DRPC::Remove as RPCType, &[&bytes], &mut [&mut data_out]) {
-            Ok(_) => {
-                let (result, size, page) = decode_response(&mut data_out);
-                debug!(
-                    "Received - result: {:?}, size: {:?}, page: {:?}",
-                    result, size, page
-                );
+    fn rpc_pwrite(&mut self, fd: i32, page: &[u8], offset: i64) -> Result<i32, FxRpcError> {
+        let max_io = self.max_io;
+        chunked(page.len(), max_io, |chunk| {
+            let offset = offset + chunk.start as i64;
+            self.write_chunk(DRPC::PWrite, fd, &page[chunk], offset)
+        })
+    }
 
-                Ok(result)
+    fn rpc_preadv(
+        &mut self,
+        fd: i32,
+        segments: &mut [(i64, &mut [u8])],
+    ) -> Result<i32, FxRpcError> {
+        let lens = segments.iter().map(|(_, buf)| buf.len());
+        let mut done = 0;
+        for batch in batches(lens, self.max_io, IOV_MAX) {
+            let want = batch.len();
+            let res = self.preadv_batch(fd, &mut segments_in_mut(segments, batch));
+            if !advance(&mut done, want, res)? {
+                break;
             }
-            Err(_) => Err(Box::from("Remove RPC failed")),
         }
+        Ok(done as i32)
     }
 
-    fn rpc_mkdir(&mut self, path: &str, mode: u32) -> Result<i32, Box<dyn std::error::Error>> {
+    fn rpc_pwritev(&mut self, fd: i32, segments: &[(i64, &[u8])]) -> Result<i32, FxRpcError> {
+        let lens = segments.iter().map(|(_, buf)| buf.len());
+        let mut done = 0;
+        for batch in batches(lens, self.max_io, IOV_MAX) {
+            let want = batch.len();
+            let res = self.pwritev_batch(fd, &segments_in(segments, batch));
+            if !advance(&mut done, want, res)? {
+                break;
+            }
+        }
+        Ok(done as i32)
+    }
+
+    fn rpc_close(&mut self, fd: i32) -> Result<i32, FxRpcError> {
+        call_fixed(self, DRPC::Close, &CloseReq { fd: fd })
+    }
+
+    fn rpc_remove(&mut self, path: &str) -> Result<i32, FxRpcError> {
+        let request = RemoveReq {
+            path: path.as_bytes().to_vec(),
+        };
+        call_fixed(self, DRPC::Remove, &request)
+    }
+
+    fn rpc_mkdir(&mut self, path: &str, mode: u32) -> Result<i32, FxRpcError> {
         let request = MkdirReq {
             path: path.as_bytes().to_vec(),
             mode: mode,
         };
+        call_fixed(self, DRPC::MkDir, &request)
+    }
+
+    fn rpc_rmdir(&mut self, path: &str) -> Result<i32, FxRpcError> {
+        let request = RmdirReq {
+            path: path.as_bytes().to_vec(),
+        };
+        call_fixed(self, DRPC::RmDir, &request)
+    }
 
-        let mut bytes = Vec::new();
-        unsafe { encode(&request, &mut bytes) }.expect("Failed to encode open request");
-        let mut data_out = [0u8; std::mem::size_of::<Response>()];
+    fn rpc_fsync(&mut self, fd: i32) -> Result<i32, FxRpcError> {
+        call_fixed(self, DRPC::Fsync, &FsyncReq { fd: fd })
+    }
 
-        match self.call(DRPC::MkDir as RPCType, &[&bytes], &mut [&mut data_out]) {
-            Ok(_) => {
-                let (result, size, page) = decode_response(&mut data_out);
-                debug!(
-                    "Received - result: {:?}, size: {:?}, page: {:?}",
-                    result, size, page
-                );
+    fn rpc_fstat(&mut self, fd: i32) -> Result<i64, FxRpcError> {
+        let bytes = self.request(&FstatReq { fd: fd });
+        // The size of the file comes back in place of a data length
+        let (_, size) = self.call(DRPC::Fstat, &[&bytes], &mut [])?;
+        Ok(size as i64)
+    }
 
-                Ok(result)
-            }
-            Err(_) => Err(Box::from("Mkdir RPC failed")),
-        }
+    fn rpc_readdir(&mut self, path: &str, cookie: u64, count: u32) -> Result<DirPage, FxRpcError> {
+        let request = ReaddirReq {
+            path: path.as_bytes().to_vec(),
+            cookie: cookie,
+            count: count,
+        };
+        let bytes = self.request(&request);
+        let mut listing = vec![0u8; READDIR_BUF_LEN];
+
+        let (count, size) = self.call(DRPC::Readdir, &[&bytes], &mut [&mut listing])?;
+        listing
+            .get_mut(..size)
+            .and_then(|listing| decode_listing(self.codec, listing, count as usize))
+            .ok_or_else(|| FxRpcError::Decode(String::from("Cannot decode listing")))
     }
 
-    fn rpc_rmdir(&mut self, path: &str) -> Result<i32, Box<dyn std::error::Error>> {
+    fn rpc_rename(&mut self, oldpath: &str, newpath: &str, flags: u32) -> Result<i32, FxRpcError> {
+        let request = RenameReq {
+            oldpath: oldpath.as_bytes().to_vec(),
+            newpath: newpath.as_bytes().to_vec(),
+            flags: flags,
+        };
+        call_fixed(self, DRPC::Rename, &request)
+    }
+
+    fn rpc_link(&mut self, oldpath: &str, newpath: &str) -> Result<i32, FxRpcError> {
+        let request = LinkReq {
+            oldpath: oldpath.as_bytes().to_vec(),
+            newpath: newpath.as_bytes().to_vec(),
+        };
+        call_fixed(self, DRPC::Link, &request)
+    }
+
+    fn rpc_unlink(&mut self, path: &str) -> Result<i32, FxRpcError> {
         let request = RemoveReq {
             path: path.as_bytes().to_vec(),
         };
+        call_fixed(self, DRPC::Unlink, &request)
+    }
+
+    fn rpc_truncate(&mut self, path: &str, len: i64) -> Result<i32, FxRpcError> {
+        let request = TruncateReq {
+            path: path.as_bytes().to_vec(),
+            len: len,
+        };
+        call_fixed(self, DRPC::Truncate, &request)
+    }
 
-        let mut bytes = Vec::new();
-        unsafe { encode(&request, &mut bytes) }.expect("Failed to encode open request");
-        let mut data_out = [0u8; std::mem::size_of::<Response>()];
+    fn rpc_ftruncate(&mut self, fd: i32, len: i64) -> Result<i32, FxRpcError> {
+        call_fixed(self, DRPC::FTruncate, &FTruncateReq { fd: fd, len: len })
+    }
 
-        match self.call(DRPC::RmDir as RPCType, &[&bytes], &mut [&mut data_out]) {
-            Ok(_) => {
-                let (result, size, page) = decode_response(&mut data_out);
-                debug!(
-                    "Received - result: {:?}, size: {:?}, page: {:?}",
-                    result, size, page
-                );
+    fn rpc_stat(&mut self, path: &str) -> Result<FileStat, FxRpcError> {
+        call_stat(self, DRPC::Stat, path)
+    }
 
-                Ok(result)
-            }
-            Err(_) => Err(Box::from("Rmdir RPC failed")),
+    fn rpc_lstat(&mut self, path: &str) -> Result<FileStat, FxRpcError> {
+        call_stat(self, DRPC::LStat, path)
+    }
+
+    fn rpc_compound(&mut self, compound: &Compound) -> Result<Vec<OpResult>, FxRpcError> {
+        let request = CompoundReq {
+            ops: compound.ops.iter().map(CompoundOpReq::from).collect(),
+        };
+        let bytes = self.request(&request);
+        let mut results = vec![0u8; compound_res_len(compound)];
+
+        let (_, size) = self.call(DRPC::Compound, &[&bytes], &mut [&mut results])?;
+        results
+            .get_mut(..size)
+            .and_then(|results| decode_compound(self.codec, results))
+            .ok_or_else(|| FxRpcError::Decode(String::from("Cannot decode results")))
+    }
+
+    fn rpc_stats(&mut self) -> Result<ServerStats, FxRpcError> {
+        let header = self.codec.header();
+        let mut stats = vec![0u8; stats_res_len()];
+
+        let (_, size) = self.call(DRPC::Stats, &[&header], &mut [&mut stats])?;
+        match stats.get_mut(..size) {
+            Some(stats) => decode_stats(self.codec, stats),
+            None => Err(FxRpcError::Decode(String::from("Stats response too large"))),
         }
     }
+
+    fn rpc_shutdown(&mut self, token: &str) -> Result<i32, FxRpcError> {
+        let request = ShutdownReq {
+            token: token.as_bytes().to_vec(),
+        };
+        call_fixed(self, DRPC::Shutdown, &request)
+    }
 }
 
-pub fn init_client_drpc_tcp(bind_addr: &str) -> Client {
-    let stream = TcpStream::connect(bind_addr).unwrap();
-    let transport = StdTCP {
-        stream: Arc::new(Mutex::new(stream)),
+// Connects over TLS if there is a configuration for it
+pub fn init_client_drpc_tcp(
+    bind_addr: &str,
+    codec: Codec,
+    tls: Option<&TlsConfig>,
+) -> Result<DrpcClient, FxRpcError> {
+    let (transport, max_io): (Box<dyn Transport + Send>, usize) = match tls {
+        Some(tls) => {
+            let mut stream = connect_tls(bind_addr, tls)?;
+            let max_io = handshake(&mut stream, codec)?;
+            let transport = TlsTCP {
+                stream: Arc::new(Mutex::new(stream)),
+            };
+            (Box::new(transport), max_io)
+        }
+        None => {
+            let mut stream = TcpStream::connect(bind_addr).map_err(connect_failed)?;
+            let max_io = handshake(&mut stream, codec)?;
+            let transport = StdTCP {
+                stream: Arc::new(Mutex::new(stream)),
+            };
+            (Box::new(transport), max_io)
+        }
     };
-    let client = Client::new(Box::new(transport));
-    client
+    Ok(DrpcClient {
+        client: Client::new(transport),
+        codec: codec,
+        max_io: max_io,
+    })
 }
 
-pub fn init_client_drpc_uds(bind_addr: &str) -> Client {
-    let stream = UnixStream::connect(bind_addr).unwrap();
+pub fn init_client_drpc_uds(path: &Path, codec: Codec) -> Result<DrpcClient, FxRpcError> {
+    let mut stream = UnixStream::connect(path).map_err(connect_failed)?;
+    let max_io = handshake(&mut stream, codec)?;
     let transport = UDS {
         stream: Arc::new(Mutex::new(stream)),
     };
-    let client = Client::new(Box::new(transport));
-    client
+    Ok(DrpcClient {
+        client: Client::new(Box::new(transport)),
+        codec: codec,
+        max_io: max_io,
+    })
+}
+
+pub(crate) fn connect_failed(e: std::io::Error) -> FxRpcError {
+    FxRpcError::Transport(format!("Cannot connect: {}", e))
 }
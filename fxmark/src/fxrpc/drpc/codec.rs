@@ -0,0 +1,326 @@
+use abomonation::{decode, encode, Abomonation};
+use rpc::rpc::*;
+use std::io::{Read, Write};
+
+use crate::fxrpc::drpc::pipeline::{hdr_bytes, hdr_bytes_mut};
+use crate::fxrpc::drpc::DRPC;
+use crate::fxrpc::FxRpcError;
+
+////////////////////////////////// WIRE FORMAT //////////////////////////////////
+
+/// Version of the DRPC messages, bumped whenever one of them changes.
+pub const WIRE_VERSION: u8 = 1;
+
+const MAGIC: [u8; 4] = *b"FXRP";
+
+/// Length of the header every request and response starts with: the magic, the wire
+/// version, the codec of the message and padding that keeps the message aligned.
+pub const WIRE_HDR_LEN: usize = 8;
+
+/// How DRPC messages are serialised. The client picks one when it connects, the server
+/// answers every request in the codec it arrived in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum Codec {
+    /// The in-memory layout of the messages, only understood by builds for the same
+    /// pointer width and endianness.
+    #[default]
+    Abomonation = 1,
+    /// A fixed little-endian layout that does not depend on the host.
+    Le = 2,
+}
+
+impl Codec {
+    fn from_id(id: u8) -> Option<Codec> {
+        match id {
+            id if id == Codec::Abomonation as u8 => Some(Codec::Abomonation),
+            id if id == Codec::Le as u8 => Some(Codec::Le),
+            _ => None,
+        }
+    }
+
+    /// Header announcing a message in this codec.
+    pub fn header(&self) -> [u8; WIRE_HDR_LEN] {
+        let mut hdr = [0u8; WIRE_HDR_LEN];
+        hdr[..MAGIC.len()].copy_from_slice(&MAGIC);
+        hdr[4] = WIRE_VERSION;
+        hdr[5] = *self as u8;
+        hdr
+    }
+
+    pub(crate) fn encode<T: Message>(&self, msg: &T, out: &mut Vec<u8>) {
+        match self {
+            Codec::Abomonation => {
+                unsafe { encode(msg, out) }.expect("Failed to encode message");
+            }
+            Codec::Le => msg.put(out),
+        }
+    }
+
+    /// Decodes a message from the start of `bytes`, None if they do not hold a valid
+    /// one. Returns a copy of the message and whatever follows it.
+    pub(crate) fn decode<'a, T: Message>(&self, bytes: &'a mut [u8]) -> Option<(T, &'a mut [u8])> {
+        let (msg, rest) = match self {
+            Codec::Abomonation if bytes.as_ptr().align_offset(std::mem::align_of::<T>()) == 0 => {
+                decode_in_place::<T>(bytes)?
+            }
+            Codec::Abomonation => {
+                // abomonation reads messages where they are, so misaligned ones are
+                // decoded from an aligned copy
+                let mut words = vec![0u64; (bytes.len() + 7) / 8];
+                let copy = unsafe {
+                    std::slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, bytes.len())
+                };
+                copy.copy_from_slice(bytes);
+                decode_in_place::<T>(copy)?
+            }
+            Codec::Le => {
+                let mut input: &[u8] = bytes;
+                let msg = T::take(&mut input)?;
+                (msg, input.len())
+            }
+        };
+        let used = bytes.len() - rest;
+        Some((msg, &mut bytes[used..]))
+    }
+}
+
+// Decodes an abomonation message at an aligned address, returning a copy of it and the
+// number of bytes that follow it
+fn decode_in_place<T: Message>(bytes: &mut [u8]) -> Option<(T, usize)> {
+    let len = bytes.len().checked_sub(std::mem::size_of::<T>())?;
+    // The head of the message is in bounds and aligned, and `fits` only reads the lengths
+    // of its vectors, never what they point to
+    if !unsafe { &*(bytes.as_ptr() as *const T) }.fits(len) {
+        return None;
+    }
+    let (msg, rest) = unsafe { decode::<T>(bytes) }?;
+    Some((msg.clone(), rest.len()))
+}
+
+impl std::fmt::Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Codec::Abomonation => write!(f, "abomonation"),
+            Codec::Le => write!(f, "le"),
+        }
+    }
+}
+
+impl std::str::FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "abomonation" => Ok(Codec::Abomonation),
+            "le" => Ok(Codec::Le),
+            _ => Err(format!("Unknown codec {}", s)),
+        }
+    }
+}
+
+/// Why the header of a message was refused.
+#[derive(Debug, PartialEq)]
+pub enum HeaderError {
+    /// Too short or without the magic, not a DRPC message of any version.
+    Malformed,
+    /// Written by a build speaking another wire version.
+    Version(u8),
+    /// In a codec this build does not know.
+    Codec(u8),
+}
+
+impl std::fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HeaderError::Malformed => write!(f, "message without a DRPC wire header"),
+            HeaderError::Version(version) => write!(
+                f,
+                "peer speaks DRPC wire version {}, this build {}",
+                version, WIRE_VERSION
+            ),
+            HeaderError::Codec(id) => write!(f, "peer uses unknown codec {}", id),
+        }
+    }
+}
+
+/// Reads the header a message starts with and returns the codec of the message.
+pub fn read_header(bytes: &[u8]) -> Result<Codec, HeaderError> {
+    match bytes.get(..WIRE_HDR_LEN) {
+        Some(hdr) if hdr[..MAGIC.len()] == MAGIC => {
+            if hdr[4] != WIRE_VERSION {
+                return Err(HeaderError::Version(hdr[4]));
+            }
+            Codec::from_id(hdr[5]).ok_or(HeaderError::Codec(hdr[5]))
+        }
+        _ => Err(HeaderError::Malformed),
+    }
+}
+
+/// Reads the result, errno and data size a response reports. Responses are decoded in
+/// the codec they name, errors may come back in another codec than the request went out.
+pub fn read_response(payload: &mut [u8]) -> Result<(i32, i32, usize), FxRpcError> {
+    let codec = read_header(payload).map_err(|e| FxRpcError::Decode(e.to_string()))?;
+    match payload
+        .get_mut(WIRE_HDR_LEN..)
+        .and_then(|body| codec.decode::<Response>(body))
+    {
+        Some((res, _)) => Ok((res.result, res.errno, res.size)),
+        None => Err(FxRpcError::Decode(String::from("Cannot decode response"))),
+    }
+}
+
+/// Checks over a fresh connection that the server speaks this wire version and `codec`,
+/// before any request relies on it. Returns the largest read or write the server takes in
+/// one request, unbounded for servers that do not say.
+pub(crate) fn handshake<S: Read + Write>(
+    stream: &mut S,
+    codec: Codec,
+) -> Result<usize, FxRpcError> {
+    let transport = |e: std::io::Error| FxRpcError::Transport(format!("Hello failed: {}", e));
+
+    let mut hdr: RPCHeader = unsafe { std::mem::zeroed() };
+    hdr.msg_type = DRPC::Hello as RPCType;
+    hdr.msg_len = WIRE_HDR_LEN as MsgLen;
+    stream.write_all(hdr_bytes(&hdr)).map_err(transport)?;
+    stream.write_all(&codec.header()).map_err(transport)?;
+    stream.flush().map_err(transport)?;
+
+    stream
+        .read_exact(hdr_bytes_mut(&mut hdr))
+        .map_err(transport)?;
+    let mut response = vec![0u8; hdr.msg_len as usize];
+    stream.read_exact(&mut response).map_err(transport)?;
+    let (result, errno, max_io) = read_response(&mut response)?;
+    FxRpcError::check(result, errno)?;
+    Ok(if max_io == 0 { usize::MAX } else { max_io })
+}
+
+/// Messages either codec carries. abomonation takes the length of every vector it finds
+/// at its word, so `fits` refuses lengths that could not be followed within the `len`
+/// bytes behind the message before abomonation sees them. Vectors of bytes need no
+/// check, abomonation bounds those itself.
+pub(crate) trait Message: Abomonation + Wire + Clone {
+    fn fits(&self, _len: usize) -> bool {
+        true
+    }
+}
+
+////////////////////////////////// LITTLE-ENDIAN CODEC //////////////////////////////////
+
+/// Fixed little-endian layout of a value. Integers take their full width, `usize` that
+/// of a u64, and vectors are a u64 element count followed by the elements.
+pub(crate) trait Wire: Sized {
+    fn put(&self, out: &mut Vec<u8>);
+    fn take(input: &mut &[u8]) -> Option<Self>;
+}
+
+macro_rules! wire_int {
+    ($($t:ty),*) => {
+        $(
+            impl Wire for $t {
+                fn put(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_le_bytes());
+                }
+
+                fn take(input: &mut &[u8]) -> Option<Self> {
+                    const LEN: usize = std::mem::size_of::<$t>();
+                    let value = <$t>::from_le_bytes(input.get(..LEN)?.try_into().ok()?);
+                    *input = &input[LEN..];
+                    Some(value)
+                }
+            }
+        )*
+    };
+}
+
+wire_int!(u8, i32, u32, i64, u64);
+
+impl Wire for usize {
+    fn put(&self, out: &mut Vec<u8>) {
+        (*self as u64).put(out);
+    }
+
+    fn take(input: &mut &[u8]) -> Option<Self> {
+        usize::try_from(u64::take(input)?).ok()
+    }
+}
+
+impl Wire for bool {
+    fn put(&self, out: &mut Vec<u8>) {
+        (*self as u8).put(out);
+    }
+
+    fn take(input: &mut &[u8]) -> Option<Self> {
+        match u8::take(input)? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl<A: Wire, B: Wire> Wire for (A, B) {
+    fn put(&self, out: &mut Vec<u8>) {
+        self.0.put(out);
+        self.1.put(out);
+    }
+
+    fn take(input: &mut &[u8]) -> Option<Self> {
+        Some((A::take(input)?, B::take(input)?))
+    }
+}
+
+impl<T: Wire> Wire for Vec<T> {
+    fn put(&self, out: &mut Vec<u8>) {
+        self.len().put(out);
+        for element in self.iter() {
+            element.put(out);
+        }
+    }
+
+    fn take(input: &mut &[u8]) -> Option<Self> {
+        let len = usize::take(input)?;
+        // Every element takes at least a byte, which bounds what a bogus count allocates
+        if len > input.len() {
+            return None;
+        }
+        (0..len).map(|_| T::take(input)).collect()
+    }
+}
+
+// Lays out the named fields of a struct one after the other, like unsafe_abomonate!
+macro_rules! wire {
+    ($t:ty : $($field:ident),*) => {
+        impl Wire for $t {
+            fn put(&self, out: &mut Vec<u8>) {
+                $( self.$field.put(out); )*
+            }
+
+            fn take(input: &mut &[u8]) -> Option<Self> {
+                Some(Self {
+                    $( $field: Wire::take(input)?, )*
+                })
+            }
+        }
+    };
+}
+
+////////////////////////////////// RESPONSES //////////////////////////////////
+
+/// Fixed-size part of every response. For reads, `size` bytes of data follow it in the
+/// payload.
+#[derive(Clone)]
+pub struct Response {
+    pub result: i32,
+    pub errno: i32, // only set if result < 0
+    pub size: usize,
+}
+
+unsafe_abomonate!(Response : result, errno, size);
+wire!(Response : result, errno, size);
+
+impl Message for Response {}
+
+/// Length of the header and response of either codec, data follows at this offset.
+pub const RESPONSE_LEN: usize = WIRE_HDR_LEN + 16;
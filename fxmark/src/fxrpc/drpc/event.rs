@@ -0,0 +1,447 @@
+use libc::{EMSGSIZE, ENOSYS};
+use log::debug;
+use mio::event::Source;
+use mio::net::{TcpListener, UnixListener};
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
+use rpc::rpc::*;
+use rpc::server::RPCHandler;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::fxmark::utils::{pin_thread, topology::Cpu};
+use crate::fxrpc::drpc::pipeline::{hdr_bytes, hdr_bytes_mut};
+use crate::fxrpc::drpc::server::{
+    backend, dispatch_table, enter_session, error_response, max_request_len, payload_len,
+    set_backend, ACCEPT_POLL,
+};
+use crate::fxrpc::{prepare_uds_path, shutdown, FsBackend, HandleTable, WorkerPool};
+
+////////////////////////////////// EVENT-DRIVEN SERVER //////////////////////////////////
+
+const HDR_LEN: usize = std::mem::size_of::<RPCHeader>();
+
+// Bytes read from a connection at once
+const READ_CHUNK: usize = 64 * 1024;
+
+// Responses queued on a connection past which its requests are left in the socket until
+// the client takes some, so a client that does not read its responses cannot make the
+// worker buffer them
+const OUTPUT_CAP: usize = 4 * 1024 * 1024;
+
+// Time connections get to take their queued responses once shutdown is requested, after
+// which the ones still holding some are dropped
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(usize::MAX);
+
+// Sockets the event-driven server accepts connections on
+trait EventStream: Read + Write + Source + Send {}
+
+impl<T: Read + Write + Source + Send> EventStream for T {}
+
+enum Listener {
+    Tcp(TcpListener),
+    Uds(UnixListener),
+}
+
+impl Listener {
+    fn accept(&self) -> io::Result<Box<dyn EventStream>> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, _) = listener.accept()?;
+                let _ = stream.set_nodelay(true);
+                Ok(Box::new(stream))
+            }
+            Listener::Uds(listener) => {
+                let (stream, _) = listener.accept()?;
+                Ok(Box::new(stream))
+            }
+        }
+    }
+
+    fn register(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => registry.register(listener, LISTENER, Interest::READABLE),
+            Listener::Uds(listener) => registry.register(listener, LISTENER, Interest::READABLE),
+        }
+    }
+}
+
+// A client connection with the requests received and responses not sent yet
+struct Connection {
+    stream: Box<dyn EventStream>,
+    session: Arc<HandleTable>,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    written: usize,
+    // Bytes of a refused request still to be skipped
+    discard: usize,
+    // Requests were left unread because responses piled up. Readiness events only tell
+    // of new data, so reading resumes once the client took enough responses.
+    paused: bool,
+}
+
+impl Connection {
+    // Reads what the socket has, up to READ_CHUNK bytes. False once the client closed
+    // the connection.
+    fn receive(&mut self) -> io::Result<bool> {
+        loop {
+            let len = self.input.len();
+            self.input.resize(len + READ_CHUNK, 0);
+            let res = self.stream.read(&mut self.input[len..]);
+            self.input.truncate(len + *res.as_ref().unwrap_or(&0));
+            match res {
+                Ok(0) => return Ok(false),
+                Ok(_) => return Ok(true),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn backlogged(&self) -> bool {
+        self.output.len() - self.written >= OUTPUT_CAP
+    }
+
+    // Reads and handles requests until the socket has no more, or until responses pile up
+    // and the rest is left for later. False once the connection is done with.
+    fn pump(&mut self, table: &[Option<RPCHandler>], payload: &mut Vec<u8>) -> bool {
+        loop {
+            if let Err(e) = self.serve(table, payload) {
+                debug!("Dropping the connection ({:?})", e);
+                return false;
+            }
+            if self.backlogged() {
+                self.paused = true;
+                return true;
+            }
+            match self.receive() {
+                Ok(true) => {}
+                Ok(false) => {
+                    // The client may only have shut down its side, answer what it sent
+                    let _ = self.send();
+                    return false;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.paused = false;
+                    return true;
+                }
+                Err(e) => {
+                    debug!("Failed to receive: {}", e);
+                    return false;
+                }
+            }
+        }
+    }
+
+    // Handles the complete requests received so far and queues their responses, until
+    // the responses reach OUTPUT_CAP. Requests with an unknown opcode or too large to
+    // buffer are answered with an error. Fails only if a handler asks to drop the
+    // connection.
+    fn serve(
+        &mut self,
+        table: &[Option<RPCHandler>],
+        payload: &mut Vec<u8>,
+    ) -> Result<(), RPCError> {
+        enter_session(self.session.clone());
+        let mut start = 0;
+        loop {
+            let skipped = self.discard.min(self.input.len() - start);
+            self.discard -= skipped;
+            start += skipped;
+            if self.discard > 0 || self.input.len() - start < HDR_LEN || self.backlogged() {
+                break;
+            }
+
+            let mut hdr: RPCHeader = unsafe { std::mem::zeroed() };
+            hdr_bytes_mut(&mut hdr).copy_from_slice(&self.input[start..start + HDR_LEN]);
+            let body = start + HDR_LEN;
+            let len = usize::try_from(hdr.msg_len).unwrap_or(usize::MAX);
+            if len > max_request_len() {
+                debug!("Refusing a request of {} bytes", len);
+                error_response(&mut hdr, payload, EMSGSIZE)?;
+                self.queue(&hdr, payload)?;
+                self.discard = len;
+                start = body;
+                continue;
+            }
+            if self.input.len() - body < len {
+                break;
+            }
+
+            match table.get(hdr.msg_type as usize).copied().flatten() {
+                Some(handler) => {
+                    if payload.len() < len {
+                        payload.resize(len, 0);
+                    }
+                    payload[..len].copy_from_slice(&self.input[body..body + len]);
+                    handler(&mut hdr, &mut payload[..])?;
+                }
+                None => {
+                    debug!("No handler for RPC type {}", hdr.msg_type);
+                    error_response(&mut hdr, payload, ENOSYS)?;
+                }
+            }
+            self.queue(&hdr, payload)?;
+            start = body + len;
+        }
+        self.input.drain(..start);
+        Ok(())
+    }
+
+    // Queues the response a handler left in `payload`
+    fn queue(&mut self, hdr: &RPCHeader, payload: &[u8]) -> Result<(), RPCError> {
+        let response = payload
+            .get(..hdr.msg_len as usize)
+            .ok_or(RPCError::TransportError)?;
+        self.output.extend_from_slice(hdr_bytes(hdr));
+        self.output.extend_from_slice(response);
+        Ok(())
+    }
+
+    // Sends as much of the queued responses as the socket takes. What was sent is dropped
+    // from the queue, so it does not grow while the client keeps up.
+    fn send(&mut self) -> io::Result<()> {
+        while self.written < self.output.len() {
+            match self.stream.write(&self.output[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.written += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.output.drain(..self.written);
+                    self.written = 0;
+                    return Ok(());
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        self.output.clear();
+        self.written = 0;
+        Ok(())
+    }
+
+    fn flushed(&self) -> bool {
+        self.output.is_empty()
+    }
+
+    // Handles a readiness event, false once the connection is done with. Requests left
+    // unread are taken up as soon as sending makes room for their responses.
+    fn ready(
+        &mut self,
+        readable: bool,
+        draining: bool,
+        table: &[Option<RPCHandler>],
+        payload: &mut Vec<u8>,
+    ) -> bool {
+        let mut read = readable || self.paused;
+        loop {
+            if read && !draining && !self.backlogged() && !self.pump(table, payload) {
+                return false;
+            }
+            if let Err(e) = self.send() {
+                debug!("Failed to send: {}", e);
+                return false;
+            }
+            // Either the socket takes no more responses, which a writable event follows,
+            // or the requests that were left are up next
+            read = self.paused && !self.backlogged();
+            if !read || draining {
+                return true;
+            }
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        debug!(
+            "Connection closed, closing {} open handles",
+            self.session.len()
+        );
+        self.session.close_all();
+    }
+}
+
+struct Worker {
+    incoming: Sender<Box<dyn EventStream>>,
+    waker: Arc<Waker>,
+    thread: JoinHandle<()>,
+}
+
+impl Worker {
+    fn spawn(core: Cpu) -> io::Result<Worker> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let (incoming, receiver) = channel();
+        let thread = std::thread::spawn(move || {
+            pin_thread(core);
+            run_worker(poll, receiver);
+        });
+        Ok(Worker {
+            incoming: incoming,
+            waker: waker,
+            thread: thread,
+        })
+    }
+
+    fn hand(&self, stream: Box<dyn EventStream>) {
+        if self.incoming.send(stream).is_ok() {
+            let _ = self.waker.wake();
+        }
+    }
+}
+
+// Serves the connections handed to this worker until shutdown is requested. It then
+// stops reading requests, sends the responses still queued and closes the connections.
+fn run_worker(mut poll: Poll, incoming: Receiver<Box<dyn EventStream>>) {
+    let table = dispatch_table();
+    // Holds the response to the largest read, grown for larger requests
+    let mut payload = vec![0u8; payload_len()];
+    let mut connections: HashMap<Token, Connection> = HashMap::new();
+    let mut next = 0;
+    let mut events = Events::with_capacity(1024);
+    let mut deadline = None;
+
+    loop {
+        let draining = shutdown::requested();
+        if draining {
+            let deadline = *deadline.get_or_insert_with(|| Instant::now() + DRAIN_TIMEOUT);
+            if connections.values().all(Connection::flushed) {
+                break;
+            }
+            if Instant::now() >= deadline {
+                debug!("Drain timed out, dropping connections with responses queued");
+                break;
+            }
+        }
+        if let Err(e) = poll.poll(&mut events, Some(ACCEPT_POLL)) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            panic!("Failed to poll connections: {}", e);
+        }
+
+        for event in events.iter() {
+            if event.token() == WAKER {
+                while let Ok(mut stream) = incoming.try_recv() {
+                    // Tokens are never reused, the counter would take centuries to wrap
+                    next += 1;
+                    let token = Token(next);
+                    if let Err(e) = poll.registry().register(
+                        &mut stream,
+                        token,
+                        Interest::READABLE | Interest::WRITABLE,
+                    ) {
+                        debug!("Failed to register a connection: {}", e);
+                        continue;
+                    }
+                    let connection = Connection {
+                        stream: stream,
+                        session: Arc::new(HandleTable::new(backend().clone())),
+                        input: Vec::new(),
+                        output: Vec::new(),
+                        written: 0,
+                        discard: 0,
+                        paused: false,
+                    };
+                    connections.insert(token, connection);
+                }
+                continue;
+            }
+
+            let open = match connections.get_mut(&event.token()) {
+                Some(connection) => {
+                    connection.ready(event.is_readable(), draining, &table, &mut payload)
+                }
+                None => continue,
+            };
+            if !open {
+                if let Some(mut connection) = connections.remove(&event.token()) {
+                    let _ = poll.registry().deregister(&mut connection.stream);
+                }
+            }
+        }
+    }
+    debug!("Worker done, closing {} connections", connections.len());
+}
+
+// Accepts connections and hands them to the workers in turn until shutdown is requested,
+// then waits for the workers to drain theirs
+fn serve_events(mut listener: Listener, pool: &WorkerPool) {
+    let workers: Vec<Worker> = pool
+        .cores
+        .iter()
+        .map(|core| Worker::spawn(*core).expect("Failed to start a DRPC worker"))
+        .collect();
+
+    let mut poll = Poll::new().expect("Failed to create the accept poller");
+    listener
+        .register(poll.registry())
+        .expect("Failed to register the listener");
+    let mut events = Events::with_capacity(16);
+    let mut next = 0;
+
+    while !shutdown::requested() {
+        if let Err(e) = poll.poll(&mut events, Some(ACCEPT_POLL)) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            panic!("Failed to poll the listener: {}", e);
+        }
+        // The listener only signals new connections once, so take all of them
+        loop {
+            match listener.accept() {
+                Ok(stream) => {
+                    workers[next % workers.len()].hand(stream);
+                    next += 1;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    debug!("Failed to accept a connection: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+    drop(listener);
+
+    for worker in workers {
+        let _ = worker.waker.wake();
+        let _ = worker.thread.join();
+    }
+}
+
+/// Serves DRPC over TCP from a fixed pool of pinned workers, each multiplexing its share
+/// of the connections.
+pub fn start_drpc_event_server_tcp(
+    addr: &str,
+    backend: Arc<dyn FsBackend>,
+    pool: &WorkerPool,
+    max_io: usize,
+) {
+    set_backend(backend, max_io);
+    let listener = std::net::TcpListener::bind(addr).expect("Failed to create TCP listener");
+    listener
+        .set_nonblocking(true)
+        .expect("Failed to make the TCP listener nonblocking");
+    serve_events(Listener::Tcp(TcpListener::from_std(listener)), pool);
+}
+
+/// UDS counterpart of `start_drpc_event_server_tcp`.
+pub fn start_drpc_event_server_uds(
+    path: &Path,
+    backend: Arc<dyn FsBackend>,
+    pool: &WorkerPool,
+    max_io: usize,
+) {
+    set_backend(backend, max_io);
+    prepare_uds_path(path);
+    let listener = UnixListener::bind(path).expect("Failed to create UDS listener");
+    serve_events(Listener::Uds(listener), pool);
+}
@@ -1,4 +1,8 @@
-use abomonation::Abomonation;
+use crate::fxrpc::drpc::codec::{Codec, Message, Wire};
+use crate::fxrpc::metrics::{OpStats, ServerStats, LATENCY_BUCKETS, RPC_OPS};
+use crate::fxrpc::{
+    Compound, CompoundOp, DirEntry, DirPage, FdRef, FileStat, FxRpcError, OpResult,
+};
 
 ////////////////////////////// FS RPC Hdrs  //////////////////////////////
 
@@ -23,8 +27,48 @@ pub(crate) enum DRPC {
     MkDir = 8,
     /// Remove a directory.
     RmDir = 9,
+    /// Flush a file to disk.
+    Fsync = 10,
+    /// Get file status.
+    Fstat = 11,
+    /// List a directory.
+    Readdir = 12,
+    /// Rename a file, with renameat2() flags.
+    Rename = 13,
+    /// Create a hard link.
+    Link = 14,
+    /// Remove a name from the file system.
+    Unlink = 15,
+    /// Truncate a file by path.
+    Truncate = 16,
+    /// Truncate an opened file.
+    FTruncate = 17,
+    /// Get file status by path.
+    Stat = 18,
+    /// Get file status by path, without following a final symlink.
+    LStat = 19,
+    /// Read (offset, len) segments from a file.
+    PReadV = 20,
+    /// Write (offset, len) segments to a file.
+    PWriteV = 21,
+    /// Run a sequence of operations.
+    Compound = 22,
+    /// Get the server metrics.
+    Stats = 23,
+    /// Stop the server.
+    Shutdown = 24,
+    /// Check that the server speaks the wire version and codec of the client.
+    Hello = 25,
 }
 
+// Whether `len` bytes hold `count` elements of `T`
+fn holds<T>(count: usize, len: usize) -> bool {
+    count
+        .checked_mul(std::mem::size_of::<T>())
+        .map_or(false, |n| n <= len)
+}
+
+#[derive(Clone)]
 pub struct OpenReq {
     pub path: Vec<u8>,
     pub flags: i32,
@@ -32,47 +76,536 @@ pub struct OpenReq {
 }
 
 unsafe_abomonate!(OpenReq : path, flags, mode);
+wire!(OpenReq : path, flags, mode);
 
+impl Message for OpenReq {}
+
+// PReadV and PWriteV transfer the (offset, len) segments instead of size/offset
+#[derive(Clone)]
 pub struct ReadReq {
     pub fd: i32,
     pub size: usize,
     pub offset: i64,
+    pub segments: Vec<(i64, usize)>,
 }
 
-unsafe_abomonate!(ReadReq : fd, size, offset);
+unsafe_abomonate!(ReadReq : fd, size, offset, segments);
+wire!(ReadReq : fd, size, offset, segments);
+
+impl Message for ReadReq {
+    fn fits(&self, len: usize) -> bool {
+        holds::<(i64, usize)>(self.segments.len(), len)
+    }
+}
 
+// The page to write follows the encoded request in the payload. For PWriteV it holds
+// the data of all segments back to back.
+#[derive(Clone)]
 pub struct WriteReq {
     pub fd: i32,
-    pub page: Vec<u8>,
     pub size: usize,
     pub offset: i64,
+    pub segments: Vec<(i64, usize)>,
 }
 
-unsafe_abomonate!(WriteReq : fd, page, size, offset);
+unsafe_abomonate!(WriteReq : fd, size, offset, segments);
+wire!(WriteReq : fd, size, offset, segments);
+
+impl Message for WriteReq {
+    fn fits(&self, len: usize) -> bool {
+        holds::<(i64, usize)>(self.segments.len(), len)
+    }
+}
 
+#[derive(Clone)]
 pub struct CloseReq {
     pub fd: i32,
 }
 
 unsafe_abomonate!(CloseReq : fd);
+wire!(CloseReq : fd);
+
+impl Message for CloseReq {}
 
+#[derive(Clone)]
 pub struct RemoveReq {
     pub path: Vec<u8>,
 }
 
 unsafe_abomonate!(RemoveReq : path);
+wire!(RemoveReq : path);
 
+impl Message for RemoveReq {}
+
+#[derive(Clone)]
 pub struct MkdirReq {
     pub path: Vec<u8>,
     pub mode: u32,
 }
 
 unsafe_abomonate!(MkdirReq : path, mode);
+wire!(MkdirReq : path, mode);
 
-pub struct Response {
-    pub result: i32,
-    pub size: usize,
-    pub page: Vec<u8>, // only for read responses
+impl Message for MkdirReq {}
+
+#[derive(Clone)]
+pub struct RmdirReq {
+    pub path: Vec<u8>,
+}
+
+unsafe_abomonate!(RmdirReq : path);
+wire!(RmdirReq : path);
+
+impl Message for RmdirReq {}
+
+#[derive(Clone)]
+pub struct FsyncReq {
+    pub fd: i32,
+}
+
+unsafe_abomonate!(FsyncReq : fd);
+wire!(FsyncReq : fd);
+
+impl Message for FsyncReq {}
+
+#[derive(Clone)]
+pub struct FstatReq {
+    pub fd: i32,
+}
+
+unsafe_abomonate!(FstatReq : fd);
+wire!(FstatReq : fd);
+
+impl Message for FstatReq {}
+
+#[derive(Clone)]
+pub struct ReaddirReq {
+    pub path: Vec<u8>,
+    pub cookie: u64,
+    pub count: u32,
+}
+
+unsafe_abomonate!(ReaddirReq : path, cookie, count);
+wire!(ReaddirReq : path, cookie, count);
+
+impl Message for ReaddirReq {}
+
+// A readdir response carries a ReaddirRes followed by `result` encoded Dirents
+#[derive(Clone)]
+pub struct ReaddirRes {
+    pub cookie: u64,
+    pub eof: bool,
+}
+
+unsafe_abomonate!(ReaddirRes : cookie, eof);
+wire!(ReaddirRes : cookie, eof);
+
+impl Message for ReaddirRes {}
+
+#[derive(Clone)]
+pub struct Dirent {
+    pub name: Vec<u8>,
+    pub file_type: u8,
+    pub ino: u64,
+    pub cookie: u64,
+}
+
+unsafe_abomonate!(Dirent : name, file_type, ino, cookie);
+wire!(Dirent : name, file_type, ino, cookie);
+
+impl Message for Dirent {}
+
+// Upper bound on the listing a single readdir response carries
+pub const READDIR_BUF_LEN: usize = 4096;
+
+// Decodes a readdir listing holding `count` entries
+pub(crate) fn decode_listing(
+    codec: Codec,
+    mut listing: &mut [u8],
+    count: usize,
+) -> Option<DirPage> {
+    let (res, rest) = codec.decode::<ReaddirRes>(std::mem::take(&mut listing))?;
+    let (cookie, eof) = (res.cookie, res.eof);
+    listing = rest;
+
+    let mut entries = Vec::with_capacity(count.min(listing.len()));
+    for _ in 0..count {
+        let (entry, rest) = codec.decode::<Dirent>(std::mem::take(&mut listing))?;
+        entries.push(DirEntry {
+            name: String::from_utf8_lossy(&entry.name).into_owned(),
+            file_type: entry.file_type,
+            ino: entry.ino,
+            cookie: entry.cookie,
+        });
+        listing = rest;
+    }
+    Some(DirPage {
+        entries: entries,
+        cookie: cookie,
+        eof: eof,
+    })
+}
+
+#[derive(Clone)]
+pub struct RenameReq {
+    pub oldpath: Vec<u8>,
+    pub newpath: Vec<u8>,
+    pub flags: u32,
+}
+
+unsafe_abomonate!(RenameReq : oldpath, newpath, flags);
+wire!(RenameReq : oldpath, newpath, flags);
+
+impl Message for RenameReq {}
+
+#[derive(Clone)]
+pub struct LinkReq {
+    pub oldpath: Vec<u8>,
+    pub newpath: Vec<u8>,
+}
+
+unsafe_abomonate!(LinkReq : oldpath, newpath);
+wire!(LinkReq : oldpath, newpath);
+
+impl Message for LinkReq {}
+
+#[derive(Clone)]
+pub struct TruncateReq {
+    pub path: Vec<u8>,
+    pub len: i64,
+}
+
+unsafe_abomonate!(TruncateReq : path, len);
+wire!(TruncateReq : path, len);
+
+impl Message for TruncateReq {}
+
+#[derive(Clone)]
+pub struct FTruncateReq {
+    pub fd: i32,
+    pub len: i64,
 }
 
-unsafe_abomonate!(Response : result, size, page);
+unsafe_abomonate!(FTruncateReq : fd, len);
+wire!(FTruncateReq : fd, len);
+
+impl Message for FTruncateReq {}
+
+// Used by both Stat and LStat, the encoded FileStat follows the response on success
+#[derive(Clone)]
+pub struct StatReq {
+    pub path: Vec<u8>,
+}
+
+unsafe_abomonate!(StatReq : path);
+wire!(StatReq : path);
+
+impl Message for StatReq {}
+
+unsafe_abomonate!(FileStat);
+wire!(FileStat : dev, ino, mode, nlink, uid, gid, size, blocks, atime, mtime, ctime);
+
+impl Message for FileStat {}
+
+// Decodes the FileStat that follows a successful stat response
+pub(crate) fn decode_stat(codec: Codec, data: &mut [u8]) -> Result<FileStat, FxRpcError> {
+    match codec.decode::<FileStat>(data) {
+        Some((st, _)) => Ok(st),
+        None => Err(FxRpcError::Decode(String::from(
+            "Cannot decode file status",
+        ))),
+    }
+}
+
+// One operation of a compound. `op` is the opcode of the matching standalone RPC, and
+// fields the operation does not use are left empty. `fd_op` is the index of an earlier
+// operation whose descriptor to use instead of `fd`, or negative if there is none. It is
+// a plain integer so that any bytes decode to a valid request.
+#[derive(Clone)]
+pub struct CompoundOpReq {
+    pub op: u8,
+    pub fd: i32,
+    pub fd_op: i64,
+    pub path: Vec<u8>,
+    pub flags: i32,
+    pub mode: u32,
+    pub offset: i64,
+    pub len: usize,
+    pub data: Vec<u8>,
+}
+
+unsafe_abomonate!(CompoundOpReq : op, fd, fd_op, path, flags, mode, offset, len, data);
+wire!(CompoundOpReq : op, fd, fd_op, path, flags, mode, offset, len, data);
+
+#[derive(Clone)]
+pub struct CompoundReq {
+    pub ops: Vec<CompoundOpReq>,
+}
+
+unsafe_abomonate!(CompoundReq : ops);
+wire!(CompoundReq : ops);
+
+// The operations only hold vectors of bytes
+impl Message for CompoundReq {
+    fn fits(&self, len: usize) -> bool {
+        holds::<CompoundOpReq>(self.ops.len(), len)
+    }
+}
+
+#[derive(Clone)]
+pub struct CompoundOpRes {
+    pub result: i64,
+    pub errno: i32,
+    pub data: Vec<u8>,
+}
+
+unsafe_abomonate!(CompoundOpRes : result, errno, data);
+wire!(CompoundOpRes : result, errno, data);
+
+// Follows the response of a compound, `result` holds the number of operations that ran
+#[derive(Clone)]
+pub struct CompoundRes {
+    pub results: Vec<CompoundOpRes>,
+}
+
+unsafe_abomonate!(CompoundRes : results);
+wire!(CompoundRes : results);
+
+impl Message for CompoundRes {
+    fn fits(&self, len: usize) -> bool {
+        holds::<CompoundOpRes>(self.results.len(), len)
+    }
+}
+
+impl From<&CompoundOp> for CompoundOpReq {
+    fn from(op: &CompoundOp) -> Self {
+        let mut req = CompoundOpReq {
+            op: 0,
+            fd: -1,
+            fd_op: -1,
+            path: Vec::new(),
+            flags: 0,
+            mode: 0,
+            offset: 0,
+            len: 0,
+            data: Vec::new(),
+        };
+        let fd = match op {
+            CompoundOp::Open { path, flags, mode } => {
+                req.op = DRPC::Open as u8;
+                req.path = path.as_bytes().to_vec();
+                req.flags = *flags;
+                req.mode = *mode;
+                None
+            }
+            CompoundOp::PRead { fd, len, offset } => {
+                req.op = DRPC::PRead as u8;
+                req.len = *len;
+                req.offset = *offset;
+                Some(fd)
+            }
+            CompoundOp::PWrite { fd, data, offset } => {
+                req.op = DRPC::PWrite as u8;
+                req.len = data.len();
+                req.data = data.clone();
+                req.offset = *offset;
+                Some(fd)
+            }
+            CompoundOp::Fsync { fd } => {
+                req.op = DRPC::Fsync as u8;
+                Some(fd)
+            }
+            CompoundOp::Fstat { fd } => {
+                req.op = DRPC::Fstat as u8;
+                Some(fd)
+            }
+            CompoundOp::FTruncate { fd, len } => {
+                req.op = DRPC::FTruncate as u8;
+                req.offset = *len;
+                Some(fd)
+            }
+            CompoundOp::Close { fd } => {
+                req.op = DRPC::Close as u8;
+                Some(fd)
+            }
+            CompoundOp::Unlink { path } => {
+                req.op = DRPC::Unlink as u8;
+                req.path = path.as_bytes().to_vec();
+                None
+            }
+            CompoundOp::Mkdir { path, mode } => {
+                req.op = DRPC::MkDir as u8;
+                req.path = path.as_bytes().to_vec();
+                req.mode = *mode;
+                None
+            }
+        };
+        match fd {
+            Some(FdRef::Fd(fd)) => req.fd = *fd,
+            Some(FdRef::Op(index)) => req.fd_op = *index as i64,
+            None => {}
+        }
+        req
+    }
+}
+
+// Turns a decoded compound operation back into a CompoundOp, None if it is malformed
+pub(crate) fn compound_op(req: &CompoundOpReq) -> Option<CompoundOp> {
+    let fd = match usize::try_from(req.fd_op) {
+        Ok(index) => FdRef::Op(index),
+        Err(_) => FdRef::Fd(req.fd),
+    };
+    let path = || String::from_utf8(req.path.clone()).ok();
+    let op = match req.op {
+        op if op == DRPC::Open as u8 => CompoundOp::Open {
+            path: path()?,
+            flags: req.flags,
+            mode: req.mode,
+        },
+        op if op == DRPC::PRead as u8 => CompoundOp::PRead {
+            fd: fd,
+            len: req.len,
+            offset: req.offset,
+        },
+        op if op == DRPC::PWrite as u8 => CompoundOp::PWrite {
+            fd: fd,
+            data: req.data.get(..req.len)?.to_vec(),
+            offset: req.offset,
+        },
+        op if op == DRPC::Fsync as u8 => CompoundOp::Fsync { fd: fd },
+        op if op == DRPC::Fstat as u8 => CompoundOp::Fstat { fd: fd },
+        op if op == DRPC::FTruncate as u8 => CompoundOp::FTruncate {
+            fd: fd,
+            len: req.offset,
+        },
+        op if op == DRPC::Close as u8 => CompoundOp::Close { fd: fd },
+        op if op == DRPC::Unlink as u8 => CompoundOp::Unlink { path: path()? },
+        op if op == DRPC::MkDir as u8 => CompoundOp::Mkdir {
+            path: path()?,
+            mode: req.mode,
+        },
+        _ => return None,
+    };
+    Some(op)
+}
+
+// Size of the largest CompoundRes the server may send back for `compound`. The
+// little-endian codec never takes more room than abomonation.
+pub(crate) fn compound_res_len(compound: &Compound) -> usize {
+    std::mem::size_of::<CompoundRes>()
+        + compound.ops.len() * std::mem::size_of::<CompoundOpRes>()
+        + compound.read_len()
+}
+
+pub(crate) fn decode_compound(codec: Codec, data: &mut [u8]) -> Option<Vec<OpResult>> {
+    let (res, _) = codec.decode::<CompoundRes>(data)?;
+    Some(
+        res.results
+            .iter()
+            .map(|r| OpResult {
+                result: r.result,
+                errno: r.errno,
+                data: r.data.clone(),
+            })
+            .collect(),
+    )
+}
+
+#[derive(Clone)]
+pub struct ShutdownReq {
+    pub token: Vec<u8>,
+}
+
+unsafe_abomonate!(ShutdownReq : token);
+wire!(ShutdownReq : token);
+
+impl Message for ShutdownReq {}
+
+#[derive(Clone)]
+pub struct OpStatsRes {
+    pub op: Vec<u8>,
+    pub count: u64,
+    pub errors: u64,
+    pub bytes: u64,
+    pub in_flight: i64,
+    pub latency: Vec<u64>,
+}
+
+unsafe_abomonate!(OpStatsRes : op, count, errors, bytes, in_flight, latency);
+wire!(OpStatsRes : op, count, errors, bytes, in_flight, latency);
+
+// Follows the response of a stats request, `result` holds the number of RPCs listed
+#[derive(Clone)]
+pub struct StatsRes {
+    pub uptime_ns: u64,
+    pub ops: Vec<OpStatsRes>,
+}
+
+unsafe_abomonate!(StatsRes : uptime_ns, ops);
+wire!(StatsRes : uptime_ns, ops);
+
+impl Message for StatsRes {
+    fn fits(&self, len: usize) -> bool {
+        holds::<OpStatsRes>(self.ops.len(), len)
+    }
+}
+
+impl From<ServerStats> for StatsRes {
+    fn from(stats: ServerStats) -> Self {
+        StatsRes {
+            uptime_ns: stats.uptime.as_nanos() as u64,
+            ops: stats
+                .ops
+                .into_iter()
+                .map(|op| OpStatsRes {
+                    op: op.op.to_string().into_bytes(),
+                    count: op.count,
+                    errors: op.errors,
+                    bytes: op.bytes,
+                    in_flight: op.in_flight,
+                    latency: op.latency,
+                })
+                .collect(),
+        }
+    }
+}
+
+// Size of the StatsRes the server sends back
+pub(crate) fn stats_res_len() -> usize {
+    std::mem::size_of::<StatsRes>()
+        + RPC_OPS
+            .iter()
+            .map(|op| {
+                std::mem::size_of::<OpStatsRes>()
+                    + op.to_string().len()
+                    + LATENCY_BUCKETS * std::mem::size_of::<u64>()
+            })
+            .sum::<usize>()
+}
+
+pub(crate) fn decode_stats(codec: Codec, data: &mut [u8]) -> Result<ServerStats, FxRpcError> {
+    let (res, _) = match codec.decode::<StatsRes>(data) {
+        Some(res) => res,
+        None => return Err(FxRpcError::Decode(String::from("Cannot decode stats"))),
+    };
+    let ops = res
+        .ops
+        .iter()
+        .map(|op| {
+            let name = std::str::from_utf8(&op.op).map_err(|e| e.to_string())?;
+            Ok(OpStats {
+                op: name.parse()?,
+                count: op.count,
+                errors: op.errors,
+                bytes: op.bytes,
+                in_flight: op.in_flight,
+                latency: op.latency.clone(),
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()
+        .map_err(FxRpcError::Decode)?;
+    Ok(ServerStats {
+        uptime: std::time::Duration::from_nanos(res.uptime_ns),
+        ops: ops,
+    })
+}
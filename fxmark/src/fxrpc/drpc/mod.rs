@@ -1,3 +1,7 @@
+#[macro_use]
+pub mod codec;
+pub use crate::fxrpc::drpc::codec::*;
+
 mod fileops;
 use crate::fxrpc::drpc::fileops::*;
 
@@ -6,3 +10,12 @@ pub use crate::fxrpc::drpc::server::*;
 
 pub mod client;
 pub use crate::fxrpc::drpc::client::*;
+
+pub mod pipeline;
+pub use crate::fxrpc::drpc::pipeline::*;
+
+pub mod event;
+pub use crate::fxrpc::drpc::event::*;
+
+mod tls;
+pub use crate::fxrpc::drpc::tls::*;
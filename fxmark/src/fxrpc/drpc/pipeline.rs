@@ -0,0 +1,834 @@
+use log::debug;
+use rpc::rpc::*;
+use std::io::{self, IoSlice, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, OwnedSemaphorePermit, Semaphore};
+
+use crate::fxrpc::drpc::*;
+use crate::fxrpc::iov::{advance, batches, segments_in, segments_in_mut, IOV_MAX};
+use crate::fxrpc::{
+    AsyncFxRPC, Compound, DirPage, FileStat, FxRpcError, OpResult, ServerStats, TlsConfig,
+};
+
+////////////////////////////////// PIPELINED CLIENT //////////////////////////////////
+
+// Raw views of the RPC header, which goes over the wire as-is
+pub(crate) fn hdr_bytes(hdr: &RPCHeader) -> &[u8] {
+    unsafe {
+        std::slice::from_raw_parts(
+            hdr as *const RPCHeader as *const u8,
+            std::mem::size_of::<RPCHeader>(),
+        )
+    }
+}
+
+pub(crate) fn hdr_bytes_mut(hdr: &mut RPCHeader) -> &mut [u8] {
+    unsafe {
+        std::slice::from_raw_parts_mut(
+            hdr as *mut RPCHeader as *mut u8,
+            std::mem::size_of::<RPCHeader>(),
+        )
+    }
+}
+
+// Response header, followed by the response data if the caller gave no buffers for it
+type Reply = Result<Vec<u8>, FxRpcError>;
+
+// The caller's buffers a request is sent from and its response data is received into.
+// They are only valid while the caller's `Pending` is alive.
+struct Buffers {
+    data_in: Vec<(*const u8, usize)>,
+    data_out: Vec<(*mut u8, usize)>,
+}
+
+// The caller keeps away from its buffers while a thread works on them, see `Slot::users`
+unsafe impl Send for Buffers {}
+
+// A request slot is held from the moment the request is queued until its response is
+// received, even if the caller gave up waiting in the meantime.
+struct Slot {
+    hdr: Vec<u8>,
+    // None once the caller gave up, its response is then dropped
+    buffers: Option<Buffers>,
+    // Threads moving data through the buffers right now, the caller waits for them
+    users: usize,
+    sent: bool,
+    answered: bool,
+    reply: Option<oneshot::Sender<Reply>>,
+    _permit: OwnedSemaphorePermit,
+}
+
+struct Inflight {
+    slots: Vec<Option<Slot>>,
+    free_ids: Vec<usize>,
+    closed: bool,
+}
+
+// Request slots, and the callers waiting for the threads to let go of their buffers
+struct Shared {
+    inflight: Mutex<Inflight>,
+    idle: Condvar,
+}
+
+impl Shared {
+    // Lets go of the buffers of the slot `id`
+    fn release(&self, id: usize) {
+        let mut inflight = self.inflight.lock().unwrap();
+        if let Some(slot) = inflight.slots[id].as_mut() {
+            slot.users -= 1;
+        }
+        self.idle.notify_all();
+    }
+}
+
+// Held by the caller for as long as a slot points into its buffers. Dropping it, be it
+// once the response is in or because the caller gave up, waits until neither thread
+// touches the buffers anymore. A request that is on the wire stays in its slot without
+// its buffers until the response comes back, any other slot is freed.
+//
+// The buffers are borrowed by the caller's future, which must be dropped rather than
+// leaked while a request is in flight.
+struct Pending {
+    shared: Arc<Shared>,
+    id: usize,
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        let mut inflight = self.shared.inflight.lock().unwrap();
+        while inflight.slots[self.id]
+            .as_ref()
+            .map_or(false, |s| s.users > 0)
+        {
+            inflight = self.shared.idle.wait(inflight).unwrap();
+        }
+        let slot = match inflight.slots[self.id].as_mut() {
+            Some(slot) => slot,
+            None => return,
+        };
+        if slot.sent && !slot.answered {
+            slot.buffers = None;
+        } else {
+            inflight.slots[self.id] = None;
+            inflight.free_ids.push(self.id);
+        }
+    }
+}
+
+// DRPC client that keeps up to `depth` requests in flight on a single connection.
+// Requests are tagged with a free msg_id and sent by a writer thread, so callers never
+// block on the connection, and a reader thread hands each response to the request that
+// carries the same msg_id. Both threads work on the caller's buffers directly.
+pub struct PipelinedClient {
+    requests: UnboundedSender<usize>,
+    shared: Arc<Shared>,
+    permits: Arc<Semaphore>,
+    codec: Codec,
+    // Largest read or write the server takes in one request
+    max_io: usize,
+}
+
+impl PipelinedClient {
+    pub fn new(
+        reader: Box<dyn Read + Send>,
+        writer: Box<dyn Write + Send>,
+        depth: usize,
+        codec: Codec,
+        max_io: usize,
+    ) -> Self {
+        // Every in-flight request needs a distinct msg_id
+        let max_depth = 1usize << (8 * std::mem::size_of::<MsgId>()).min(16);
+        let depth = depth.clamp(1, max_depth);
+
+        let shared = Arc::new(Shared {
+            inflight: Mutex::new(Inflight {
+                slots: (0..depth).map(|_| None).collect(),
+                free_ids: (0..depth).rev().collect(),
+                closed: false,
+            }),
+            idle: Condvar::new(),
+        });
+
+        // No response is larger than the largest read the server said it serves
+        let max_len = RESPONSE_LEN.saturating_add(max_io);
+        let reader_shared = shared.clone();
+        std::thread::spawn(move || receive_loop(reader, reader_shared, max_len));
+        // At most `depth` requests are queued, as many as there are permits
+        let (requests, queued) = mpsc::unbounded_channel();
+        let writer_shared = shared.clone();
+        std::thread::spawn(move || send_loop(writer, queued, writer_shared));
+
+        PipelinedClient {
+            requests: requests,
+            shared: shared,
+            permits: Arc::new(Semaphore::new(depth)),
+            codec: codec,
+            max_io: max_io,
+        }
+    }
+
+    // Wire header and encoded request, ready to send
+    fn request<T: Message>(&self, request: &T) -> Vec<u8> {
+        let mut bytes = self.codec.header().to_vec();
+        self.codec.encode(request, &mut bytes);
+        bytes
+    }
+
+    // Sends a request made of `data_in` and receives the data following the response
+    // header into `data_out`, in order. Without `data_out` buffers, the data is returned
+    // after the response header.
+    async fn call(&self, rpc: DRPC, data_in: &[&[u8]], data_out: &mut [&mut [u8]]) -> Reply {
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|_| FxRpcError::Transport(String::from("Client is closed")))?;
+
+        let mut hdr: RPCHeader = unsafe { std::mem::zeroed() };
+        hdr.msg_type = rpc as RPCType;
+        hdr.msg_len = data_in.iter().map(|d| d.len()).sum::<usize>() as MsgLen;
+        let buffers = Buffers {
+            data_in: data_in.iter().map(|d| (d.as_ptr(), d.len())).collect(),
+            data_out: data_out
+                .iter_mut()
+                .map(|d| (d.as_mut_ptr(), d.len()))
+                .collect(),
+        };
+
+        let (tx, rx) = oneshot::channel();
+        let id = {
+            let mut inflight = self.shared.inflight.lock().unwrap();
+            if inflight.closed {
+                return Err(FxRpcError::Transport(String::from("Connection closed")));
+            }
+            // A permit guarantees a free id
+            let id = inflight.free_ids.pop().unwrap();
+            hdr.msg_id = id as MsgId;
+            inflight.slots[id] = Some(Slot {
+                hdr: hdr_bytes(&hdr).to_vec(),
+                buffers: Some(buffers),
+                users: 0,
+                sent: false,
+                answered: false,
+                reply: Some(tx),
+                _permit: permit,
+            });
+            id
+        };
+        let _pending = Pending {
+            shared: self.shared.clone(),
+            id: id,
+        };
+        // Should the writer be gone, it failed the slot when it closed the connection
+        let _ = self.requests.send(id);
+
+        rx.await
+            .unwrap_or_else(|_| Err(FxRpcError::Transport(String::from("Connection closed"))))
+    }
+
+    async fn call_fixed<T: Message + Sync>(
+        &self,
+        rpc: DRPC,
+        request: &T,
+    ) -> Result<i32, FxRpcError> {
+        let bytes = self.request(request);
+        let reply = self.call(rpc, &[&bytes], &mut []).await;
+        check_reply(reply)
+    }
+
+    // Reads into `page` a chunk per request, one after the other like the blocking
+    // client does. `offset` only applies to PRead.
+    async fn read_chunked(
+        &self,
+        rpc: DRPC,
+        fd: i32,
+        page: &mut [u8],
+        offset: i64,
+    ) -> Result<i32, FxRpcError> {
+        let mut done = 0;
+        for chunk in io_chunks(page.len(), self.max_io) {
+            let request = ReadReq {
+                fd: fd,
+                size: chunk.len(),
+                offset: offset + chunk.start as i64,
+                segments: Vec::new(),
+            };
+            let want = chunk.len();
+            let bytes = self.request(&request);
+            let reply = self.call(rpc, &[&bytes], &mut [&mut page[chunk]]).await;
+            if !advance(&mut done, want, check_reply(reply))? {
+                break;
+            }
+        }
+        Ok(done as i32)
+    }
+
+    // Writes `page` a chunk per request, `offset` only applies to PWrite
+    async fn write_chunked(
+        &self,
+        rpc: DRPC,
+        fd: i32,
+        page: &[u8],
+        offset: i64,
+    ) -> Result<i32, FxRpcError> {
+        let mut done = 0;
+        for chunk in io_chunks(page.len(), self.max_io) {
+            let request = WriteReq {
+                fd: fd,
+                size: chunk.len(),
+                offset: offset + chunk.start as i64,
+                segments: Vec::new(),
+            };
+            let want = chunk.len();
+            let bytes = self.request(&request);
+            let reply = self.call(rpc, &[&bytes, &page[chunk]], &mut []).await;
+            if !advance(&mut done, want, check_reply(reply))? {
+                break;
+            }
+        }
+        Ok(done as i32)
+    }
+
+    async fn call_stat(&self, rpc: DRPC, path: &str) -> Result<FileStat, FxRpcError> {
+        let request = StatReq {
+            path: path.as_bytes().to_vec(),
+        };
+        let bytes = self.request(&request);
+        let mut reply = self.call(rpc, &[&bytes], &mut []).await?;
+        let (result, errno, size) = read_response(&mut reply)?;
+        FxRpcError::check(result, errno)?;
+        match reply.get_mut(RESPONSE_LEN..RESPONSE_LEN + size) {
+            Some(data) => decode_stat(self.codec, data),
+            None => Err(FxRpcError::Decode(String::from("Truncated stat response"))),
+        }
+    }
+
+    // Vectored reads and writes of at most `max_io` bytes, which go in a single request
+    async fn preadv_batch(
+        &self,
+        fd: i32,
+        segments: &mut [(i64, &mut [u8])],
+    ) -> Result<i32, FxRpcError> {
+        let request = ReadReq {
+            fd: fd,
+            size: segments.iter().map(|(_, buf)| buf.len()).sum(),
+            offset: 0,
+            segments: segments
+                .iter()
+                .map(|(offset, buf)| (*offset, buf.len()))
+                .collect(),
+        };
+        let bytes = self.request(&request);
+        // The data comes back with the segments back to back, as the buffers are
+        let mut data_out: Vec<&mut [u8]> = segments.iter_mut().map(|(_, buf)| &mut **buf).collect();
+        let reply = self.call(DRPC::PReadV, &[&bytes], &mut data_out).await;
+        check_reply(reply)
+    }
+
+    async fn pwritev_batch(&self, fd: i32, segments: &[(i64, &[u8])]) -> Result<i32, FxRpcError> {
+        let request = WriteReq {
+            fd: fd,
+            size: segments.iter().map(|(_, buf)| buf.len()).sum(),
+            offset: 0,
+            segments: segments
+                .iter()
+                .map(|(offset, buf)| (*offset, buf.len()))
+                .collect(),
+        };
+        let bytes = self.request(&request);
+        let mut data_in: Vec<&[u8]> = Vec::with_capacity(segments.len() + 1);
+        data_in.push(&bytes);
+        data_in.extend(segments.iter().map(|(_, buf)| *buf));
+        let reply = self.call(DRPC::PWriteV, &data_in, &mut []).await;
+        check_reply(reply)
+    }
+}
+
+// Result of a response whose data, if any, is already in the caller's buffers
+fn check_reply(reply: Reply) -> Result<i32, FxRpcError> {
+    let mut reply = reply?;
+    let (result, errno, _) = read_response(&mut reply)?;
+    FxRpcError::check(result, errno)
+}
+
+// Fails every request still waiting on the connection and refuses further ones
+fn close(shared: &Shared) {
+    let mut inflight = shared.inflight.lock().unwrap();
+    inflight.closed = true;
+    for entry in inflight.slots.iter_mut() {
+        let detached = match entry.as_mut() {
+            Some(slot) => {
+                slot.answered = true;
+                if let Some(reply) = slot.reply.take() {
+                    let _ = reply.send(Err(FxRpcError::Transport(String::from(
+                        "Connection closed",
+                    ))));
+                }
+                slot.buffers.is_none()
+            }
+            None => false,
+        };
+        // Nobody waits on a slot the caller gave up on
+        if detached {
+            *entry = None;
+        }
+    }
+}
+
+// Writes all of `bufs` with as few calls as the writer takes
+fn write_all_vectored(writer: &mut dyn Write, bufs: &[&[u8]]) -> io::Result<()> {
+    // First buffer not written in full, and how much of it was
+    let mut first = 0;
+    let mut skip = 0;
+    let mut written = 0;
+    loop {
+        while first < bufs.len() && written >= bufs[first].len() - skip {
+            written -= bufs[first].len() - skip;
+            first += 1;
+            skip = 0;
+        }
+        if first == bufs.len() {
+            return Ok(());
+        }
+        skip += written;
+
+        let slices: Vec<IoSlice> = std::iter::once(IoSlice::new(&bufs[first][skip..]))
+            .chain(bufs[first + 1..].iter().map(|buf| IoSlice::new(buf)))
+            .collect();
+        written = match writer.write_vectored(&slices) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => written,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => 0,
+            Err(e) => return Err(e),
+        };
+    }
+}
+
+// Sends requests in the order they were queued, straight from the caller's buffers.
+// A request that was only partly sent leaves the connection out of step with the
+// server, so the connection is closed then.
+fn send_loop(
+    mut writer: Box<dyn Write + Send>,
+    mut requests: UnboundedReceiver<usize>,
+    shared: Arc<Shared>,
+) {
+    while let Some(id) = requests.blocking_recv() {
+        let (hdr, data_in) = {
+            let mut inflight = shared.inflight.lock().unwrap();
+            if inflight.closed {
+                break;
+            }
+            // The caller may have given up, and the id gone to another request since
+            match inflight.slots[id].as_mut() {
+                Some(slot) if !slot.sent => match slot.buffers.as_ref() {
+                    Some(buffers) => {
+                        slot.sent = true;
+                        slot.users += 1;
+                        (slot.hdr.clone(), buffers.data_in.clone())
+                    }
+                    None => continue,
+                },
+                _ => continue,
+            }
+        };
+
+        let mut bufs: Vec<&[u8]> = Vec::with_capacity(data_in.len() + 1);
+        bufs.push(&hdr);
+        // The caller waits in `Pending` until the slot is released
+        bufs.extend(
+            data_in
+                .iter()
+                .map(|&(ptr, len)| unsafe { std::slice::from_raw_parts(ptr, len) }),
+        );
+        let res = write_all_vectored(&mut writer, &bufs).and_then(|_| writer.flush());
+        shared.release(id);
+        if let Err(e) = res {
+            debug!("Failed to send a request: {}", e);
+            break;
+        }
+    }
+    close(&shared);
+}
+
+// Where the data of a response goes
+enum Target {
+    // Nobody waits for it
+    Drop,
+    // Into the reply, after the response header
+    Reply,
+    // Into the caller's buffers, in order
+    Buffers(Vec<(*mut u8, usize)>),
+}
+
+// Claims the slot a response with msg_id `id` is for
+fn claim(shared: &Shared, id: usize) -> (Target, Option<oneshot::Sender<Reply>>) {
+    let mut inflight = shared.inflight.lock().unwrap();
+    let entry = match inflight.slots.get_mut(id) {
+        Some(entry) => entry,
+        None => {
+            debug!("Dropping response with unknown msg_id {}", id);
+            return (Target::Drop, None);
+        }
+    };
+    let slot = match entry.as_mut() {
+        Some(slot) if slot.sent && !slot.answered => slot,
+        _ => {
+            debug!("Dropping response with unknown msg_id {}", id);
+            return (Target::Drop, None);
+        }
+    };
+    slot.answered = true;
+    let target = match slot.buffers.as_ref() {
+        Some(buffers) if buffers.data_out.is_empty() => Target::Reply,
+        Some(buffers) => {
+            slot.users += 1;
+            Target::Buffers(buffers.data_out.clone())
+        }
+        None => {
+            // The caller gave up waiting, the slot is free again
+            *entry = None;
+            inflight.free_ids.push(id);
+            return (Target::Drop, None);
+        }
+    };
+    (target, slot.reply.take())
+}
+
+// Receives `len` bytes into the buffers in order. Data beyond them is read and dropped,
+// and reported as an error once the connection is back in step.
+fn receive_into(
+    reader: &mut dyn Read,
+    buffers: &[(*mut u8, usize)],
+    mut len: usize,
+) -> io::Result<Result<(), FxRpcError>> {
+    for &(ptr, size) in buffers {
+        let size = size.min(len);
+        // The caller waits in `Pending` until the slot is released
+        let buf = unsafe { std::slice::from_raw_parts_mut(ptr, size) };
+        reader.read_exact(buf)?;
+        len -= size;
+    }
+    if len == 0 {
+        return Ok(Ok(()));
+    }
+    skip(reader, len)?;
+    Ok(Err(FxRpcError::Decode(String::from(
+        "Response carries more data than requested",
+    ))))
+}
+
+fn skip(reader: &mut dyn Read, len: usize) -> io::Result<()> {
+    let skipped = io::copy(&mut reader.take(len as u64), &mut io::sink())?;
+    if skipped < len as u64 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+// Hands each response to the request it is for. Responses larger than any the server
+// sends for a request it accepted mean the connection is broken, it is dropped then.
+fn receive_loop(mut reader: Box<dyn Read + Send>, shared: Arc<Shared>, max_len: usize) {
+    loop {
+        let mut hdr: RPCHeader = unsafe { std::mem::zeroed() };
+        if reader.read_exact(hdr_bytes_mut(&mut hdr)).is_err() {
+            break;
+        }
+        let len = usize::try_from(hdr.msg_len).unwrap_or(usize::MAX);
+        if len > max_len {
+            debug!("Dropping the connection over a response of {} bytes", len);
+            break;
+        }
+        let mut response = vec![0u8; len.min(RESPONSE_LEN)];
+        if reader.read_exact(&mut response).is_err() {
+            break;
+        }
+        let data_len = len - response.len();
+
+        let id = hdr.msg_id as usize;
+        let (target, reply) = claim(&shared, id);
+        let received = match target {
+            Target::Drop => skip(&mut reader, data_len).map(|_| Ok(())),
+            Target::Reply => {
+                response.resize(len, 0);
+                reader
+                    .read_exact(&mut response[RESPONSE_LEN..])
+                    .map(|_| Ok(()))
+            }
+            Target::Buffers(buffers) => {
+                let received = receive_into(&mut reader, &buffers, data_len);
+                shared.release(id);
+                received
+            }
+        };
+        let received = match received {
+            Ok(received) => received,
+            Err(_) => break,
+        };
+        // The caller may have stopped waiting, in which case the response is dropped
+        if let Some(reply) = reply {
+            let _ = reply.send(received.map(|_| response));
+        }
+    }
+
+    close(&shared);
+}
+
+#[tonic::async_trait]
+impl AsyncFxRPC for PipelinedClient {
+    async fn rpc_open(&self, path: &str, flags: i32, mode: u32) -> Result<i32, FxRpcError> {
+        let request = OpenReq {
+            path: path.as_bytes().to_vec(),
+            flags: flags,
+            mode: mode,
+        };
+        self.call_fixed(DRPC::Open, &request).await
+    }
+
+    async fn rpc_read(&self, fd: i32, page: &mut [u8]) -> Result<i32, FxRpcError> {
+        self.read_chunked(DRPC::Read, fd, page, 0).await
+    }
+
+    async fn rpc_pread(&self, fd: i32, page: &mut [u8], offset: i64) -> Result<i32, FxRpcError> {
+        self.read_chunked(DRPC::PRead, fd, page, offset).await
+    }
+
+    async fn rpc_write(&self, fd: i32, page: &[u8]) -> Result<i32, FxRpcError> {
+        self.write_chunked(DRPC::Write, fd, page, 0).await
+    }
+
+    async fn rpc_pwrite(&self, fd: i32, page: &[u8], offset: i64) -> Result<i32, FxRpcError> {
+        self.write_chunked(DRPC::PWrite, fd, page, offset).await
+    }
+
+    async fn rpc_preadv(
+        &self,
+        fd: i32,
+        segments: &mut [(i64, &mut [u8])],
+    ) -> Result<i32, FxRpcError> {
+        let lens = segments.iter().map(|(_, buf)| buf.len());
+        let mut done = 0;
+        for batch in batches(lens, self.max_io, IOV_MAX) {
+            let want = batch.len();
+            let res = self
+                .preadv_batch(fd, &mut segments_in_mut(segments, batch))
+                .await;
+            if !advance(&mut done, want, res)? {
+                break;
+            }
+        }
+        Ok(done as i32)
+    }
+
+    async fn rpc_pwritev(&self, fd: i32, segments: &[(i64, &[u8])]) -> Result<i32, FxRpcError> {
+        let lens = segments.iter().map(|(_, buf)| buf.len());
+        let mut done = 0;
+        for batch in batches(lens, self.max_io, IOV_MAX) {
+            let want = batch.len();
+            let res = self.pwritev_batch(fd, &segments_in(segments, batch)).await;
+            if !advance(&mut done, want, res)? {
+                break;
+            }
+        }
+        Ok(done as i32)
+    }
+
+    async fn rpc_close(&self, fd: i32) -> Result<i32, FxRpcError> {
+        self.call_fixed(DRPC::Close, &CloseReq { fd: fd }).await
+    }
+
+    async fn rpc_remove(&self, path: &str) -> Result<i32, FxRpcError> {
+        let request = RemoveReq {
+            path: path.as_bytes().to_vec(),
+        };
+        self.call_fixed(DRPC::Remove, &request).await
+    }
+
+    async fn rpc_mkdir(&self, path: &str, mode: u32) -> Result<i32, FxRpcError> {
+        let request = MkdirReq {
+            path: path.as_bytes().to_vec(),
+            mode: mode,
+        };
+        self.call_fixed(DRPC::MkDir, &request).await
+    }
+
+    async fn rpc_rmdir(&self, path: &str) -> Result<i32, FxRpcError> {
+        let request = RmdirReq {
+            path: path.as_bytes().to_vec(),
+        };
+        self.call_fixed(DRPC::RmDir, &request).await
+    }
+
+    async fn rpc_fsync(&self, fd: i32) -> Result<i32, FxRpcError> {
+        self.call_fixed(DRPC::Fsync, &FsyncReq { fd: fd }).await
+    }
+
+    async fn rpc_fstat(&self, fd: i32) -> Result<i64, FxRpcError> {
+        let bytes = self.request(&FstatReq { fd: fd });
+        let mut reply = self.call(DRPC::Fstat, &[&bytes], &mut []).await?;
+        let (result, errno, size) = read_response(&mut reply)?;
+        FxRpcError::check(result, errno)?;
+        Ok(size as i64)
+    }
+
+    async fn rpc_readdir(
+        &self,
+        path: &str,
+        cookie: u64,
+        count: u32,
+    ) -> Result<DirPage, FxRpcError> {
+        let request = ReaddirReq {
+            path: path.as_bytes().to_vec(),
+            cookie: cookie,
+            count: count,
+        };
+        let bytes = self.request(&request);
+        let mut reply = self.call(DRPC::Readdir, &[&bytes], &mut []).await?;
+        let (result, errno, size) = read_response(&mut reply)?;
+        let count = FxRpcError::check(result, errno)? as usize;
+        reply
+            .get_mut(RESPONSE_LEN..RESPONSE_LEN + size)
+            .and_then(|listing| decode_listing(self.codec, listing, count))
+            .ok_or_else(|| FxRpcError::Decode(String::from("Cannot decode listing")))
+    }
+
+    async fn rpc_rename(
+        &self,
+        oldpath: &str,
+        newpath: &str,
+        flags: u32,
+    ) -> Result<i32, FxRpcError> {
+        let request = RenameReq {
+            oldpath: oldpath.as_bytes().to_vec(),
+            newpath: newpath.as_bytes().to_vec(),
+            flags: flags,
+        };
+        self.call_fixed(DRPC::Rename, &request).await
+    }
+
+    async fn rpc_link(&self, oldpath: &str, newpath: &str) -> Result<i32, FxRpcError> {
+        let request = LinkReq {
+            oldpath: oldpath.as_bytes().to_vec(),
+            newpath: newpath.as_bytes().to_vec(),
+        };
+        self.call_fixed(DRPC::Link, &request).await
+    }
+
+    async fn rpc_unlink(&self, path: &str) -> Result<i32, FxRpcError> {
+        let request = RemoveReq {
+            path: path.as_bytes().to_vec(),
+        };
+        self.call_fixed(DRPC::Unlink, &request).await
+    }
+
+    async fn rpc_truncate(&self, path: &str, len: i64) -> Result<i32, FxRpcError> {
+        let request = TruncateReq {
+            path: path.as_bytes().to_vec(),
+            len: len,
+        };
+        self.call_fixed(DRPC::Truncate, &request).await
+    }
+
+    async fn rpc_ftruncate(&self, fd: i32, len: i64) -> Result<i32, FxRpcError> {
+        self.call_fixed(DRPC::FTruncate, &FTruncateReq { fd: fd, len: len })
+            .await
+    }
+
+    async fn rpc_compound(&self, compound: &Compound) -> Result<Vec<OpResult>, FxRpcError> {
+        let request = CompoundReq {
+            ops: compound.ops.iter().map(CompoundOpReq::from).collect(),
+        };
+        let bytes = self.request(&request);
+        let mut reply = self.call(DRPC::Compound, &[&bytes], &mut []).await?;
+        let (result, errno, size) = read_response(&mut reply)?;
+        FxRpcError::check(result, errno)?;
+        reply
+            .get_mut(RESPONSE_LEN..RESPONSE_LEN + size)
+            .and_then(|results| decode_compound(self.codec, results))
+            .ok_or_else(|| FxRpcError::Decode(String::from("Cannot decode results")))
+    }
+
+    async fn rpc_stat(&self, path: &str) -> Result<FileStat, FxRpcError> {
+        self.call_stat(DRPC::Stat, path).await
+    }
+
+    async fn rpc_lstat(&self, path: &str) -> Result<FileStat, FxRpcError> {
+        self.call_stat(DRPC::LStat, path).await
+    }
+
+    async fn rpc_stats(&self) -> Result<ServerStats, FxRpcError> {
+        let mut reply = self
+            .call(DRPC::Stats, &[&self.codec.header()], &mut [])
+            .await?;
+        let (result, errno, size) = read_response(&mut reply)?;
+        FxRpcError::check(result, errno)?;
+        match reply.get_mut(RESPONSE_LEN..RESPONSE_LEN + size) {
+            Some(stats) => decode_stats(self.codec, stats),
+            None => Err(FxRpcError::Decode(String::from("Truncated stats response"))),
+        }
+    }
+
+    async fn rpc_shutdown(&self, token: &str) -> Result<i32, FxRpcError> {
+        let request = ShutdownReq {
+            token: token.as_bytes().to_vec(),
+        };
+        self.call_fixed(DRPC::Shutdown, &request).await
+    }
+}
+
+// Connects over TLS if there is a configuration for it
+pub fn init_pipelined_drpc_tcp(
+    bind_addr: &str,
+    depth: usize,
+    codec: Codec,
+    tls: Option<&TlsConfig>,
+) -> Result<PipelinedClient, FxRpcError> {
+    match tls {
+        Some(tls) => {
+            let mut stream = connect_tls(bind_addr, tls)?;
+            let max_io = handshake(&mut stream, codec)?;
+            let (reader, writer) = split_tls(stream).map_err(connect_failed)?;
+            Ok(PipelinedClient::new(
+                Box::new(reader),
+                Box::new(writer),
+                depth,
+                codec,
+                max_io,
+            ))
+        }
+        None => {
+            let mut stream = TcpStream::connect(bind_addr).map_err(connect_failed)?;
+            let _ = stream.set_nodelay(true);
+            let max_io = handshake(&mut stream, codec)?;
+            let reader = stream.try_clone().map_err(connect_failed)?;
+            Ok(PipelinedClient::new(
+                Box::new(reader),
+                Box::new(stream),
+                depth,
+                codec,
+                max_io,
+            ))
+        }
+    }
+}
+
+pub fn init_pipelined_drpc_uds(
+    path: &Path,
+    depth: usize,
+    codec: Codec,
+) -> Result<PipelinedClient, FxRpcError> {
+    let mut stream = UnixStream::connect(path).map_err(connect_failed)?;
+    let max_io = handshake(&mut stream, codec)?;
+    let reader = stream.try_clone().map_err(connect_failed)?;
+    Ok(PipelinedClient::new(
+        Box::new(reader),
+        Box::new(stream),
+        depth,
+        codec,
+        max_io,
+    ))
+}
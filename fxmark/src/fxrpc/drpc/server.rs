@@ -1,65 +1,242 @@
 use libc::*;
 use log::debug;
 use rpc::rpc::*;
-use rpc::server::{RPCHandler, Server};
-use rpc::transport::stdtcp::*;
-use rpc::transport::uds::*;
-use std::net::{TcpListener, TcpStream};
+use rpc::server::RPCHandler;
+use std::cell::{Cell, RefCell};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::Path;
-use std::sync::{Arc, Mutex};
-
-use abomonation::{decode, encode};
+use std::sync::{Arc, OnceLock};
+use std::thread::JoinHandle;
+use std::time::Duration;
 
+use crate::fxrpc::backend::{status, transferred, FsBackend};
+use crate::fxrpc::compound::run_compound;
+use crate::fxrpc::drpc::codec::*;
 use crate::fxrpc::drpc::fileops::*;
-use crate::fxrpc::FS_PATH;
+use crate::fxrpc::drpc::pipeline::{hdr_bytes, hdr_bytes_mut};
+use crate::fxrpc::drpc::tls::serve_tls_connection;
+use crate::fxrpc::handles::{HandleTable, OpenFile};
+use crate::fxrpc::iov::segments_len;
+use crate::fxrpc::metrics::{self, RpcOp};
+use crate::fxrpc::prepare_uds_path;
+use crate::fxrpc::shutdown;
+use crate::fxrpc::{tls, TlsConfig};
 
 ////////////////////////////////// SERVER //////////////////////////////////
 
-fn construct_ret(hdr: &mut RPCHeader, payload: &mut [u8], result: i32, size: usize, page: Vec<u8>) {
-    let response = Response {
-        result: result,
-        size: size,
-        page: page,
+// Encodes the response header at the start of the payload, in the codec of the request.
+// Handlers that return data place it right behind the header, so `data_len` bytes are
+// sent along without a copy; data that would not fit the payload turns the response into
+// an EMSGSIZE error. A backend asking to drop the connection fails the handler instead,
+// which ends the server loop of the connection, as does a payload too short for the
+// header itself.
+fn construct_ret(
+    hdr: &mut RPCHeader,
+    payload: &mut [u8],
+    result: i32,
+    errno: i32,
+    size: usize,
+    data_len: usize,
+) -> Result<(), RPCError> {
+    respond(codec(), hdr, payload, result, errno, size, data_len)
+}
+
+fn respond(
+    codec: Codec,
+    hdr: &mut RPCHeader,
+    payload: &mut [u8],
+    result: i32,
+    errno: i32,
+    size: usize,
+    data_len: usize,
+) -> Result<(), RPCError> {
+    if errno == ECONNABORTED {
+        debug!("Dropping the connection");
+        return Err(RPCError::TransportError);
+    }
+
+    let fits = RESPONSE_LEN
+        .checked_add(data_len)
+        .map_or(false, |len| len <= payload.len());
+    let (response, data_len) = if fits {
+        let response = Response {
+            result: result,
+            errno: errno,
+            size: size,
+        };
+        (response, data_len)
+    } else {
+        debug!("Response of {} bytes does not fit the payload", data_len);
+        let response = Response {
+            result: -1,
+            errno: EMSGSIZE,
+            size: 0,
+        };
+        (response, 0)
     };
 
+    let mut out = codec.header().to_vec();
+    codec.encode(&response, &mut out);
+    match payload.get_mut(..RESPONSE_LEN) {
+        Some(head) if out.len() <= RESPONSE_LEN => {
+            head.fill(0);
+            head[..out.len()].copy_from_slice(&out);
+        }
+        _ => {
+            debug!("Payload of {} bytes cannot hold a response", payload.len());
+            return Err(RPCError::TransportError);
+        }
+    }
+
+    // msg_id is echoed back so pipelined clients can match responses to requests
+    hdr.msg_type = 0;
+    hdr.msg_len = (RESPONSE_LEN + data_len) as MsgLen;
+    Ok(())
+}
+
+/// Largest read or write a DRPC server serves in one request unless told otherwise.
+pub const DEFAULT_MAX_IO: usize = 16 * 1024 * 1024;
+
+// Room a write request takes beyond its data, for the request itself and its segments
+const REQUEST_SLACK: usize = 64 * 1024;
+
+// File system the server exports and the largest transfer it serves, both set once before
+// the first connection is accepted
+static BACKEND: OnceLock<Arc<dyn FsBackend>> = OnceLock::new();
+static MAX_IO: OnceLock<usize> = OnceLock::new();
+
+pub(super) fn backend() -> &'static Arc<dyn FsBackend> {
+    BACKEND.get().expect("DRPC backend is not set")
+}
+
+pub(super) fn set_backend(backend: Arc<dyn FsBackend>, max_io: usize) {
+    if BACKEND.set(backend).is_err() {
+        panic!("The DRPC backend can only be set once per process");
+    }
+    let _ = MAX_IO.set(max_io);
+}
+
+fn max_io() -> usize {
+    MAX_IO.get().copied().unwrap_or(DEFAULT_MAX_IO)
+}
+
+// Payload buffer a connection is served from, which holds the response to the largest
+// read. Pages are only touched as requests reach them.
+pub(super) fn payload_len() -> usize {
+    RESPONSE_LEN + max_io()
+}
+
+// Largest request the server buffers, larger ones are refused with EMSGSIZE unread
+pub(super) fn max_request_len() -> usize {
+    WIRE_HDR_LEN + max_io() + REQUEST_SLACK
+}
+
+thread_local! {
+    // Handles of the connection whose requests this thread serves. A thread serving a
+    // single connection keeps its table for good, event workers swap in the table of the
+    // connection before handling one of its requests.
+    static SESSION: RefCell<Arc<HandleTable>> =
+        RefCell::new(Arc::new(HandleTable::new(backend().clone())));
+}
+
+fn session() -> Arc<HandleTable> {
+    SESSION.with(|session| session.borrow().clone())
+}
+
+thread_local! {
+    // Codec of the request this thread is handling, named by the header of the request
+    static CODEC: Cell<Codec> = Cell::new(Codec::default());
+}
+
+fn codec() -> Codec {
+    CODEC.with(|codec| codec.get())
+}
+
+// Makes the following requests on this thread use the handles of `table`
+pub(super) fn enter_session(table: Arc<HandleTable>) {
+    SESSION.with(|session| *session.borrow_mut() = table);
+}
+
+// Descriptor behind a handle of this thread's connection
+fn session_fd(handle: i32) -> Result<Arc<OpenFile>, i32> {
+    session().get(handle)
+}
+
+// Answers a request the server could not make sense of. The response is in the
+// little-endian codec, which clients read whatever codec they picked.
+pub(super) fn error_response(
+    hdr: &mut RPCHeader,
+    payload: &mut [u8],
+    errno: i32,
+) -> Result<(), RPCError> {
+    respond(Codec::Le, hdr, payload, -1, errno, 0, 0)
+}
+
+// Decodes the request behind the wire header, failing with EINVAL if `payload` does not
+// hold a valid one. Returns the request and whatever follows it.
+fn decode_request<T: Message>(payload: &mut [u8]) -> Result<(T, &mut [u8]), i32> {
+    let body = payload.get_mut(WIRE_HDR_LEN..).ok_or(EINVAL)?;
+    codec().decode::<T>(body).ok_or(EINVAL)
+}
+
+// Encodes `msg` where read data goes, returning its length, None if it does not fit
+fn encode_data<T: Message>(payload: &mut [u8], msg: &T) -> Option<usize> {
     let mut bytes = Vec::new();
-    unsafe { encode(&response, &mut bytes) }.expect("Failed to encode response");
+    codec().encode(msg, &mut bytes);
+    data_region(payload, bytes.len())?.copy_from_slice(&bytes);
+    Some(bytes.len())
+}
 
-    payload[0..bytes.len()].copy_from_slice(&bytes);
+// Paths arrive as bytes, ones that are not UTF-8 are refused like ones with NUL bytes
+fn path_str(path: &[u8]) -> Result<&str, i32> {
+    std::str::from_utf8(path).map_err(|_| EINVAL)
+}
 
-    hdr.msg_id = 0;
-    hdr.msg_type = 0;
-    hdr.msg_len = (bytes.len() * std::mem::size_of::<u8>()) as MsgLen;
+// Refuses writes of more than `max_io` bytes like reads that would not fit the payload
+fn check_write_len(len: usize) -> Result<(), i32> {
+    if len > max_io() {
+        Err(EMSGSIZE)
+    } else {
+        Ok(())
+    }
+}
+
+// Region of the payload that read data is placed in, if `size` bytes fit
+fn data_region(payload: &mut [u8], size: usize) -> Option<&mut [u8]> {
+    payload.get_mut(RESPONSE_LEN..)?.get_mut(..size)
 }
 
 fn handle_open(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
-    let (path, flags, modes) = match unsafe { decode::<OpenReq>(payload) } {
-        Some((req, _)) => (req.path.clone(), req.flags, req.mode),
-        None => panic!("Cannot decode open request!"),
+    let (path, flags, modes) = match decode_request::<OpenReq>(payload) {
+        Ok((req, _)) => (req.path.clone(), req.flags, req.mode),
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
     };
 
-    let path = std::str::from_utf8(&path).unwrap();
+    let path = match path_str(&path) {
+        Ok(path) => path,
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
 
     debug!(
         "Open request - path: {:?}, flags: {:?}, modes: {:?}",
         path, flags, modes
     );
 
-    let file_path = format!("{}{}{}", FS_PATH, path, char::from(0));
-    let fd;
-    unsafe {
-        fd = open(file_path.as_ptr() as *const i8, flags, modes);
-    }
+    // The client only ever sees the handle
+    let (handle, errno) = match backend().open(path, flags, modes) {
+        Ok(fd) => (session().insert(fd), 0),
+        Err(errno) => (-1, errno),
+    };
 
-    construct_ret(hdr, payload, fd, 0, vec![]);
-    Ok(())
+    construct_ret(hdr, payload, handle, errno, 0, 0)
 }
 
 fn handle_read(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
-    let (fd, size, offset) = match unsafe { decode::<ReadReq>(payload) } {
-        Some((req, _)) => (req.fd, req.size, req.offset),
-        None => panic!("Cannot decode read request!"),
+    let (fd, size, offset) = match decode_request::<ReadReq>(payload) {
+        Ok((req, _)) => (req.fd, req.size, req.offset),
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
     };
 
     debug!(
@@ -67,20 +244,24 @@ fn handle_read(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError>
         fd, size, offset
     );
 
-    let res;
-    let page: Vec<u8> = vec![0; size];
-    unsafe {
-        res = read(fd, page.as_ptr() as *mut c_void, size);
-    }
+    let fd = match session_fd(fd) {
+        Ok(fd) => fd,
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
 
-    construct_ret(hdr, payload, res as i32, size, page.to_vec());
-    Ok(())
+    let (res, errno) = match data_region(payload, size) {
+        Some(page) => transferred(backend().read(fd.fd(), page)),
+        None => (-1, EMSGSIZE),
+    };
+
+    let len = if res > 0 { res as usize } else { 0 };
+    construct_ret(hdr, payload, res as i32, errno, len, len)
 }
 
 fn handle_pread(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
-    let (fd, size, offset) = match unsafe { decode::<ReadReq>(payload) } {
-        Some((req, _)) => (req.fd, req.size, req.offset),
-        None => panic!("Cannot decode pread request!"),
+    let (fd, size, offset) = match decode_request::<ReadReq>(payload) {
+        Ok((req, _)) => (req.fd, req.size, req.offset),
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
     };
 
     debug!(
@@ -88,196 +269,813 @@ fn handle_pread(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError>
         fd, size, offset
     );
 
-    let res;
-    let page: Vec<u8> = vec![0; size];
-    unsafe {
-        res = pread(fd, page.as_ptr() as *mut c_void, size, offset);
-    }
+    let fd = match session_fd(fd) {
+        Ok(fd) => fd,
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
 
-    construct_ret(hdr, payload, res as i32, size, page.to_vec());
-    Ok(())
+    let (res, errno) = match data_region(payload, size) {
+        Some(page) => transferred(backend().pread(fd.fd(), page, offset)),
+        None => (-1, EMSGSIZE),
+    };
+
+    let len = if res > 0 { res as usize } else { 0 };
+    construct_ret(hdr, payload, res as i32, errno, len, len)
 }
 
 fn handle_write(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
-    let (fd, page, size, offset) = match unsafe { decode::<WriteReq>(payload) } {
-        Some((req, _)) => (req.fd, req.page.clone(), req.size, req.offset),
-        None => panic!("Cannot decode write request!"),
+    // The page is written straight from the payload it arrived in
+    let (res, errno) = match decode_request::<WriteReq>(payload) {
+        Ok((req, page)) => {
+            debug!("Write request - fd: {:?}, size: {:?}", req.fd, req.size);
+
+            match check_write_len(req.size).and_then(|_| session_fd(req.fd)) {
+                Ok(fd) => {
+                    let size = req.size.min(page.len());
+                    transferred(backend().write(fd.fd(), &page[..size]))
+                }
+                Err(errno) => (-1, errno),
+            }
+        }
+        Err(errno) => (-1, errno),
+    };
+
+    construct_ret(hdr, payload, res as i32, errno, 0, 0)
+}
+
+fn handle_pwrite(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
+    let (res, errno) = match decode_request::<WriteReq>(payload) {
+        Ok((req, page)) => {
+            debug!(
+                "PWrite request - fd: {:?}, size: {:?}, offset: {:?}",
+                req.fd, req.size, req.offset
+            );
+
+            match check_write_len(req.size).and_then(|_| session_fd(req.fd)) {
+                Ok(fd) => {
+                    let size = req.size.min(page.len());
+                    transferred(backend().pwrite(fd.fd(), &page[..size], req.offset))
+                }
+                Err(errno) => (-1, errno),
+            }
+        }
+        Err(errno) => (-1, errno),
+    };
+
+    construct_ret(hdr, payload, res as i32, errno, 0, 0)
+}
+
+fn handle_preadv(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
+    let (fd, segments) = match decode_request::<ReadReq>(payload) {
+        Ok((req, _)) => (req.fd, req.segments.clone()),
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
+
+    debug!("PReadV request - fd: {:?}, segments: {:?}", fd, segments);
+
+    let fd = match session_fd(fd) {
+        Ok(fd) => fd,
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
+
+    // The segments are read back to back into the response
+    let (res, errno) = match data_region(payload, segments_len(&segments)) {
+        Some(data) => transferred(backend().preadv(fd.fd(), &segments, data)),
+        None => (-1, EMSGSIZE),
+    };
+
+    let len = if res > 0 { res as usize } else { 0 };
+    construct_ret(hdr, payload, res as i32, errno, len, len)
+}
+
+fn handle_pwritev(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
+    let (res, errno) = match decode_request::<WriteReq>(payload) {
+        Ok((req, data)) => {
+            debug!(
+                "PWriteV request - fd: {:?}, segments: {:?}",
+                req.fd, req.segments
+            );
+            match check_write_len(segments_len(&req.segments)).and_then(|_| session_fd(req.fd)) {
+                Ok(fd) => transferred(backend().pwritev(fd.fd(), &req.segments, data)),
+                Err(errno) => (-1, errno),
+            }
+        }
+        Err(errno) => (-1, errno),
+    };
+
+    construct_ret(hdr, payload, res as i32, errno, 0, 0)
+}
+
+fn handle_close(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
+    let fd = match decode_request::<CloseReq>(payload) {
+        Ok((req, _)) => req.fd,
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
+
+    debug!("Close request - fd: {:?}", fd);
+
+    let (res, errno) = session().close(fd);
+
+    construct_ret(hdr, payload, res, errno, 0, 0)
+}
+
+fn handle_remove(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
+    let path = match decode_request::<RemoveReq>(payload) {
+        Ok((req, _)) => req.path.clone(),
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
+
+    let path = match path_str(&path) {
+        Ok(path) => path,
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
+
+    debug!("Remove request - path: {:?}", path);
+
+    let (res, errno) = status(backend().remove(path));
+
+    construct_ret(hdr, payload, res, errno, 0, 0)
+}
+
+fn handle_mkdir(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
+    let (path, modes) = match decode_request::<MkdirReq>(payload) {
+        Ok((req, _)) => (req.path.clone(), req.mode),
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
+
+    let path = match path_str(&path) {
+        Ok(path) => path,
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
+
+    debug!("Mkdir request - path: {:?}, modes: {:?}", path, modes);
+
+    let (res, errno) = status(backend().mkdir(path, modes));
+
+    construct_ret(hdr, payload, res, errno, 0, 0)
+}
+
+fn handle_rmdir(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
+    let path = match decode_request::<RmdirReq>(payload) {
+        Ok((req, _)) => req.path.clone(),
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
+
+    let path = match path_str(&path) {
+        Ok(path) => path,
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
+
+    debug!("Rmdir request - path: {:?}", path);
+
+    let (res, errno) = status(backend().rmdir(path));
+
+    construct_ret(hdr, payload, res, errno, 0, 0)
+}
+
+fn handle_fsync(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
+    let fd = match decode_request::<FsyncReq>(payload) {
+        Ok((req, _)) => req.fd,
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
+
+    debug!("Fsync request - fd: {:?}", fd);
+
+    let fd = match session_fd(fd) {
+        Ok(fd) => fd,
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
+
+    let (res, errno) = status(backend().fsync(fd.fd()));
+
+    construct_ret(hdr, payload, res, errno, 0, 0)
+}
+
+// Like the gRPC server, only the file size is returned
+fn handle_fstat(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
+    let fd = match decode_request::<FstatReq>(payload) {
+        Ok((req, _)) => req.fd,
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
+
+    debug!("Fstat request - fd: {:?}", fd);
+
+    let fd = match session_fd(fd) {
+        Ok(fd) => fd,
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
+
+    let (res, errno, fsize) = match backend().fstat(fd.fd()) {
+        Ok(st) => (0, 0, st.size),
+        Err(errno) => (-1, errno, 0),
+    };
+
+    construct_ret(hdr, payload, res, errno, fsize as usize, 0)
+}
+
+fn handle_readdir(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
+    let (path, cookie, count) = match decode_request::<ReaddirReq>(payload) {
+        Ok((req, _)) => (req.path.clone(), req.cookie, req.count),
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
+
+    let path = match path_str(&path) {
+        Ok(path) => path,
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
     };
 
     debug!(
-        "Write request - fd: {:?}, page: {:?}, size: {:?}, offset: {:?}",
-        fd, page, size, offset
+        "Readdir request - path: {:?}, cookie: {:?}, count: {:?}",
+        path, cookie, count
     );
 
-    let res;
-    unsafe {
-        res = write(fd, page.as_ptr() as *const c_void, size);
+    let mut page = match backend().readdir(path, cookie, count as usize) {
+        Ok(page) => page,
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
+    let entries: Vec<Dirent> = page
+        .entries
+        .iter()
+        .map(|e| Dirent {
+            name: e.name.as_bytes().to_vec(),
+            file_type: e.file_type,
+            ino: e.ino,
+            cookie: e.cookie,
+        })
+        .collect();
+
+    // Entries that do not fit are left for the next call
+    let capacity = payload
+        .len()
+        .saturating_sub(RESPONSE_LEN)
+        .min(READDIR_BUF_LEN);
+    let encoded: Vec<Vec<u8>> = entries
+        .iter()
+        .map(|entry| {
+            let mut bytes = Vec::new();
+            codec().encode(entry, &mut bytes);
+            bytes
+        })
+        .collect();
+    let mut head = Vec::new();
+    codec().encode(
+        &ReaddirRes {
+            cookie: 0,
+            eof: false,
+        },
+        &mut head,
+    );
+    let mut used = head.len();
+    let mut fit = 0;
+    for entry in encoded.iter() {
+        used += entry.len();
+        if used > capacity {
+            break;
+        }
+        fit += 1;
     }
+    page.truncate(fit);
 
-    construct_ret(hdr, payload, res as i32, 0, vec![]);
-    Ok(())
+    let mut listing = Vec::new();
+    let res = ReaddirRes {
+        cookie: page.cookie,
+        eof: page.eof,
+    };
+    codec().encode(&res, &mut listing);
+    for entry in encoded[..fit].iter() {
+        listing.extend_from_slice(entry);
+    }
+
+    match data_region(payload, listing.len()) {
+        Some(out) if listing.len() <= capacity => {
+            out.copy_from_slice(&listing);
+            let len = listing.len();
+            construct_ret(hdr, payload, fit as i32, 0, len, len)
+        }
+        _ => construct_ret(hdr, payload, -1, EMSGSIZE, 0, 0),
+    }
 }
 
-fn handle_pwrite(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
-    let (fd, page, size, offset) = match unsafe { decode::<WriteReq>(payload) } {
-        Some((req, _)) => (req.fd, req.page.clone(), req.size, req.offset),
-        None => panic!("Cannot decode pwrite request!"),
+fn handle_rename(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
+    let (oldpath, newpath, flags) = match decode_request::<RenameReq>(payload) {
+        Ok((req, _)) => (req.oldpath.clone(), req.newpath.clone(), req.flags),
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
+
+    let (oldpath, newpath) = match (path_str(&oldpath), path_str(&newpath)) {
+        (Ok(oldpath), Ok(newpath)) => (oldpath, newpath),
+        (Err(errno), _) | (_, Err(errno)) => return construct_ret(hdr, payload, -1, errno, 0, 0),
     };
 
     debug!(
-        "PWrite request - fd: {:?}, page: {:?}, size: {:?}, offset: {:?}",
-        fd, page, size, offset
+        "Rename request - oldpath: {:?}, newpath: {:?}, flags: {:?}",
+        oldpath, newpath, flags
     );
 
-    let res;
-    unsafe {
-        res = pwrite(fd, page.as_ptr() as *const c_void, size, offset);
-    }
+    let (res, errno) = status(backend().rename(oldpath, newpath, flags));
 
-    construct_ret(hdr, payload, res as i32, 0, vec![]);
-    Ok(())
+    construct_ret(hdr, payload, res, errno, 0, 0)
 }
 
-fn handle_close(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
-    let fd = match unsafe { decode::<CloseReq>(payload) } {
-        Some((req, _)) => req.fd,
-        None => panic!("Cannot decode close request!"),
+fn handle_link(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
+    let (oldpath, newpath) = match decode_request::<LinkReq>(payload) {
+        Ok((req, _)) => (req.oldpath.clone(), req.newpath.clone()),
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
     };
 
-    debug!("Close request - fd: {:?}", fd);
+    let (oldpath, newpath) = match (path_str(&oldpath), path_str(&newpath)) {
+        (Ok(oldpath), Ok(newpath)) => (oldpath, newpath),
+        (Err(errno), _) | (_, Err(errno)) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
+
+    debug!(
+        "Link request - oldpath: {:?}, newpath: {:?}",
+        oldpath, newpath
+    );
+
+    let (res, errno) = status(backend().link(oldpath, newpath));
+
+    construct_ret(hdr, payload, res, errno, 0, 0)
+}
+
+fn handle_unlink(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
+    let path = match decode_request::<RemoveReq>(payload) {
+        Ok((req, _)) => req.path.clone(),
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
 
-    let res;
-    unsafe {
-        res = close(fd);
+    let path = match path_str(&path) {
+        Ok(path) => path,
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
+
+    debug!("Unlink request - path: {:?}", path);
+
+    let (res, errno) = status(backend().unlink(path));
+
+    construct_ret(hdr, payload, res, errno, 0, 0)
+}
+
+fn handle_truncate(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
+    let (path, len) = match decode_request::<TruncateReq>(payload) {
+        Ok((req, _)) => (req.path.clone(), req.len),
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
+
+    let path = match path_str(&path) {
+        Ok(path) => path,
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
+
+    debug!("Truncate request - path: {:?}, len: {:?}", path, len);
+
+    let (res, errno) = status(backend().truncate(path, len));
+
+    construct_ret(hdr, payload, res, errno, 0, 0)
+}
+
+fn handle_ftruncate(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
+    let (fd, len) = match decode_request::<FTruncateReq>(payload) {
+        Ok((req, _)) => (req.fd, req.len),
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
+
+    debug!("FTruncate request - fd: {:?}, len: {:?}", fd, len);
+
+    let fd = match session_fd(fd) {
+        Ok(fd) => fd,
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
+
+    let (res, errno) = status(backend().ftruncate(fd.fd(), len));
+
+    construct_ret(hdr, payload, res, errno, 0, 0)
+}
+
+// Shared by stat and lstat, the FileStat is encoded right behind the response
+fn stat_common(hdr: &mut RPCHeader, payload: &mut [u8], follow: bool) -> Result<(), RPCError> {
+    let path = match decode_request::<StatReq>(payload) {
+        Ok((req, _)) => req.path.clone(),
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
+
+    let path = match path_str(&path) {
+        Ok(path) => path,
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
+
+    debug!("Stat request - path: {:?}, follow: {:?}", path, follow);
+
+    let st = match backend().stat(path, follow) {
+        Ok(st) => st,
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
+    };
+
+    match encode_data(payload, &st) {
+        Some(len) => construct_ret(hdr, payload, 0, 0, len, len),
+        None => construct_ret(hdr, payload, -1, EMSGSIZE, 0, 0),
     }
+}
 
-    construct_ret(hdr, payload, res as i32, 0, vec![]);
-    Ok(())
+fn handle_stat(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
+    stat_common(hdr, payload, true)
 }
 
-fn handle_remove(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
-    let path = match unsafe { decode::<RemoveReq>(payload) } {
-        Some((req, _)) => req.path.clone(),
-        None => panic!("Cannot decode remove request!"),
+fn handle_lstat(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
+    stat_common(hdr, payload, false)
+}
+
+fn handle_compound(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
+    let ops = match decode_request::<CompoundReq>(payload) {
+        Ok((req, _)) => req.ops.iter().map(compound_op).collect::<Option<Vec<_>>>(),
+        Err(errno) => return construct_ret(hdr, payload, -1, errno, 0, 0),
     };
 
-    let path = std::str::from_utf8(&path).unwrap();
+    let ops = match ops {
+        Some(ops) => ops,
+        None => return construct_ret(hdr, payload, -1, EINVAL, 0, 0),
+    };
 
-    debug!("Remove request - path: {:?}", path);
+    debug!("Compound request - ops: {:?}", ops);
 
-    let file_path = format!("{}{}{}", FS_PATH, path, char::from(0));
-    let fd;
-    unsafe {
-        fd = remove(file_path.as_ptr() as *const i8);
+    let results = run_compound(&ops, &**backend(), &session());
+    if let Some(dropped) = results.iter().find(|r| r.errno == ECONNABORTED) {
+        return construct_ret(hdr, payload, -1, dropped.errno, 0, 0);
     }
+    let results = CompoundRes {
+        results: results
+            .into_iter()
+            .map(|r| CompoundOpRes {
+                result: r.result,
+                errno: r.errno,
+                data: r.data,
+            })
+            .collect(),
+    };
 
-    construct_ret(hdr, payload, fd, 0, vec![]);
-    Ok(())
+    match encode_data(payload, &results) {
+        Some(len) => construct_ret(hdr, payload, results.results.len() as i32, 0, len, len),
+        None => construct_ret(hdr, payload, -1, EMSGSIZE, 0, 0),
+    }
 }
 
-fn handle_mkdir(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
-    let (path, modes) = match unsafe { decode::<MkdirReq>(payload) } {
-        Some((req, _)) => (req.path.clone(), req.mode),
-        None => panic!("Cannot decode mkdir request!"),
+// Not recorded itself, so polling the metrics does not change them
+fn handle_stats(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
+    let stats = StatsRes::from(metrics::snapshot());
+
+    match encode_data(payload, &stats) {
+        Some(len) => construct_ret(hdr, payload, stats.ops.len() as i32, 0, len, len),
+        None => construct_ret(hdr, payload, -1, EMSGSIZE, 0, 0),
+    }
+}
+
+fn handle_shutdown(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
+    let res = match decode_request::<ShutdownReq>(payload) {
+        Ok((req, _)) => shutdown::request_with_token(&req.token),
+        Err(errno) => Err(errno),
     };
+    let (res, errno) = status(res);
 
-    let path = std::str::from_utf8(&path).unwrap();
+    debug!("Shutdown request - result: {:?}, errno: {:?}", res, errno);
+    construct_ret(hdr, payload, res, errno, 0, 0)
+}
 
-    debug!("Mkdir request - path: {:?}, modes: {:?}", path, modes);
+// The wire header has already been checked, answers with the version of the server and
+// the largest read or write it serves in one request
+fn handle_hello(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
+    debug!("Hello request - codec: {}", codec());
+    construct_ret(hdr, payload, WIRE_VERSION as i32, 0, max_io(), 0)
+}
 
-    let dir_path = format!("{}{}{}", FS_PATH, path, char::from(0));
-    let res;
-    unsafe {
-        res = mkdir(dir_path.as_ptr() as *const i8, modes.try_into().unwrap());
+// Runs a handler on a request in the codec its wire header names. Requests in a wire
+// version or codec this server does not speak are refused with EPROTONOSUPPORT.
+fn run_framed(
+    handler: fn(&mut RPCHeader, &mut [u8]) -> Result<(), RPCError>,
+    hdr: &mut RPCHeader,
+    payload: &mut [u8],
+) -> Result<(), RPCError> {
+    match read_header(payload) {
+        Ok(codec) => {
+            CODEC.with(|current| current.set(codec));
+            handler(hdr, payload)
+        }
+        Err(e) => {
+            debug!("Refusing request: {}", e);
+            error_response(hdr, payload, EPROTONOSUPPORT)
+        }
     }
+}
 
-    construct_ret(hdr, payload, res, 0, vec![]);
+// Runs a handler and records the call in the server metrics, with the result it left in
+// the response header. A dropped connection counts as a failed call.
+fn run_metered(
+    op: RpcOp,
+    handler: fn(&mut RPCHeader, &mut [u8]) -> Result<(), RPCError>,
+    hdr: &mut RPCHeader,
+    payload: &mut [u8],
+) -> Result<(), RPCError> {
+    let timer = metrics::start(op);
+    run_framed(handler, hdr, payload)?;
+    if let Ok((result, _, _)) = read_response(payload) {
+        timer.finish(result as i64);
+    }
     Ok(())
 }
 
-fn handle_rmdir(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
-    debug!("Rmdir request - UNIMPLEMENTED");
-    construct_ret(hdr, payload, 0, 0, vec![]);
-    Ok(())
+// Defines the handler registered for an RPC that is not metered
+macro_rules! framed {
+    ($name:ident, $handler:ident) => {
+        const $name: RPCHandler = {
+            fn framed(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
+                run_framed($handler, hdr, payload)
+            }
+            framed
+        };
+    };
 }
 
-const OPEN_HANDLER: RPCHandler = handle_open;
-const READ_HANDLER: RPCHandler = handle_read;
-const PREAD_HANDLER: RPCHandler = handle_pread;
-const WRITE_HANDLER: RPCHandler = handle_write;
-const PWRITE_HANDLER: RPCHandler = handle_pwrite;
-const CLOSE_HANDLER: RPCHandler = handle_close;
-const REMOVE_HANDLER: RPCHandler = handle_remove;
-const MKDIR_HANDLER: RPCHandler = handle_mkdir;
-const RMDIR_HANDLER: RPCHandler = handle_rmdir;
-
-fn register_rpcs(server: &mut Server) {
-    server
-        .register(DRPC::Open as RPCType, &OPEN_HANDLER)
-        .unwrap();
-    server
-        .register(DRPC::Read as RPCType, &READ_HANDLER)
-        .unwrap();
-    server
-        .register(DRPC::PRead as RPCType, &PREAD_HANDLER)
-        .unwrap();
-    server
-        .register(DRPC::Write as RPCType, &WRITE_HANDLER)
-        .unwrap();
-    server
-        .register(DRPC::PWrite as RPCType, &PWRITE_HANDLER)
-        .unwrap();
-    server
-        .register(DRPC::Close as RPCType, &CLOSE_HANDLER)
-        .unwrap();
-    server
-        .register(DRPC::Remove as RPCType, &REMOVE_HANDLER)
-        .unwrap();
-    server
-        .register(DRPC::MkDir as RPCType, &MKDIR_HANDLER)
-        .unwrap();
-    server
-        .register(DRPC::RmDir as RPCType, &RMDIR_HANDLER)
-        .unwrap();
-}
-
-fn server_from_stream(stream: TcpStream) {
-    let transport = StdTCP {
-        stream: Arc::new(Mutex::new(stream)),
-    };
-    let mut server = Server::new(Box::new(transport));
-    register_rpcs(&mut server);
-    let _ = server.run_server();
-}
-
-pub fn start_drpc_server_tcp(bind_addr: &str, port: u16) {
-    let addr = format!("{}:{}", bind_addr, port);
-    let listener = TcpListener::bind(addr).expect("Failed to create TCP listener");
+// Defines the handler registered for an RPC, which meters calls of `$handler` as `$op`
+macro_rules! metered {
+    ($name:ident, $op:expr, $handler:ident) => {
+        const $name: RPCHandler = {
+            fn metered(hdr: &mut RPCHeader, payload: &mut [u8]) -> Result<(), RPCError> {
+                run_metered($op, $handler, hdr, payload)
+            }
+            metered
+        };
+    };
+}
+
+metered!(OPEN_HANDLER, RpcOp::Open, handle_open);
+metered!(READ_HANDLER, RpcOp::Read, handle_read);
+metered!(PREAD_HANDLER, RpcOp::PRead, handle_pread);
+metered!(WRITE_HANDLER, RpcOp::Write, handle_write);
+metered!(PWRITE_HANDLER, RpcOp::PWrite, handle_pwrite);
+metered!(PREADV_HANDLER, RpcOp::PReadV, handle_preadv);
+metered!(PWRITEV_HANDLER, RpcOp::PWriteV, handle_pwritev);
+metered!(COMPOUND_HANDLER, RpcOp::Compound, handle_compound);
+metered!(CLOSE_HANDLER, RpcOp::Close, handle_close);
+metered!(REMOVE_HANDLER, RpcOp::Remove, handle_remove);
+metered!(MKDIR_HANDLER, RpcOp::Mkdir, handle_mkdir);
+metered!(RMDIR_HANDLER, RpcOp::Rmdir, handle_rmdir);
+metered!(FSYNC_HANDLER, RpcOp::Fsync, handle_fsync);
+metered!(FSTAT_HANDLER, RpcOp::Fstat, handle_fstat);
+metered!(READDIR_HANDLER, RpcOp::Readdir, handle_readdir);
+metered!(RENAME_HANDLER, RpcOp::Rename, handle_rename);
+metered!(LINK_HANDLER, RpcOp::Link, handle_link);
+metered!(UNLINK_HANDLER, RpcOp::Unlink, handle_unlink);
+metered!(TRUNCATE_HANDLER, RpcOp::Truncate, handle_truncate);
+metered!(FTRUNCATE_HANDLER, RpcOp::FTruncate, handle_ftruncate);
+metered!(STAT_HANDLER, RpcOp::Stat, handle_stat);
+metered!(LSTAT_HANDLER, RpcOp::LStat, handle_lstat);
+framed!(STATS_HANDLER, handle_stats);
+framed!(SHUTDOWN_HANDLER, handle_shutdown);
+framed!(HELLO_HANDLER, handle_hello);
 
-    for stream in listener.incoming() {
-        std::thread::spawn(move || server_from_stream(stream.unwrap()));
+// Handler of every RPC
+pub(super) static HANDLERS: [(DRPC, RPCHandler); 25] = [
+    (DRPC::Open, OPEN_HANDLER),
+    (DRPC::Read, READ_HANDLER),
+    (DRPC::PRead, PREAD_HANDLER),
+    (DRPC::Write, WRITE_HANDLER),
+    (DRPC::PWrite, PWRITE_HANDLER),
+    (DRPC::Close, CLOSE_HANDLER),
+    (DRPC::Remove, REMOVE_HANDLER),
+    (DRPC::MkDir, MKDIR_HANDLER),
+    (DRPC::RmDir, RMDIR_HANDLER),
+    (DRPC::Fsync, FSYNC_HANDLER),
+    (DRPC::Fstat, FSTAT_HANDLER),
+    (DRPC::Readdir, READDIR_HANDLER),
+    (DRPC::Rename, RENAME_HANDLER),
+    (DRPC::Link, LINK_HANDLER),
+    (DRPC::Unlink, UNLINK_HANDLER),
+    (DRPC::Truncate, TRUNCATE_HANDLER),
+    (DRPC::FTruncate, FTRUNCATE_HANDLER),
+    (DRPC::Stat, STAT_HANDLER),
+    (DRPC::LStat, LSTAT_HANDLER),
+    (DRPC::PReadV, PREADV_HANDLER),
+    (DRPC::PWriteV, PWRITEV_HANDLER),
+    (DRPC::Compound, COMPOUND_HANDLER),
+    (DRPC::Stats, STATS_HANDLER),
+    (DRPC::Shutdown, SHUTDOWN_HANDLER),
+    (DRPC::Hello, HELLO_HANDLER),
+];
+
+// Opcode to handler, None for opcodes nothing is registered for
+pub(super) fn dispatch_table() -> Vec<Option<RPCHandler>> {
+    let len = HANDLERS
+        .iter()
+        .map(|(rpc, _)| *rpc as usize)
+        .max()
+        .unwrap_or(0)
+        + 1;
+    let mut table = vec![None; len];
+    for (rpc, handler) in HANDLERS.iter() {
+        table[*rpc as usize] = Some(*handler);
     }
+    table
+}
+
+/// Sets the backend `serve_raw` hands requests to, later calls keep the first one.
+#[doc(hidden)]
+pub fn init_raw(backend: Arc<dyn FsBackend>) {
+    let _ = BACKEND.set(backend);
+}
+
+/// Runs the handler of `opcode` on `request` as if it had arrived on a connection with a
+/// payload buffer of `capacity` bytes, and returns the response it leaves there. None if
+/// the handler would have dropped the connection or the opcode is unknown. Lets fuzzers
+/// and tests reach the handlers without a transport.
+#[doc(hidden)]
+pub fn serve_raw(opcode: u8, request: &[u8], capacity: usize) -> Option<Vec<u8>> {
+    let (_, handler) = HANDLERS.iter().find(|(rpc, _)| *rpc as u8 == opcode)?;
+    let mut hdr: RPCHeader = unsafe { std::mem::zeroed() };
+    hdr.msg_type = opcode as RPCType;
+    hdr.msg_len = request.len() as MsgLen;
+    let mut payload = vec![0u8; capacity.max(request.len())];
+    payload[..request.len()].copy_from_slice(request);
+
+    handler(&mut hdr, &mut payload[..]).ok()?;
+    payload.truncate(hdr.msg_len as usize);
+    Some(payload)
 }
 
-fn server_from_unix(stream: UnixStream) {
-    let transport = UDS {
-        stream: Arc::new(Mutex::new(stream)),
+// Serves the requests of a connection one after the other until the client goes away.
+// Requests with an unknown opcode or too large to buffer are answered with an error, the
+// latter skipped unread. Fails if the connection breaks or a handler asks to drop it.
+fn serve_requests<C: Read + Write>(stream: &mut C) -> Result<(), RPCError> {
+    let transport = |e: io::Error| {
+        debug!("Connection failed: {}", e);
+        RPCError::TransportError
     };
-    let mut server = Server::new(Box::new(transport));
-    register_rpcs(&mut server);
-    let _ = server.run_server();
+    let table = dispatch_table();
+    let mut payload = vec![0u8; payload_len()];
+
+    loop {
+        let mut hdr: RPCHeader = unsafe { std::mem::zeroed() };
+        match stream.read_exact(hdr_bytes_mut(&mut hdr)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(transport(e)),
+        }
+
+        let len = usize::try_from(hdr.msg_len).unwrap_or(usize::MAX);
+        if len > max_request_len() {
+            debug!("Refusing a request of {} bytes", len);
+            let skipped = io::copy(&mut stream.by_ref().take(len as u64), &mut io::sink())
+                .map_err(transport)?;
+            if skipped < len as u64 {
+                return Ok(());
+            }
+            error_response(&mut hdr, &mut payload, EMSGSIZE)?;
+        } else {
+            if payload.len() < len {
+                payload.resize(len, 0);
+            }
+            stream.read_exact(&mut payload[..len]).map_err(transport)?;
+            match table.get(hdr.msg_type as usize).copied().flatten() {
+                Some(handler) => handler(&mut hdr, &mut payload[..])?,
+                None => {
+                    debug!("No handler for RPC type {}", hdr.msg_type);
+                    error_response(&mut hdr, &mut payload, ENOSYS)?;
+                }
+            }
+        }
+
+        let response = payload
+            .get(..hdr.msg_len as usize)
+            .ok_or(RPCError::TransportError)?;
+        stream.write_all(hdr_bytes(&hdr)).map_err(transport)?;
+        stream.write_all(response).map_err(transport)?;
+    }
 }
 
-pub fn start_drpc_server_uds(path: &str) {
-    // Remove existing UDS dir
-    let _ = std::fs::remove_dir_all(Path::new(path).parent().unwrap());
+pub(super) fn serve_connection<C: Read + Write>(mut stream: C) {
+    let res = serve_requests(&mut stream);
+    end_session(res);
+}
+
+// The client is gone once the server loop returns, close whatever it left open
+fn end_session(res: Result<(), RPCError>) {
+    let session = session();
+    debug!(
+        "Connection closed ({:?}), closing {} open handles",
+        res,
+        session.len()
+    );
+    session.close_all();
+}
 
-    // Create UDS dir
-    let _ = std::fs::create_dir_all(Path::new(path).parent().unwrap());
+// How often the accept loop checks whether shutdown was requested
+pub(super) const ACCEPT_POLL: Duration = Duration::from_millis(50);
 
-    let listener = UnixListener::bind(path).expect("Failed to create UDS listener");
+// Sockets the DRPC servers accept connections on
+trait Connection: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+impl Connection for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+}
+
+// Serves every connection `accept` returns on its own thread until shutdown is requested.
+// `accept` has to be nonblocking, a blocking accept would never notice the request.
+// Connections are then drained: shutting down their read side lets each thread answer
+// the request it is working on, after which it sees the client gone and closes the
+// handles left open.
+fn serve_until_shutdown<C: Connection>(
+    mut accept: impl FnMut() -> io::Result<C>,
+    serve: impl Fn(C) + Clone + Send + 'static,
+) {
+    let mut connections: Vec<(C, JoinHandle<()>)> = Vec::new();
+    while !shutdown::requested() {
+        match accept() {
+            Ok(stream) => {
+                stream
+                    .set_nonblocking(false)
+                    .expect("Failed to make the connection blocking");
+                let peer = stream.try_clone().expect("Failed to clone the connection");
+                connections.retain(|(_, thread)| !thread.is_finished());
+                let serve = serve.clone();
+                connections.push((peer, std::thread::spawn(move || serve(stream))));
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => std::thread::sleep(ACCEPT_POLL),
+            Err(e) => debug!("Failed to accept a connection: {}", e),
+        }
+    }
+    drop(accept);
+
+    debug!("Shutting down, draining {} connections", connections.len());
+    for (peer, _) in connections.iter() {
+        let _ = peer.shutdown(Shutdown::Read);
+    }
+    for (_, thread) in connections {
+        let _ = thread.join();
+    }
+}
+
+// Serves TLS connections only if there is a configuration for it
+pub fn start_drpc_server_tcp(
+    addr: &str,
+    backend: Arc<dyn FsBackend>,
+    max_io: usize,
+    tls: Option<&TlsConfig>,
+) {
+    set_backend(backend, max_io);
+    let listener = TcpListener::bind(addr).expect("Failed to create TCP listener");
+    listener
+        .set_nonblocking(true)
+        .expect("Failed to make the TCP listener nonblocking");
 
-    for stream in listener.incoming() {
-        std::thread::spawn(move || server_from_unix(stream.unwrap()));
+    let accept = move || {
+        let (stream, _) = listener.accept()?;
+        let _ = stream.set_nodelay(true);
+        Ok(stream)
+    };
+    match tls {
+        Some(tls) => {
+            let config = Arc::new(tls::server_config(tls).expect("Failed to set up TLS"));
+            serve_until_shutdown(accept, move |stream| {
+                serve_tls_connection(stream, config.clone())
+            });
+        }
+        None => serve_until_shutdown(accept, serve_connection),
     }
 }
+
+pub fn start_drpc_server_uds(path: &Path, backend: Arc<dyn FsBackend>, max_io: usize) {
+    set_backend(backend, max_io);
+    prepare_uds_path(path);
+
+    let listener = UnixListener::bind(path).expect("Failed to create UDS listener");
+    listener
+        .set_nonblocking(true)
+        .expect("Failed to make the UDS listener nonblocking");
+
+    serve_until_shutdown(
+        move || listener.accept().map(|(stream, _)| stream),
+        serve_connection,
+    );
+}
@@ -0,0 +1,174 @@
+use log::debug;
+use rpc::rpc::*;
+use rpc::transport::Transport;
+use rustls::{ClientConnection, ServerConfig, ServerConnection, StreamOwned};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+
+use crate::fxrpc::drpc::pipeline::{hdr_bytes, hdr_bytes_mut};
+use crate::fxrpc::drpc::server::serve_connection;
+use crate::fxrpc::{tls, FxRpcError, TlsConfig};
+
+////////////////////////////////// CLIENT //////////////////////////////////
+
+/// TLS connection to a DRPC server.
+pub type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// Connects to the DRPC server at `addr` over TLS. The TLS handshake happens along with
+/// the first message sent.
+pub fn connect_tls(addr: &str, tls: &TlsConfig) -> Result<TlsStream, FxRpcError> {
+    let config = tls::client_config(tls).map_err(FxRpcError::Transport)?;
+    let name = tls::server_name(tls).map_err(FxRpcError::Transport)?;
+    let conn = ClientConnection::new(Arc::new(config), name)
+        .map_err(|e| FxRpcError::Transport(format!("Cannot start a TLS session: {}", e)))?;
+    let stream = TcpStream::connect(addr)
+        .map_err(|e| FxRpcError::Transport(format!("Cannot connect to {}: {}", addr, e)))?;
+    let _ = stream.set_nodelay(true);
+    Ok(StreamOwned::new(conn, stream))
+}
+
+fn failed(e: io::Error) -> RPCError {
+    debug!("TLS connection failed: {}", e);
+    RPCError::TransportError
+}
+
+/// Transport of a `DrpcClient` over TLS, the encrypted counterpart of `StdTCP`.
+pub struct TlsTCP {
+    pub stream: Arc<Mutex<TlsStream>>,
+}
+
+impl Transport for TlsTCP {
+    fn max_send(&self) -> usize {
+        usize::MAX
+    }
+
+    fn max_recv(&self) -> usize {
+        usize::MAX
+    }
+
+    fn send_msg(&self, hdr: &RPCHeader, payload: &[&[u8]]) -> Result<(), RPCError> {
+        let mut stream = self.stream.lock().unwrap();
+        stream.write_all(hdr_bytes(hdr)).map_err(failed)?;
+        for data in payload {
+            stream.write_all(data).map_err(failed)?;
+        }
+        stream.flush().map_err(failed)
+    }
+
+    fn try_send_msg(&self, hdr: &RPCHeader, payload: &[&[u8]]) -> Result<bool, RPCError> {
+        self.send_msg(hdr, payload).map(|()| true)
+    }
+
+    // Fills the buffers of `payload` in turn with the message. A message that does not
+    // fit would leave the rest of it to be taken for the next one, so it fails.
+    fn recv_msg(&self, hdr: &mut RPCHeader, payload: &mut [&mut [u8]]) -> Result<(), RPCError> {
+        let mut stream = self.stream.lock().unwrap();
+        stream.read_exact(hdr_bytes_mut(hdr)).map_err(failed)?;
+        let mut left = hdr.msg_len as usize;
+        for data in payload.iter_mut() {
+            let len = data.len().min(left);
+            stream.read_exact(&mut data[..len]).map_err(failed)?;
+            left -= len;
+        }
+        if left > 0 {
+            debug!("Response of {} bytes does not fit", hdr.msg_len);
+            return Err(RPCError::TransportError);
+        }
+        Ok(())
+    }
+
+    fn try_recv_msg(
+        &self,
+        hdr: &mut RPCHeader,
+        payload: &mut [&mut [u8]],
+    ) -> Result<bool, RPCError> {
+        self.recv_msg(hdr, payload).map(|()| true)
+    }
+
+    // Connected by `connect_tls` already
+    fn client_connect(&mut self) -> Result<(), RPCError> {
+        Ok(())
+    }
+
+    // Servers serve TLS connections themselves, see `start_drpc_server_tcp`
+    fn server_accept(&self) -> Result<(), RPCError> {
+        Err(RPCError::TransportError)
+    }
+}
+
+/// Reading half of a TLS connection split by `split_tls`.
+pub struct TlsReader {
+    conn: Arc<Mutex<ClientConnection>>,
+    sock: TcpStream,
+}
+
+/// Writing half of a TLS connection split by `split_tls`.
+pub struct TlsWriter {
+    conn: Arc<Mutex<ClientConnection>>,
+    sock: TcpStream,
+}
+
+/// Splits a TLS connection into halves that read and write on different threads, like
+/// a `PipelinedClient` does. The halves share the session but each has a socket of its
+/// own, and neither holds the session while it waits on its socket: a reader waiting for
+/// responses does not hold up requests, nor a writer stuck behind a busy server the
+/// responses that would unblock it.
+pub fn split_tls(stream: TlsStream) -> io::Result<(TlsReader, TlsWriter)> {
+    let StreamOwned { conn, sock } = stream;
+    let conn = Arc::new(Mutex::new(conn));
+    let reader = TlsReader {
+        conn: conn.clone(),
+        sock: sock.try_clone()?,
+    };
+    Ok((reader, TlsWriter { conn, sock }))
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.conn.lock().unwrap().reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                res => return res,
+            }
+            // Waits for more records without the session, then takes in what arrived.
+            // Anything the session has to send in return goes out with the next write.
+            self.sock.peek(&mut [0u8; 1])?;
+            let mut conn = self.conn.lock().unwrap();
+            conn.read_tls(&mut self.sock)?;
+            conn.process_new_packets()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+    }
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut records = Vec::new();
+        let len = {
+            let mut conn = self.conn.lock().unwrap();
+            let len = conn.writer().write(buf)?;
+            while conn.wants_write() {
+                conn.write_tls(&mut records)?;
+            }
+            len
+        };
+        self.sock.write_all(&records)?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sock.flush()
+    }
+}
+
+////////////////////////////////// SERVER //////////////////////////////////
+
+// Serves a connection accepted by a TLS server, the handshake happens as the first
+// request is read
+pub(super) fn serve_tls_connection(stream: TcpStream, config: Arc<ServerConfig>) {
+    match ServerConnection::new(config) {
+        Ok(conn) => serve_connection(StreamOwned::new(conn, stream)),
+        Err(e) => debug!("Cannot start a TLS session: {}", e),
+    }
+}
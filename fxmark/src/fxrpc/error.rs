@@ -0,0 +1,68 @@
+use nix::errno::Errno;
+
+/// Error type returned by all FxRPC client calls.
+#[derive(Debug)]
+pub enum FxRpcError {
+    /// The request could not be sent or no response was received.
+    Transport(String),
+    /// A response was received but could not be decoded.
+    Decode(String),
+    /// The system call ran on the server and failed with the given errno.
+    Errno(i32),
+}
+
+impl FxRpcError {
+    /// Returns the remote errno, if the error originated from a failed system call.
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            FxRpcError::Errno(e) => Some(*e),
+            _ => None,
+        }
+    }
+
+    /// Turns a (result, errno) pair received from the server into a `Result`.
+    pub fn check(result: i32, errno: i32) -> Result<i32, FxRpcError> {
+        if result < 0 {
+            Err(FxRpcError::Errno(errno))
+        } else {
+            Ok(result)
+        }
+    }
+}
+
+impl std::fmt::Display for FxRpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FxRpcError::Transport(msg) => write!(f, "transport error: {}", msg),
+            FxRpcError::Decode(msg) => write!(f, "decode error: {}", msg),
+            FxRpcError::Errno(e) => {
+                let errno = Errno::from_i32(*e);
+                write!(f, "remote error: {:?} ({})", errno, errno.desc())
+            }
+        }
+    }
+}
+
+impl std::error::Error for FxRpcError {}
+
+impl From<tonic::Status> for FxRpcError {
+    fn from(status: tonic::Status) -> Self {
+        FxRpcError::Transport(status.to_string())
+    }
+}
+
+impl From<tonic::transport::Error> for FxRpcError {
+    fn from(err: tonic::transport::Error) -> Self {
+        FxRpcError::Transport(err.to_string())
+    }
+}
+
+/// Returns the errno of the last failed system call, or 0 if `res` indicates success.
+/// Must be called right after the system call that produced `res`.
+pub fn errno_for(res: i64) -> i32 {
+    if res < 0 {
+        std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
+    } else {
+        0
+    }
+}
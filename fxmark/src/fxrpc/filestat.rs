@@ -0,0 +1,52 @@
+use libc::*;
+use std::ffi::CStr;
+
+use crate::fxrpc::error::errno_for;
+
+/// File status returned by `rpc_stat` and `rpc_lstat`. Times are in nanoseconds since
+/// the epoch.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FileStat {
+    pub dev: u64,
+    pub ino: u64,
+    pub mode: u32,
+    pub nlink: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: i64,
+    pub blocks: i64,
+    pub atime: i64,
+    pub mtime: i64,
+    pub ctime: i64,
+}
+
+impl From<&stat> for FileStat {
+    fn from(st: &stat) -> Self {
+        let ns = |sec: i64, nsec: i64| sec * 1_000_000_000 + nsec;
+        FileStat {
+            dev: st.st_dev as u64,
+            ino: st.st_ino as u64,
+            mode: st.st_mode as u32,
+            nlink: st.st_nlink as u64,
+            uid: st.st_uid,
+            gid: st.st_gid,
+            size: st.st_size,
+            blocks: st.st_blocks,
+            atime: ns(st.st_atime, st.st_atime_nsec),
+            mtime: ns(st.st_mtime, st.st_mtime_nsec),
+            ctime: ns(st.st_ctime, st.st_ctime_nsec),
+        }
+    }
+}
+
+// fstatat()s `name` in the directory `dir`. Fails with the errno on error.
+pub(crate) fn stat_at(dir: i32, name: &CStr, flags: i32) -> Result<FileStat, i32> {
+    let mut info = std::mem::MaybeUninit::<stat>::uninit();
+    unsafe {
+        let res = fstatat(dir, name.as_ptr(), info.as_mut_ptr(), flags);
+        if res < 0 {
+            return Err(errno_for(res as i64));
+        }
+        Ok(FileStat::from(&info.assume_init()))
+    }
+}
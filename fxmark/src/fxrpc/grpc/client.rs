@@ -3,227 +3,724 @@
     Zack McKevitt - 2023
 */
 
+use bytes::{Bytes, BytesMut};
+use std::path::PathBuf;
+use std::sync::Arc;
 use syscalls::{
-    syscall_client::SyscallClient, CloseRequest, DirRequest, OpenRequest, ReadRequest,
-    RemoveRequest, WriteRequest,
+    syscall_client::SyscallClient, CloseRequest, CompoundRequest, DirRequest, FstatRequest,
+    FsyncRequest, LinkRequest, OpenRequest, ReadRequest, ReaddirRequest, RemoveRequest,
+    RenameRequest, Segment, ShutdownRequest, StatRequest, StatsRequest, TruncateRequest,
+    WriteRequest,
 };
-use tokio::net::UnixStream;
+use tokio::net::{TcpStream, UnixStream};
 use tokio::runtime::Builder;
 use tokio::runtime::Runtime;
+use tokio_rustls::TlsConnector;
 use tonic::{transport::Endpoint, transport::Uri};
 use tower::service_fn;
 
+use crate::fxrpc::grpc::server::GRPC_ALPN;
+use crate::fxrpc::iov::{advance, batches, scatter, segments_in, segments_in_mut};
+use crate::fxrpc::tls;
 use crate::fxrpc::*;
 
 //////////////////////////////////////// CLIENT ////////////////////////////////////////
-pub struct BlockingClient {
+
+// tonic hands out owned messages, so the calls taking the caller's buffer copy the
+// received page into it exactly once. Requests need an owned, 'static page for the same
+// reason, so writes from a borrowed buffer copy it once too. `read_bytes`, `write_bytes`
+// and their positional variants hand the buffers over instead and copy nothing.
+fn copy_page(page: &mut [u8], data: &[u8]) -> Result<(), FxRpcError> {
+    match page.get_mut(..data.len()) {
+        Some(dst) => {
+            dst.copy_from_slice(data);
+            Ok(())
+        }
+        None => Err(FxRpcError::Decode(format!(
+            "Response carries {} bytes but the buffer only holds {}",
+            data.len(),
+            page.len()
+        ))),
+    }
+}
+
+fn read_request(pread: bool, fd: i32, len: usize, offset: i64) -> ReadRequest {
+    ReadRequest {
+        pread: pread,
+        fd: fd,
+        size: len as u32,
+        offset: offset,
+        segments: Vec::new(),
+    }
+}
+
+// Tokio-native client. Calls only borrow the client, and every call runs on its own
+// clone of the channel, so any number of requests can be in flight at once.
+#[derive(Clone)]
+pub struct AsyncClient {
     client: SyscallClient<tonic::transport::Channel>,
-    rt: Option<Runtime>,
+    limits: GrpcLimits,
 }
 
-impl BlockingClient {
-    pub fn connect_tcp<D>(dst: D) -> Result<Self, tonic::transport::Error>
+impl AsyncClient {
+    pub async fn connect_tcp<D>(dst: D, limits: GrpcLimits) -> Result<Self, tonic::transport::Error>
     where
         D: TryInto<tonic::transport::Endpoint>,
         D::Error: Into<StdError>,
     {
-        let rt = Builder::new_multi_thread().enable_all().build().unwrap();
-        let client = rt.block_on(SyscallClient::connect(dst))?;
-
-        Ok(Self {
-            client,
-            rt: Some(rt),
-        })
+        let client = SyscallClient::connect(dst).await?;
+        Ok(Self::with_limits(client, limits))
     }
 
-    pub fn connect_uds() -> Result<Self, tonic::transport::Error> {
-        async fn connect_uds_async() -> tonic::transport::Channel {
-            Endpoint::try_from("http://[::]:8080")
-                .unwrap()
-                .connect_with_connector(service_fn(|_: Uri| UnixStream::connect(UDS_PATH)))
-                .await
-                .unwrap()
-        }
+    pub async fn connect_uds(
+        path: PathBuf,
+        limits: GrpcLimits,
+    ) -> Result<Self, tonic::transport::Error> {
+        // The URI is required by tonic but ignored by the connector
+        let channel = Endpoint::try_from("http://[::]:8080")?
+            .connect_with_connector(service_fn(move |_: Uri| UnixStream::connect(path.clone())))
+            .await?;
+        let client = SyscallClient::new(channel);
+        Ok(Self::with_limits(client, limits))
+    }
 
-        let rt = tokio::runtime::Builder::new_multi_thread()
-            .enable_all()
-            .build()
-            .unwrap();
-        let channel = rt.block_on(connect_uds_async());
+    // Connects to `addr`, a host:port, over TLS
+    pub async fn connect_tls(
+        addr: &str,
+        tls: &TlsConfig,
+        limits: GrpcLimits,
+    ) -> Result<Self, FxRpcError> {
+        let mut config = tls::client_config(tls).map_err(FxRpcError::Transport)?;
+        config.alpn_protocols = vec![GRPC_ALPN.to_vec()];
+        let connector = TlsConnector::from(Arc::new(config));
+        let name = tls::server_name(tls).map_err(FxRpcError::Transport)?;
+        let addr = addr.to_string();
+        // The URI is required by tonic but ignored by the connector, which dials `addr`
+        let channel = Endpoint::try_from("http://[::]:8080")?
+            .connect_with_connector(service_fn(move |_: Uri| {
+                let (connector, name, addr) = (connector.clone(), name.clone(), addr.clone());
+                async move {
+                    let stream = TcpStream::connect(addr).await?;
+                    stream.set_nodelay(true)?;
+                    connector.connect(name, stream).await
+                }
+            }))
+            .await?;
         let client = SyscallClient::new(channel);
+        Ok(Self::with_limits(client, limits))
+    }
 
-        Ok(Self {
-            client,
-            rt: Some(rt),
-        })
+    fn with_limits(client: SyscallClient<tonic::transport::Channel>, limits: GrpcLimits) -> Self {
+        let client = client
+            .max_decoding_message_size(limits.max_message)
+            .max_encoding_message_size(limits.max_message);
+        Self {
+            client: client,
+            limits: limits,
+        }
     }
-}
 
-impl FxRPC for BlockingClient {
-    fn rpc_open(
-        &mut self,
-        path: &str,
-        flags: i32,
-        mode: u32,
-    ) -> Result<i32, Box<dyn std::error::Error>> {
-        let request = tonic::Request::new(OpenRequest {
-            path: path.to_string(),
-            flags: flags,
-            mode: mode,
-        });
-        let response = self
-            .rt
-            .as_ref()
-            .unwrap()
-            .block_on(self.client.open(request))?
-            .into_inner();
-        Ok(response.result)
+    // Reads more than a message holds over ReadStream, copying every chunk into `page`
+    // as it arrives. A failed chunk only fails the read if nothing was read before it.
+    async fn read_stream(&self, request: ReadRequest, page: &mut [u8]) -> Result<i32, FxRpcError> {
+        let request = tonic::Request::new(request);
+        let mut stream = self.client.clone().read_stream(request).await?.into_inner();
+        let mut done = 0;
+        let mut failed = None;
+        while let Some(chunk) = stream.message().await? {
+            match FxRpcError::check(chunk.result, chunk.errno) {
+                Ok(_) => {
+                    copy_page(&mut page[done..], &chunk.page)?;
+                    done += chunk.page.len();
+                }
+                Err(e) => failed = Some(e),
+            }
+        }
+        match failed {
+            Some(e) if done == 0 => Err(e),
+            _ => Ok(done as i32),
+        }
     }
 
-    fn rpc_read(
-        &mut self,
+    // Writes more than a message holds over WriteStream, a chunk per request. The chunks
+    // are slices of `data`.
+    async fn write_stream(
+        &self,
+        pwrite: bool,
         fd: i32,
-        page: &mut Vec<u8>,
-        size: usize,
-    ) -> Result<i32, Box<dyn std::error::Error>> {
-        let request = tonic::Request::new(ReadRequest {
-            pread: false,
-            fd: fd,
-            size: size as u32,
-            offset: 0,
-        });
-
+        data: Bytes,
+        offset: i64,
+    ) -> Result<i32, FxRpcError> {
+        let chunk = self.limits.stream_chunk;
+        let requests: Vec<WriteRequest> = (0..data.len())
+            .step_by(chunk)
+            .map(|start| {
+                let page = data.slice(start..(start + chunk).min(data.len()));
+                WriteRequest {
+                    pwrite: pwrite,
+                    fd: fd,
+                    len: page.len() as u32,
+                    page: page,
+                    offset: offset,
+                    segments: Vec::new(),
+                }
+            })
+            .collect();
+        let request = tonic::Request::new(futures::stream::iter(requests));
         let response = self
-            .rt
-            .as_ref()
-            .unwrap()
-            .block_on(self.client.read(request))?
+            .client
+            .clone()
+            .write_stream(request)
+            .await?
             .into_inner();
-        *page = response.page;
-        Ok(response.result)
+        FxRpcError::check(response.result, response.errno)
     }
 
-    fn rpc_pread(
-        &mut self,
+    fn streamed(&self, len: usize) -> bool {
+        len > self.limits.stream_chunk
+    }
+
+    // Reads into `page`, over ReadStream if it takes more than a message
+    async fn read_into(&self, request: ReadRequest, page: &mut [u8]) -> Result<i32, FxRpcError> {
+        if self.streamed(page.len()) {
+            return self.read_stream(request, page).await;
+        }
+        let request = tonic::Request::new(request);
+        let response = self.client.clone().read(request).await?.into_inner();
+        let result = FxRpcError::check(response.result, response.errno)?;
+        copy_page(page, &response.page)?;
+        Ok(result)
+    }
+
+    // Reads up to `request.size` bytes into a buffer of their own. A read that fits a
+    // message hands over the buffer it arrived in, a streamed one is gathered.
+    async fn read_owned(&self, request: ReadRequest) -> Result<Bytes, FxRpcError> {
+        let len = request.size as usize;
+        if self.streamed(len) {
+            let mut page = BytesMut::zeroed(len);
+            let done = self.read_stream(request, &mut page).await?;
+            page.truncate(done as usize);
+            return Ok(page.freeze());
+        }
+        let request = tonic::Request::new(request);
+        let response = self.client.clone().read(request).await?.into_inner();
+        FxRpcError::check(response.result, response.errno)?;
+        Ok(response.page)
+    }
+
+    async fn write_owned(
+        &self,
+        pwrite: bool,
         fd: i32,
-        page: &mut Vec<u8>,
-        size: usize,
+        page: Bytes,
         offset: i64,
-    ) -> Result<i32, Box<dyn std::error::Error>> {
-        let request = tonic::Request::new(ReadRequest {
-            pread: true,
+    ) -> Result<i32, FxRpcError> {
+        if self.streamed(page.len()) {
+            return self.write_stream(pwrite, fd, page, offset).await;
+        }
+        let request = tonic::Request::new(WriteRequest {
+            pwrite: pwrite,
             fd: fd,
-            size: size as u32,
+            len: page.len() as u32,
+            page: page,
             offset: offset,
+            segments: Vec::new(),
         });
+        let response = self.client.clone().write(request).await?.into_inner();
+        FxRpcError::check(response.result, response.errno)
+    }
 
-        let response = self
-            .rt
-            .as_ref()
-            .unwrap()
-            .block_on(self.client.read(request))?
-            .into_inner();
-        *page = response.page;
-        Ok(response.result)
+    /// Reads up to `len` bytes and returns them in the buffer they were received in.
+    pub async fn read_bytes(&self, fd: i32, len: usize) -> Result<Bytes, FxRpcError> {
+        self.read_owned(read_request(false, fd, len, 0)).await
     }
 
-    fn rpc_write(
-        &mut self,
+    /// Positional counterpart of `read_bytes`.
+    pub async fn pread_bytes(&self, fd: i32, len: usize, offset: i64) -> Result<Bytes, FxRpcError> {
+        self.read_owned(read_request(true, fd, len, offset)).await
+    }
+
+    /// Writes `page`, which the request takes over rather than copying it.
+    pub async fn write_bytes(&self, fd: i32, page: Bytes) -> Result<i32, FxRpcError> {
+        self.write_owned(false, fd, page, 0).await
+    }
+
+    /// Positional counterpart of `write_bytes`.
+    pub async fn pwrite_bytes(&self, fd: i32, page: Bytes, offset: i64) -> Result<i32, FxRpcError> {
+        self.write_owned(true, fd, page, offset).await
+    }
+
+    async fn stat(&self, path: &str, lstat: bool) -> Result<FileStat, FxRpcError> {
+        let request = tonic::Request::new(StatRequest {
+            lstat: lstat,
+            path: path.to_string(),
+        });
+        let response = self.client.clone().stat(request).await?.into_inner();
+        FxRpcError::check(response.result, response.errno)?;
+        response
+            .stat
+            .map(FileStat::from)
+            .ok_or_else(|| FxRpcError::Decode(String::from("Stat response carries no status")))
+    }
+
+    // Vectored reads and writes of at most `stream_chunk` bytes, which go in a single
+    // message
+    async fn preadv_batch(
+        &self,
         fd: i32,
-        page: &Vec<u8>,
-        size: usize,
-    ) -> Result<i32, Box<dyn std::error::Error>> {
-        let request = tonic::Request::new(WriteRequest {
-            pwrite: false,
+        segments: &mut [(i64, &mut [u8])],
+    ) -> Result<i32, FxRpcError> {
+        let request = tonic::Request::new(ReadRequest {
+            pread: true,
             fd: fd,
-            page: page.to_vec(),
-            len: size as u32,
+            size: 0,
             offset: 0,
+            segments: segments
+                .iter()
+                .map(|(offset, buf)| Segment {
+                    offset: *offset,
+                    len: buf.len() as u32,
+                })
+                .collect(),
         });
-
-        let response = self
-            .rt
-            .as_ref()
-            .unwrap()
-            .block_on(self.client.write(request))?
-            .into_inner();
-        Ok(response.result)
+        let response = self.client.clone().read(request).await?.into_inner();
+        let result = FxRpcError::check(response.result, response.errno)?;
+        scatter(segments, &response.page)?;
+        Ok(result)
     }
 
-    fn rpc_pwrite(
-        &mut self,
-        fd: i32,
-        page: &Vec<u8>,
-        size: usize,
-        offset: i64,
-    ) -> Result<i32, Box<dyn std::error::Error>> {
+    async fn pwritev_batch(&self, fd: i32, segments: &[(i64, &[u8])]) -> Result<i32, FxRpcError> {
+        let mut page = BytesMut::with_capacity(segments.iter().map(|(_, buf)| buf.len()).sum());
+        for (_, buf) in segments {
+            page.extend_from_slice(buf);
+        }
         let request = tonic::Request::new(WriteRequest {
             pwrite: true,
             fd: fd,
-            page: page.to_vec(),
-            len: size as u32,
-            offset: offset,
+            len: page.len() as u32,
+            page: page.freeze(),
+            offset: 0,
+            segments: segments
+                .iter()
+                .map(|(offset, buf)| Segment {
+                    offset: *offset,
+                    len: buf.len() as u32,
+                })
+                .collect(),
         });
+        let response = self.client.clone().write(request).await?.into_inner();
+        FxRpcError::check(response.result, response.errno)
+    }
+}
 
-        let response = self
-            .rt
-            .as_ref()
-            .unwrap()
-            .block_on(self.client.write(request))?
-            .into_inner();
-        Ok(response.result)
+#[tonic::async_trait]
+impl AsyncFxRPC for AsyncClient {
+    async fn rpc_open(&self, path: &str, flags: i32, mode: u32) -> Result<i32, FxRpcError> {
+        let request = tonic::Request::new(OpenRequest {
+            path: path.to_string(),
+            flags: flags,
+            mode: mode,
+        });
+        let response = self.client.clone().open(request).await?.into_inner();
+        FxRpcError::check(response.result, response.errno)
     }
 
-    fn rpc_close(&mut self, fd: i32) -> Result<i32, Box<dyn std::error::Error>> {
-        let request = tonic::Request::new(CloseRequest { fd: fd });
+    async fn rpc_read(&self, fd: i32, page: &mut [u8]) -> Result<i32, FxRpcError> {
+        let request = read_request(false, fd, page.len(), 0);
+        self.read_into(request, page).await
+    }
 
-        let response = self
-            .rt
-            .as_ref()
-            .unwrap()
-            .block_on(self.client.close(request))?
-            .into_inner();
-        Ok(response.result)
+    async fn rpc_pread(&self, fd: i32, page: &mut [u8], offset: i64) -> Result<i32, FxRpcError> {
+        let request = read_request(true, fd, page.len(), offset);
+        self.read_into(request, page).await
+    }
+
+    async fn rpc_write(&self, fd: i32, page: &[u8]) -> Result<i32, FxRpcError> {
+        self.write_owned(false, fd, Bytes::copy_from_slice(page), 0)
+            .await
+    }
+
+    async fn rpc_pwrite(&self, fd: i32, page: &[u8], offset: i64) -> Result<i32, FxRpcError> {
+        self.write_owned(true, fd, Bytes::copy_from_slice(page), offset)
+            .await
+    }
+
+    async fn rpc_preadv(
+        &self,
+        fd: i32,
+        segments: &mut [(i64, &mut [u8])],
+    ) -> Result<i32, FxRpcError> {
+        let lens = segments.iter().map(|(_, buf)| buf.len());
+        let mut done = 0;
+        for batch in batches(lens, self.limits.stream_chunk, self.limits.max_segments()) {
+            let want = batch.len();
+            let res = self
+                .preadv_batch(fd, &mut segments_in_mut(segments, batch))
+                .await;
+            if !advance(&mut done, want, res)? {
+                break;
+            }
+        }
+        Ok(done as i32)
+    }
+
+    async fn rpc_pwritev(&self, fd: i32, segments: &[(i64, &[u8])]) -> Result<i32, FxRpcError> {
+        let lens = segments.iter().map(|(_, buf)| buf.len());
+        let mut done = 0;
+        for batch in batches(lens, self.limits.stream_chunk, self.limits.max_segments()) {
+            let want = batch.len();
+            let res = self.pwritev_batch(fd, &segments_in(segments, batch)).await;
+            if !advance(&mut done, want, res)? {
+                break;
+            }
+        }
+        Ok(done as i32)
+    }
+
+    async fn rpc_close(&self, fd: i32) -> Result<i32, FxRpcError> {
+        let request = tonic::Request::new(CloseRequest { fd: fd });
+        let response = self.client.clone().close(request).await?.into_inner();
+        FxRpcError::check(response.result, response.errno)
     }
 
-    fn rpc_remove(&mut self, path: &str) -> Result<i32, Box<dyn std::error::Error>> {
+    async fn rpc_remove(&self, path: &str) -> Result<i32, FxRpcError> {
         let request = tonic::Request::new(RemoveRequest {
             path: path.to_string(),
         });
-        let response = self
-            .rt
-            .as_ref()
-            .unwrap()
-            .block_on(self.client.remove(request))?
-            .into_inner();
-        Ok(response.result)
+        let response = self.client.clone().remove(request).await?.into_inner();
+        FxRpcError::check(response.result, response.errno)
     }
 
-    fn rpc_mkdir(&mut self, path: &str, mode: u32) -> Result<i32, Box<dyn std::error::Error>> {
+    async fn rpc_mkdir(&self, path: &str, mode: u32) -> Result<i32, FxRpcError> {
         let request = tonic::Request::new(DirRequest {
             path: path.to_string(),
             mode: mode,
         });
-        let response = self
-            .rt
-            .as_ref()
-            .unwrap()
-            .block_on(self.client.mkdir(request))?
-            .into_inner();
-        Ok(response.result)
+        let response = self.client.clone().mkdir(request).await?.into_inner();
+        FxRpcError::check(response.result, response.errno)
     }
 
-    fn rpc_rmdir(&mut self, path: &str) -> Result<i32, Box<dyn std::error::Error>> {
+    async fn rpc_rmdir(&self, path: &str) -> Result<i32, FxRpcError> {
         let request = tonic::Request::new(DirRequest {
             path: path.to_string(),
             mode: 0,
         });
-        let response = self
-            .rt
-            .as_ref()
-            .unwrap()
-            .block_on(self.client.rmdir(request))?
-            .into_inner();
-        Ok(response.result)
+        let response = self.client.clone().rmdir(request).await?.into_inner();
+        FxRpcError::check(response.result, response.errno)
+    }
+
+    async fn rpc_fsync(&self, fd: i32) -> Result<i32, FxRpcError> {
+        let request = tonic::Request::new(FsyncRequest { fd: fd });
+        let response = self.client.clone().fsync(request).await?.into_inner();
+        FxRpcError::check(response.result, response.errno)
+    }
+
+    async fn rpc_fstat(&self, fd: i32) -> Result<i64, FxRpcError> {
+        let request = tonic::Request::new(FstatRequest { fd: fd });
+        let response = self.client.clone().fstat(request).await?.into_inner();
+        FxRpcError::check(response.result, response.errno)?;
+        Ok(response.size)
+    }
+
+    async fn rpc_readdir(
+        &self,
+        path: &str,
+        cookie: u64,
+        count: u32,
+    ) -> Result<DirPage, FxRpcError> {
+        let request = tonic::Request::new(ReaddirRequest {
+            path: path.to_string(),
+            cookie: cookie,
+            count: count,
+        });
+        let response = self.client.clone().readdir(request).await?.into_inner();
+        FxRpcError::check(response.result, response.errno)?;
+        Ok(DirPage {
+            entries: response
+                .entries
+                .into_iter()
+                .map(|e| DirEntry {
+                    name: e.name,
+                    file_type: e.file_type as u8,
+                    ino: e.ino,
+                    cookie: e.cookie,
+                })
+                .collect(),
+            cookie: response.cookie,
+            eof: response.eof,
+        })
+    }
+
+    async fn rpc_rename(
+        &self,
+        oldpath: &str,
+        newpath: &str,
+        flags: u32,
+    ) -> Result<i32, FxRpcError> {
+        let request = tonic::Request::new(RenameRequest {
+            oldpath: oldpath.to_string(),
+            newpath: newpath.to_string(),
+            flags: flags,
+        });
+        let response = self.client.clone().rename(request).await?.into_inner();
+        FxRpcError::check(response.result, response.errno)
+    }
+
+    async fn rpc_link(&self, oldpath: &str, newpath: &str) -> Result<i32, FxRpcError> {
+        let request = tonic::Request::new(LinkRequest {
+            oldpath: oldpath.to_string(),
+            newpath: newpath.to_string(),
+        });
+        let response = self.client.clone().link(request).await?.into_inner();
+        FxRpcError::check(response.result, response.errno)
+    }
+
+    async fn rpc_unlink(&self, path: &str) -> Result<i32, FxRpcError> {
+        let request = tonic::Request::new(RemoveRequest {
+            path: path.to_string(),
+        });
+        let response = self.client.clone().unlink(request).await?.into_inner();
+        FxRpcError::check(response.result, response.errno)
+    }
+
+    async fn rpc_truncate(&self, path: &str, len: i64) -> Result<i32, FxRpcError> {
+        let request = tonic::Request::new(TruncateRequest {
+            ftruncate: false,
+            path: path.to_string(),
+            fd: 0,
+            len: len,
+        });
+        let response = self.client.clone().truncate(request).await?.into_inner();
+        FxRpcError::check(response.result, response.errno)
+    }
+
+    async fn rpc_ftruncate(&self, fd: i32, len: i64) -> Result<i32, FxRpcError> {
+        let request = tonic::Request::new(TruncateRequest {
+            ftruncate: true,
+            path: String::new(),
+            fd: fd,
+            len: len,
+        });
+        let response = self.client.clone().truncate(request).await?.into_inner();
+        FxRpcError::check(response.result, response.errno)
+    }
+
+    async fn rpc_compound(&self, compound: &Compound) -> Result<Vec<OpResult>, FxRpcError> {
+        let request = tonic::Request::new(CompoundRequest {
+            ops: compound
+                .ops
+                .iter()
+                .map(syscalls::CompoundOp::from)
+                .collect(),
+        });
+        let response = self.client.clone().compound(request).await?.into_inner();
+        Ok(response
+            .results
+            .into_iter()
+            .map(|r| OpResult {
+                result: r.result,
+                errno: r.errno,
+                data: r.page.to_vec(),
+            })
+            .collect())
+    }
+
+    async fn rpc_stat(&self, path: &str) -> Result<FileStat, FxRpcError> {
+        self.stat(path, false).await
+    }
+
+    async fn rpc_lstat(&self, path: &str) -> Result<FileStat, FxRpcError> {
+        self.stat(path, true).await
+    }
+
+    async fn rpc_stats(&self) -> Result<ServerStats, FxRpcError> {
+        let request = tonic::Request::new(StatsRequest {});
+        let response = self.client.clone().stats(request).await?.into_inner();
+        ServerStats::try_from(response).map_err(FxRpcError::Decode)
+    }
+
+    async fn rpc_shutdown(&self, token: &str) -> Result<i32, FxRpcError> {
+        let request = tonic::Request::new(ShutdownRequest {
+            token: Bytes::copy_from_slice(token.as_bytes()),
+        });
+        let response = self.client.clone().shutdown(request).await?.into_inner();
+        FxRpcError::check(response.result, response.errno)
+    }
+}
+
+// Synchronous wrapper around AsyncClient that drives each call on its own runtime
+pub struct BlockingClient {
+    client: AsyncClient,
+    rt: Option<Runtime>,
+}
+
+impl BlockingClient {
+    pub fn connect_tcp<D>(dst: D, limits: GrpcLimits) -> Result<Self, tonic::transport::Error>
+    where
+        D: TryInto<tonic::transport::Endpoint>,
+        D::Error: Into<StdError>,
+    {
+        let rt = Builder::new_multi_thread().enable_all().build().unwrap();
+        let client = rt.block_on(AsyncClient::connect_tcp(dst, limits))?;
+
+        Ok(Self {
+            client,
+            rt: Some(rt),
+        })
+    }
+
+    pub fn connect_uds(path: PathBuf, limits: GrpcLimits) -> Result<Self, tonic::transport::Error> {
+        let rt = Builder::new_multi_thread().enable_all().build().unwrap();
+        let client = rt.block_on(AsyncClient::connect_uds(path, limits))?;
+
+        Ok(Self {
+            client,
+            rt: Some(rt),
+        })
+    }
+
+    pub fn connect_tls(
+        addr: &str,
+        tls: &TlsConfig,
+        limits: GrpcLimits,
+    ) -> Result<Self, FxRpcError> {
+        let rt = Builder::new_multi_thread().enable_all().build().unwrap();
+        let client = rt.block_on(AsyncClient::connect_tls(addr, tls, limits))?;
+
+        Ok(Self {
+            client,
+            rt: Some(rt),
+        })
+    }
+
+    fn block_on<F: std::future::Future>(&self, future: F) -> F::Output {
+        self.rt.as_ref().unwrap().block_on(future)
+    }
+
+    /// See `AsyncClient::read_bytes`.
+    pub fn read_bytes(&mut self, fd: i32, len: usize) -> Result<Bytes, FxRpcError> {
+        self.block_on(self.client.read_bytes(fd, len))
+    }
+
+    /// See `AsyncClient::pread_bytes`.
+    pub fn pread_bytes(&mut self, fd: i32, len: usize, offset: i64) -> Result<Bytes, FxRpcError> {
+        self.block_on(self.client.pread_bytes(fd, len, offset))
+    }
+
+    /// See `AsyncClient::write_bytes`.
+    pub fn write_bytes(&mut self, fd: i32, page: Bytes) -> Result<i32, FxRpcError> {
+        self.block_on(self.client.write_bytes(fd, page))
+    }
+
+    /// See `AsyncClient::pwrite_bytes`.
+    pub fn pwrite_bytes(&mut self, fd: i32, page: Bytes, offset: i64) -> Result<i32, FxRpcError> {
+        self.block_on(self.client.pwrite_bytes(fd, page, offset))
+    }
+}
+
+impl FxRPC for BlockingClient {
+    fn rpc_open(&mut self, path: &str, flags: i32, mode: u32) -> Result<i32, FxRpcError> {
+        self.block_on(AsyncFxRPC::rpc_open(&self.client, path, flags, mode))
+    }
+
+    fn rpc_read(&mut self, fd: i32, page: &mut [u8]) -> Result<i32, FxRpcError> {
+        self.block_on(AsyncFxRPC::rpc_read(&self.client, fd, page))
+    }
+
+    fn rpc_pread(&mut self, fd: i32, page: &mut [u8], offset: i64) -> Result<i32, FxRpcError> {
+        self.block_on(AsyncFxRPC::rpc_pread(&self.client, fd, page, offset))
+    }
+
+    fn rpc_write(&mut self, fd: i32, page: &[u8]) -> Result<i32, FxRpcError> {
+        self.block_on(AsyncFxRPC::rpc_write(&self.client, fd, page))
+    }
+
+    fn rpc_pwrite(&mut self, fd: i32, page: &[u8], offset: i64) -> Result<i32, FxRpcError> {
+        self.block_on(AsyncFxRPC::rpc_pwrite(&self.client, fd, page, offset))
+    }
+
+    fn rpc_preadv(
+        &mut self,
+        fd: i32,
+        segments: &mut [(i64, &mut [u8])],
+    ) -> Result<i32, FxRpcError> {
+        self.block_on(AsyncFxRPC::rpc_preadv(&self.client, fd, segments))
+    }
+
+    fn rpc_pwritev(&mut self, fd: i32, segments: &[(i64, &[u8])]) -> Result<i32, FxRpcError> {
+        self.block_on(AsyncFxRPC::rpc_pwritev(&self.client, fd, segments))
+    }
+
+    fn rpc_close(&mut self, fd: i32) -> Result<i32, FxRpcError> {
+        self.block_on(AsyncFxRPC::rpc_close(&self.client, fd))
+    }
+
+    fn rpc_remove(&mut self, path: &str) -> Result<i32, FxRpcError> {
+        self.block_on(AsyncFxRPC::rpc_remove(&self.client, path))
+    }
+
+    fn rpc_mkdir(&mut self, path: &str, mode: u32) -> Result<i32, FxRpcError> {
+        self.block_on(AsyncFxRPC::rpc_mkdir(&self.client, path, mode))
+    }
+
+    fn rpc_rmdir(&mut self, path: &str) -> Result<i32, FxRpcError> {
+        self.block_on(AsyncFxRPC::rpc_rmdir(&self.client, path))
+    }
+
+    fn rpc_fsync(&mut self, fd: i32) -> Result<i32, FxRpcError> {
+        self.block_on(AsyncFxRPC::rpc_fsync(&self.client, fd))
+    }
+
+    fn rpc_fstat(&mut self, fd: i32) -> Result<i64, FxRpcError> {
+        self.block_on(AsyncFxRPC::rpc_fstat(&self.client, fd))
+    }
+
+    fn rpc_readdir(&mut self, path: &str, cookie: u64, count: u32) -> Result<DirPage, FxRpcError> {
+        self.block_on(AsyncFxRPC::rpc_readdir(&self.client, path, cookie, count))
+    }
+
+    fn rpc_rename(&mut self, oldpath: &str, newpath: &str, flags: u32) -> Result<i32, FxRpcError> {
+        self.block_on(AsyncFxRPC::rpc_rename(
+            &self.client,
+            oldpath,
+            newpath,
+            flags,
+        ))
+    }
+
+    fn rpc_link(&mut self, oldpath: &str, newpath: &str) -> Result<i32, FxRpcError> {
+        self.block_on(AsyncFxRPC::rpc_link(&self.client, oldpath, newpath))
+    }
+
+    fn rpc_unlink(&mut self, path: &str) -> Result<i32, FxRpcError> {
+        self.block_on(AsyncFxRPC::rpc_unlink(&self.client, path))
+    }
+
+    fn rpc_truncate(&mut self, path: &str, len: i64) -> Result<i32, FxRpcError> {
+        self.block_on(AsyncFxRPC::rpc_truncate(&self.client, path, len))
+    }
+
+    fn rpc_ftruncate(&mut self, fd: i32, len: i64) -> Result<i32, FxRpcError> {
+        self.block_on(AsyncFxRPC::rpc_ftruncate(&self.client, fd, len))
+    }
+
+    fn rpc_compound(&mut self, compound: &Compound) -> Result<Vec<OpResult>, FxRpcError> {
+        self.block_on(AsyncFxRPC::rpc_compound(&self.client, compound))
+    }
+
+    fn rpc_stat(&mut self, path: &str) -> Result<FileStat, FxRpcError> {
+        self.block_on(AsyncFxRPC::rpc_stat(&self.client, path))
+    }
+
+    fn rpc_lstat(&mut self, path: &str) -> Result<FileStat, FxRpcError> {
+        self.block_on(AsyncFxRPC::rpc_lstat(&self.client, path))
+    }
+
+    fn rpc_stats(&mut self) -> Result<ServerStats, FxRpcError> {
+        self.block_on(AsyncFxRPC::rpc_stats(&self.client))
+    }
+
+    fn rpc_shutdown(&mut self, token: &str) -> Result<i32, FxRpcError> {
+        self.block_on(AsyncFxRPC::rpc_shutdown(&self.client, token))
     }
 }
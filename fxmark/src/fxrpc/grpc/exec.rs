@@ -0,0 +1,87 @@
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+use tonic::Status;
+
+use crate::fxmark::utils::{pin_thread, topology::Cpu};
+use crate::fxrpc::IoExec;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// Threads pinned to the cores of a pool, running the backend calls handed to them. They
+// exit once the pool is dropped.
+pub struct IoPool {
+    jobs: Mutex<Sender<Job>>,
+}
+
+impl IoPool {
+    fn new(cores: &[Cpu]) -> IoPool {
+        let (jobs, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for &core in cores {
+            let receiver = receiver.clone();
+            std::thread::spawn(move || {
+                pin_thread(core);
+                loop {
+                    // The lock is only held while waiting, not while the job runs
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                }
+            });
+        }
+        IoPool {
+            jobs: Mutex::new(jobs),
+        }
+    }
+
+    fn submit(&self, job: Job) -> bool {
+        self.jobs.lock().unwrap().send(job).is_ok()
+    }
+}
+
+/// Runs the calls the gRPC server makes into the backend, which block, where the
+/// server's `IoExec` says.
+#[derive(Clone)]
+pub enum Executor {
+    Inline,
+    Blocking,
+    Pool(Arc<IoPool>),
+}
+
+impl Executor {
+    pub fn new(exec: &IoExec) -> Executor {
+        match exec {
+            IoExec::Inline => Executor::Inline,
+            IoExec::Blocking => Executor::Blocking,
+            IoExec::Pool(pool) => Executor::Pool(Arc::new(IoPool::new(&pool.cores))),
+        }
+    }
+
+    /// Runs `call` and returns its result once it is done.
+    pub async fn run<T, F>(&self, call: F) -> Result<T, Status>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        match self {
+            Executor::Inline => Ok(call()),
+            Executor::Blocking => tokio::task::spawn_blocking(call)
+                .await
+                .map_err(|e| Status::internal(format!("Backend call failed: {}", e))),
+            Executor::Pool(pool) => {
+                let (tx, rx) = oneshot::channel();
+                let submitted = pool.submit(Box::new(move || {
+                    let _ = tx.send(call());
+                }));
+                if !submitted {
+                    return Err(Status::unavailable("I/O threads are gone"));
+                }
+                rx.await
+                    .map_err(|_| Status::internal("Backend call failed on an I/O thread"))
+            }
+        }
+    }
+}
@@ -4,9 +4,236 @@ pub use crate::fxrpc::grpc::server::*;
 pub mod client;
 pub use crate::fxrpc::grpc::client::*;
 
+pub mod session;
+pub use crate::fxrpc::grpc::session::SessionStream;
+
+pub mod exec;
+pub use crate::fxrpc::grpc::exec::Executor;
+
+use bytes::Bytes;
+use std::sync::Arc;
+
+use crate::fxrpc::{CompoundOp, FdRef, FsBackend, GrpcLimits, OpStats, ServerStats};
+
 pub mod syscalls {
     tonic::include_proto!("syscalls");
 }
 
-#[derive(Debug, Default)]
-pub struct SyscallService {}
+impl From<crate::fxrpc::FileStat> for syscalls::FileStat {
+    fn from(st: crate::fxrpc::FileStat) -> Self {
+        syscalls::FileStat {
+            dev: st.dev,
+            ino: st.ino,
+            mode: st.mode,
+            nlink: st.nlink,
+            uid: st.uid,
+            gid: st.gid,
+            size: st.size,
+            blocks: st.blocks,
+            atime: st.atime,
+            mtime: st.mtime,
+            ctime: st.ctime,
+        }
+    }
+}
+
+impl From<syscalls::FileStat> for crate::fxrpc::FileStat {
+    fn from(st: syscalls::FileStat) -> Self {
+        crate::fxrpc::FileStat {
+            dev: st.dev,
+            ino: st.ino,
+            mode: st.mode,
+            nlink: st.nlink,
+            uid: st.uid,
+            gid: st.gid,
+            size: st.size,
+            blocks: st.blocks,
+            atime: st.atime,
+            mtime: st.mtime,
+            ctime: st.ctime,
+        }
+    }
+}
+
+impl From<ServerStats> for syscalls::StatsResponse {
+    fn from(stats: ServerStats) -> Self {
+        syscalls::StatsResponse {
+            uptime_ns: stats.uptime.as_nanos() as u64,
+            ops: stats
+                .ops
+                .into_iter()
+                .map(|op| syscalls::OpStats {
+                    op: op.op.to_string(),
+                    count: op.count,
+                    errors: op.errors,
+                    bytes: op.bytes,
+                    in_flight: op.in_flight,
+                    latency: op.latency,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<syscalls::StatsResponse> for ServerStats {
+    type Error = String;
+
+    fn try_from(stats: syscalls::StatsResponse) -> Result<Self, Self::Error> {
+        let ops = stats
+            .ops
+            .into_iter()
+            .map(|op| {
+                Ok(OpStats {
+                    op: op.op.parse()?,
+                    count: op.count,
+                    errors: op.errors,
+                    bytes: op.bytes,
+                    in_flight: op.in_flight,
+                    latency: op.latency,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(ServerStats {
+            uptime: std::time::Duration::from_nanos(stats.uptime_ns),
+            ops: ops,
+        })
+    }
+}
+
+impl From<FdRef> for syscalls::FdRef {
+    fn from(fd: FdRef) -> Self {
+        let target = match fd {
+            FdRef::Fd(fd) => syscalls::fd_ref::Target::Fd(fd),
+            FdRef::Op(index) => syscalls::fd_ref::Target::OpIndex(index as u32),
+        };
+        syscalls::FdRef {
+            target: Some(target),
+        }
+    }
+}
+
+fn fd_ref(fd: Option<syscalls::FdRef>) -> Option<FdRef> {
+    match fd?.target? {
+        syscalls::fd_ref::Target::Fd(fd) => Some(FdRef::Fd(fd)),
+        syscalls::fd_ref::Target::OpIndex(index) => Some(FdRef::Op(index as usize)),
+    }
+}
+
+fn compound_io(fd: FdRef, offset: i64, len: usize, page: Bytes) -> syscalls::CompoundIo {
+    syscalls::CompoundIo {
+        fd: Some(fd.into()),
+        offset: offset,
+        len: len as u32,
+        page: page,
+    }
+}
+
+impl From<&CompoundOp> for syscalls::CompoundOp {
+    fn from(op: &CompoundOp) -> Self {
+        use syscalls::compound_op::Op;
+        let op = match op {
+            CompoundOp::Open { path, flags, mode } => Op::Open(syscalls::OpenRequest {
+                path: path.clone(),
+                flags: *flags,
+                mode: *mode,
+            }),
+            CompoundOp::PRead { fd, len, offset } => {
+                Op::Pread(compound_io(*fd, *offset, *len, Bytes::new()))
+            }
+            CompoundOp::PWrite { fd, data, offset } => Op::Pwrite(compound_io(
+                *fd,
+                *offset,
+                data.len(),
+                Bytes::copy_from_slice(data),
+            )),
+            CompoundOp::Fsync { fd } => Op::Fsync((*fd).into()),
+            CompoundOp::Fstat { fd } => Op::Fstat((*fd).into()),
+            CompoundOp::FTruncate { fd, len } => Op::Ftruncate(syscalls::CompoundTruncate {
+                fd: Some((*fd).into()),
+                len: *len,
+            }),
+            CompoundOp::Close { fd } => Op::Close((*fd).into()),
+            CompoundOp::Unlink { path } => {
+                Op::Unlink(syscalls::RemoveRequest { path: path.clone() })
+            }
+            CompoundOp::Mkdir { path, mode } => Op::Mkdir(syscalls::DirRequest {
+                path: path.clone(),
+                mode: *mode,
+            }),
+        };
+        syscalls::CompoundOp { op: Some(op) }
+    }
+}
+
+impl TryFrom<syscalls::CompoundOp> for CompoundOp {
+    type Error = String;
+
+    fn try_from(op: syscalls::CompoundOp) -> Result<Self, Self::Error> {
+        use syscalls::compound_op::Op;
+        let missing_fd = || String::from("Compound operation without a descriptor");
+        let op = match op
+            .op
+            .ok_or_else(|| String::from("Empty compound operation"))?
+        {
+            Op::Open(r) => CompoundOp::Open {
+                path: r.path,
+                flags: r.flags,
+                mode: r.mode,
+            },
+            Op::Pread(r) => CompoundOp::PRead {
+                fd: fd_ref(r.fd).ok_or_else(missing_fd)?,
+                len: r.len as usize,
+                offset: r.offset,
+            },
+            Op::Pwrite(r) => {
+                let len = (r.len as usize).min(r.page.len());
+                CompoundOp::PWrite {
+                    fd: fd_ref(r.fd).ok_or_else(missing_fd)?,
+                    data: r.page[..len].to_vec(),
+                    offset: r.offset,
+                }
+            }
+            Op::Fsync(fd) => CompoundOp::Fsync {
+                fd: fd_ref(Some(fd)).ok_or_else(missing_fd)?,
+            },
+            Op::Fstat(fd) => CompoundOp::Fstat {
+                fd: fd_ref(Some(fd)).ok_or_else(missing_fd)?,
+            },
+            Op::Ftruncate(r) => CompoundOp::FTruncate {
+                fd: fd_ref(r.fd).ok_or_else(missing_fd)?,
+                len: r.len,
+            },
+            Op::Close(fd) => CompoundOp::Close {
+                fd: fd_ref(Some(fd)).ok_or_else(missing_fd)?,
+            },
+            Op::Unlink(r) => CompoundOp::Unlink { path: r.path },
+            Op::Mkdir(r) => CompoundOp::Mkdir {
+                path: r.path,
+                mode: r.mode,
+            },
+        };
+        Ok(op)
+    }
+}
+
+pub struct SyscallService {
+    // Every request is served from the backend
+    backend: Arc<dyn FsBackend>,
+    // Where the blocking calls into the backend run
+    exec: Executor,
+    // Bytes of data in every message of a ReadStream
+    stream_chunk: usize,
+    // Most bytes of data a unary read returns, checked before its buffer is allocated
+    max_data: usize,
+}
+
+impl SyscallService {
+    pub fn new(backend: Arc<dyn FsBackend>, exec: Executor, limits: GrpcLimits) -> Self {
+        SyscallService {
+            backend: backend,
+            exec: exec,
+            stream_chunk: limits.stream_chunk,
+            max_data: limits.max_data(),
+        }
+    }
+}
@@ -3,272 +3,999 @@
     Zack McKevitt - 2023
 */
 
-use libc::*;
+use bytes::{Bytes, BytesMut};
+use futures::{Future, Stream};
+use futures::{StreamExt, TryStreamExt};
+use libc::{ECONNABORTED, EMSGSIZE};
+use log::debug;
 use syscalls::{
     syscall_server::{Syscall, SyscallServer},
-    CloseRequest, DirRequest, FstatRequest, FstatResponse, FsyncRequest, OpenRequest, ReadRequest,
-    RemoveRequest, SyscallResponse, WriteRequest,
+    CloseRequest, CompoundRequest, CompoundResponse, DirRequest, FstatRequest, FstatResponse,
+    FsyncRequest, LinkRequest, OpenRequest, ReadRequest, ReaddirRequest, ReaddirResponse,
+    RemoveRequest, RenameRequest, ShutdownRequest, StatRequest, StatResponse, StatsRequest,
+    StatsResponse, SyscallResponse, TruncateRequest, WriteRequest,
 };
-use tokio::net::UnixListener;
-use tokio::runtime::Runtime;
-use tokio_stream::wrappers::UnixListenerStream;
-use tonic::{transport::Server, Request, Response, Status};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpSocket, UnixListener};
+use tokio::runtime::{Builder, Runtime};
+use tokio::sync::mpsc;
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream, UnixListenerStream};
+use tonic::{transport::Server, Request, Response, Status, Streaming};
 
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::os::unix::net::UnixListener as StdUnixListener;
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
+use crate::fxmark::utils::pin_thread;
+use crate::fxrpc::backend::{status, transferred};
+use crate::fxrpc::compound::run_compound;
+use crate::fxrpc::grpc::exec::Executor;
+use crate::fxrpc::grpc::session::SessionStream;
+use crate::fxrpc::handles::OpenFile;
+use crate::fxrpc::iov::segments_len;
+use crate::fxrpc::metrics::{self, OpTimer};
+use crate::fxrpc::shutdown;
+use crate::fxrpc::tls;
 pub use crate::fxrpc::*;
 
 //////////////////////////////////////// SERVER ////////////////////////////////////////
 
-fn libc_open(filename: &str, flags: i32, mode: u32) -> Response<syscalls::SyscallResponse> {
-    let file_path = format!("{}{}{}", FS_PATH, filename, char::from(0));
-    let fd;
-    unsafe {
-        fd = open(file_path.as_ptr() as *const i8, flags, mode);
-    }
+// Handles of the connection a request arrived on
+fn session<T>(request: &Request<T>) -> Result<Arc<HandleTable>, Status> {
+    request
+        .extensions()
+        .get::<Arc<HandleTable>>()
+        .cloned()
+        .ok_or_else(|| Status::internal("Request arrived outside of a session"))
+}
+
+// Response to a request that fails before reaching the backend, such as one on a handle
+// the connection does not own
+fn failed(errno: i32) -> Response<syscalls::SyscallResponse> {
     Response::new(syscalls::SyscallResponse {
-        result: fd,
-        page: vec![0],
+        result: -1,
+        errno: errno,
+        page: Bytes::new(),
     })
 }
 
-fn libc_read(fd: i32, size: usize) -> Response<syscalls::SyscallResponse> {
-    let res;
-    //let page: &mut [u8; size] = &mut [0; size];
-    let page: Vec<u8> = vec![0; size];
-    unsafe {
-        res = read(fd, page.as_ptr() as *mut c_void, size);
-    }
+// The client only ever sees the handle of the new descriptor
+fn fs_open(
+    backend: &dyn FsBackend,
+    handles: &HandleTable,
+    filename: &str,
+    flags: i32,
+    mode: u32,
+) -> Response<syscalls::SyscallResponse> {
+    let (handle, errno) = match backend.open(filename, flags, mode) {
+        Ok(fd) => (handles.insert(fd), 0),
+        Err(errno) => (-1, errno),
+    };
     Response::new(syscalls::SyscallResponse {
-        result: res as i32,
-        page: page.to_vec(),
+        result: handle,
+        errno: errno,
+        page: Bytes::new(),
     })
 }
 
-fn libc_pread(fd: i32, size: usize, offset: i64) -> Response<syscalls::SyscallResponse> {
-    let res;
-    //let page: &mut [u8; size] = &mut [0; size];
-    let page: Vec<u8> = vec![0; size];
-    unsafe {
-        res = pread(fd, page.as_ptr() as *mut c_void, size, offset);
-    }
+// Replies to a call that returns nothing but its outcome
+fn fs_status(res: Result<(), i32>) -> Response<syscalls::SyscallResponse> {
+    let (res, errno) = status(res);
     Response::new(syscalls::SyscallResponse {
-        result: res as i32,
-        page: page.to_vec(),
+        result: res,
+        errno: errno,
+        page: Bytes::new(),
     })
 }
 
-fn libc_write(fd: i32, page: Vec<u8>, len: usize) -> Response<syscalls::SyscallResponse> {
-    let res;
-    unsafe {
-        res = write(fd, page.as_ptr() as *const c_void, len);
-    }
+// Replies to a read with the part of `page` that was read
+fn fs_data(res: Result<usize, i32>, mut page: BytesMut) -> Response<syscalls::SyscallResponse> {
+    page.truncate(*res.as_ref().unwrap_or(&0));
+    let (res, errno) = transferred(res);
     Response::new(syscalls::SyscallResponse {
         result: res as i32,
-        page: vec![0],
+        errno: errno,
+        page: page.freeze(),
     })
 }
 
-fn libc_pwrite(
+// Reads go straight into the buffer that becomes the response payload
+fn fs_read(backend: &dyn FsBackend, fd: i32, size: usize) -> Response<syscalls::SyscallResponse> {
+    let mut page = BytesMut::zeroed(size);
+    let res = backend.read(fd, &mut page);
+    fs_data(res, page)
+}
+
+fn fs_pread(
+    backend: &dyn FsBackend,
     fd: i32,
-    page: Vec<u8>,
-    len: usize,
+    size: usize,
     offset: i64,
 ) -> Response<syscalls::SyscallResponse> {
-    let res;
-    unsafe {
-        res = pwrite(fd, page.as_ptr() as *const c_void, len, offset);
-    }
+    let mut page = BytesMut::zeroed(size);
+    let res = backend.pread(fd, &mut page, offset);
+    fs_data(res, page)
+}
+
+// The page is a slice of the request buffer, so writes are issued without copying it
+fn fs_write(
+    backend: &dyn FsBackend,
+    fd: i32,
+    page: Bytes,
+    len: usize,
+) -> Response<syscalls::SyscallResponse> {
+    let (res, errno) = transferred(backend.write(fd, &page[..len.min(page.len())]));
     Response::new(syscalls::SyscallResponse {
         result: res as i32,
-        page: vec![0],
+        errno: errno,
+        page: Bytes::new(),
     })
 }
 
-fn libc_close(fd: i32) -> Response<syscalls::SyscallResponse> {
-    let res;
-    unsafe {
-        res = close(fd);
-    }
+fn fs_pwrite(
+    backend: &dyn FsBackend,
+    fd: i32,
+    page: Bytes,
+    len: usize,
+    offset: i64,
+) -> Response<syscalls::SyscallResponse> {
+    let (res, errno) = transferred(backend.pwrite(fd, &page[..len.min(page.len())], offset));
     Response::new(syscalls::SyscallResponse {
-        result: res,
-        page: vec![0],
+        result: res as i32,
+        errno: errno,
+        page: Bytes::new(),
     })
 }
 
-fn libc_remove(filename: &str) -> Response<syscalls::SyscallResponse> {
-    let file_path = format!("{}{}{}", FS_PATH, filename, char::from(0));
-    let fd;
-    unsafe {
-        fd = remove(file_path.as_ptr() as *const i8);
-    }
+// Vectored reads return the data of all segments back to back
+fn fs_preadv(
+    backend: &dyn FsBackend,
+    fd: i32,
+    segments: &[(i64, usize)],
+) -> Response<syscalls::SyscallResponse> {
+    let mut page = BytesMut::zeroed(segments_len(segments));
+    let res = backend.preadv(fd, segments, &mut page);
+    fs_data(res, page)
+}
+
+fn fs_pwritev(
+    backend: &dyn FsBackend,
+    fd: i32,
+    segments: &[(i64, usize)],
+    page: Bytes,
+) -> Response<syscalls::SyscallResponse> {
+    let (res, errno) = transferred(backend.pwritev(fd, segments, &page));
     Response::new(syscalls::SyscallResponse {
-        result: fd,
-        page: vec![0],
+        result: res as i32,
+        errno: errno,
+        page: Bytes::new(),
     })
 }
 
-fn libc_fsync(fd: i32) -> Response<syscalls::SyscallResponse> {
-    let res;
-    unsafe {
-        res = fsync(fd);
-    }
+fn fs_close(handles: &HandleTable, handle: i32) -> Response<syscalls::SyscallResponse> {
+    let (res, errno) = handles.close(handle);
     Response::new(syscalls::SyscallResponse {
         result: res,
-        page: vec![0],
+        errno: errno,
+        page: Bytes::new(),
     })
 }
 
-fn libc_mkdir(dirname: &str, mode: u32) -> Response<syscalls::SyscallResponse> {
-    let dir_path = format!("{}{}{}", FS_PATH, dirname, char::from(0));
-    let res;
-    unsafe {
-        res = mkdir(dir_path.as_ptr() as *const i8, mode.try_into().unwrap());
+fn fs_stat(
+    backend: &dyn FsBackend,
+    filename: &str,
+    follow: bool,
+) -> Response<syscalls::StatResponse> {
+    let response = match backend.stat(filename, follow) {
+        Ok(st) => syscalls::StatResponse {
+            result: 0,
+            errno: 0,
+            stat: Some(st.into()),
+        },
+        Err(errno) => syscalls::StatResponse {
+            result: -1,
+            errno: errno,
+            stat: None,
+        },
+    };
+    Response::new(response)
+}
+
+// Mix only needs the file size, the full attributes are served by stat
+fn fs_fstat_size(backend: &dyn FsBackend, fd: i32) -> Response<syscalls::FstatResponse> {
+    let response = match backend.fstat(fd) {
+        Ok(st) => syscalls::FstatResponse {
+            result: 0,
+            size: st.size,
+            errno: 0,
+        },
+        Err(errno) => syscalls::FstatResponse {
+            result: -1,
+            size: 0,
+            errno: errno,
+        },
+    };
+    Response::new(response)
+}
+
+fn fs_readdir(
+    backend: &dyn FsBackend,
+    dirname: &str,
+    cookie: u64,
+    count: u32,
+) -> Response<syscalls::ReaddirResponse> {
+    let response = match backend.readdir(dirname, cookie, count as usize) {
+        Ok(page) => syscalls::ReaddirResponse {
+            result: page.entries.len() as i32,
+            errno: 0,
+            entries: page
+                .entries
+                .into_iter()
+                .map(|e| syscalls::DirEntry {
+                    name: e.name,
+                    file_type: e.file_type as u32,
+                    ino: e.ino,
+                    cookie: e.cookie,
+                })
+                .collect(),
+            cookie: page.cookie,
+            eof: page.eof,
+        },
+        Err(errno) => syscalls::ReaddirResponse {
+            result: -1,
+            errno: errno,
+            entries: Vec::new(),
+            cookie: cookie,
+            eof: false,
+        },
+    };
+    Response::new(response)
+}
+
+fn fs_compound(
+    backend: &dyn FsBackend,
+    handles: &HandleTable,
+    ops: &[CompoundOp],
+) -> Response<syscalls::CompoundResponse> {
+    let results = run_compound(ops, backend, handles)
+        .into_iter()
+        .map(|r| syscalls::CompoundResult {
+            result: r.result,
+            errno: r.errno,
+            page: Bytes::from(r.data),
+        })
+        .collect();
+    Response::new(syscalls::CompoundResponse { results: results })
+}
+
+// Responses that carry the (result, errno) of the call they answer
+trait Outcome {
+    fn outcome(&self) -> (i64, i32);
+}
+
+impl Outcome for syscalls::SyscallResponse {
+    fn outcome(&self) -> (i64, i32) {
+        (self.result as i64, self.errno)
     }
-    Response::new(syscalls::SyscallResponse {
-        result: res,
-        page: vec![0],
-    })
 }
 
-fn libc_rmdir(dirname: &str) -> Response<syscalls::SyscallResponse> {
-    let dir_path = format!("{}{}{}", FS_PATH, dirname, char::from(0));
-    let res;
-    unsafe {
-        res = rmdir(dir_path.as_ptr() as *const i8);
+impl Outcome for syscalls::FstatResponse {
+    fn outcome(&self) -> (i64, i32) {
+        (self.result as i64, self.errno)
     }
-    Response::new(syscalls::SyscallResponse {
-        result: res,
-        page: vec![0],
-    })
 }
 
-// Currently only supporting fstat file size
-// Not yet clear how to conver MaybeUninit<stat> to Vec<u8>
-// Mix only needs file size anyways
-fn libc_fstat_size(fd: i32) -> Response<syscalls::FstatResponse> {
-    let res;
-    let fsize;
-    let mut info = std::mem::MaybeUninit::uninit();
-    unsafe {
-        res = fstat(fd, info.as_mut_ptr());
-        fsize = info.assume_init().st_size;
+impl Outcome for syscalls::StatResponse {
+    fn outcome(&self) -> (i64, i32) {
+        (self.result as i64, self.errno)
     }
-    Response::new(syscalls::FstatResponse {
-        result: res,
-        size: fsize,
-    })
 }
 
-// TODO: Do error handling
+impl Outcome for syscalls::ReaddirResponse {
+    fn outcome(&self) -> (i64, i32) {
+        (self.result as i64, self.errno)
+    }
+}
+
+// A compound succeeds with the number of operations it ran, whatever their results,
+// unless one of them asked to drop the connection
+impl Outcome for syscalls::CompoundResponse {
+    fn outcome(&self) -> (i64, i32) {
+        match self.results.iter().find(|r| r.errno == ECONNABORTED) {
+            Some(r) => (-1, r.errno),
+            None => (self.results.len() as i64, 0),
+        }
+    }
+}
+
+// Records the call in the server metrics. A backend asking to drop the connection is
+// answered with UNAVAILABLE, which the client sees as a transport error just like a
+// connection that went away.
+fn reply<T: Outcome>(timer: OpTimer, response: Response<T>) -> Result<Response<T>, Status> {
+    let (result, errno) = response.get_ref().outcome();
+    timer.finish(result);
+    if errno == ECONNABORTED {
+        return Err(Status::unavailable("Connection dropped by the server"));
+    }
+    Ok(response)
+}
+
+// Sends the chunks of a ReadStream as they are read, up to `size` bytes in all. Stops
+// after a short chunk or a failed one, whose errno the client gets in its result, and
+// returns what the whole read amounts to: the bytes read, or -1 if the first chunk failed.
+async fn read_chunks(
+    backend: Arc<dyn FsBackend>,
+    exec: Executor,
+    fd: Arc<OpenFile>,
+    r: ReadRequest,
+    chunk: usize,
+    tx: mpsc::Sender<Result<SyscallResponse, Status>>,
+) -> i64 {
+    let size = r.size as usize;
+    let mut done = 0;
+    loop {
+        let len = chunk.min(size - done);
+        let offset = r.offset + done as i64;
+        let (backend, fd) = (backend.clone(), fd.clone());
+        let response = exec
+            .run(move || match r.pread {
+                true => fs_pread(&*backend, fd.fd(), len, offset),
+                false => fs_read(&*backend, fd.fd(), len),
+            })
+            .await;
+        let response = match response {
+            Ok(response) => response.into_inner(),
+            Err(status) => {
+                let _ = tx.send(Err(status)).await;
+                return -1;
+            }
+        };
+        if response.errno == ECONNABORTED {
+            let status = Status::unavailable("Connection dropped by the server");
+            let _ = tx.send(Err(status)).await;
+            return -1;
+        }
+
+        let result = response.result;
+        // A client that went away stops the read like a failed chunk
+        if tx.send(Ok(response)).await.is_err() || result < 0 {
+            return if done > 0 { done as i64 } else { -1 };
+        }
+        done += result as usize;
+        if (result as usize) < len || done == size {
+            return done as i64;
+        }
+    }
+}
+
+fn segments(segments: &[syscalls::Segment]) -> Vec<(i64, usize)> {
+    segments
+        .iter()
+        .map(|s| (s.offset, s.len as usize))
+        .collect()
+}
+
 #[tonic::async_trait]
 impl Syscall for SyscallService {
     async fn open(
         &self,
         request: Request<OpenRequest>,
     ) -> Result<Response<SyscallResponse>, Status> {
+        let handles = session(&request)?;
         let r = request.into_inner();
-        Ok(libc_open(&r.path, r.flags, r.mode))
+        let timer = metrics::start(RpcOp::Open);
+        let backend = self.backend.clone();
+        let response = self
+            .exec
+            .run(move || fs_open(&*backend, &handles, &r.path, r.flags, r.mode))
+            .await?;
+        reply(timer, response)
     }
     async fn read(
         &self,
         request: Request<ReadRequest>,
     ) -> Result<Response<SyscallResponse>, Status> {
+        let handles = session(&request)?;
         let r = request.into_inner();
-        match r.pread {
-            true => Ok(libc_pread(r.fd, r.size as usize, r.offset)),
-            false => Ok(libc_read(r.fd, r.size as usize)),
+        let op = match (r.segments.is_empty(), r.pread) {
+            (false, _) => RpcOp::PReadV,
+            (true, true) => RpcOp::PRead,
+            (true, false) => RpcOp::Read,
+        };
+        let timer = metrics::start(op);
+        let segs = segments(&r.segments);
+        let size = match op {
+            RpcOp::PReadV => segments_len(&segs),
+            _ => r.size as usize,
+        };
+        // The client picks the size, so it is checked before anything is allocated
+        if size > self.max_data {
+            return reply(timer, failed(EMSGSIZE));
         }
+        let fd = match handles.get(r.fd) {
+            Ok(fd) => fd,
+            Err(errno) => return reply(timer, failed(errno)),
+        };
+        let backend = self.backend.clone();
+        let response = self
+            .exec
+            .run(move || match op {
+                RpcOp::PReadV => fs_preadv(&*backend, fd.fd(), &segs),
+                RpcOp::PRead => fs_pread(&*backend, fd.fd(), r.size as usize, r.offset),
+                _ => fs_read(&*backend, fd.fd(), r.size as usize),
+            })
+            .await?;
+        reply(timer, response)
     }
     async fn write(
         &self,
         request: Request<WriteRequest>,
     ) -> Result<Response<SyscallResponse>, Status> {
+        let handles = session(&request)?;
         let r = request.into_inner();
-        match r.pwrite {
-            true => Ok(libc_pwrite(r.fd, r.page, r.len as usize, r.offset)),
-            false => Ok(libc_write(r.fd, r.page, r.len as usize)),
-        }
+        let op = match (r.segments.is_empty(), r.pwrite) {
+            (false, _) => RpcOp::PWriteV,
+            (true, true) => RpcOp::PWrite,
+            (true, false) => RpcOp::Write,
+        };
+        let timer = metrics::start(op);
+        let fd = match handles.get(r.fd) {
+            Ok(fd) => fd,
+            Err(errno) => return reply(timer, failed(errno)),
+        };
+        let backend = self.backend.clone();
+        let response = self
+            .exec
+            .run(move || match op {
+                RpcOp::PWriteV => fs_pwritev(&*backend, fd.fd(), &segments(&r.segments), r.page),
+                RpcOp::PWrite => fs_pwrite(&*backend, fd.fd(), r.page, r.len as usize, r.offset),
+                _ => fs_write(&*backend, fd.fd(), r.page, r.len as usize),
+            })
+            .await?;
+        reply(timer, response)
     }
     async fn close(
         &self,
         request: Request<CloseRequest>,
     ) -> Result<Response<SyscallResponse>, Status> {
+        let handles = session(&request)?;
         let r = request.into_inner();
-        Ok(libc_close(r.fd))
+        let timer = metrics::start(RpcOp::Close);
+        let response = self.exec.run(move || fs_close(&handles, r.fd)).await?;
+        reply(timer, response)
     }
     async fn remove(
         &self,
         request: Request<RemoveRequest>,
     ) -> Result<Response<SyscallResponse>, Status> {
         let r = request.into_inner();
-        Ok(libc_remove(&r.path))
+        let timer = metrics::start(RpcOp::Remove);
+        let backend = self.backend.clone();
+        let response = self
+            .exec
+            .run(move || fs_status(backend.remove(&r.path)))
+            .await?;
+        reply(timer, response)
     }
     async fn fsync(
         &self,
         request: Request<FsyncRequest>,
     ) -> Result<Response<SyscallResponse>, Status> {
+        let handles = session(&request)?;
         let r = request.into_inner();
-        Ok(libc_fsync(r.fd))
+        let timer = metrics::start(RpcOp::Fsync);
+        let fd = match handles.get(r.fd) {
+            Ok(fd) => fd,
+            Err(errno) => return reply(timer, failed(errno)),
+        };
+        let backend = self.backend.clone();
+        let response = self
+            .exec
+            .run(move || fs_status(backend.fsync(fd.fd())))
+            .await?;
+        reply(timer, response)
     }
     async fn mkdir(
         &self,
         request: Request<DirRequest>,
     ) -> Result<Response<SyscallResponse>, Status> {
         let r = request.into_inner();
-        Ok(libc_mkdir(&r.path, r.mode))
+        let timer = metrics::start(RpcOp::Mkdir);
+        let backend = self.backend.clone();
+        let response = self
+            .exec
+            .run(move || fs_status(backend.mkdir(&r.path, r.mode)))
+            .await?;
+        reply(timer, response)
     }
     async fn rmdir(
         &self,
         request: Request<DirRequest>,
     ) -> Result<Response<SyscallResponse>, Status> {
         let r = request.into_inner();
-        Ok(libc_rmdir(&r.path))
+        let timer = metrics::start(RpcOp::Rmdir);
+        let backend = self.backend.clone();
+        let response = self
+            .exec
+            .run(move || fs_status(backend.rmdir(&r.path)))
+            .await?;
+        reply(timer, response)
     }
     async fn fstat(
         &self,
         request: Request<FstatRequest>,
     ) -> Result<Response<FstatResponse>, Status> {
+        let handles = session(&request)?;
+        let r = request.into_inner();
+        let timer = metrics::start(RpcOp::Fstat);
+        let fd = match handles.get(r.fd) {
+            Ok(fd) => fd,
+            Err(errno) => {
+                let response = Response::new(syscalls::FstatResponse {
+                    result: -1,
+                    size: 0,
+                    errno: errno,
+                });
+                return reply(timer, response);
+            }
+        };
+        let backend = self.backend.clone();
+        let response = self
+            .exec
+            .run(move || fs_fstat_size(&*backend, fd.fd()))
+            .await?;
+        reply(timer, response)
+    }
+    async fn readdir(
+        &self,
+        request: Request<ReaddirRequest>,
+    ) -> Result<Response<ReaddirResponse>, Status> {
+        let r = request.into_inner();
+        let timer = metrics::start(RpcOp::Readdir);
+        let backend = self.backend.clone();
+        let response = self
+            .exec
+            .run(move || fs_readdir(&*backend, &r.path, r.cookie, r.count))
+            .await?;
+        reply(timer, response)
+    }
+    async fn rename(
+        &self,
+        request: Request<RenameRequest>,
+    ) -> Result<Response<SyscallResponse>, Status> {
+        let r = request.into_inner();
+        let timer = metrics::start(RpcOp::Rename);
+        let backend = self.backend.clone();
+        let response = self
+            .exec
+            .run(move || fs_status(backend.rename(&r.oldpath, &r.newpath, r.flags)))
+            .await?;
+        reply(timer, response)
+    }
+    async fn link(
+        &self,
+        request: Request<LinkRequest>,
+    ) -> Result<Response<SyscallResponse>, Status> {
+        let r = request.into_inner();
+        let timer = metrics::start(RpcOp::Link);
+        let backend = self.backend.clone();
+        let response = self
+            .exec
+            .run(move || fs_status(backend.link(&r.oldpath, &r.newpath)))
+            .await?;
+        reply(timer, response)
+    }
+    async fn unlink(
+        &self,
+        request: Request<RemoveRequest>,
+    ) -> Result<Response<SyscallResponse>, Status> {
+        let r = request.into_inner();
+        let timer = metrics::start(RpcOp::Unlink);
+        let backend = self.backend.clone();
+        let response = self
+            .exec
+            .run(move || fs_status(backend.unlink(&r.path)))
+            .await?;
+        reply(timer, response)
+    }
+    async fn truncate(
+        &self,
+        request: Request<TruncateRequest>,
+    ) -> Result<Response<SyscallResponse>, Status> {
+        let handles = session(&request)?;
+        let r = request.into_inner();
+        let backend = self.backend.clone();
+        if r.ftruncate {
+            let timer = metrics::start(RpcOp::FTruncate);
+            let fd = match handles.get(r.fd) {
+                Ok(fd) => fd,
+                Err(errno) => return reply(timer, failed(errno)),
+            };
+            let response = self
+                .exec
+                .run(move || fs_status(backend.ftruncate(fd.fd(), r.len)))
+                .await?;
+            reply(timer, response)
+        } else {
+            let timer = metrics::start(RpcOp::Truncate);
+            let response = self
+                .exec
+                .run(move || fs_status(backend.truncate(&r.path, r.len)))
+                .await?;
+            reply(timer, response)
+        }
+    }
+    async fn stat(&self, request: Request<StatRequest>) -> Result<Response<StatResponse>, Status> {
+        let r = request.into_inner();
+        let timer = metrics::start(if r.lstat { RpcOp::LStat } else { RpcOp::Stat });
+        let backend = self.backend.clone();
+        let response = self
+            .exec
+            .run(move || fs_stat(&*backend, &r.path, !r.lstat))
+            .await?;
+        reply(timer, response)
+    }
+    async fn compound(
+        &self,
+        request: Request<CompoundRequest>,
+    ) -> Result<Response<CompoundResponse>, Status> {
+        let handles = session(&request)?;
         let r = request.into_inner();
-        Ok(libc_fstat_size(r.fd))
+        let timer = metrics::start(RpcOp::Compound);
+        let ops = r
+            .ops
+            .into_iter()
+            .map(CompoundOp::try_from)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Status::invalid_argument)?;
+        let backend = self.backend.clone();
+        let response = self
+            .exec
+            .run(move || fs_compound(&*backend, &handles, &ops))
+            .await?;
+        reply(timer, response)
     }
+    type ReadStreamStream = ReceiverStream<Result<SyscallResponse, Status>>;
+
+    // Reads too large for a message, recorded like the unary read they stand in for
+    async fn read_stream(
+        &self,
+        request: Request<ReadRequest>,
+    ) -> Result<Response<Self::ReadStreamStream>, Status> {
+        let handles = session(&request)?;
+        let r = request.into_inner();
+        if !r.segments.is_empty() {
+            return Err(Status::invalid_argument("Vectored reads are not streamed"));
+        }
+        let timer = metrics::start(if r.pread { RpcOp::PRead } else { RpcOp::Read });
+        // A chunk in flight and the next one read ahead
+        let (tx, rx) = mpsc::channel(2);
+        match handles.get(r.fd) {
+            Ok(fd) => {
+                let (backend, exec) = (self.backend.clone(), self.exec.clone());
+                let chunk = self.stream_chunk;
+                tokio::spawn(async move {
+                    timer.finish(read_chunks(backend, exec, fd, r, chunk, tx).await);
+                });
+            }
+            Err(errno) => {
+                timer.finish(-1);
+                let _ = tx.send(Ok(failed(errno).into_inner())).await;
+            }
+        }
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+    // Writes too large for a message, a chunk at a time as they arrive. After a short or
+    // failed chunk, the rest of the stream is received but not written.
+    async fn write_stream(
+        &self,
+        request: Request<Streaming<WriteRequest>>,
+    ) -> Result<Response<SyscallResponse>, Status> {
+        let handles = session(&request)?;
+        let mut stream = request.into_inner();
+        // The first request names the file and offset, the following ones only carry data
+        let first = stream
+            .message()
+            .await?
+            .ok_or_else(|| Status::invalid_argument("Empty write stream"))?;
+        if !first.segments.is_empty() {
+            return Err(Status::invalid_argument("Vectored writes are not streamed"));
+        }
+        let (pwrite, offset) = (first.pwrite, first.offset);
+        let timer = metrics::start(if pwrite { RpcOp::PWrite } else { RpcOp::Write });
+        let fd = match handles.get(first.fd) {
+            Ok(fd) => fd,
+            Err(errno) => return reply(timer, failed(errno)),
+        };
+
+        let mut done = 0;
+        let mut stopped = None;
+        let mut next = Some(first);
+        while let Some(r) = next {
+            if stopped.is_none() {
+                let len = (r.len as usize).min(r.page.len());
+                let at = offset + done as i64;
+                let (backend, fd) = (self.backend.clone(), fd.clone());
+                let response = self
+                    .exec
+                    .run(move || match pwrite {
+                        true => fs_pwrite(&*backend, fd.fd(), r.page, len, at),
+                        false => fs_write(&*backend, fd.fd(), r.page, len),
+                    })
+                    .await?
+                    .into_inner();
+                if response.result < 0 {
+                    stopped = Some(match done {
+                        0 => (-1, response.errno),
+                        _ if response.errno == ECONNABORTED => (-1, response.errno),
+                        _ => (done as i32, 0),
+                    });
+                } else {
+                    done += response.result as usize;
+                    if (response.result as usize) < len {
+                        stopped = Some((done as i32, 0));
+                    }
+                }
+            }
+            next = stream.message().await?;
+        }
+
+        let (result, errno) = stopped.unwrap_or((done as i32, 0));
+        let response = Response::new(syscalls::SyscallResponse {
+            result: result,
+            errno: errno,
+            page: Bytes::new(),
+        });
+        reply(timer, response)
+    }
+    // Not recorded itself, so polling the metrics does not change them
+    async fn stats(
+        &self,
+        _request: Request<StatsRequest>,
+    ) -> Result<Response<StatsResponse>, Status> {
+        Ok(Response::new(metrics::snapshot().into()))
+    }
+    async fn shutdown(
+        &self,
+        request: Request<ShutdownRequest>,
+    ) -> Result<Response<SyscallResponse>, Status> {
+        let r = request.into_inner();
+        Ok(fs_status(shutdown::request_with_token(&r.token)))
+    }
+}
+
+// Serves the connections of `incoming` until shutdown is requested, every connection with
+// a session of its own
+async fn serve<I, IO, IE>(
+    incoming: I,
+    backend: Arc<dyn FsBackend>,
+    exec: Executor,
+    limits: GrpcLimits,
+) -> Result<(), tonic::transport::Error>
+where
+    I: Stream<Item = Result<IO, IE>>,
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    IE: Into<StdError>,
+{
+    let syscalls_service = SyscallService::new(backend.clone(), exec, limits);
+    let incoming = incoming.map_ok(move |io| SessionStream::new(io, backend.clone()));
+    let syscalls_server = SyscallServer::new(syscalls_service)
+        .max_decoding_message_size(limits.max_message)
+        .max_encoding_message_size(limits.max_message);
+    Server::builder()
+        .add_service(syscalls_server)
+        .serve_with_incoming_shutdown(incoming, shutdown::wait())
+        .await
 }
 
-pub fn start_rpc_server_tcp(bind_addr: &str, port: u16) {
-    // Create Syscall server
-    let address = format!("{}:{}", bind_addr, port).parse().unwrap();
-    let syscalls_service = SyscallService::default();
+// ALPN protocol of gRPC, which only runs over HTTP/2
+pub(crate) const GRPC_ALPN: &[u8] = b"h2";
 
-    let rt = Runtime::new().expect("Failed to obtain runtime object.");
-    let server_future = Server::builder()
-        .add_service(SyscallServer::new(syscalls_service))
-        .serve(address);
-    rt.block_on(server_future)
-        .expect("Failed to successfully run the future on RunTime.");
+// Time a client gets to complete its TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The connections of `incoming` once their TLS handshake is done. Handshakes run
+// concurrently, so a slow client does not hold up the others, and one that fails only
+// drops its own connection.
+fn tls_incoming<I, IO, IE>(
+    incoming: I,
+    acceptor: TlsAcceptor,
+) -> ReceiverStream<Result<TlsStream<IO>, IE>>
+where
+    I: Stream<Item = Result<IO, IE>> + Send + 'static,
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    IE: Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(128);
+    tokio::spawn(async move {
+        let mut incoming = Box::pin(incoming);
+        loop {
+            // Stops accepting once the server is gone
+            let io = tokio::select! {
+                io = incoming.next() => io,
+                _ = tx.closed() => return,
+            };
+            let io = match io {
+                Some(Ok(io)) => io,
+                Some(Err(e)) => {
+                    let _ = tx.send(Err(e)).await;
+                    continue;
+                }
+                None => return,
+            };
+            let (acceptor, tx) = (acceptor.clone(), tx.clone());
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(io)).await {
+                    Ok(Ok(io)) => {
+                        let _ = tx.send(Ok(io)).await;
+                    }
+                    Ok(Err(e)) => debug!("TLS handshake failed: {}", e),
+                    Err(_) => debug!("TLS handshake timed out"),
+                }
+            });
+        }
+    });
+    ReceiverStream::new(rx)
 }
 
-#[tokio::main]
-pub async fn start_rpc_server_uds(path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    println!("UDS path: {}", path);
+// Serves the connections accepted by `listener`, over TLS if there is an acceptor
+async fn serve_tcp(
+    listener: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    backend: Arc<dyn FsBackend>,
+    exec: Executor,
+    limits: GrpcLimits,
+) -> Result<(), tonic::transport::Error> {
+    let incoming = TcpListenerStream::new(listener);
+    match acceptor {
+        Some(acceptor) => serve(tls_incoming(incoming, acceptor), backend, exec, limits).await,
+        None => serve(incoming, backend, exec, limits).await,
+    }
+}
 
-    // Remove existing UDS dir
-    let _ = std::fs::remove_dir_all(Path::new(path).parent().unwrap());
+// TLS side of the server, None without TLS
+fn grpc_acceptor(config: &ServerConfig) -> Option<TlsAcceptor> {
+    let tls = config.tls.as_ref()?;
+    let mut tls = tls::server_config(tls).expect("Failed to set up TLS");
+    tls.alpn_protocols = vec![GRPC_ALPN.to_vec()];
+    Some(TlsAcceptor::from(Arc::new(tls)))
+}
 
-    // Create dir for UDS
-    let _ = std::fs::create_dir_all(Path::new(path).parent().unwrap());
+// Multi-threaded runtime, with as many workers as the pool has if there is one. The pool's
+// cores are handed out to the threads in the order they start, the workers come first and
+// any blocking threads started later reuse the cores round-robin.
+fn multi_thread_runtime(pool: Option<&WorkerPool>) -> Runtime {
+    let pool = match pool {
+        Some(pool) => pool,
+        None => return Runtime::new().expect("Failed to obtain runtime object."),
+    };
+    let cores = pool.cores.clone();
+    let next = AtomicUsize::new(0);
+    Builder::new_multi_thread()
+        .worker_threads(pool.workers)
+        .enable_all()
+        .on_thread_start(move || {
+            pin_thread(cores[next.fetch_add(1, Ordering::Relaxed) % cores.len()]);
+        })
+        .build()
+        .expect("Failed to obtain runtime object.")
+}
 
-    let syscalls_service = SyscallService::default();
+// Runs `serve` on one current-thread runtime per core of the pool, each on a thread pinned
+// to its core, until all of them return
+fn run_per_core<F, Fut>(pool: &WorkerPool, serve: F)
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()>,
+{
+    let serve = Arc::new(serve);
+    let threads: Vec<_> = pool
+        .cores
+        .iter()
+        .map(|&core| {
+            let serve = serve.clone();
+            std::thread::spawn(move || {
+                pin_thread(core);
+                let rt = Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .expect("Failed to obtain runtime object.");
+                rt.block_on(serve());
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().expect("gRPC server thread panicked");
+    }
+}
+
+// Listener of one per-core runtime. SO_REUSEPORT lets every runtime bind the same
+// address, and the kernel spreads incoming connections over them.
+fn reuseport_listener(address: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = match address {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    socket.set_reuseport(true)?;
+    socket.bind(address)?;
+    socket.listen(1024)
+}
+
+pub fn start_rpc_server_tcp(addr: &str, backend: Arc<dyn FsBackend>, config: &ServerConfig) {
+    let address = addr
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .expect("Failed to resolve server address");
+
+    let exec = Executor::new(&config.io_exec);
+    let limits = config.grpc_limits;
+    let acceptor = grpc_acceptor(config);
+    match (config.pool.as_ref(), config.runtime) {
+        (Some(pool), RuntimeFlavor::PerCore) => run_per_core(pool, move || {
+            let backend = backend.clone();
+            let exec = exec.clone();
+            let acceptor = acceptor.clone();
+            async move {
+                let listener = reuseport_listener(address).expect("Failed to create TCP listener");
+                serve_tcp(listener, acceptor, backend, exec, limits)
+                    .await
+                    .expect("Failed to successfully run the future on RunTime.");
+            }
+        }),
+        (pool, _) => {
+            let rt = multi_thread_runtime(pool);
+            rt.block_on(async {
+                let listener = TcpListener::bind(address)
+                    .await
+                    .expect("Failed to create TCP listener");
+                serve_tcp(listener, acceptor, backend, exec, limits).await
+            })
+            .expect("Failed to successfully run the future on RunTime.");
+        }
+    }
+}
+
+pub fn start_rpc_server_uds(path: &Path, backend: Arc<dyn FsBackend>, config: &ServerConfig) {
+    println!("UDS path: {}", path.display());
+
+    prepare_uds_path(path);
 
     // Create standard, blocking UDS
     let std_uds = StdUnixListener::bind(path).unwrap();
+    std_uds.set_nonblocking(true).unwrap();
 
-    // Create tokio UDS
-    let uds = UnixListener::from_std(std_uds).unwrap();
-    let uds_stream = UnixListenerStream::new(uds);
-    Server::builder()
-        .add_service(SyscallServer::new(syscalls_service))
-        .serve_with_incoming(uds_stream)
-        .await?;
-    Ok(())
+    let exec = Executor::new(&config.io_exec);
+    let limits = config.grpc_limits;
+    match (config.pool.as_ref(), config.runtime) {
+        // There is no SO_REUSEPORT for UDS, so every runtime accepts from the same socket
+        (Some(pool), RuntimeFlavor::PerCore) => {
+            let std_uds = Arc::new(std_uds);
+            run_per_core(pool, move || {
+                let backend = backend.clone();
+                let exec = exec.clone();
+                let std_uds = std_uds.try_clone().unwrap();
+                async move {
+                    let uds = UnixListener::from_std(std_uds).unwrap();
+                    serve(UnixListenerStream::new(uds), backend, exec, limits)
+                        .await
+                        .expect("Failed to successfully run the future on RunTime.");
+                }
+            })
+        }
+        (pool, _) => {
+            let rt = multi_thread_runtime(pool);
+            rt.block_on(async {
+                // Create tokio UDS
+                let uds = UnixListener::from_std(std_uds).unwrap();
+                serve(UnixListenerStream::new(uds), backend, exec, limits).await
+            })
+            .expect("Failed to successfully run the future on RunTime.");
+        }
+    }
 }
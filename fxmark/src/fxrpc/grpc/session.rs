@@ -0,0 +1,79 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tonic::transport::server::Connected;
+
+use crate::fxrpc::{FsBackend, HandleTable};
+
+// A client connection together with the handles its client has open. tonic attaches the
+// ConnectInfo of the connection to every request that arrives on it, which is how the
+// service finds the right table. The table is emptied once the connection is dropped.
+pub struct SessionStream<IO> {
+    io: IO,
+    handles: Arc<HandleTable>,
+}
+
+impl<IO> SessionStream<IO> {
+    pub fn new(io: IO, backend: Arc<dyn FsBackend>) -> Self {
+        SessionStream {
+            io: io,
+            handles: Arc::new(HandleTable::new(backend)),
+        }
+    }
+}
+
+impl<IO> Connected for SessionStream<IO> {
+    type ConnectInfo = Arc<HandleTable>;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        self.handles.clone()
+    }
+}
+
+impl<IO> Drop for SessionStream<IO> {
+    fn drop(&mut self) {
+        self.handles.close_all();
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for SessionStream<IO> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_read(cx, buf)
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for SessionStream<IO> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
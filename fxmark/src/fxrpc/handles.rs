@@ -0,0 +1,107 @@
+use libc::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::fxrpc::backend::{status, FsBackend};
+
+// A descriptor of the backend, closed when dropped. Handle tables share them with the
+// requests using them, so a descriptor is only closed once the table has dropped it and
+// the last request is done. A close racing with a read on the same handle thus cannot
+// make the read hit a descriptor the backend has already reused.
+pub struct OpenFile {
+    fd: i32,
+    backend: Arc<dyn FsBackend>,
+}
+
+impl OpenFile {
+    pub fn fd(&self) -> i32 {
+        self.fd
+    }
+
+    // Closes the descriptor and reports the outcome, which dropping it cannot
+    fn close(mut self) -> Result<(), i32> {
+        let fd = std::mem::replace(&mut self.fd, -1);
+        self.backend.close(fd)
+    }
+}
+
+impl Drop for OpenFile {
+    fn drop(&mut self) {
+        if self.fd >= 0 {
+            let _ = self.backend.close(self.fd);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Handles {
+    next: i32,
+    files: HashMap<i32, Arc<OpenFile>>,
+}
+
+/// Maps the opaque handles a server hands out to clients to the descriptors its backend
+/// has open on their behalf. Every connection has its own table, so a client can only
+/// use the handles it opened itself, and a handle is not reused until the counter wraps
+/// around, so stale handles are rejected as well. Dropping the table closes whatever is
+/// left open.
+pub struct HandleTable {
+    backend: Arc<dyn FsBackend>,
+    handles: Mutex<Handles>,
+}
+
+impl HandleTable {
+    pub fn new(backend: Arc<dyn FsBackend>) -> HandleTable {
+        HandleTable {
+            backend: backend,
+            handles: Mutex::new(Handles::default()),
+        }
+    }
+
+    /// Takes ownership of an open descriptor of the backend and returns its handle.
+    pub fn insert(&self, fd: i32) -> i32 {
+        let file = Arc::new(OpenFile {
+            fd: fd,
+            backend: self.backend.clone(),
+        });
+        let mut handles = self.handles.lock().unwrap();
+        let mut handle = handles.next;
+        while handles.files.contains_key(&handle) {
+            handle = handle.checked_add(1).unwrap_or(0);
+        }
+        handles.next = handle.checked_add(1).unwrap_or(0);
+        handles.files.insert(handle, file);
+        handle
+    }
+
+    /// Looks up the descriptor behind a handle, EBADF if this connection does not own it.
+    pub fn get(&self, handle: i32) -> Result<Arc<OpenFile>, i32> {
+        let handles = self.handles.lock().unwrap();
+        handles.files.get(&handle).cloned().ok_or(EBADF)
+    }
+
+    /// Closes the descriptor behind a handle and returns (result, errno) like close().
+    /// If a request still uses the descriptor, it is closed once that request is done.
+    pub fn close(&self, handle: i32) -> (i32, i32) {
+        let file = self.handles.lock().unwrap().files.remove(&handle);
+        match file.map(Arc::try_unwrap) {
+            Some(Ok(file)) => status(file.close()),
+            Some(Err(_)) => (0, 0),
+            None => (-1, EBADF),
+        }
+    }
+
+    /// Number of handles currently open.
+    pub fn len(&self) -> usize {
+        self.handles.lock().unwrap().files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Closes every descriptor of the table, called when the client disconnects.
+    pub fn close_all(&self) {
+        let files = std::mem::take(&mut self.handles.lock().unwrap().files);
+        drop(files);
+    }
+}
@@ -0,0 +1,210 @@
+use libc::*;
+use std::ops::Range;
+
+use crate::fxrpc::error::errno_for;
+use crate::fxrpc::FxRpcError;
+
+// Most iovecs handed to a single preadv()/pwritev() call (UIO_MAXIOV on Linux)
+pub(crate) const IOV_MAX: usize = 1024;
+
+// Transfers (offset, len) segments to/from the buffer at `base`, in which they are laid
+// out back to back. Runs of segments that are contiguous in the file go through a
+// single `op` call. Stops at the first short transfer and returns the bytes moved so
+// far, or (-1, errno) if nothing was moved before an error. Segments reaching past the
+// largest file offset fail with EINVAL before anything is moved.
+unsafe fn vectored<F>(segments: &[(i64, usize)], base: *mut u8, op: F) -> (i64, i32)
+where
+    F: Fn(*const iovec, c_int, i64) -> isize,
+{
+    if segments
+        .iter()
+        .any(|&(offset, len)| segment_end(offset, len).is_none())
+    {
+        return (-1, EINVAL);
+    }
+
+    let mut done: i64 = 0;
+    let mut pos = 0;
+    let mut i = 0;
+    while i < segments.len() {
+        let start = segments[i].0;
+        let mut end = start;
+        let mut run_len = 0;
+        let mut iov = Vec::new();
+        while i < segments.len() && segments[i].0 == end && iov.len() < IOV_MAX {
+            let (_, len) = segments[i];
+            iov.push(iovec {
+                iov_base: base.add(pos + run_len) as *mut c_void,
+                iov_len: len,
+            });
+            end = segment_end(end, len).unwrap();
+            run_len += len;
+            i += 1;
+        }
+
+        let res = op(iov.as_ptr(), iov.len() as c_int, start);
+        if res < 0 {
+            let errno = errno_for(res as i64);
+            return if done > 0 { (done, 0) } else { (-1, errno) };
+        }
+        done += res as i64;
+        pos += run_len;
+        if (res as usize) < run_len {
+            break;
+        }
+    }
+    (done, 0)
+}
+
+// File offset following the segment at `offset`, if it can be represented
+fn segment_end(offset: i64, len: usize) -> Option<i64> {
+    i64::try_from(len)
+        .ok()
+        .and_then(|len| offset.checked_add(len))
+}
+
+// Total number of bytes covered by `segments`. Saturates rather than wrapping, so
+// segments with absurd lengths never appear to fit a buffer.
+pub(crate) fn segments_len(segments: &[(i64, usize)]) -> usize {
+    segments
+        .iter()
+        .fold(0usize, |total, (_, len)| total.saturating_add(*len))
+}
+
+// Reads the segments into `base`, which must have room for `segments_len(segments)` bytes
+pub(crate) unsafe fn preadv_segments(
+    fd: i32,
+    segments: &[(i64, usize)],
+    base: *mut u8,
+) -> (i64, i32) {
+    vectored(segments, base, |iov, cnt, offset| {
+        preadv(fd, iov, cnt, offset)
+    })
+}
+
+// Writes the segments from `data`, which holds them back to back
+pub(crate) fn pwritev_segments(fd: i32, segments: &[(i64, usize)], data: &[u8]) -> (i64, i32) {
+    if segments_len(segments) > data.len() {
+        return (-1, EINVAL);
+    }
+    // pwritev() only reads through the iovecs
+    unsafe {
+        vectored(segments, data.as_ptr() as *mut u8, |iov, cnt, offset| {
+            pwritev(fd, iov, cnt, offset)
+        })
+    }
+}
+
+// Copies the data of a vectored read, received back to back, into the segment buffers
+pub(crate) fn scatter(
+    segments: &mut [(i64, &mut [u8])],
+    mut data: &[u8],
+) -> Result<(), FxRpcError> {
+    for (_, buf) in segments.iter_mut() {
+        let len = buf.len().min(data.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        data = &data[len..];
+    }
+    if data.is_empty() {
+        Ok(())
+    } else {
+        Err(FxRpcError::Decode(String::from(
+            "Vectored read returned more data than requested",
+        )))
+    }
+}
+
+// Adds what the chunk of `want` bytes moved to `done`, true if the next chunk follows.
+// A transfer stops at the first short chunk, and at an error once something was moved,
+// so it returns the bytes moved so far like a short read or write would.
+pub(crate) fn advance(
+    done: &mut usize,
+    want: usize,
+    res: Result<i32, FxRpcError>,
+) -> Result<bool, FxRpcError> {
+    match res {
+        Ok(moved) => {
+            *done += moved as usize;
+            Ok(moved as usize == want)
+        }
+        Err(FxRpcError::Errno(_)) if *done > 0 => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// Splits a vectored transfer over segments of lengths `lens` into batches of at most
+// `max_len` bytes in at most `max_segments` pieces of segments, as ranges of the data laid
+// out back to back. Segments that do not fit are cut. An empty transfer is still a
+// single, empty batch.
+pub(crate) fn batches<I>(lens: I, max_len: usize, max_segments: usize) -> Vec<Range<usize>>
+where
+    I: IntoIterator<Item = usize>,
+{
+    let (max_len, max_segments) = (max_len.max(1), max_segments.max(1));
+    let mut batches = Vec::new();
+    let (mut start, mut end, mut pieces) = (0, 0, 0);
+    for len in lens {
+        let mut left = len;
+        while left > 0 {
+            if end - start == max_len || pieces == max_segments {
+                batches.push(start..end);
+                start = end;
+                pieces = 0;
+            }
+            let take = left.min(max_len - (end - start));
+            end += take;
+            left -= take;
+            pieces += 1;
+        }
+    }
+    batches.push(start..end);
+    batches
+}
+
+// Pieces of the segments of lengths `lens` holding bytes `range` of their data laid out
+// back to back, as the index of the segment and the range within it
+fn pieces<I>(lens: I, range: Range<usize>) -> Vec<(usize, Range<usize>)>
+where
+    I: IntoIterator<Item = usize>,
+{
+    let mut pieces = Vec::new();
+    let mut pos = 0;
+    for (i, len) in lens.into_iter().enumerate() {
+        let (start, end) = (range.start.max(pos), range.end.min(pos + len));
+        if start < end {
+            pieces.push((i, start - pos..end - pos));
+        }
+        pos += len;
+    }
+    pieces
+}
+
+// The segments of a batch, cut down to bytes `range` of their data laid out back to back
+pub(crate) fn segments_in<'a>(
+    segments: &[(i64, &'a [u8])],
+    range: Range<usize>,
+) -> Vec<(i64, &'a [u8])> {
+    pieces(segments.iter().map(|(_, buf)| buf.len()), range)
+        .into_iter()
+        .map(|(i, piece)| {
+            let (offset, buf) = segments[i];
+            (offset.saturating_add(piece.start as i64), &buf[piece])
+        })
+        .collect()
+}
+
+pub(crate) fn segments_in_mut<'a>(
+    segments: &'a mut [(i64, &mut [u8])],
+    range: Range<usize>,
+) -> Vec<(i64, &'a mut [u8])> {
+    let mut pieces = pieces(segments.iter().map(|(_, buf)| buf.len()), range)
+        .into_iter()
+        .peekable();
+    let mut cut = Vec::new();
+    for (i, (offset, buf)) in segments.iter_mut().enumerate() {
+        if let Some((_, piece)) = pieces.next_if(|(j, _)| *j == i) {
+            cut.push((offset.saturating_add(piece.start as i64), &mut buf[piece]));
+        }
+    }
+    cut
+}
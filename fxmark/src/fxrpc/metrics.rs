@@ -0,0 +1,295 @@
+use lazy_static::lazy_static;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// RPCs the server keeps metrics for, the same on both protocols.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RpcOp {
+    Open,
+    Read,
+    PRead,
+    Write,
+    PWrite,
+    PReadV,
+    PWriteV,
+    Close,
+    Remove,
+    Mkdir,
+    Rmdir,
+    Fsync,
+    Fstat,
+    Readdir,
+    Rename,
+    Link,
+    Unlink,
+    Truncate,
+    FTruncate,
+    Stat,
+    LStat,
+    Compound,
+}
+
+/// Every RpcOp, in the order of their discriminants.
+pub const RPC_OPS: [RpcOp; 22] = [
+    RpcOp::Open,
+    RpcOp::Read,
+    RpcOp::PRead,
+    RpcOp::Write,
+    RpcOp::PWrite,
+    RpcOp::PReadV,
+    RpcOp::PWriteV,
+    RpcOp::Close,
+    RpcOp::Remove,
+    RpcOp::Mkdir,
+    RpcOp::Rmdir,
+    RpcOp::Fsync,
+    RpcOp::Fstat,
+    RpcOp::Readdir,
+    RpcOp::Rename,
+    RpcOp::Link,
+    RpcOp::Unlink,
+    RpcOp::Truncate,
+    RpcOp::FTruncate,
+    RpcOp::Stat,
+    RpcOp::LStat,
+    RpcOp::Compound,
+];
+
+impl RpcOp {
+    // Reads and writes count the bytes they transfer
+    fn transfers_data(&self) -> bool {
+        matches!(
+            self,
+            RpcOp::Read
+                | RpcOp::PRead
+                | RpcOp::Write
+                | RpcOp::PWrite
+                | RpcOp::PReadV
+                | RpcOp::PWriteV
+        )
+    }
+}
+
+impl std::fmt::Display for RpcOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            RpcOp::Open => "open",
+            RpcOp::Read => "read",
+            RpcOp::PRead => "pread",
+            RpcOp::Write => "write",
+            RpcOp::PWrite => "pwrite",
+            RpcOp::PReadV => "preadv",
+            RpcOp::PWriteV => "pwritev",
+            RpcOp::Close => "close",
+            RpcOp::Remove => "remove",
+            RpcOp::Mkdir => "mkdir",
+            RpcOp::Rmdir => "rmdir",
+            RpcOp::Fsync => "fsync",
+            RpcOp::Fstat => "fstat",
+            RpcOp::Readdir => "readdir",
+            RpcOp::Rename => "rename",
+            RpcOp::Link => "link",
+            RpcOp::Unlink => "unlink",
+            RpcOp::Truncate => "truncate",
+            RpcOp::FTruncate => "ftruncate",
+            RpcOp::Stat => "stat",
+            RpcOp::LStat => "lstat",
+            RpcOp::Compound => "compound",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl std::str::FromStr for RpcOp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        RPC_OPS
+            .iter()
+            .find(|op| op.to_string() == s)
+            .copied()
+            .ok_or_else(|| format!("Unknown RPC {}", s))
+    }
+}
+
+/// Number of latency histogram buckets. Bucket `i` counts calls that took between 2^i
+/// and 2^(i+1) nanoseconds, the last one also counts everything slower.
+pub const LATENCY_BUCKETS: usize = 32;
+
+#[derive(Default)]
+struct OpMetrics {
+    count: AtomicU64,
+    errors: AtomicU64,
+    bytes: AtomicU64,
+    in_flight: AtomicI64,
+    latency: [AtomicU64; LATENCY_BUCKETS],
+}
+
+struct Metrics {
+    started: Instant,
+    ops: Vec<OpMetrics>,
+}
+
+lazy_static! {
+    // Shared by every connection and both protocols, counters are only ever updated
+    // atomically so handlers never wait on each other
+    static ref METRICS: Metrics = Metrics {
+        started: Instant::now(),
+        ops: RPC_OPS.iter().map(|_| OpMetrics::default()).collect(),
+    };
+}
+
+/// Starts the uptime clock, called once the server is up.
+pub fn init() {
+    lazy_static::initialize(&METRICS);
+}
+
+fn bucket(latency: Duration) -> usize {
+    let ns = latency.as_nanos().max(1) as u64;
+    (63 - ns.leading_zeros() as usize).min(LATENCY_BUCKETS - 1)
+}
+
+/// Measures a single call. The call counts as in flight until the timer is dropped, and
+/// as failed unless `finish` reports otherwise.
+pub struct OpTimer {
+    op: RpcOp,
+    start: Instant,
+    result: i64,
+}
+
+/// Starts measuring a call of `op`.
+pub fn start(op: RpcOp) -> OpTimer {
+    METRICS.ops[op as usize]
+        .in_flight
+        .fetch_add(1, Ordering::Relaxed);
+    OpTimer {
+        op: op,
+        start: Instant::now(),
+        result: -1,
+    }
+}
+
+impl OpTimer {
+    /// Records the result the call returns, negative on failure.
+    pub fn finish(mut self, result: i64) {
+        self.result = result;
+    }
+}
+
+impl Drop for OpTimer {
+    fn drop(&mut self) {
+        let metrics = &METRICS.ops[self.op as usize];
+        metrics.count.fetch_add(1, Ordering::Relaxed);
+        if self.result < 0 {
+            metrics.errors.fetch_add(1, Ordering::Relaxed);
+        } else if self.op.transfers_data() {
+            metrics
+                .bytes
+                .fetch_add(self.result as u64, Ordering::Relaxed);
+        }
+        metrics.latency[bucket(self.start.elapsed())].fetch_add(1, Ordering::Relaxed);
+        metrics.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Metrics of one RPC since the server started.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OpStats {
+    pub op: RpcOp,
+    pub count: u64,
+    pub errors: u64,
+    // Bytes read or written by calls that succeeded
+    pub bytes: u64,
+    pub in_flight: i64,
+    // Histogram with LATENCY_BUCKETS log2 buckets of nanoseconds
+    pub latency: Vec<u64>,
+}
+
+impl OpStats {
+    /// Upper bound of the latency that fraction `q` of the calls stayed below, None if
+    /// there were no calls.
+    pub fn percentile(&self, q: f64) -> Option<Duration> {
+        let total: u64 = self.latency.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let rank = ((total as f64 * q).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, count) in self.latency.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(Duration::from_nanos(1u64 << (i + 1)));
+            }
+        }
+        None
+    }
+}
+
+/// Metrics a server collected, as returned by the Stats RPC.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerStats {
+    pub uptime: Duration,
+    pub ops: Vec<OpStats>,
+}
+
+impl ServerStats {
+    /// Writes one CSV row per RPC, latency percentiles in nanoseconds.
+    pub fn write_csv<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        writeln!(
+            out,
+            "uptime_ns,op,count,errors,bytes,in_flight,p50_ns,p90_ns,p99_ns,p999_ns"
+        )?;
+        for op in self.ops.iter() {
+            let percentile = |q| op.percentile(q).map_or(0, |d| d.as_nanos());
+            writeln!(
+                out,
+                "{},{},{},{},{},{},{},{},{},{}",
+                self.uptime.as_nanos(),
+                op.op,
+                op.count,
+                op.errors,
+                op.bytes,
+                op.in_flight,
+                percentile(0.5),
+                percentile(0.9),
+                percentile(0.99),
+                percentile(0.999)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the current value of every metric.
+pub fn snapshot() -> ServerStats {
+    let ops = RPC_OPS
+        .iter()
+        .zip(METRICS.ops.iter())
+        .map(|(op, metrics)| OpStats {
+            op: *op,
+            count: metrics.count.load(Ordering::Relaxed),
+            errors: metrics.errors.load(Ordering::Relaxed),
+            bytes: metrics.bytes.load(Ordering::Relaxed),
+            in_flight: metrics.in_flight.load(Ordering::Relaxed),
+            latency: metrics
+                .latency
+                .iter()
+                .map(|count| count.load(Ordering::Relaxed))
+                .collect(),
+        })
+        .collect();
+    ServerStats {
+        uptime: METRICS.started.elapsed(),
+        ops: ops,
+    }
+}
+
+/// Writes the current metrics to `path` as CSV.
+pub fn dump(path: &Path) -> std::io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    snapshot().write_csv(&mut out)?;
+    out.flush()
+}
@@ -1,14 +1,37 @@
+pub mod backend;
+pub mod compound;
+pub mod config;
+pub mod dir;
 pub mod drpc;
+pub mod error;
+pub mod filestat;
 pub mod grpc;
+pub mod handles;
+pub mod iov;
+pub mod metrics;
+pub mod shutdown;
+pub mod tls;
+pub use crate::fxrpc::backend::{init_backend, BackendType, FaultConfig, FsBackend};
+pub use crate::fxrpc::compound::{Compound, CompoundOp, FdRef, OpResult};
+pub use crate::fxrpc::config::{
+    parse_cores, ClientConfig, GrpcLimits, IoExec, RuntimeFlavor, ServerAddr, ServerConfig,
+    TlsConfig, WorkerPool,
+};
+pub use crate::fxrpc::dir::{DirEntry, DirPage};
 use crate::fxrpc::drpc::*;
+pub use crate::fxrpc::drpc::{Codec, DEFAULT_MAX_IO};
+pub use crate::fxrpc::error::FxRpcError;
+pub use crate::fxrpc::filestat::FileStat;
 use crate::fxrpc::grpc::*;
+pub use crate::fxrpc::handles::HandleTable;
+pub use crate::fxrpc::metrics::{OpStats, RpcOp, ServerStats};
 
 pub use crate::fxmark::PAGE_SIZE;
 
 type StdError = Box<dyn std::error::Error + Send + Sync + 'static>;
 type Result<T, E = StdError> = ::std::result::Result<T, E>;
 
-// File system path
+// Default export root of the server
 pub const FS_PATH: &str = "/dev/shm/";
 pub const UDS_PATH: &str = "/dev/shm/uds";
 
@@ -19,7 +42,7 @@ pub enum LogMode {
     STDOUT,
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 #[repr(C)]
 pub enum ConnType {
     TcpLocal,
@@ -37,7 +60,7 @@ impl std::fmt::Display for ConnType {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 #[repr(C)]
 pub enum RPCType {
     DRPC,
@@ -61,79 +84,228 @@ pub struct ClientParams {
     pub ccores: usize,
     pub log_mode: LogMode,
     pub conn_type: ConnType,
-    pub rpc_type: RPCType,
+    pub config: ClientConfig,
+    pub qdepth: usize,
+    pub batch: usize,
 }
 
+// File descriptors passed to and returned by the RPCs are handles private to the client's
+// connection, not the server's kernel descriptors. Handles left open are closed by the
+// server once the connection goes away.
 pub trait FxRPC {
-    fn rpc_open(
-        &mut self,
-        path: &str,
-        flags: i32,
-        mode: u32,
-    ) -> Result<i32, Box<dyn std::error::Error>>;
-    fn rpc_read(
-        &mut self,
-        fd: i32,
-        page: &mut Vec<u8>,
-        size: usize,
-    ) -> Result<i32, Box<dyn std::error::Error>>;
-    fn rpc_pread(
-        &mut self,
-        fd: i32,
-        page: &mut Vec<u8>,
-        size: usize,
-        offset: i64,
-    ) -> Result<i32, Box<dyn std::error::Error>>;
-    fn rpc_write(
-        &mut self,
-        fd: i32,
-        page: &Vec<u8>,
-        size: usize,
-    ) -> Result<i32, Box<dyn std::error::Error>>;
-    fn rpc_pwrite(
-        &mut self,
+    fn rpc_open(&mut self, path: &str, flags: i32, mode: u32) -> Result<i32, FxRpcError>;
+    // Reads and writes transfer `page.len()` bytes to/from the caller's buffer
+    fn rpc_read(&mut self, fd: i32, page: &mut [u8]) -> Result<i32, FxRpcError>;
+    fn rpc_pread(&mut self, fd: i32, page: &mut [u8], offset: i64) -> Result<i32, FxRpcError>;
+    fn rpc_write(&mut self, fd: i32, page: &[u8]) -> Result<i32, FxRpcError>;
+    fn rpc_pwrite(&mut self, fd: i32, page: &[u8], offset: i64) -> Result<i32, FxRpcError>;
+    // Vectored reads and writes move every (offset, buffer) segment in a single call and
+    // return the total number of bytes transferred, which is short at the end of file
+    fn rpc_preadv(&mut self, fd: i32, segments: &mut [(i64, &mut [u8])])
+        -> Result<i32, FxRpcError>;
+    fn rpc_pwritev(&mut self, fd: i32, segments: &[(i64, &[u8])]) -> Result<i32, FxRpcError>;
+    fn rpc_close(&mut self, fd: i32) -> Result<i32, FxRpcError>;
+    fn rpc_remove(&mut self, path: &str) -> Result<i32, FxRpcError>;
+    fn rpc_mkdir(&mut self, path: &str, mode: u32) -> Result<i32, FxRpcError>;
+    fn rpc_rmdir(&mut self, path: &str) -> Result<i32, FxRpcError>;
+    fn rpc_fsync(&mut self, fd: i32) -> Result<i32, FxRpcError>;
+    // Returns the file size
+    fn rpc_fstat(&mut self, fd: i32) -> Result<i64, FxRpcError>;
+    // Lists up to `count` entries following `cookie`, 0 starts at the beginning
+    fn rpc_readdir(&mut self, path: &str, cookie: u64, count: u32) -> Result<DirPage, FxRpcError>;
+    // `flags` takes the renameat2() RENAME_* flags
+    fn rpc_rename(&mut self, oldpath: &str, newpath: &str, flags: u32) -> Result<i32, FxRpcError>;
+    fn rpc_link(&mut self, oldpath: &str, newpath: &str) -> Result<i32, FxRpcError>;
+    fn rpc_unlink(&mut self, path: &str) -> Result<i32, FxRpcError>;
+    fn rpc_truncate(&mut self, path: &str, len: i64) -> Result<i32, FxRpcError>;
+    fn rpc_ftruncate(&mut self, fd: i32, len: i64) -> Result<i32, FxRpcError>;
+    fn rpc_stat(&mut self, path: &str) -> Result<FileStat, FxRpcError>;
+    fn rpc_lstat(&mut self, path: &str) -> Result<FileStat, FxRpcError>;
+    fn rpc_compound(&mut self, compound: &Compound) -> Result<Vec<OpResult>, FxRpcError>;
+    // Metrics the server collected since it started
+    fn rpc_stats(&mut self) -> Result<ServerStats, FxRpcError>;
+    // Stops the server, `token` has to match the one it was started with
+    fn rpc_shutdown(&mut self, token: &str) -> Result<i32, FxRpcError>;
+}
+
+// Asynchronous counterpart of FxRPC. Calls take `&self`, so several requests can be
+// in flight on the same connection at once.
+#[tonic::async_trait]
+pub trait AsyncFxRPC: Send + Sync {
+    async fn rpc_open(&self, path: &str, flags: i32, mode: u32) -> Result<i32, FxRpcError>;
+    async fn rpc_read(&self, fd: i32, page: &mut [u8]) -> Result<i32, FxRpcError>;
+    async fn rpc_pread(&self, fd: i32, page: &mut [u8], offset: i64) -> Result<i32, FxRpcError>;
+    async fn rpc_write(&self, fd: i32, page: &[u8]) -> Result<i32, FxRpcError>;
+    async fn rpc_pwrite(&self, fd: i32, page: &[u8], offset: i64) -> Result<i32, FxRpcError>;
+    async fn rpc_preadv(
+        &self,
         fd: i32,
-        page: &Vec<u8>,
-        size: usize,
-        offset: i64,
-    ) -> Result<i32, Box<dyn std::error::Error>>;
-    fn rpc_close(&mut self, fd: i32) -> Result<i32, Box<dyn std::error::Error>>;
-    fn rpc_remove(&mut self, path: &str) -> Result<i32, Box<dyn std::error::Error>>;
-    fn rpc_mkdir(&mut self, path: &str, mode: u32) -> Result<i32, Box<dyn std::error::Error>>;
-    fn rpc_rmdir(&mut self, path: &str) -> Result<i32, Box<dyn std::error::Error>>;
+        segments: &mut [(i64, &mut [u8])],
+    ) -> Result<i32, FxRpcError>;
+    async fn rpc_pwritev(&self, fd: i32, segments: &[(i64, &[u8])]) -> Result<i32, FxRpcError>;
+    async fn rpc_close(&self, fd: i32) -> Result<i32, FxRpcError>;
+    async fn rpc_remove(&self, path: &str) -> Result<i32, FxRpcError>;
+    async fn rpc_mkdir(&self, path: &str, mode: u32) -> Result<i32, FxRpcError>;
+    async fn rpc_rmdir(&self, path: &str) -> Result<i32, FxRpcError>;
+    async fn rpc_fsync(&self, fd: i32) -> Result<i32, FxRpcError>;
+    // Returns the file size
+    async fn rpc_fstat(&self, fd: i32) -> Result<i64, FxRpcError>;
+    async fn rpc_readdir(&self, path: &str, cookie: u64, count: u32)
+        -> Result<DirPage, FxRpcError>;
+    async fn rpc_rename(&self, oldpath: &str, newpath: &str, flags: u32)
+        -> Result<i32, FxRpcError>;
+    async fn rpc_link(&self, oldpath: &str, newpath: &str) -> Result<i32, FxRpcError>;
+    async fn rpc_unlink(&self, path: &str) -> Result<i32, FxRpcError>;
+    async fn rpc_truncate(&self, path: &str, len: i64) -> Result<i32, FxRpcError>;
+    async fn rpc_ftruncate(&self, fd: i32, len: i64) -> Result<i32, FxRpcError>;
+    async fn rpc_stat(&self, path: &str) -> Result<FileStat, FxRpcError>;
+    async fn rpc_lstat(&self, path: &str) -> Result<FileStat, FxRpcError>;
+    async fn rpc_compound(&self, compound: &Compound) -> Result<Vec<OpResult>, FxRpcError>;
+    async fn rpc_stats(&self) -> Result<ServerStats, FxRpcError>;
+    async fn rpc_shutdown(&self, token: &str) -> Result<i32, FxRpcError>;
 }
 
-pub fn init_client(conn_type: ConnType, rpc_type: RPCType) -> Box<dyn FxRPC> {
-    match rpc_type {
-        RPCType::GRPC => match conn_type {
-            ConnType::TcpLocal => {
-                Box::new(BlockingClient::connect_tcp("http://[::1]:8080").unwrap())
-            }
-            ConnType::TcpRemote => {
-                Box::new(BlockingClient::connect_tcp("http://172.31.0.1:8080").unwrap())
-            }
-            ConnType::UDS => Box::new(BlockingClient::connect_uds().unwrap()),
+// Connects to the server `config` points at. Fails if the server cannot be reached or
+// refuses the connection.
+pub fn init_client(config: &ClientConfig) -> Result<Box<dyn FxRPC>, FxRpcError> {
+    let client: Box<dyn FxRPC> = match (config.rpc_type, &config.addr) {
+        (RPCType::GRPC, ServerAddr::Tcp(addr)) => match &config.tls {
+            Some(tls) => Box::new(BlockingClient::connect_tls(addr, tls, config.grpc_limits)?),
+            None => Box::new(BlockingClient::connect_tcp(
+                format!("http://{}", addr),
+                config.grpc_limits,
+            )?),
         },
-        RPCType::DRPC => match conn_type {
-            ConnType::TcpLocal => Box::new(init_client_drpc_tcp("127.0.0.1:8080")),
-            ConnType::TcpRemote => Box::new(init_client_drpc_tcp("172.31.0.1:8080")),
-            ConnType::UDS => Box::new(init_client_drpc_uds(UDS_PATH)),
+        (RPCType::GRPC, ServerAddr::Uds(path)) => Box::new(BlockingClient::connect_uds(
+            path.clone(),
+            config.grpc_limits,
+        )?),
+        (RPCType::DRPC, ServerAddr::Tcp(addr)) => Box::new(init_client_drpc_tcp(
+            addr,
+            config.codec,
+            config.tls.as_ref(),
+        )?),
+        (RPCType::DRPC, ServerAddr::Uds(path)) => {
+            Box::new(init_client_drpc_uds(path, config.codec)?)
+        }
+    };
+    Ok(client)
+}
+
+// `depth` bounds the number of requests in flight on the DRPC connection
+pub async fn init_async_client(
+    config: &ClientConfig,
+    depth: usize,
+) -> Result<Box<dyn AsyncFxRPC>, FxRpcError> {
+    let client: Box<dyn AsyncFxRPC> = match (config.rpc_type, &config.addr) {
+        (RPCType::GRPC, ServerAddr::Tcp(addr)) => match &config.tls {
+            Some(tls) => Box::new(AsyncClient::connect_tls(addr, tls, config.grpc_limits).await?),
+            None => Box::new(
+                AsyncClient::connect_tcp(format!("http://{}", addr), config.grpc_limits).await?,
+            ),
         },
+        (RPCType::GRPC, ServerAddr::Uds(path)) => {
+            Box::new(AsyncClient::connect_uds(path.clone(), config.grpc_limits).await?)
+        }
+        (RPCType::DRPC, ServerAddr::Tcp(addr)) => Box::new(init_pipelined_drpc_tcp(
+            addr,
+            depth,
+            config.codec,
+            config.tls.as_ref(),
+        )?),
+        (RPCType::DRPC, ServerAddr::Uds(path)) => {
+            Box::new(init_pipelined_drpc_uds(path, depth, config.codec)?)
+        }
+    };
+    Ok(client)
+}
+
+// Removes a stale socket left behind at `path` and creates its parent directory.
+// Only the socket itself is touched, so other servers' sockets next to it survive.
+pub fn prepare_uds_path(path: &std::path::Path) {
+    let _ = std::fs::remove_file(path);
+    if let Some(parent) = path.parent() {
+        let _ = std::fs::create_dir_all(parent);
     }
 }
 
-pub fn run_server(conn_type: ConnType, rpc_type: RPCType, port: u16) {
-    println!("Starting {} {} server", rpc_type, conn_type);
-    match rpc_type {
-        RPCType::GRPC => match conn_type {
-            ConnType::TcpLocal => start_rpc_server_tcp("[::1]", port),
-            ConnType::TcpRemote => start_rpc_server_tcp("172.31.0.1", port),
-            ConnType::UDS => start_rpc_server_uds(UDS_PATH).unwrap(),
-        },
-        RPCType::DRPC => match conn_type {
-            ConnType::TcpLocal => start_drpc_server_tcp("127.0.0.1", port),
-            ConnType::TcpRemote => start_drpc_server_tcp("172.31.0.1", port),
-            ConnType::UDS => start_drpc_server_uds(UDS_PATH),
-        },
+// Serves until SIGINT, SIGTERM or the Shutdown RPC, then waits for the connections to
+// finish their requests, which closes the handles they left open.
+pub fn run_server(config: &ServerConfig) {
+    if config.rpc_type == RPCType::DRPC && config.pool.is_some() && config.tls.is_some() {
+        eprintln!("DRPC servers with a worker pool do not serve TLS");
+        return;
+    }
+    shutdown::handle_signals().expect("Failed to install signal handlers");
+    shutdown::set_token(config.shutdown_token.clone());
+    let backend = init_backend(config);
+    metrics::init();
+    match config.backend {
+        BackendType::Posix => println!(
+            "Starting {} server on {}, exporting {}",
+            config.rpc_type,
+            config.addr,
+            config.root.display()
+        ),
+        BackendType::Memory => println!(
+            "Starting {} server on {}, exporting an in-memory file system",
+            config.rpc_type, config.addr
+        ),
+    }
+    if config.faults.is_some() {
+        println!("Injecting faults into the backend");
+    }
+    if let Some(tls) = &config.tls {
+        match tls.ca {
+            Some(_) => println!("Serving over TLS, clients have to present a certificate"),
+            None => println!("Serving over TLS"),
+        }
+    }
+    match (config.rpc_type, &config.pool) {
+        (RPCType::DRPC, Some(pool)) => println!(
+            "Serving connections from {} workers on cores {:?}",
+            pool.workers, pool.cores
+        ),
+        (RPCType::GRPC, Some(pool)) => println!(
+            "Running a {} runtime with {} workers on cores {:?}",
+            config.runtime, pool.workers, pool.cores
+        ),
+        (_, None) => {}
+    }
+    match (config.rpc_type, &config.io_exec) {
+        (RPCType::GRPC, IoExec::Blocking) => println!("Running backend calls on blocking threads"),
+        (RPCType::GRPC, IoExec::Pool(io)) => println!(
+            "Running backend calls on {} I/O threads on cores {:?}",
+            io.workers, io.cores
+        ),
+        _ => {}
+    }
+    let pool = config.pool.as_ref();
+    match (config.rpc_type, &config.addr, pool) {
+        (RPCType::GRPC, ServerAddr::Tcp(addr), _) => start_rpc_server_tcp(addr, backend, config),
+        (RPCType::GRPC, ServerAddr::Uds(path), _) => start_rpc_server_uds(path, backend, config),
+        (RPCType::DRPC, ServerAddr::Tcp(addr), None) => {
+            start_drpc_server_tcp(addr, backend, config.max_io, config.tls.as_ref())
+        }
+        (RPCType::DRPC, ServerAddr::Uds(path), None) => {
+            start_drpc_server_uds(path, backend, config.max_io)
+        }
+        (RPCType::DRPC, ServerAddr::Tcp(addr), Some(pool)) => {
+            start_drpc_event_server_tcp(addr, backend, pool, config.max_io)
+        }
+        (RPCType::DRPC, ServerAddr::Uds(path), Some(pool)) => {
+            start_drpc_event_server_uds(path, backend, pool, config.max_io)
+        }
     };
+
+    if let ServerAddr::Uds(path) = &config.addr {
+        let _ = std::fs::remove_file(path);
+    }
+    if let Some(path) = &config.metrics_out {
+        match metrics::dump(path) {
+            Ok(()) => println!("Wrote server metrics to {}", path.display()),
+            Err(e) => eprintln!("Failed to write metrics to {}: {}", path.display(), e),
+        }
+    }
+    println!("Server stopped");
 }
@@ -0,0 +1,84 @@
+use lazy_static::lazy_static;
+use libc::{EACCES, EPERM};
+use nix::sys::signal::{SigSet, Signal};
+use std::sync::OnceLock;
+use tokio::sync::watch;
+
+lazy_static! {
+    // Flips to true once, the receiver kept here makes sure sending never fails
+    static ref SHUTDOWN: (watch::Sender<bool>, watch::Receiver<bool>) = watch::channel(false);
+}
+
+// Token the Shutdown RPC has to present, the RPC is refused if the server has none
+static TOKEN: OnceLock<String> = OnceLock::new();
+
+/// Asks the server to stop accepting connections and exit once the ones it has are done.
+pub fn request() {
+    let _ = SHUTDOWN.0.send(true);
+}
+
+/// Whether shutdown was requested, for servers that poll.
+pub fn requested() -> bool {
+    *SHUTDOWN.1.borrow()
+}
+
+/// Resolves once shutdown is requested, for servers that await.
+pub async fn wait() {
+    let mut receiver = SHUTDOWN.1.clone();
+    while !*receiver.borrow() {
+        if receiver.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Sets the token that authorizes the Shutdown RPC, without one the RPC is disabled.
+pub fn set_token(token: Option<String>) {
+    if let Some(token) = token {
+        if TOKEN.set(token).is_err() {
+            panic!("The shutdown token can only be set once per process");
+        }
+    }
+}
+
+/// Requests shutdown on behalf of a client presenting `token`. Fails with EPERM if the
+/// server has no token and with EACCES if `token` is not it.
+pub fn request_with_token(token: &[u8]) -> Result<(), i32> {
+    let expected = TOKEN.get().ok_or(EPERM)?.as_bytes();
+    // Compares every byte, so the time taken does not tell how much of the token matched
+    let mismatch = expected.len() != token.len()
+        || expected
+            .iter()
+            .zip(token.iter())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            != 0;
+    if mismatch {
+        return Err(EACCES);
+    }
+    request();
+    Ok(())
+}
+
+/// Turns SIGINT and SIGTERM into a shutdown request, a second one exits right away.
+/// Has to be called before the server starts any thread, since the signals are blocked
+/// in the calling thread and only the threads spawned after inherit that.
+pub fn handle_signals() -> nix::Result<()> {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGTERM);
+    signals.thread_block()?;
+
+    std::thread::spawn(move || {
+        let mut received = 0;
+        while let Ok(signal) = signals.wait() {
+            received += 1;
+            if received > 1 {
+                eprintln!("Received {} again, exiting", signal);
+                std::process::exit(128 + signal as i32);
+            }
+            println!("Received {}, shutting down", signal);
+            request();
+        }
+    });
+    Ok(())
+}
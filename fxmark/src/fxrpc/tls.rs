@@ -0,0 +1,179 @@
+use rcgen::{
+    BasicConstraints, Certificate as RcgenCertificate, CertificateParams, DistinguishedName,
+    DnType, ExtendedKeyUsagePurpose, IsCa, KeyUsagePurpose, SanType,
+};
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, ServerName};
+use rustls_pemfile::Item;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use crate::fxrpc::TlsConfig;
+
+/// Files `generate` writes, clients present the client certificate for mutual TLS.
+pub const CA_CERT: &str = "ca.pem";
+pub const SERVER_CERT: &str = "server.pem";
+pub const SERVER_KEY: &str = "server.key";
+pub const CLIENT_CERT: &str = "client.pem";
+pub const CLIENT_KEY: &str = "client.key";
+
+/// Name clients check the server's certificate against unless told otherwise, which the
+/// generated server certificate is always issued for.
+pub const DEFAULT_SERVER_NAME: &str = "localhost";
+
+// Addresses the generated server certificate is issued for besides the given names: the
+// loopback ones of `tcplocal` and the bridge one of `tcpremote`
+const DEFAULT_SERVER_IPS: [&str; 3] = ["127.0.0.1", "::1", "172.31.0.1"];
+
+fn open(path: &Path) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format!("Cannot read {}: {}", path.display(), e))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>, String> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .map_err(|e| format!("Cannot parse {}: {}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificate in {}", path.display()));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+// The first private key in `path`, in any of the encodings PEM files carry them in
+fn read_key(path: &Path) -> Result<PrivateKey, String> {
+    let items = rustls_pemfile::read_all(&mut open(path)?)
+        .map_err(|e| format!("Cannot parse {}: {}", path.display(), e))?;
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| format!("No private key in {}", path.display()))
+}
+
+fn read_roots(path: &Path) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(path)? {
+        roots
+            .add(&cert)
+            .map_err(|e| format!("Bad CA certificate in {}: {}", path.display(), e))?;
+    }
+    Ok(roots)
+}
+
+// Certificate and key of `tls`, which come as a pair
+fn identity(tls: &TlsConfig) -> Result<Option<(Vec<Certificate>, PrivateKey)>, String> {
+    match (&tls.cert, &tls.key) {
+        (Some(cert), Some(key)) => Ok(Some((read_certs(cert)?, read_key(key)?))),
+        (None, None) => Ok(None),
+        _ => Err(String::from(
+            "A TLS certificate needs its key and vice versa",
+        )),
+    }
+}
+
+/// Server side of `tls`. Clients have to present a certificate if it names CAs.
+pub fn server_config(tls: &TlsConfig) -> Result<ServerConfig, String> {
+    let (certs, key) = identity(tls)?.ok_or("A TLS server needs a certificate and key")?;
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &tls.ca {
+        Some(ca) => builder
+            .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(read_roots(ca)?).boxed()),
+        None => builder.with_no_client_auth(),
+    };
+    builder
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("Bad server certificate or key: {}", e))
+}
+
+/// Client side of `tls`, which presents a certificate if it has one.
+pub fn client_config(tls: &TlsConfig) -> Result<ClientConfig, String> {
+    let ca = tls
+        .ca
+        .as_ref()
+        .ok_or("A TLS client needs the CA certificate to check the server against")?;
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(read_roots(ca)?);
+    match identity(tls)? {
+        Some((certs, key)) => builder
+            .with_client_auth_cert(certs, key)
+            .map_err(|e| format!("Bad client certificate or key: {}", e)),
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+/// Name the server's certificate has to be issued for, a host name or an address.
+pub fn server_name(tls: &TlsConfig) -> Result<ServerName, String> {
+    ServerName::try_from(tls.server_name.as_str())
+        .map_err(|_| format!("Bad TLS server name '{}'", tls.server_name))
+}
+
+fn san(name: &str) -> SanType {
+    match name.parse::<IpAddr>() {
+        Ok(ip) => SanType::IpAddress(ip),
+        Err(_) => SanType::DnsName(name.to_string()),
+    }
+}
+
+fn params(common_name: &str) -> CertificateParams {
+    let mut params = CertificateParams::default();
+    params.distinguished_name = DistinguishedName::new();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, common_name);
+    params
+}
+
+fn write(dir: &Path, name: &str, pem: &str, private: bool) -> Result<(), String> {
+    let path = dir.join(name);
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    if private {
+        options.mode(0o600);
+    }
+    options
+        .open(&path)
+        .and_then(|mut file| file.write_all(pem.as_bytes()))
+        .map_err(|e| format!("Cannot write {}: {}", path.display(), e))
+}
+
+/// Writes a throwaway CA into `dir` along with a server and a client certificate it
+/// issued, for local runs. The server certificate is issued for `names` on top of
+/// `DEFAULT_SERVER_NAME` and the addresses of the default transports. The CA's key is
+/// not kept, so no further certificates can be issued.
+pub fn generate(dir: &Path, names: &[String]) -> Result<(), String> {
+    let err = |e: rcgen::RcgenError| format!("Cannot generate certificates: {}", e);
+    std::fs::create_dir_all(dir).map_err(|e| format!("Cannot create {}: {}", dir.display(), e))?;
+
+    let mut ca = params("fxrpc CA");
+    ca.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    let ca = RcgenCertificate::from_params(ca).map_err(err)?;
+
+    let mut server = params("fxrpc server");
+    server.subject_alt_names = std::iter::once(DEFAULT_SERVER_NAME)
+        .chain(DEFAULT_SERVER_IPS)
+        .chain(names.iter().map(String::as_str))
+        .map(san)
+        .collect();
+    server.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    let server = RcgenCertificate::from_params(server).map_err(err)?;
+
+    let mut client = params("fxrpc client");
+    client.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    let client = RcgenCertificate::from_params(client).map_err(err)?;
+
+    write(dir, CA_CERT, &ca.serialize_pem().map_err(err)?, false)?;
+    let pem = server.serialize_pem_with_signer(&ca).map_err(err)?;
+    write(dir, SERVER_CERT, &pem, false)?;
+    write(dir, SERVER_KEY, &server.serialize_private_key_pem(), true)?;
+    let pem = client.serialize_pem_with_signer(&ca).map_err(err)?;
+    write(dir, CLIENT_CERT, &pem, false)?;
+    write(dir, CLIENT_KEY, &client.serialize_private_key_pem(), true)
+}
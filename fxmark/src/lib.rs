@@ -0,0 +1,8 @@
+/*
+    Fxmark benchmark and the gRPC and DRPC system call servers it runs against.
+*/
+#[macro_use]
+extern crate abomonation;
+
+pub mod fxmark;
+pub mod fxrpc;
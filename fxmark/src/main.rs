@@ -5,18 +5,13 @@
 use clap::{crate_version, value_t, App, Arg};
 use std::fs::{remove_file, OpenOptions};
 use std::io::Write;
+use std::path::PathBuf;
 
-#[macro_use]
-extern crate abomonation;
-
-mod fxmark;
-use crate::fxmark::bench;
-use crate::fxmark::utils::topology::MachineTopology;
-
-pub mod fxrpc;
-use crate::fxrpc::ConnType;
-use crate::fxrpc::RPCType;
-use crate::fxrpc::*;
+use fxrpc::fxmark::bench;
+use fxrpc::fxmark::utils::topology::MachineTopology;
+use fxrpc::fxrpc::ConnType;
+use fxrpc::fxrpc::RPCType;
+use fxrpc::fxrpc::*;
 
 const DEFAULT_OUTFILE: &str = "fxrpc_bench.csv";
 
@@ -29,14 +24,14 @@ fn parseargs(args: std::env::Args) -> clap::ArgMatches<'static> {
             Arg::with_name("mode")
                 .long("mode")
                 .required(true)
-                .help("client or server")
+                .help("client, server, shutdown to stop a running server, or certs to generate TLS certificates")
                 .takes_value(true)
-                .possible_values(&["client", "server", "loc_client_drpc"]),
+                .possible_values(&["client", "server", "shutdown", "certs", "loc_client_drpc"]),
         )
         .arg(
             Arg::with_name("rpc")
                 .long("rpc")
-                .required(true)
+                .required_unless("certs-dir")
                 .help("Dinos RPC (drpc) or gRPC (grpc)")
                 .takes_value(true)
                 .possible_values(&["drpc", "grpc"]),
@@ -44,7 +39,7 @@ fn parseargs(args: std::env::Args) -> clap::ArgMatches<'static> {
         .arg(
             Arg::with_name("transport")
                 .long("transport")
-                .required(true)
+                .required_unless("certs-dir")
                 .help("TCP Local (tcplocal) TCP Remote (tcpremote) UDS (uds)")
                 .takes_value(true)
                 .possible_values(&["tcplocal", "tcpremote", "uds"]),
@@ -58,6 +53,128 @@ fn parseargs(args: std::env::Args) -> clap::ArgMatches<'static> {
                 .default_value("8080")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("addr")
+                .long("addr")
+                .required(false)
+                .help("Server address, tcp://host:port or unix:///path (overrides transport defaults)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("root")
+                .long("root")
+                .required(false)
+                .help("Directory the server exports, client paths cannot leave it")
+                .default_value(FS_PATH)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("backend")
+                .long("backend")
+                .required(false)
+                .help("File system the server exports, memory ignores --root")
+                .possible_values(&["posix", "memory"])
+                .default_value("posix")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("faults")
+                .long("faults")
+                .required(false)
+                .help("YAML file of latency, errors, short I/O and drops to inject into the backend")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fault-seed")
+                .long("fault-seed")
+                .required(false)
+                .help("Seed of the injected faults, overrides the seed of the fault file")
+                .requires("faults")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metrics-out")
+                .long("metrics-out")
+                .required(false)
+                .help("CSV file the server writes its per-RPC metrics to when it exits")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("shutdown-token")
+                .long("shutdown-token")
+                .required_if("mode", "shutdown")
+                .help("Token authorizing the Shutdown RPC, which the server refuses without one")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("workers")
+                .long("workers")
+                .required(false)
+                .help("Server worker threads, event-driven for DRPC, runtime workers for gRPC")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("server-cores")
+                .long("server-cores")
+                .required(false)
+                .help("Cores to pin the server workers to, like 0-3,8")
+                .requires("workers")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("grpc-runtime")
+                .long("grpc-runtime")
+                .required(false)
+                .help("One multi-threaded runtime (multi) or a runtime per worker (per-core)")
+                .default_value("multi")
+                .possible_values(&["multi", "per-core"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("io-exec")
+                .long("io-exec")
+                .required(false)
+                .help("Where gRPC runs backend calls: inline, blocking or on a pool of I/O threads")
+                .default_value("inline")
+                .possible_values(&["inline", "blocking", "pool"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("io-threads")
+                .long("io-threads")
+                .required_if("io-exec", "pool")
+                .help("I/O threads of the gRPC server with --io-exec pool")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("io-cores")
+                .long("io-cores")
+                .required(false)
+                .help("Cores to pin the I/O threads to, like 4-7")
+                .requires("io-threads")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-io")
+                .long("max-io")
+                .required(false)
+                .help("Largest read or write in bytes a DRPC server serves at once, 16 MiB by default")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("grpc-max-message")
+                .long("grpc-max-message")
+                .required(false)
+                .help("Largest gRPC message in bytes, 4 MiB by default; set it alike on client and server")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("grpc-chunk")
+                .long("grpc-chunk")
+                .required(false)
+                .help("gRPC reads and writes of more bytes are streamed in chunks of this size, 1 MiB by default")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("wratio")
                 .long("wratio")
@@ -84,6 +201,68 @@ fn parseargs(args: std::env::Args) -> clap::ArgMatches<'static> {
                 .default_value("10")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("qdepth")
+                .long("qdepth")
+                .required(false)
+                .help("Requests kept in flight per client thread")
+                .default_value("1")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("batch")
+                .long("batch")
+                .required(false)
+                .help("Pages moved per vectored read/write (1 disables vectored I/O)")
+                .default_value("1")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("codec")
+                .long("codec")
+                .required(false)
+                .help("How DRPC clients serialise messages, the server speaks both")
+                .default_value("abomonation")
+                .possible_values(&["abomonation", "le"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tls-cert")
+                .long("tls-cert")
+                .required(false)
+                .help("PEM certificate presented over TLS, which servers need and clients present for mutual TLS")
+                .requires("tls-key")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tls-key")
+                .long("tls-key")
+                .required(false)
+                .help("PEM private key of --tls-cert")
+                .requires("tls-cert")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tls-ca")
+                .long("tls-ca")
+                .required(false)
+                .help("PEM CA certificate clients check the server against; servers given one require client certificates")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tls-name")
+                .long("tls-name")
+                .required(false)
+                .help("Name the server certificate is checked against, localhost by default; certs issues it for this name too")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("certs-dir")
+                .long("certs-dir")
+                .required_if("mode", "certs")
+                .help("Directory certs writes a throwaway CA and server and client certificates to")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("cid")
                 .long("cid")
@@ -122,6 +301,20 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let matches = parseargs(args);
 
     let mode = value_t!(matches, "mode", String).unwrap();
+    if mode == "certs" {
+        let dir = PathBuf::from(matches.value_of("certs-dir").unwrap());
+        let names: Vec<String> = matches
+            .value_of("tls-name")
+            .map(String::from)
+            .into_iter()
+            .collect();
+        tls::generate(&dir, &names)?;
+        println!(
+            "Wrote a CA and server and client certificates to {}",
+            dir.display()
+        );
+        return Ok(());
+    }
     let port = value_t!(matches, "port", u16).unwrap();
     let conn_type: ConnType = {
         match value_t!(matches, "transport", String).unwrap().as_str() {
@@ -136,12 +329,120 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         "drpc" => RPCType::DRPC,
         &_ => panic!("Unknown RPCType!"),
     };
+    let addr = match matches.value_of("addr") {
+        Some(addr) => addr.parse::<ServerAddr>()?,
+        None => ServerAddr::default_for(conn_type, rpc_type, port),
+    };
+    let codec = value_t!(matches, "codec", Codec).unwrap_or_else(|e| e.exit());
+    let mut grpc_limits = GrpcLimits::default();
+    if matches.is_present("grpc-max-message") {
+        grpc_limits.max_message =
+            value_t!(matches, "grpc-max-message", usize).unwrap_or_else(|e| e.exit());
+    }
+    if matches.is_present("grpc-chunk") {
+        grpc_limits.stream_chunk =
+            value_t!(matches, "grpc-chunk", usize).unwrap_or_else(|e| e.exit());
+    }
+    grpc_limits.check()?;
+    let tls = if matches.is_present("tls-cert") || matches.is_present("tls-ca") {
+        Some(TlsConfig {
+            cert: matches.value_of("tls-cert").map(PathBuf::from),
+            key: matches.value_of("tls-key").map(PathBuf::from),
+            ca: matches.value_of("tls-ca").map(PathBuf::from),
+            server_name: matches
+                .value_of("tls-name")
+                .unwrap_or(tls::DEFAULT_SERVER_NAME)
+                .to_string(),
+        })
+    } else {
+        None
+    };
+    if tls.is_some() && !matches!(addr, ServerAddr::Tcp(_)) {
+        return Err("TLS only secures TCP connections".into());
+    }
     let bench_name = String::from("mix");
     let outfile = value_t!(matches, "outfile", String).unwrap();
 
     match mode.as_str() {
         "server" => {
-            run_server(conn_type, rpc_type, port);
+            let faults = match matches.value_of("faults") {
+                Some(path) => {
+                    let mut faults = FaultConfig::from_file(path)?;
+                    if matches.is_present("fault-seed") {
+                        faults.seed =
+                            Some(value_t!(matches, "fault-seed", u64).unwrap_or_else(|e| e.exit()));
+                    }
+                    Some(faults)
+                }
+                None => None,
+            };
+            let pool = match matches.value_of("workers") {
+                Some(_) => {
+                    let workers = value_t!(matches, "workers", usize).unwrap_or_else(|e| e.exit());
+                    let cores = matches
+                        .value_of("server-cores")
+                        .map(parse_cores)
+                        .transpose()?;
+                    Some(WorkerPool::new(workers, cores)?)
+                }
+                None => None,
+            };
+            let runtime =
+                value_t!(matches, "grpc-runtime", RuntimeFlavor).unwrap_or_else(|e| e.exit());
+            if runtime == RuntimeFlavor::PerCore && pool.is_none() {
+                return Err("--grpc-runtime per-core needs --workers".into());
+            }
+            if rpc_type == RPCType::DRPC && pool.is_some() && tls.is_some() {
+                return Err("DRPC servers with --workers do not serve TLS".into());
+            }
+            let io_exec = match matches.value_of("io-exec") {
+                Some("blocking") => IoExec::Blocking,
+                Some("pool") => {
+                    let threads =
+                        value_t!(matches, "io-threads", usize).unwrap_or_else(|e| e.exit());
+                    let cores = matches.value_of("io-cores").map(parse_cores).transpose()?;
+                    IoExec::Pool(WorkerPool::new(threads, cores)?)
+                }
+                _ => IoExec::Inline,
+            };
+            let max_io = if matches.is_present("max-io") {
+                value_t!(matches, "max-io", usize).unwrap_or_else(|e| e.exit())
+            } else {
+                DEFAULT_MAX_IO
+            };
+            if max_io == 0 {
+                return Err("--max-io has to be at least one byte".into());
+            }
+            let config = ServerConfig {
+                rpc_type: rpc_type,
+                addr: addr,
+                root: value_t!(matches, "root", PathBuf).unwrap_or_else(|e| e.exit()),
+                backend: value_t!(matches, "backend", BackendType).unwrap_or_else(|e| e.exit()),
+                faults: faults,
+                metrics_out: matches.value_of("metrics-out").map(PathBuf::from),
+                shutdown_token: matches.value_of("shutdown-token").map(String::from),
+                pool: pool,
+                runtime: runtime,
+                io_exec: io_exec,
+                max_io: max_io,
+                grpc_limits: grpc_limits,
+                tls: tls,
+            };
+            run_server(&config);
+        }
+        "shutdown" => {
+            let config = ClientConfig {
+                rpc_type: rpc_type,
+                addr: addr,
+                codec: codec,
+                grpc_limits: grpc_limits,
+                tls: tls,
+            };
+            let token = matches.value_of("shutdown-token").unwrap();
+            let mut client = init_client(&config)
+                .map_err(|e| format!("Cannot connect to {}: {}", config.addr, e))?;
+            client.rpc_shutdown(token)?;
+            println!("Server at {} is shutting down", config.addr);
         }
         "client" => {
             let wratios: Vec<&str> = matches.values_of("wratio").unwrap().collect();
@@ -156,6 +457,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .collect();
 
             let duration = value_t!(matches, "duration", u64).unwrap_or_else(|e| e.exit());
+            let qdepth = value_t!(matches, "qdepth", usize).unwrap_or_else(|e| e.exit());
+            let batch = value_t!(matches, "batch", usize).unwrap_or_else(|e| e.exit());
 
             let cid = if conn_type != ConnType::TcpLocal {
                 value_t!(matches, "cid", usize).unwrap_or_else(|e| e.exit())
@@ -189,7 +492,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 ccores: ccores,
                 log_mode: log_mode,
                 conn_type: conn_type,
-                rpc_type: rpc_type,
+                config: ClientConfig {
+                    rpc_type: rpc_type,
+                    addr: addr,
+                    codec: codec,
+                    grpc_limits: grpc_limits,
+                    tls: tls,
+                },
+                qdepth: qdepth,
+                batch: batch.max(1),
             };
 
             let row = "thread_id,benchmark,ncores,write_ratio,open_files,duration_total,duration,operations,client_id,client_cores,nclients,rpctype\n";
@@ -0,0 +1,193 @@
+// Not every test binary uses every helper
+#![allow(dead_code)]
+
+use libc::{EACCES, O_CREAT, O_RDWR, S_IRWXU};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use fxrpc::fxrpc::*;
+
+// Attempts to reach a server that is starting up, 10ms apart
+const CONNECT_ATTEMPTS: usize = 500;
+
+// Time a server gets to stop once shutdown was requested
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Empty directory of this test binary, named after `name`.
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fxrpc-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("Failed to create scratch directory");
+    dir
+}
+
+/// Socket path of this test binary, named after `name`.
+pub fn socket_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("fxrpc-{}-{}.sock", name, std::process::id()))
+}
+
+/// A loopback port nothing listens on right now.
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("Failed to find a free port")
+        .port()
+}
+
+/// Starts a server on a thread of its own and returns how to reach it once it accepts
+/// connections. The server runs until the test binary exits.
+pub fn start_server(
+    rpc_type: RPCType,
+    addr: ServerAddr,
+    backend: BackendType,
+    root: PathBuf,
+) -> ClientConfig {
+    start_configured(server_config(rpc_type, addr, backend, root))
+}
+
+/// Configuration `start_server` runs a server with, for tests that change some of it.
+pub fn server_config(
+    rpc_type: RPCType,
+    addr: ServerAddr,
+    backend: BackendType,
+    root: PathBuf,
+) -> ServerConfig {
+    ServerConfig {
+        rpc_type: rpc_type,
+        addr: addr,
+        root: root,
+        backend: backend,
+        faults: None,
+        metrics_out: None,
+        shutdown_token: None,
+        pool: None,
+        runtime: RuntimeFlavor::MultiThread,
+        io_exec: IoExec::Inline,
+        max_io: DEFAULT_MAX_IO,
+        grpc_limits: GrpcLimits::default(),
+        tls: None,
+    }
+}
+
+/// Starts a server like `start_server` does, with the given configuration. Clients of a
+/// TLS server have to add their own side of it to the returned configuration.
+pub fn start_configured(config: ServerConfig) -> ClientConfig {
+    let (rpc_type, addr, grpc_limits) = (config.rpc_type, config.addr.clone(), config.grpc_limits);
+    std::thread::spawn(move || run_server(&config));
+
+    for _ in 0..CONNECT_ATTEMPTS {
+        let reachable = match &addr {
+            ServerAddr::Tcp(addr) => TcpStream::connect(addr).is_ok(),
+            ServerAddr::Uds(path) => UnixStream::connect(path).is_ok(),
+        };
+        if reachable {
+            return ClientConfig {
+                rpc_type: rpc_type,
+                addr: addr,
+                codec: Codec::default(),
+                grpc_limits: grpc_limits,
+                tls: None,
+            };
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    panic!("{} server on {} did not come up", rpc_type, addr);
+}
+
+/// Directory exported by the servers of the basic tests, holding the file they read.
+pub fn read_test_root(name: &str) -> PathBuf {
+    let root = scratch_dir(name);
+    std::fs::write(root.join("read_test.txt"), "ReadTest\n").expect("Failed to create test file");
+    root
+}
+
+/// Writes and reads back several MiB in a single call each, with both clients, which a
+/// server with the default limits takes in a single request.
+pub fn large_io_test(config: &ClientConfig) {
+    let len = 8 * 1024 * 1024 + 123;
+    let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+
+    let mut client = init_client(config).unwrap();
+    let fd = client.rpc_open("large", O_CREAT | O_RDWR, S_IRWXU).unwrap();
+    assert_eq!(client.rpc_pwrite(fd, &data, 0).unwrap(), len as i32);
+    let mut page = vec![0u8; len];
+    assert_eq!(client.rpc_pread(fd, &mut page, 0).unwrap(), len as i32);
+    assert!(page == data);
+    client.rpc_close(fd).unwrap();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let client = init_async_client(config, 4).await.unwrap();
+        let fd = client.rpc_open("large", O_RDWR, 0).await.unwrap();
+        assert_eq!(client.rpc_pwrite(fd, &data, 1).await.unwrap(), len as i32);
+        let mut page = vec![0u8; len];
+        assert_eq!(
+            client.rpc_pread(fd, &mut page, 1).await.unwrap(),
+            len as i32
+        );
+        assert!(page == data);
+        client.rpc_close(fd).await.unwrap();
+    });
+}
+
+/// Descriptors of this process, the servers included, open on files under `dir`.
+pub fn open_under(dir: &Path) -> usize {
+    std::fs::read_dir("/proc/self/fd")
+        .unwrap()
+        .filter_map(|entry| std::fs::read_link(entry.ok()?.path()).ok())
+        .filter(|target| target.starts_with(dir) && target != dir)
+        .count()
+}
+
+/// Starts a POSIX server taking `token` for the Shutdown RPC, checks that it refuses
+/// other tokens and that the right one stops it with the handles clients left open
+/// closed. Shutdown stops every server of a process, so each protocol needs a test
+/// binary of its own.
+pub fn shutdown_test(rpc_type: RPCType, token: &str) {
+    let name = format!("shutdown-{}", rpc_type);
+    let root = scratch_dir(&name).canonicalize().unwrap();
+    let path = socket_path(&name);
+    let config = server_config(
+        rpc_type,
+        ServerAddr::Uds(path.clone()),
+        BackendType::Posix,
+        root.clone(),
+    );
+    let config = start_configured(ServerConfig {
+        shutdown_token: Some(String::from(token)),
+        ..config
+    });
+
+    let mut client = init_client(&config).unwrap();
+    let fd = client
+        .rpc_open("left-open", O_CREAT | O_RDWR, S_IRWXU)
+        .unwrap();
+    let longer = format!("{}x", token);
+    for wrong in ["", &token[1..], longer.as_str(), "wrong"] {
+        assert!(
+            matches!(client.rpc_shutdown(wrong), Err(FxRpcError::Errno(EACCES))),
+            "{} took shutdown token {:?}",
+            rpc_type,
+            wrong
+        );
+    }
+    assert_eq!(client.rpc_pwrite(fd, b"x", 0).unwrap(), 1);
+    assert_eq!(open_under(&root), 1);
+
+    init_client(&config).unwrap().rpc_shutdown(token).unwrap();
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    while (path.exists() || open_under(&root) > 0) && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(!path.exists(), "{} server did not stop", rpc_type);
+    assert_eq!(
+        open_under(&root),
+        0,
+        "{} server stopped with handles open",
+        rpc_type
+    );
+    assert!(UnixStream::connect(&path).is_err());
+    assert!(client.rpc_pwrite(fd, b"x", 0).is_err());
+}
@@ -0,0 +1,293 @@
+//! Runs the same scenarios against a gRPC and a DRPC server, the latter once with each
+//! codec, and checks that they all return the same results, errors included.
+
+mod common;
+
+use fxrpc::fxrpc::*;
+use libc::*;
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+// Every server exports an in-memory backend of its own, started by the first test. A
+// process runs a single DRPC server, which both codecs are spoken to.
+fn servers() -> &'static [ClientConfig] {
+    static SERVERS: OnceLock<Vec<ClientConfig>> = OnceLock::new();
+    SERVERS.get_or_init(|| {
+        let start = |rpc_type: RPCType| {
+            let name = format!("conformance-{}", rpc_type);
+            let addr = ServerAddr::Uds(common::socket_path(&name));
+            common::start_server(rpc_type, addr, BackendType::Memory, PathBuf::from(FS_PATH))
+        };
+        let drpc = start(RPCType::DRPC);
+        vec![
+            start(RPCType::GRPC),
+            ClientConfig {
+                codec: Codec::Abomonation,
+                ..drpc.clone()
+            },
+            ClientConfig {
+                codec: Codec::Le,
+                ..drpc
+            },
+        ]
+    })
+}
+
+// What a scenario observed, one line per call
+#[derive(Debug, Default, PartialEq)]
+struct Trace(Vec<String>);
+
+impl Trace {
+    // Records the value or errno a call returned. Transport errors fail the test right
+    // away, they mean the protocol itself is broken rather than disagreeing.
+    fn record<T: Debug>(&mut self, call: &str, res: Result<T, FxRpcError>) -> Option<T> {
+        match res {
+            Ok(value) => {
+                self.0.push(format!("{}: {:?}", call, value));
+                Some(value)
+            }
+            Err(FxRpcError::Errno(errno)) => {
+                self.0.push(format!("{}: errno {}", call, errno));
+                None
+            }
+            Err(e) => panic!("{} failed: {}", call, e),
+        }
+    }
+
+    // File attributes that do not depend on when or in which order the tests ran
+    fn stat(&mut self, call: &str, res: Result<FileStat, FxRpcError>) {
+        self.record(call, res.map(|st| (st.mode, st.nlink, st.size)));
+    }
+}
+
+// Runs `scenario` in a directory named after it on every server, each over a connection
+// of its own. Configs sharing a server get a directory each.
+fn conform(name: &str, scenario: fn(&mut dyn FxRPC, &str, &mut Trace)) {
+    let traces: Vec<Trace> = servers()
+        .iter()
+        .enumerate()
+        .map(|(i, config)| {
+            let dir = format!("{}-{}", name, i);
+            let mut client = init_client(config).unwrap();
+            client
+                .rpc_mkdir(&dir, S_IRWXU)
+                .expect("Failed to create the scenario directory");
+            let mut trace = Trace::default();
+            scenario(&mut *client, &dir, &mut trace);
+            trace
+        })
+        .collect();
+    for (config, trace) in servers().iter().zip(traces.iter()).skip(1) {
+        assert_eq!(
+            traces[0], *trace,
+            "gRPC and {} with {} disagree on {}",
+            config.rpc_type, config.codec, name
+        );
+    }
+}
+
+#[test]
+fn file_io() {
+    conform("file_io", |client, dir, trace| {
+        let path = format!("{}/file", dir);
+        let fd = trace
+            .record("open", client.rpc_open(&path, O_CREAT | O_RDWR, S_IRWXU))
+            .unwrap();
+        trace.record("write", client.rpc_write(fd, b"hello"));
+        trace.record("pwrite", client.rpc_pwrite(fd, b"world", 5));
+        trace.record("fstat", client.rpc_fstat(fd));
+
+        let mut page = vec![0u8; 16];
+        trace.record("pread", client.rpc_pread(fd, &mut page, 0));
+        trace.record("pread data", Ok(page.clone()));
+        trace.record("read", client.rpc_read(fd, &mut page));
+        trace.record("pread past eof", client.rpc_pread(fd, &mut page, 64));
+
+        trace.record("fsync", client.rpc_fsync(fd));
+        trace.record("ftruncate", client.rpc_ftruncate(fd, 3));
+        assert_eq!(client.rpc_fstat(fd).unwrap(), 3);
+        trace.record("close", client.rpc_close(fd));
+        trace.record("close again", client.rpc_close(fd));
+
+        trace.record("remove", client.rpc_remove(&path));
+        trace.record("open removed", client.rpc_open(&path, O_RDWR, 0));
+    });
+}
+
+#[test]
+fn vectored_io() {
+    conform("vectored_io", |client, dir, trace| {
+        let path = format!("{}/file", dir);
+        let fd = trace
+            .record("open", client.rpc_open(&path, O_CREAT | O_RDWR, S_IRWXU))
+            .unwrap();
+        let segments: [(i64, &[u8]); 3] = [(0, &b"aaaa"[..]), (8, &b"bb"[..]), (4, &b"cccc"[..])];
+        trace.record("pwritev", client.rpc_pwritev(fd, &segments));
+
+        let (mut first, mut second) = (vec![0u8; 6], vec![0u8; 8]);
+        let mut segments: [(i64, &mut [u8]); 2] = [(2, &mut first[..]), (6, &mut second[..])];
+        trace.record("preadv short", client.rpc_preadv(fd, &mut segments));
+        trace.record("preadv data", Ok((first, second)));
+        trace.record("close", client.rpc_close(fd));
+    });
+}
+
+#[test]
+fn large_io() {
+    conform("large_io", |client, dir, trace| {
+        let path = format!("{}/file", dir);
+        let fd = trace
+            .record("open", client.rpc_open(&path, O_CREAT | O_RDWR, S_IRWXU))
+            .unwrap();
+        // Far beyond a page, and not a multiple of one
+        let data: Vec<u8> = (0..(1 << 20) + 3).map(|i| (i % 251) as u8).collect();
+        trace.record("pwrite", client.rpc_pwrite(fd, &data, 5));
+        trace.record("write", client.rpc_write(fd, &data));
+
+        let mut page = vec![0u8; data.len()];
+        trace.record("pread", client.rpc_pread(fd, &mut page, 5));
+        trace.record("pread data", Ok(page == data));
+        trace.record("read", client.rpc_read(fd, &mut page));
+        trace.record("read data", Ok(page == data));
+        trace.record("close", client.rpc_close(fd));
+    });
+}
+
+#[test]
+fn directories() {
+    conform("directories", |client, dir, trace| {
+        let sub = format!("{}/sub", dir);
+        let file = format!("{}/file", sub);
+        trace.record("mkdir", client.rpc_mkdir(&sub, S_IRWXU));
+        trace.record("mkdir existing", client.rpc_mkdir(&sub, S_IRWXU));
+        let fd = trace
+            .record("open", client.rpc_open(&file, O_CREAT | O_RDWR, S_IRWXU))
+            .unwrap();
+        trace.record("close", client.rpc_close(fd));
+
+        trace.record("rmdir not empty", client.rpc_rmdir(&sub));
+        trace.record("rmdir file", client.rpc_rmdir(&file));
+        trace.record("unlink", client.rpc_unlink(&file));
+        trace.record("rmdir", client.rpc_rmdir(&sub));
+        assert_eq!(client.rpc_stat(&sub).unwrap_err().errno(), Some(ENOENT));
+        trace.stat("stat removed", client.rpc_stat(&sub));
+        trace.record("rmdir removed", client.rpc_rmdir(&sub));
+    });
+}
+
+#[test]
+fn names() {
+    conform("names", |client, dir, trace| {
+        let (a, b, c) = (
+            format!("{}/a", dir),
+            format!("{}/b", dir),
+            format!("{}/c", dir),
+        );
+        let fd = trace
+            .record("open", client.rpc_open(&a, O_CREAT | O_RDWR, S_IRWXU))
+            .unwrap();
+        trace.record("close", client.rpc_close(fd));
+
+        trace.record("link", client.rpc_link(&a, &b));
+        trace.stat("stat link", client.rpc_stat(&b));
+        trace.record("link existing", client.rpc_link(&a, &b));
+        trace.record("rename", client.rpc_rename(&a, &c, 0));
+        trace.stat("stat renamed", client.rpc_stat(&a));
+        trace.record(
+            "rename noreplace",
+            client.rpc_rename(&b, &c, RENAME_NOREPLACE),
+        );
+        trace.record("unlink", client.rpc_unlink(&b));
+        trace.stat("lstat", client.rpc_lstat(&c));
+
+        trace.record("truncate", client.rpc_truncate(&c, 100));
+        trace.stat("stat truncated", client.rpc_stat(&c));
+        trace.record("truncate missing", client.rpc_truncate(&a, 0));
+        trace.stat("stat escaping root", client.rpc_stat("../etc/passwd"));
+    });
+}
+
+#[test]
+fn listing() {
+    conform("listing", |client, dir, trace| {
+        for name in ["e", "a", "d", "b", "c"] {
+            let path = format!("{}/{}", dir, name);
+            let fd = client.rpc_open(&path, O_CREAT | O_RDWR, S_IRWXU).unwrap();
+            client.rpc_close(fd).unwrap();
+        }
+        client.rpc_mkdir(&format!("{}/f", dir), S_IRWXU).unwrap();
+
+        let mut cookie = 0;
+        loop {
+            let page = trace.record(
+                "readdir",
+                client.rpc_readdir(dir, cookie, 2).map(|page| {
+                    let entries: Vec<(String, u8)> = page
+                        .entries
+                        .iter()
+                        .map(|e| (e.name.clone(), e.file_type))
+                        .collect();
+                    (entries, page.eof, page.cookie)
+                }),
+            );
+            match page {
+                Some((_, false, next)) => cookie = next,
+                _ => break,
+            }
+        }
+        trace.record(
+            "readdir nothing",
+            client.rpc_readdir(dir, 0, 0).map(|page| page.entries.len()),
+        );
+        trace.record(
+            "readdir missing",
+            client
+                .rpc_readdir(&format!("{}/missing", dir), 0, 2)
+                .map(|page| page.entries.len()),
+        );
+    });
+}
+
+#[test]
+fn bad_handles() {
+    conform("bad_handles", |client, _dir, trace| {
+        let mut page = vec![0u8; 8];
+        trace.record("read", client.rpc_read(42, &mut page));
+        trace.record("pread", client.rpc_pread(42, &mut page, 0));
+        trace.record("write", client.rpc_write(42, &page));
+        trace.record("fsync", client.rpc_fsync(42));
+        trace.record("fstat", client.rpc_fstat(42));
+        trace.record("ftruncate", client.rpc_ftruncate(42, 0));
+        trace.record("close", client.rpc_close(42));
+    });
+}
+
+#[test]
+fn compounds() {
+    conform("compounds", |client, dir, trace| {
+        let path = format!("{}/file", dir);
+        let mut compound = Compound::new();
+        let fd = compound.open(&path, O_CREAT | O_RDWR, S_IRWXU);
+        compound
+            .pwrite(fd, b"compound", 0)
+            .fsync(fd)
+            .fstat(fd)
+            .pread(fd, 4, 4)
+            .close(fd);
+        trace.record("compound", client.rpc_compound(&compound));
+
+        // Stops at the open that fails
+        let mut compound = Compound::new();
+        let fd = compound.open(&format!("{}/missing", dir), O_RDWR, 0);
+        compound.pread(fd, 4, 0).close(fd);
+        trace.record("compound failing", client.rpc_compound(&compound));
+
+        let mut compound = Compound::new();
+        compound
+            .unlink(&path)
+            .mkdir(&format!("{}/sub", dir), S_IRWXU);
+        trace.record("compound names", client.rpc_compound(&compound));
+        trace.stat("stat unlinked", client.rpc_stat(&path));
+    });
+}
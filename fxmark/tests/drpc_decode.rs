@@ -0,0 +1,103 @@
+//! Feeds malformed requests straight to every DRPC handler and checks that each one
+//! answers with an error instead of panicking or overrunning its payload.
+
+use fxrpc::fxrpc::backend::MemoryBackend;
+use fxrpc::fxrpc::drpc::{init_raw, read_response, serve_raw, WIRE_VERSION};
+use fxrpc::fxrpc::Codec;
+use libc::{EINVAL, EPROTONOSUPPORT};
+use std::sync::Arc;
+
+const CODECS: [Codec; 2] = [Codec::Abomonation, Codec::Le];
+
+// Opcodes of the handlers that decode a request, every one but Stats and Hello
+fn opcodes() -> impl Iterator<Item = u8> {
+    (1..=24).filter(|&opcode| opcode != 23)
+}
+
+// A small payload buffer, which no response may overrun
+const CAPACITY: usize = 8192;
+
+// Result and errno of the response to a raw request, wire header included
+fn serve_bytes(opcode: u8, request: &[u8]) -> (i32, i32) {
+    init_raw(Arc::new(MemoryBackend::new()));
+    let mut response = serve_raw(opcode, request, CAPACITY)
+        .unwrap_or_else(|| panic!("Opcode {} dropped the connection", opcode));
+    assert!(response.len() <= CAPACITY);
+    let (result, errno, _) = read_response(&mut response).expect("Unreadable response");
+    (result, errno)
+}
+
+fn serve(codec: Codec, opcode: u8, body: &[u8]) -> (i32, i32) {
+    let mut request = codec.header().to_vec();
+    request.extend_from_slice(body);
+    serve_bytes(opcode, &request)
+}
+
+#[test]
+fn truncated_requests() {
+    for codec in CODECS {
+        for opcode in opcodes() {
+            // Shorter than the smallest request, a lone handle
+            for len in [0, 1, 3] {
+                assert_eq!(
+                    serve(codec, opcode, &vec![0u8; len]),
+                    (-1, EINVAL),
+                    "opcode {} with {} bytes in {}",
+                    opcode,
+                    len,
+                    codec
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn garbage_requests() {
+    // Deterministic bytes, every length field they land in is huge
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+    let garbage: Vec<u8> = (0..512)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state as u8) | 0x80
+        })
+        .collect();
+    for codec in CODECS {
+        for opcode in opcodes() {
+            for len in [16, 64, 512] {
+                let (result, errno) = serve(codec, opcode, &garbage[..len]);
+                assert_eq!(result, -1, "opcode {} with {} bytes", opcode, len);
+                assert!(errno > 0, "opcode {} with {} bytes", opcode, len);
+            }
+        }
+    }
+}
+
+#[test]
+fn foreign_headers() {
+    let mut newer = Codec::Le.header();
+    newer[4] = WIRE_VERSION + 1;
+    let mut unknown_codec = Codec::Le.header();
+    unknown_codec[5] = 0xff;
+    let headers: [&[u8]; 4] = [&newer, &unknown_codec, b"NOTDRPC!", b"FX"];
+    for header in headers {
+        for opcode in 1..=25 {
+            assert_eq!(
+                serve_bytes(opcode, header),
+                (-1, EPROTONOSUPPORT),
+                "opcode {} with header {:?}",
+                opcode,
+                header
+            );
+        }
+    }
+}
+
+#[test]
+fn hello() {
+    for codec in CODECS {
+        assert_eq!(serve(codec, 25, &[]), (WIRE_VERSION as i32, 0));
+    }
+}
@@ -0,0 +1,54 @@
+//! The event-driven DRPC server with more responses in flight on a connection than it
+//! queues, so it has to stop reading requests and take them up again.
+
+mod common;
+
+use fxrpc::fxrpc::*;
+use libc::{O_CREAT, O_RDWR, S_IRWXU};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+// Reads small enough for the payload buffer a worker starts out with
+const LEN: usize = 4096;
+
+// Responses in flight at once, together several times what a connection queues
+const DEPTH: usize = 2048;
+
+// Event-driven server with a single worker, started by the first test that needs it
+fn config() -> &'static ClientConfig {
+    static SERVER: OnceLock<ClientConfig> = OnceLock::new();
+    SERVER.get_or_init(|| {
+        let addr = ServerAddr::Uds(common::socket_path("drpc-event"));
+        let config =
+            common::server_config(RPCType::DRPC, addr, BackendType::Memory, PathBuf::from("/"));
+        common::start_configured(ServerConfig {
+            pool: Some(WorkerPool::new(1, None).unwrap()),
+            ..config
+        })
+    })
+}
+
+#[test]
+fn responses_past_the_queue_limit() {
+    let data: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let client = init_async_client(config(), DEPTH).await.unwrap();
+        let fd = client
+            .rpc_open("backlog", O_CREAT | O_RDWR, S_IRWXU)
+            .await
+            .unwrap();
+        assert_eq!(client.rpc_pwrite(fd, &data, 0).await.unwrap(), LEN as i32);
+
+        let reads = (0..DEPTH).map(|_| async {
+            let mut page = vec![0u8; LEN];
+            let res = client.rpc_pread(fd, &mut page, 0).await;
+            (res, page)
+        });
+        for (res, page) in futures::future::join_all(reads).await {
+            assert_eq!(res.unwrap(), LEN as i32);
+            assert!(page == data);
+        }
+        client.rpc_close(fd).await.unwrap();
+    });
+}
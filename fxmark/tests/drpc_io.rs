@@ -0,0 +1,168 @@
+//! Reads and writes larger than what a DRPC server takes in one request, vectored ones
+//! included, which clients split into requests it does take.
+
+mod common;
+
+use futures::FutureExt;
+use fxrpc::fxrpc::*;
+use libc::{O_CREAT, O_RDWR, S_IRWXU};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+// Largest read or write the server takes in one request
+const MAX_IO: usize = 4096;
+
+// Neither a multiple of MAX_IO nor of a page
+const LEN: usize = 10 * MAX_IO + 123;
+
+// DRPC server with a small limit, started by the first test that needs it
+fn config() -> &'static ClientConfig {
+    static SERVER: OnceLock<ClientConfig> = OnceLock::new();
+    SERVER.get_or_init(|| {
+        let addr = ServerAddr::Uds(common::socket_path("drpc-io"));
+        let config =
+            common::server_config(RPCType::DRPC, addr, BackendType::Memory, PathBuf::from("/"));
+        common::start_configured(ServerConfig {
+            max_io: MAX_IO,
+            ..config
+        })
+    })
+}
+
+fn data() -> Vec<u8> {
+    (0..LEN).map(|i| (i % 251) as u8).collect()
+}
+
+#[test]
+fn chunked_io() {
+    let mut client = init_client(config()).unwrap();
+    let fd = client
+        .rpc_open("chunked", O_CREAT | O_RDWR, S_IRWXU)
+        .unwrap();
+    let data = data();
+    assert_eq!(client.rpc_write(fd, &data).unwrap(), LEN as i32);
+    assert_eq!(
+        client.rpc_pwrite(fd, &data, LEN as i64).unwrap(),
+        LEN as i32
+    );
+
+    let mut page = vec![0u8; LEN];
+    assert_eq!(
+        client.rpc_pread(fd, &mut page, LEN as i64).unwrap(),
+        LEN as i32
+    );
+    assert!(page == data);
+    assert_eq!(client.rpc_read(fd, &mut page).unwrap(), LEN as i32);
+    assert!(page == data);
+
+    // Ends at the first short chunk, with what was read up to there
+    let mut page = vec![0u8; 3 * LEN];
+    assert_eq!(client.rpc_pread(fd, &mut page, 0).unwrap(), 2 * LEN as i32);
+    assert!(page[LEN..2 * LEN] == data[..]);
+    client.rpc_close(fd).unwrap();
+}
+
+#[test]
+fn pipelined_chunked_io() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let client = init_async_client(config(), 4).await.unwrap();
+        let fd = client
+            .rpc_open("pipelined", O_CREAT | O_RDWR, S_IRWXU)
+            .await
+            .unwrap();
+        let data = data();
+        assert_eq!(client.rpc_pwrite(fd, &data, 7).await.unwrap(), LEN as i32);
+
+        let mut page = vec![0u8; LEN];
+        assert_eq!(
+            client.rpc_pread(fd, &mut page, 7).await.unwrap(),
+            LEN as i32
+        );
+        assert!(page == data);
+        client.rpc_close(fd).await.unwrap();
+    });
+}
+
+#[test]
+fn pipelined_cancelled_io() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let client = init_async_client(config(), 2).await.unwrap();
+        let fd = client
+            .rpc_open("cancelled", O_CREAT | O_RDWR, S_IRWXU)
+            .await
+            .unwrap();
+        let data = data();
+        assert_eq!(client.rpc_pwrite(fd, &data, 0).await.unwrap(), LEN as i32);
+
+        // Requests given up on free their slots, and their responses do not end up in
+        // buffers that are gone or in later requests
+        for _ in 0..16 {
+            let mut page = vec![0u8; LEN];
+            let _ = client.rpc_pread(fd, &mut page, 0).now_or_never();
+            let _ = client.rpc_pwrite(fd, &data, 0).now_or_never();
+        }
+        let mut page = vec![0u8; LEN];
+        assert_eq!(
+            client.rpc_pread(fd, &mut page, 0).await.unwrap(),
+            LEN as i32
+        );
+        assert!(page == data);
+        client.rpc_close(fd).await.unwrap();
+    });
+}
+
+// A segment that fits a request and one that is cut into several, far apart in the file
+fn segments(data: &[u8]) -> [(i64, &[u8]); 2] {
+    [(0, &data[..MAX_IO]), (2 * LEN as i64, &data[MAX_IO..])]
+}
+
+#[test]
+fn vectored_io_is_split() {
+    let mut client = init_client(config()).unwrap();
+    let fd = client
+        .rpc_open("vectored", O_CREAT | O_RDWR, S_IRWXU)
+        .unwrap();
+    let data = data();
+    assert_eq!(
+        client.rpc_pwritev(fd, &segments(&data)).unwrap(),
+        LEN as i32
+    );
+
+    let mut page = vec![0u8; LEN];
+    let (first, second) = page.split_at_mut(MAX_IO);
+    let mut read: [(i64, &mut [u8]); 2] = [(0, first), (2 * LEN as i64, second)];
+    assert_eq!(client.rpc_preadv(fd, &mut read).unwrap(), LEN as i32);
+    assert!(page == data);
+
+    // Ends at the first short batch, with what was read up to there
+    let (first, second) = page.split_at_mut(MAX_IO);
+    let mut read: [(i64, &mut [u8]); 2] = [(0, first), (3 * LEN as i64, second)];
+    assert_eq!(client.rpc_preadv(fd, &mut read).unwrap(), MAX_IO as i32);
+    client.rpc_close(fd).unwrap();
+}
+
+#[test]
+fn pipelined_vectored_io_is_split() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let client = init_async_client(config(), 4).await.unwrap();
+        let fd = client
+            .rpc_open("pipelined-vectored", O_CREAT | O_RDWR, S_IRWXU)
+            .await
+            .unwrap();
+        let data = data();
+        assert_eq!(
+            client.rpc_pwritev(fd, &segments(&data)).await.unwrap(),
+            LEN as i32
+        );
+
+        let mut page = vec![0u8; LEN];
+        let (first, second) = page.split_at_mut(MAX_IO);
+        let mut read: [(i64, &mut [u8]); 2] = [(0, first), (2 * LEN as i64, second)];
+        assert_eq!(client.rpc_preadv(fd, &mut read).await.unwrap(), LEN as i32);
+        assert!(page == data);
+        client.rpc_close(fd).await.unwrap();
+    });
+}
@@ -0,0 +1,122 @@
+//! Faults a server injects into its backend, which clients have to see as they were
+//! injected: errors as the errno of the call, short reads and writes as short results.
+
+mod common;
+
+use fxrpc::fxrpc::*;
+use libc::{EINTR, EIO, ENOSPC, O_CREAT, O_RDWR, S_IRWXU};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+const LEN: usize = 100;
+
+// Every call of these operations fails or comes up short
+const FAULTS: &str = "
+seed: 7
+ops:
+  mkdir: { eio: 1 }
+  unlink: { eintr: 1 }
+  truncate: { enospc: 1 }
+  read: { short: 1 }
+  write: { short: 1 }
+";
+
+// Memory servers injecting `FAULTS`, one per protocol as a process runs at most one DRPC
+// server
+fn server(rpc_type: RPCType) -> &'static ClientConfig {
+    static GRPC: OnceLock<ClientConfig> = OnceLock::new();
+    static DRPC: OnceLock<ClientConfig> = OnceLock::new();
+    let server = match rpc_type {
+        RPCType::GRPC => &GRPC,
+        RPCType::DRPC => &DRPC,
+    };
+    server.get_or_init(|| {
+        let addr = ServerAddr::Uds(common::socket_path(&format!("faults-{}", rpc_type)));
+        let config = common::server_config(rpc_type, addr, BackendType::Memory, PathBuf::from("/"));
+        common::start_configured(ServerConfig {
+            faults: Some(FaultConfig::from_yaml(FAULTS).unwrap()),
+            ..config
+        })
+    })
+}
+
+// Lengths a transfer of `len` bytes is shortened to
+fn shortened(len: usize) -> RangeInclusive<i32> {
+    1..=len as i32 - 1
+}
+
+#[test]
+fn errors_reach_clients() {
+    for rpc_type in [RPCType::GRPC, RPCType::DRPC] {
+        let mut client = init_client(server(rpc_type)).unwrap();
+        let name = format!("errors-{}", rpc_type);
+        assert!(
+            matches!(
+                client.rpc_mkdir(&name, S_IRWXU),
+                Err(FxRpcError::Errno(EIO))
+            ),
+            "{} lost an injected EIO",
+            rpc_type
+        );
+
+        let fd = client.rpc_open(&name, O_CREAT | O_RDWR, S_IRWXU).unwrap();
+        client.rpc_close(fd).unwrap();
+        assert!(
+            matches!(
+                client.rpc_truncate(&name, 0),
+                Err(FxRpcError::Errno(ENOSPC))
+            ),
+            "{} lost an injected ENOSPC",
+            rpc_type
+        );
+        assert!(
+            matches!(client.rpc_unlink(&name), Err(FxRpcError::Errno(EINTR))),
+            "{} lost an injected EINTR",
+            rpc_type
+        );
+        // The failed calls never reached the backend
+        assert_eq!(client.rpc_stat(&name).unwrap().size, 0);
+    }
+}
+
+#[test]
+fn short_transfers_reach_clients() {
+    let data: Vec<u8> = (0..LEN).map(|i| i as u8).collect();
+    for rpc_type in [RPCType::GRPC, RPCType::DRPC] {
+        let mut client = init_client(server(rpc_type)).unwrap();
+        let name = format!("short-{}", rpc_type);
+        let fd = client.rpc_open(&name, O_CREAT | O_RDWR, S_IRWXU).unwrap();
+
+        let written = client.rpc_pwrite(fd, &data, 0).unwrap();
+        assert!(
+            shortened(LEN).contains(&written),
+            "{} wrote {} of {} bytes",
+            rpc_type,
+            written,
+            LEN
+        );
+        assert_eq!(client.rpc_fstat(fd).unwrap(), written as i64);
+
+        let mut page = vec![0u8; written as usize];
+        let read = client.rpc_pread(fd, &mut page, 0).unwrap();
+        if written > 1 {
+            assert!(shortened(written as usize).contains(&read));
+        } else {
+            assert_eq!(read, written);
+        }
+        assert_eq!(page[..read as usize], data[..read as usize]);
+
+        client.rpc_ftruncate(fd, LEN as i64).unwrap();
+        let (mut first, mut second) = (vec![0u8; LEN / 2], vec![0u8; LEN / 2]);
+        let mut segments = [(0, &mut first[..]), (LEN as i64 / 2, &mut second[..])];
+        let read = client.rpc_preadv(fd, &mut segments).unwrap();
+        assert!(shortened(LEN).contains(&read));
+        let half = &data[..LEN / 2];
+        let written = client
+            .rpc_pwritev(fd, &[(0, half), (LEN as i64 / 2, half)])
+            .unwrap();
+        assert!(shortened(LEN).contains(&written));
+        client.rpc_close(fd).unwrap();
+    }
+}
@@ -0,0 +1,184 @@
+//! Reads and writes larger than a gRPC message, which clients stream in chunks or, if
+//! they are vectored, split into several calls.
+
+mod common;
+
+use bytes::Bytes;
+use fxrpc::fxrpc::grpc::BlockingClient;
+use fxrpc::fxrpc::*;
+use libc::{EMSGSIZE, O_CREAT, O_RDWR, S_IRWXU};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+// Small enough that every read and write below has to be streamed
+const LIMITS: GrpcLimits = GrpcLimits {
+    max_message: 16 * 1024,
+    stream_chunk: 4096,
+};
+
+// Several messages' worth, and not a multiple of a chunk
+const LEN: usize = 5 * LIMITS.max_message + 123;
+
+// gRPC server with small messages, started by the first test that needs it
+fn config() -> &'static ClientConfig {
+    static SERVER: OnceLock<ClientConfig> = OnceLock::new();
+    SERVER.get_or_init(|| {
+        let addr = ServerAddr::Uds(common::socket_path("grpc-stream"));
+        let config =
+            common::server_config(RPCType::GRPC, addr, BackendType::Memory, PathBuf::from("/"));
+        common::start_configured(ServerConfig {
+            grpc_limits: LIMITS,
+            ..config
+        })
+    })
+}
+
+fn data() -> Vec<u8> {
+    (0..LEN).map(|i| (i % 251) as u8).collect()
+}
+
+#[test]
+fn streamed_io() {
+    let mut client = init_client(config()).unwrap();
+    let fd = client
+        .rpc_open("streamed", O_CREAT | O_RDWR, S_IRWXU)
+        .unwrap();
+    let data = data();
+    assert_eq!(client.rpc_write(fd, &data).unwrap(), LEN as i32);
+    assert_eq!(
+        client.rpc_pwrite(fd, &data, LEN as i64).unwrap(),
+        LEN as i32
+    );
+
+    let mut page = vec![0u8; LEN];
+    assert_eq!(
+        client.rpc_pread(fd, &mut page, LEN as i64).unwrap(),
+        LEN as i32
+    );
+    assert!(page == data);
+    assert_eq!(client.rpc_read(fd, &mut page).unwrap(), LEN as i32);
+    assert!(page == data);
+
+    // Ends at the end of the file, with what was read up to there
+    let mut page = vec![0u8; 3 * LEN];
+    assert_eq!(client.rpc_pread(fd, &mut page, 0).unwrap(), 2 * LEN as i32);
+    assert!(page[LEN..2 * LEN] == data[..]);
+    client.rpc_close(fd).unwrap();
+}
+
+#[test]
+fn async_streamed_io() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let client = init_async_client(config(), 4).await.unwrap();
+        let fd = client
+            .rpc_open("async-streamed", O_CREAT | O_RDWR, S_IRWXU)
+            .await
+            .unwrap();
+        let data = data();
+        assert_eq!(client.rpc_pwrite(fd, &data, 7).await.unwrap(), LEN as i32);
+
+        let mut page = vec![0u8; LEN];
+        assert_eq!(
+            client.rpc_pread(fd, &mut page, 7).await.unwrap(),
+            LEN as i32
+        );
+        assert!(page == data);
+        client.rpc_close(fd).await.unwrap();
+    });
+}
+
+// Buffers handed over whole, in a single message and streamed
+#[test]
+fn owned_buffers() {
+    let config = config();
+    let path = match &config.addr {
+        ServerAddr::Uds(path) => path.clone(),
+        ServerAddr::Tcp(_) => unreachable!(),
+    };
+    let mut client = BlockingClient::connect_uds(path, LIMITS).unwrap();
+    let fd = client.rpc_open("owned", O_CREAT | O_RDWR, S_IRWXU).unwrap();
+    let data = Bytes::from(data());
+
+    let small = data.slice(..LIMITS.stream_chunk);
+    assert_eq!(
+        client.write_bytes(fd, small.clone()).unwrap(),
+        small.len() as i32
+    );
+    assert_eq!(client.pread_bytes(fd, small.len(), 0).unwrap(), small);
+    assert_eq!(
+        client.pwrite_bytes(fd, data.clone(), 0).unwrap(),
+        LEN as i32
+    );
+    assert_eq!(client.pread_bytes(fd, 2 * LEN, 0).unwrap(), data);
+    assert_eq!(client.read_bytes(fd, LEN).unwrap().len(), LEN - small.len());
+    client.rpc_close(fd).unwrap();
+}
+
+#[test]
+fn vectored_io_is_split() {
+    let mut client = init_client(config()).unwrap();
+    let fd = client
+        .rpc_open("vectored", O_CREAT | O_RDWR, S_IRWXU)
+        .unwrap();
+    let data = data();
+    let chunk = LIMITS.stream_chunk;
+    let segments: [(i64, &[u8]); 2] = [(0, &data[..chunk]), (2 * LEN as i64, &data[chunk..])];
+    assert_eq!(client.rpc_pwritev(fd, &segments).unwrap(), LEN as i32);
+
+    let mut page = vec![0u8; LEN];
+    let (first, second) = page.split_at_mut(chunk);
+    let mut read: [(i64, &mut [u8]); 2] = [(0, first), (2 * LEN as i64, second)];
+    assert_eq!(client.rpc_preadv(fd, &mut read).unwrap(), LEN as i32);
+    assert!(page == data);
+    client.rpc_close(fd).unwrap();
+}
+
+#[test]
+fn streamed_io_on_a_closed_file_fails() {
+    let mut client = init_client(config()).unwrap();
+    let fd = client
+        .rpc_open("closed", O_CREAT | O_RDWR, S_IRWXU)
+        .unwrap();
+    client.rpc_close(fd).unwrap();
+    let data = data();
+    assert!(matches!(
+        client.rpc_pwrite(fd, &data, 0),
+        Err(FxRpcError::Errno(libc::EBADF))
+    ));
+    let mut page = vec![0u8; LEN];
+    assert!(matches!(
+        client.rpc_pread(fd, &mut page, 0),
+        Err(FxRpcError::Errno(libc::EBADF))
+    ));
+}
+
+// A client allowing larger messages than the server sends reads the server refuses before
+// allocating anything for them
+#[test]
+fn oversized_reads_are_refused() {
+    let config = ClientConfig {
+        grpc_limits: GrpcLimits {
+            max_message: 4 * LIMITS.max_message,
+            stream_chunk: 2 * LIMITS.max_message,
+        },
+        ..config().clone()
+    };
+    let mut client = init_client(&config).unwrap();
+    let fd = client
+        .rpc_open("oversized", O_CREAT | O_RDWR, S_IRWXU)
+        .unwrap();
+
+    let mut page = vec![0u8; 2 * LIMITS.max_message];
+    assert!(matches!(
+        client.rpc_pread(fd, &mut page, 0),
+        Err(FxRpcError::Errno(EMSGSIZE))
+    ));
+    let (first, second) = page.split_at_mut(LIMITS.max_message);
+    let mut segments: [(i64, &mut [u8]); 2] = [(0, first), (0, second)];
+    assert!(matches!(
+        client.rpc_preadv(fd, &mut segments),
+        Err(FxRpcError::Errno(EMSGSIZE))
+    ));
+    client.rpc_close(fd).unwrap();
+}
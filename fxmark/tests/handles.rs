@@ -0,0 +1,94 @@
+//! Handles are only good on the connection that opened them and until they are closed,
+//! and the servers close the ones a client leaves open when it goes away.
+
+mod common;
+
+use fxrpc::fxrpc::*;
+use libc::{EBADF, O_CREAT, O_RDWR, S_IRWXU};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+// Time a server gets to notice a client went away
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// POSIX servers, one per protocol as a process runs at most one DRPC server, each with a
+// root of its own
+fn server(rpc_type: RPCType) -> &'static (ClientConfig, PathBuf) {
+    static GRPC: OnceLock<(ClientConfig, PathBuf)> = OnceLock::new();
+    static DRPC: OnceLock<(ClientConfig, PathBuf)> = OnceLock::new();
+    let server = match rpc_type {
+        RPCType::GRPC => &GRPC,
+        RPCType::DRPC => &DRPC,
+    };
+    server.get_or_init(|| {
+        let name = format!("handles-{}", rpc_type);
+        let root = common::scratch_dir(&name).canonicalize().unwrap();
+        let addr = ServerAddr::Uds(common::socket_path(&name));
+        let config = common::start_server(rpc_type, addr, BackendType::Posix, root.clone());
+        (config, root)
+    })
+}
+
+#[test]
+fn foreign_and_closed_handles_are_refused() {
+    for rpc_type in [RPCType::GRPC, RPCType::DRPC] {
+        let (config, _) = server(rpc_type);
+        let mut owner = init_client(config).unwrap();
+        let mut other = init_client(config).unwrap();
+        let fd = owner
+            .rpc_open("foreign", O_CREAT | O_RDWR, S_IRWXU)
+            .unwrap();
+
+        assert!(
+            matches!(other.rpc_pwrite(fd, b"x", 0), Err(FxRpcError::Errno(EBADF))),
+            "{} let a foreign handle be written",
+            rpc_type
+        );
+        assert!(
+            matches!(other.rpc_close(fd), Err(FxRpcError::Errno(EBADF))),
+            "{} let a foreign handle be closed",
+            rpc_type
+        );
+        assert_eq!(owner.rpc_pwrite(fd, b"x", 0).unwrap(), 1);
+
+        owner.rpc_close(fd).unwrap();
+        assert!(
+            matches!(owner.rpc_pwrite(fd, b"x", 0), Err(FxRpcError::Errno(EBADF))),
+            "{} let a closed handle be written",
+            rpc_type
+        );
+        assert!(
+            matches!(owner.rpc_close(fd), Err(FxRpcError::Errno(EBADF))),
+            "{} let a handle be closed twice",
+            rpc_type
+        );
+    }
+}
+
+#[test]
+fn handles_left_open_are_closed_on_disconnect() {
+    for rpc_type in [RPCType::GRPC, RPCType::DRPC] {
+        let (config, root) = server(rpc_type);
+        let mut client = init_client(config).unwrap();
+        client.rpc_mkdir("left-open", S_IRWXU).unwrap();
+        for i in 0..3 {
+            let path = format!("left-open/{}", i);
+            client.rpc_open(&path, O_CREAT | O_RDWR, S_IRWXU).unwrap();
+        }
+        let dir = root.join("left-open");
+        assert_eq!(common::open_under(&dir), 3);
+
+        drop(client);
+        let deadline = Instant::now() + DISCONNECT_TIMEOUT;
+        while common::open_under(&dir) > 0 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            common::open_under(&dir),
+            0,
+            "{} kept the handles of a client that went away",
+            rpc_type
+        );
+    }
+}
@@ -0,0 +1,215 @@
+//! The in-memory backend on its own, without a server in front of it.
+
+use fxrpc::fxrpc::backend::MemoryBackend;
+use fxrpc::fxrpc::*;
+use libc::*;
+
+// Backend holding `path` with `data` in it
+fn with_file(path: &str, data: &[u8]) -> MemoryBackend {
+    let fs = MemoryBackend::new();
+    let fd = fs.open(path, O_CREAT | O_WRONLY, S_IRWXU).unwrap();
+    assert_eq!(fs.write(fd, data), Ok(data.len()));
+    fs.close(fd).unwrap();
+    fs
+}
+
+fn contents(fs: &MemoryBackend, path: &str) -> Vec<u8> {
+    let fd = fs.open(path, O_RDONLY, 0).unwrap();
+    let mut buf = vec![0u8; fs.fstat(fd).unwrap().size as usize];
+    assert_eq!(fs.pread(fd, &mut buf, 0), Ok(buf.len()));
+    fs.close(fd).unwrap();
+    buf
+}
+
+fn names(page: &DirPage) -> Vec<&str> {
+    page.entries.iter().map(|e| e.name.as_str()).collect()
+}
+
+#[test]
+fn read() {
+    let fs = with_file("file", b"0123456789");
+    let fd = fs.open("file", O_RDONLY, 0).unwrap();
+    let mut buf = [0u8; 4];
+    assert_eq!(fs.read(fd, &mut buf), Ok(4));
+    assert_eq!(&buf, b"0123");
+    assert_eq!(fs.read(fd, &mut buf), Ok(4));
+    assert_eq!(&buf, b"4567");
+    assert_eq!(fs.read(fd, &mut buf), Ok(2));
+    assert_eq!(&buf[..2], b"89");
+    assert_eq!(fs.read(fd, &mut buf), Ok(0));
+
+    // pread() leaves the offset alone
+    assert_eq!(fs.pread(fd, &mut buf, 3), Ok(4));
+    assert_eq!(&buf, b"3456");
+    assert_eq!(fs.pread(fd, &mut buf, 8), Ok(2));
+    assert_eq!(fs.pread(fd, &mut buf, 100), Ok(0));
+    assert_eq!(fs.pread(fd, &mut buf, -1), Err(EINVAL));
+    assert_eq!(fs.read(fd, &mut buf), Ok(0));
+    fs.close(fd).unwrap();
+
+    let fd = fs.open("file", O_WRONLY, 0).unwrap();
+    assert_eq!(fs.read(fd, &mut buf), Err(EBADF));
+    assert_eq!(fs.pread(fd, &mut buf, 0), Err(EBADF));
+    fs.close(fd).unwrap();
+    assert_eq!(fs.read(fd, &mut buf), Err(EBADF));
+
+    fs.mkdir("dir", S_IRWXU).unwrap();
+    let fd = fs.open("dir", O_RDONLY | O_DIRECTORY, 0).unwrap();
+    assert_eq!(fs.read(fd, &mut buf), Err(EISDIR));
+    fs.close(fd).unwrap();
+}
+
+#[test]
+fn write() {
+    let fs = MemoryBackend::new();
+    let fd = fs.open("file", O_CREAT | O_RDWR, S_IRWXU).unwrap();
+    assert_eq!(fs.write(fd, b"abc"), Ok(3));
+    assert_eq!(fs.write(fd, b"def"), Ok(3));
+    // Writing past the end fills the gap with zeros
+    assert_eq!(fs.pwrite(fd, b"xy", 8), Ok(2));
+    assert_eq!(fs.pwrite(fd, b"DE", 3), Ok(2));
+    assert_eq!(fs.pwrite(fd, b"z", -1), Err(EINVAL));
+    fs.close(fd).unwrap();
+    assert_eq!(contents(&fs, "file"), b"abcDEf\0\0xy");
+
+    // O_APPEND writes at the end whatever the offset
+    let fd = fs.open("file", O_WRONLY | O_APPEND, 0).unwrap();
+    assert_eq!(fs.pwrite(fd, b"!", 0), Ok(1));
+    assert_eq!(fs.write(fd, b"?"), Ok(1));
+    fs.close(fd).unwrap();
+    assert_eq!(contents(&fs, "file"), b"abcDEf\0\0xy!?");
+
+    let fd = fs.open("file", O_WRONLY | O_TRUNC, 0).unwrap();
+    assert_eq!(fs.fstat(fd).unwrap().size, 0);
+    fs.close(fd).unwrap();
+
+    let fd = fs.open("file", O_RDONLY, 0).unwrap();
+    assert_eq!(fs.write(fd, b"x"), Err(EBADF));
+    assert_eq!(fs.pwrite(fd, b"x", 0), Err(EBADF));
+    fs.close(fd).unwrap();
+    assert_eq!(fs.write(fd, b"x"), Err(EBADF));
+
+    // Files stop well short of the memory an offset could ask for
+    let fd = fs.open("file", O_WRONLY, 0).unwrap();
+    assert_eq!(fs.pwrite(fd, b"x", i64::MAX), Err(EFBIG));
+    fs.close(fd).unwrap();
+    assert_eq!(fs.stat("file", true).unwrap().size, 0);
+}
+
+#[test]
+fn truncate() {
+    let fs = with_file("file", b"0123456789");
+    fs.truncate("file", 4).unwrap();
+    assert_eq!(contents(&fs, "file"), b"0123");
+    fs.truncate("file", 6).unwrap();
+    assert_eq!(contents(&fs, "file"), b"0123\0\0");
+    assert_eq!(fs.truncate("file", -1), Err(EINVAL));
+    assert_eq!(fs.truncate("file", i64::MAX), Err(EFBIG));
+    assert_eq!(fs.truncate("missing", 0), Err(ENOENT));
+
+    let fd = fs.open("file", O_RDWR, 0).unwrap();
+    fs.ftruncate(fd, 2).unwrap();
+    assert_eq!(fs.fstat(fd).unwrap().size, 2);
+    fs.close(fd).unwrap();
+    let fd = fs.open("file", O_RDONLY, 0).unwrap();
+    assert_eq!(fs.ftruncate(fd, 0), Err(EINVAL));
+    fs.close(fd).unwrap();
+    assert_eq!(contents(&fs, "file"), b"01");
+
+    fs.mkdir("dir", S_IRWXU).unwrap();
+    assert_eq!(fs.truncate("dir", 0), Err(EISDIR));
+}
+
+#[test]
+fn rename() {
+    let fs = with_file("a", b"a");
+    fs.mkdir("dir", S_IRWXU).unwrap();
+    fs.rename("a", "dir/b", 0).unwrap();
+    assert_eq!(fs.stat("a", true), Err(ENOENT));
+    assert_eq!(contents(&fs, "dir/b"), b"a");
+    assert_eq!(fs.rename("a", "c", 0), Err(ENOENT));
+
+    // Replacing a file drops the one replaced
+    let fd = fs.open("c", O_CREAT | O_WRONLY, S_IRWXU).unwrap();
+    assert_eq!(fs.write(fd, b"c"), Ok(1));
+    fs.close(fd).unwrap();
+    assert_eq!(fs.rename("dir/b", "c", RENAME_NOREPLACE), Err(EEXIST));
+    fs.rename("dir/b", "c", 0).unwrap();
+    assert_eq!(contents(&fs, "c"), b"a");
+    assert_eq!(fs.stat("dir/b", true), Err(ENOENT));
+
+    let fd = fs.open("d", O_CREAT | O_WRONLY, S_IRWXU).unwrap();
+    assert_eq!(fs.write(fd, b"d"), Ok(1));
+    fs.close(fd).unwrap();
+    fs.rename("c", "d", RENAME_EXCHANGE).unwrap();
+    assert_eq!(contents(&fs, "c"), b"d");
+    assert_eq!(contents(&fs, "d"), b"a");
+    assert_eq!(fs.rename("c", "e", RENAME_EXCHANGE), Err(ENOENT));
+    let both = RENAME_EXCHANGE | RENAME_NOREPLACE;
+    assert_eq!(fs.rename("c", "d", both), Err(EINVAL));
+
+    // Directories move with their contents and links to their parents
+    fs.mkdir("dir/sub", S_IRWXU).unwrap();
+    fs.rename("dir/sub", "sub", 0).unwrap();
+    assert_eq!(fs.stat("dir", true).unwrap().nlink, 2);
+    assert_eq!(fs.stat(".", true).unwrap().nlink, 4);
+    assert_eq!(fs.rename("dir", "dir/inner", 0), Err(EINVAL));
+    assert_eq!(fs.rename("dir", "c", 0), Err(ENOTDIR));
+    assert_eq!(fs.rename("c", "dir", 0), Err(EISDIR));
+    fs.rename("c", "sub/c", 0).unwrap();
+    assert_eq!(fs.rename("dir", "sub", 0), Err(ENOTEMPTY));
+    fs.rename("sub", "dir", 0).unwrap();
+    assert_eq!(contents(&fs, "dir/c"), b"d");
+    assert_eq!(fs.rename(".", "x", 0), Err(EBUSY));
+}
+
+#[test]
+fn readdir() {
+    let fs = MemoryBackend::new();
+    for name in ["c", "a", "b", "e"] {
+        let fd = fs.open(name, O_CREAT | O_WRONLY, S_IRWXU).unwrap();
+        fs.close(fd).unwrap();
+    }
+    fs.mkdir("d", S_IRWXU).unwrap();
+
+    // Entries come sorted by name, a page at a time
+    let page = fs.readdir(".", 0, 2).unwrap();
+    assert_eq!(names(&page), ["a", "b"]);
+    assert!(!page.eof);
+    let page = fs.readdir(".", page.cookie, 2).unwrap();
+    assert_eq!(names(&page), ["c", "d"]);
+    assert_eq!(page.entries[1].file_type, DT_DIR);
+    assert_eq!(page.entries[0].file_type, DT_REG);
+    assert_eq!(page.entries[1].ino, fs.stat("d", true).unwrap().ino);
+    let page = fs.readdir(".", page.cookie, 2).unwrap();
+    assert_eq!(names(&page), ["e"]);
+    assert!(page.eof);
+
+    // Listing resumes after the entry a cookie came with
+    let page = fs.readdir(".", 0, 16).unwrap();
+    assert_eq!(names(&page), ["a", "b", "c", "d", "e"]);
+    assert!(page.eof);
+    let resumed = fs.readdir(".", page.entries[2].cookie, 16).unwrap();
+    assert_eq!(names(&resumed), ["d", "e"]);
+    let past = fs.readdir(".", 100, 16).unwrap();
+    assert!(past.entries.is_empty() && past.eof);
+
+    assert!(fs.readdir("d", 0, 16).unwrap().entries.is_empty());
+    assert_eq!(fs.readdir("a", 0, 16), Err(ENOTDIR));
+    assert_eq!(fs.readdir("missing", 0, 16), Err(ENOENT));
+    assert_eq!(fs.readdir(".", 0, 0), Err(EINVAL));
+}
+
+#[test]
+fn unlinked_files_live_on_while_open() {
+    let fs = with_file("file", b"data");
+    let fd = fs.open("file", O_RDWR, 0).unwrap();
+    fs.unlink("file").unwrap();
+    assert_eq!(fs.stat("file", true), Err(ENOENT));
+    assert_eq!(fs.pwrite(fd, b"more", 4), Ok(4));
+    let mut buf = [0u8; 8];
+    assert_eq!(fs.pread(fd, &mut buf, 0), Ok(8));
+    assert_eq!(&buf, b"datamore");
+    assert_eq!(fs.fstat(fd).unwrap().nlink, 0);
+    fs.close(fd).unwrap();
+}
@@ -0,0 +1,128 @@
+//! Server metrics, as clients read them with `rpc_stats` and as `metrics::dump` writes
+//! them out. Metrics are kept per process, so the tests take turns.
+
+mod common;
+
+use fxrpc::fxrpc::metrics::{self, RPC_OPS};
+use fxrpc::fxrpc::*;
+use libc::{O_CREAT, O_RDWR, S_IRWXU};
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+// Held by a test while it calls RPCs and reads metrics
+static SERIAL: Mutex<()> = Mutex::new(());
+
+// Memory servers, one per protocol as a process runs at most one DRPC server
+fn server(rpc_type: RPCType) -> &'static ClientConfig {
+    static GRPC: OnceLock<ClientConfig> = OnceLock::new();
+    static DRPC: OnceLock<ClientConfig> = OnceLock::new();
+    let server = match rpc_type {
+        RPCType::GRPC => &GRPC,
+        RPCType::DRPC => &DRPC,
+    };
+    server.get_or_init(|| {
+        let addr = ServerAddr::Uds(common::socket_path(&format!("metrics-{}", rpc_type)));
+        common::start_server(rpc_type, addr, BackendType::Memory, PathBuf::from("/"))
+    })
+}
+
+fn op_stats(stats: &ServerStats, op: RpcOp) -> &OpStats {
+    stats.ops.iter().find(|stats| stats.op == op).unwrap()
+}
+
+#[test]
+fn stats_count_calls() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    for rpc_type in [RPCType::GRPC, RPCType::DRPC] {
+        let mut client = init_client(server(rpc_type)).unwrap();
+        let before = client.rpc_stats().unwrap();
+
+        client.rpc_mkdir("counted", S_IRWXU).unwrap();
+        assert!(client.rpc_mkdir("counted", S_IRWXU).is_err());
+        let fd = client
+            .rpc_open("counted/file", O_CREAT | O_RDWR, S_IRWXU)
+            .unwrap();
+        assert_eq!(client.rpc_pwrite(fd, b"0123456789", 0).unwrap(), 10);
+        let mut page = [0u8; 16];
+        assert_eq!(client.rpc_pread(fd, &mut page, 4).unwrap(), 6);
+        client.rpc_close(fd).unwrap();
+        client.rpc_remove("counted/file").unwrap();
+        client.rpc_rmdir("counted").unwrap();
+
+        let after = client.rpc_stats().unwrap();
+        assert!(after.uptime >= before.uptime);
+        for op in RPC_OPS {
+            let (before, after) = (op_stats(&before, op), op_stats(&after, op));
+            let (count, errors, bytes) = match op {
+                RpcOp::Mkdir => (2, 1, 0),
+                RpcOp::PWrite => (1, 0, 10),
+                RpcOp::PRead => (1, 0, 6),
+                RpcOp::Open | RpcOp::Close | RpcOp::Remove | RpcOp::Rmdir => (1, 0, 0),
+                _ => (0, 0, 0),
+            };
+            assert_eq!(
+                (
+                    after.count - before.count,
+                    after.errors - before.errors,
+                    after.bytes - before.bytes
+                ),
+                (count, errors, bytes),
+                "{} counted {} calls wrong",
+                rpc_type,
+                op
+            );
+            assert_eq!(after.in_flight, 0);
+            let calls: u64 = after.latency.iter().sum();
+            assert_eq!(calls, after.count);
+        }
+    }
+}
+
+#[test]
+fn dump_writes_a_row_per_rpc() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let mut client = init_client(server(RPCType::GRPC)).unwrap();
+    client.rpc_mkdir("dumped", S_IRWXU).unwrap();
+    let stats = client.rpc_stats().unwrap();
+
+    let path = common::scratch_dir("metrics").join("metrics.csv");
+    metrics::dump(&path).unwrap();
+    let csv = std::fs::read_to_string(&path).unwrap();
+    let mut lines = csv.lines();
+    assert_eq!(
+        lines.next(),
+        Some("uptime_ns,op,count,errors,bytes,in_flight,p50_ns,p90_ns,p99_ns,p999_ns")
+    );
+    let rows: Vec<Vec<&str>> = lines.map(|line| line.split(',').collect()).collect();
+    assert_eq!(rows.len(), RPC_OPS.len());
+
+    for (row, op) in rows.iter().zip(RPC_OPS) {
+        assert_eq!(row.len(), 10);
+        assert_eq!(row[1], op.to_string());
+        let numbers: Vec<u128> = row
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != 1)
+            .map(|(_, field)| field.parse().unwrap())
+            .collect();
+        // Uptime is the same on every row and runs on from what the RPC returned
+        assert_eq!(numbers[0], rows[0][0].parse::<u128>().unwrap());
+        assert!(numbers[0] >= stats.uptime.as_nanos());
+
+        let stats = op_stats(&stats, op);
+        let (count, errors, bytes) = (numbers[1], numbers[2], numbers[3]);
+        assert_eq!(
+            (count, errors, bytes),
+            (
+                stats.count as u128,
+                stats.errors as u128,
+                stats.bytes as u128
+            )
+        );
+        assert_eq!(numbers[4], 0);
+        // Percentiles are 0 without calls and never decrease
+        let percentiles = &numbers[5..];
+        assert!(percentiles.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(count == 0, percentiles[0] == 0);
+    }
+}
@@ -0,0 +1,27 @@
+//! The POSIX backend on its own, without a server in front of it.
+
+mod common;
+
+use fxrpc::fxrpc::backend::PosixBackend;
+use fxrpc::fxrpc::*;
+use libc::{EILSEQ, EINVAL};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+
+fn names(page: &DirPage) -> Vec<&str> {
+    page.entries.iter().map(|e| e.name.as_str()).collect()
+}
+
+#[test]
+fn readdir_refuses_pages_it_cannot_list() {
+    let dir = common::scratch_dir("posix-readdir");
+    std::fs::write(dir.join("file"), b"").unwrap();
+    let fs = PosixBackend::new(&dir).unwrap();
+
+    assert_eq!(fs.readdir(".", 0, 0), Err(EINVAL));
+    assert_eq!(names(&fs.readdir(".", 0, 16).unwrap()), ["file"]);
+
+    // Names go over the wire as strings, which this one is not
+    std::fs::write(dir.join(OsStr::from_bytes(b"latin1-\xe9")), b"").unwrap();
+    assert_eq!(fs.readdir(".", 0, 16), Err(EILSEQ));
+}
@@ -0,0 +1,163 @@
+//! Paths a POSIX server resolves below its root, none of which may reach a file outside
+//! of it: `..`, absolute paths, embedded NUL bytes and symlinks placed in the root.
+
+mod common;
+
+use fxrpc::fxrpc::*;
+use libc::{EACCES, EINVAL, ELOOP, ENOTDIR, O_CREAT, O_RDONLY, O_RDWR, S_IFLNK, S_IFMT, S_IRWXU};
+use std::ffi::CString;
+use std::fmt::Debug;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+const SECRET: &str = "outside the root\n";
+
+// Scratch directory holding the exported root next to a file and a directory outside of
+// it, which symlinks in the root point to
+fn base() -> &'static Path {
+    static BASE: OnceLock<PathBuf> = OnceLock::new();
+    BASE.get_or_init(|| {
+        let base = common::scratch_dir("sandbox").canonicalize().unwrap();
+        let root = base.join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(base.join("outside.txt"), SECRET).unwrap();
+        std::fs::create_dir(base.join("outside-dir")).unwrap();
+        std::fs::write(root.join("inside.txt"), "inside the root\n").unwrap();
+        std::fs::create_dir(root.join("sub")).unwrap();
+        symlink("../outside.txt", root.join("file-link")).unwrap();
+        symlink(base.join("outside.txt"), root.join("abs-link")).unwrap();
+        symlink(base.join("outside-dir"), root.join("dir-link")).unwrap();
+        base
+    })
+}
+
+// gRPC server over UDS exporting the root, started by the first test that needs it
+fn client() -> Box<dyn FxRPC> {
+    static SERVER: OnceLock<ClientConfig> = OnceLock::new();
+    let config = SERVER.get_or_init(|| {
+        let addr = ServerAddr::Uds(common::socket_path("sandbox"));
+        common::start_server(RPCType::GRPC, addr, BackendType::Posix, base().join("root"))
+    });
+    init_client(config).unwrap()
+}
+
+fn errno<T: Debug>(res: Result<T, FxRpcError>) -> i32 {
+    match res {
+        Err(FxRpcError::Errno(errno)) => errno,
+        res => panic!("Expected an errno, got {:?}", res),
+    }
+}
+
+// Nothing outside the root changed
+fn assert_untouched() {
+    let base = base();
+    assert_eq!(
+        std::fs::read_to_string(base.join("outside.txt")).unwrap(),
+        SECRET
+    );
+    assert_eq!(
+        std::fs::read_dir(base.join("outside-dir")).unwrap().count(),
+        0
+    );
+    assert!(!base.join("escaped").exists());
+}
+
+#[test]
+fn parent_components_are_refused() {
+    let mut client = client();
+    assert_eq!(errno(client.rpc_open("../outside.txt", O_RDWR, 0)), EACCES);
+    assert_eq!(
+        errno(client.rpc_open("sub/../../outside.txt", O_RDWR, 0)),
+        EACCES
+    );
+    assert_eq!(
+        errno(client.rpc_open("sub/../inside.txt", O_RDWR, 0)),
+        EACCES
+    );
+    assert_eq!(errno(client.rpc_stat("..")), EACCES);
+    assert_eq!(errno(client.rpc_mkdir("../escaped", S_IRWXU)), EACCES);
+    assert_eq!(errno(client.rpc_truncate("../outside.txt", 0)), EACCES);
+    assert_eq!(errno(client.rpc_unlink("../outside.txt")), EACCES);
+    assert_eq!(
+        errno(client.rpc_rename("inside.txt", "../escaped", 0)),
+        EACCES
+    );
+    assert_eq!(errno(client.rpc_link("../outside.txt", "stolen")), EACCES);
+    assert_eq!(errno(client.rpc_readdir("..", 0, 16)), EACCES);
+    assert_untouched();
+}
+
+#[test]
+fn absolute_paths_are_refused() {
+    let mut client = client();
+    let outside = base().join("outside.txt");
+    let outside = outside.to_str().unwrap();
+    assert_eq!(errno(client.rpc_open(outside, O_RDWR, 0)), EACCES);
+    assert_eq!(errno(client.rpc_stat("/")), EACCES);
+    assert_eq!(errno(client.rpc_truncate(outside, 0)), EACCES);
+    assert_eq!(errno(client.rpc_link(outside, "stolen")), EACCES);
+    assert_eq!(errno(client.rpc_readdir("/", 0, 16)), EACCES);
+    assert_untouched();
+}
+
+#[test]
+fn embedded_nul_is_refused() {
+    let mut client = client();
+    assert_eq!(
+        errno(client.rpc_open("inside.txt\0/../../outside.txt", O_RDWR, 0)),
+        EINVAL
+    );
+    assert_eq!(errno(client.rpc_mkdir("sub\0dir", S_IRWXU)), EINVAL);
+    assert_eq!(errno(client.rpc_stat("inside.txt\0")), EINVAL);
+    assert_untouched();
+}
+
+#[test]
+fn symlinks_out_of_the_root_are_not_followed() {
+    let mut client = client();
+    let through_link = |errno: i32| errno == ELOOP || errno == ENOTDIR;
+
+    for link in ["file-link", "abs-link"] {
+        assert_eq!(errno(client.rpc_open(link, O_RDWR, 0)), ELOOP);
+        assert_eq!(
+            errno(client.rpc_open(link, O_RDWR | O_CREAT, S_IRWXU)),
+            ELOOP
+        );
+        assert_eq!(errno(client.rpc_truncate(link, 0)), ELOOP);
+        assert_eq!(errno(client.rpc_stat(link)), ELOOP);
+        let st = client.rpc_lstat(link).unwrap();
+        assert_eq!(st.mode & S_IFMT, S_IFLNK);
+    }
+
+    let res = client.rpc_open("dir-link/new.txt", O_RDWR | O_CREAT, S_IRWXU);
+    assert!(through_link(errno(res)));
+    assert!(through_link(errno(
+        client.rpc_mkdir("dir-link/new", S_IRWXU)
+    )));
+    assert!(through_link(errno(client.rpc_stat("dir-link/new.txt"))));
+    assert!(through_link(errno(client.rpc_readdir("dir-link", 0, 16))));
+    let res = client.rpc_rename("inside.txt", "dir-link/inside.txt", 0);
+    assert!(through_link(errno(res)));
+    assert!(through_link(errno(
+        client.rpc_open("dir-link", O_RDONLY, 0)
+    )));
+    assert_untouched();
+
+    // Removing a symlink removes the link, never what it points to
+    client.rpc_unlink("abs-link").unwrap();
+    assert_untouched();
+}
+
+#[test]
+fn truncate_refuses_fifos() {
+    let fifo = base().join("root").join("fifo");
+    let c_path = CString::new(fifo.as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+
+    // Opening it to write would wait for a reader that never comes
+    let mut client = client();
+    assert_eq!(errno(client.rpc_truncate("fifo", 0)), EINVAL);
+    assert_eq!(errno(client.rpc_truncate("sub", 0)), libc::EISDIR);
+}
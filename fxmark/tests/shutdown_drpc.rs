@@ -0,0 +1,10 @@
+//! The Shutdown RPC of a DRPC server, which stops every server of the process.
+
+mod common;
+
+use fxrpc::fxrpc::RPCType;
+
+#[test]
+fn shutdown_with_token() {
+    common::shutdown_test(RPCType::DRPC, "drpc-shutdown-token");
+}
@@ -0,0 +1,10 @@
+//! The Shutdown RPC of a gRPC server, which stops every server of the process.
+
+mod common;
+
+use fxrpc::fxrpc::RPCType;
+
+#[test]
+fn shutdown_with_token() {
+    common::shutdown_test(RPCType::GRPC, "grpc-shutdown-token");
+}
@@ -0,0 +1,175 @@
+//! gRPC and DRPC over TCP secured with certificates from `tls::generate`, with and
+//! without client certificates.
+
+mod common;
+
+use fxrpc::fxrpc::grpc::BlockingClient;
+use fxrpc::fxrpc::*;
+use libc::{O_CREAT, O_RDWR, S_IRWXU};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+// Throwaway certificates shared by the tests
+fn certs() -> &'static Path {
+    static DIR: OnceLock<PathBuf> = OnceLock::new();
+    DIR.get_or_init(|| {
+        let dir = common::scratch_dir("tls");
+        tls::generate(&dir, &[]).expect("Failed to generate certificates");
+        dir
+    })
+}
+
+fn tls_config(cert: Option<&str>, key: Option<&str>, ca: Option<&str>) -> TlsConfig {
+    TlsConfig {
+        cert: cert.map(|cert| certs().join(cert)),
+        key: key.map(|key| certs().join(key)),
+        ca: ca.map(|ca| certs().join(ca)),
+        server_name: String::from(tls::DEFAULT_SERVER_NAME),
+    }
+}
+
+// Client side of mutual TLS
+fn client_tls() -> TlsConfig {
+    tls_config(
+        Some(tls::CLIENT_CERT),
+        Some(tls::CLIENT_KEY),
+        Some(tls::CA_CERT),
+    )
+}
+
+// Memory server on a free loopback port, requiring client certificates if `mutual`
+fn start(rpc_type: RPCType, mutual: bool) -> ClientConfig {
+    let addr = ServerAddr::Tcp(format!("127.0.0.1:{}", common::free_port()));
+    let ca = if mutual { Some(tls::CA_CERT) } else { None };
+    let config = common::server_config(rpc_type, addr, BackendType::Memory, PathBuf::from("/"));
+    common::start_configured(ServerConfig {
+        tls: Some(tls_config(
+            Some(tls::SERVER_CERT),
+            Some(tls::SERVER_KEY),
+            ca,
+        )),
+        ..config
+    })
+}
+
+// Servers requiring client certificates, one per protocol as a process runs at most one
+// DRPC server
+fn mutual_server(rpc_type: RPCType) -> &'static ClientConfig {
+    static GRPC: OnceLock<ClientConfig> = OnceLock::new();
+    static DRPC: OnceLock<ClientConfig> = OnceLock::new();
+    let server = match rpc_type {
+        RPCType::GRPC => &GRPC,
+        RPCType::DRPC => &DRPC,
+    };
+    server.get_or_init(|| start(rpc_type, true))
+}
+
+fn roundtrip(client: &mut dyn FxRPC) {
+    let fd = client.rpc_open("tls", O_CREAT | O_RDWR, S_IRWXU).unwrap();
+    assert_eq!(client.rpc_pwrite(fd, b"over TLS", 0).unwrap(), 8);
+    let mut page = [0u8; 8];
+    assert_eq!(client.rpc_pread(fd, &mut page, 0).unwrap(), 8);
+    assert_eq!(&page, b"over TLS");
+    client.rpc_close(fd).unwrap();
+}
+
+#[test]
+fn server_authenticated() {
+    let config = ClientConfig {
+        tls: Some(tls_config(None, None, Some(tls::CA_CERT))),
+        ..start(RPCType::GRPC, false)
+    };
+    roundtrip(&mut *init_client(&config).unwrap());
+}
+
+#[test]
+fn mutually_authenticated() {
+    for rpc_type in [RPCType::GRPC, RPCType::DRPC] {
+        let config = ClientConfig {
+            tls: Some(client_tls()),
+            ..mutual_server(rpc_type).clone()
+        };
+        roundtrip(&mut *init_client(&config).unwrap());
+    }
+}
+
+// Requests and responses in flight at once, and larger than a TLS record
+#[test]
+fn drpc_pipelined() {
+    let config = ClientConfig {
+        tls: Some(client_tls()),
+        ..mutual_server(RPCType::DRPC).clone()
+    };
+    let data: Vec<u8> = (0..1024 * 1024 + 3).map(|i| (i % 251) as u8).collect();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let client = init_async_client(&config, 4).await.unwrap();
+        let fd = client
+            .rpc_open("pipelined", O_CREAT | O_RDWR, S_IRWXU)
+            .await
+            .unwrap();
+        let (first, second) = data.split_at(data.len() / 2);
+        let (a, b) = tokio::join!(
+            client.rpc_pwrite(fd, first, 0),
+            client.rpc_pwrite(fd, second, first.len() as i64)
+        );
+        assert_eq!(a.unwrap() + b.unwrap(), data.len() as i32);
+
+        let mut page = vec![0u8; data.len()];
+        assert_eq!(
+            client.rpc_pread(fd, &mut page, 0).await.unwrap(),
+            data.len() as i32
+        );
+        assert!(page == data);
+        client.rpc_close(fd).await.unwrap();
+    });
+}
+
+#[test]
+fn client_without_certificate_is_refused() {
+    let ServerAddr::Tcp(addr) = &mutual_server(RPCType::GRPC).addr else {
+        unreachable!()
+    };
+    let tls = tls_config(None, None, Some(tls::CA_CERT));
+    let refused = BlockingClient::connect_tls(addr, &tls, GrpcLimits::default())
+        .and_then(|mut client| client.rpc_mkdir("refused", S_IRWXU));
+    assert!(matches!(refused, Err(FxRpcError::Transport(_))));
+
+    // DRPC clients fail to connect
+    let config = ClientConfig {
+        tls: Some(tls),
+        ..mutual_server(RPCType::DRPC).clone()
+    };
+    assert!(init_client(&config).is_err());
+}
+
+#[test]
+fn plaintext_client_is_refused() {
+    let ServerAddr::Tcp(addr) = &mutual_server(RPCType::GRPC).addr else {
+        unreachable!()
+    };
+    let refused = BlockingClient::connect_tcp(format!("http://{}", addr), GrpcLimits::default())
+        .map_err(FxRpcError::from)
+        .and_then(|mut client| client.rpc_mkdir("refused", S_IRWXU));
+    assert!(refused.is_err());
+
+    let config = mutual_server(RPCType::DRPC);
+    assert!(init_client(config).is_err());
+}
+
+// Refused up front, before the server takes the process' DRPC backend or the port
+#[test]
+fn drpc_worker_pool_is_refused() {
+    let addr = ServerAddr::Tcp(format!("127.0.0.1:{}", common::free_port()));
+    let config =
+        common::server_config(RPCType::DRPC, addr, BackendType::Memory, PathBuf::from("/"));
+    run_server(&ServerConfig {
+        pool: Some(WorkerPool::new(1, None).unwrap()),
+        tls: Some(tls_config(
+            Some(tls::SERVER_CERT),
+            Some(tls::SERVER_KEY),
+            None,
+        )),
+        ..config
+    });
+}
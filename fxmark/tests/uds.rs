@@ -1,25 +1,38 @@
-use fxmark_grpc::*;
+mod common;
+
+use fxrpc::fxrpc::*;
 use libc::{O_CREAT, O_RDWR, S_IRWXU};
+use std::path::PathBuf;
+use std::sync::OnceLock;
 
 const PAGE_SIZE: usize = 1024;
 
+// gRPC server over UDS, started by the first test that needs it
+fn client() -> Box<dyn FxRPC> {
+    static SERVER: OnceLock<ClientConfig> = OnceLock::new();
+    let config = SERVER.get_or_init(|| {
+        let root = common::read_test_root("uds");
+        let addr = ServerAddr::Uds(common::socket_path("uds"));
+        common::start_server(RPCType::GRPC, addr, BackendType::Posix, root)
+    });
+    init_client(config).unwrap()
+}
+
 fn read_test_base(pread: bool) -> Result<(), Box<dyn std::error::Error>> {
-    let mut client = BlockingClient::connect_uds()?;
+    let mut client = client();
 
     let test = if pread { "pReadTest" } else { "ReadTest" };
 
     let filename = "read_test.txt";
-    let fd = client
-        .grpc_open(filename, O_CREAT | O_RDWR, S_IRWXU)
-        .unwrap();
+    let fd = client.rpc_open(filename, O_CREAT | O_RDWR, S_IRWXU)?;
     assert!(fd != -1, "{}: Open Failed", test);
 
     // let page: &mut [u8; PAGE_SIZE] = &mut [0; PAGE_SIZE];
     let mut page: Vec<u8> = vec![0; PAGE_SIZE];
     let result = if pread {
-        client.grpc_pread(fd, &mut page, PAGE_SIZE, 0).unwrap()
+        client.rpc_pread(fd, &mut page, 0)?
     } else {
-        client.grpc_read(fd, &mut page, PAGE_SIZE).unwrap()
+        client.rpc_read(fd, &mut page)?
     };
     assert!(result != -1, "{}: Read Failed", test);
 
@@ -32,10 +45,10 @@ fn read_test_base(pread: bool) -> Result<(), Box<dyn std::error::Error>> {
         page_str
     );
 
-    let result = client.grpc_fsync(fd).unwrap();
+    let result = client.rpc_fsync(fd)?;
     assert!(result != -1, "{}: Fsync Failed", test);
 
-    let result = client.grpc_close(fd).unwrap();
+    let result = client.rpc_close(fd)?;
     assert!(result != -1, "{}: Close Failed", test);
 
     Ok(())
@@ -52,35 +65,31 @@ fn pread_test() -> Result<(), Box<dyn std::error::Error>> {
 }
 
 fn write_test_base(pwrite: bool) -> Result<(), Box<dyn std::error::Error>> {
-    let mut client = BlockingClient::connect_uds()?;
+    let mut client = client();
 
     let test = if pwrite { "pWriteTest" } else { "WriteTest" };
 
     let filename = format!("{}{}", test, ".txt");
-    let fd = client
-        .grpc_open(&filename, O_CREAT | O_RDWR, S_IRWXU)
-        .unwrap();
+    let fd = client.rpc_open(&filename, O_CREAT | O_RDWR, S_IRWXU)?;
     assert!(fd != -1, "{}: Open Failed", test);
 
     let page = "WriteTest".as_bytes();
     let result = if pwrite {
-        client
-            .grpc_pwrite(fd, &page.to_vec(), page.len(), 0)
-            .unwrap()
+        client.rpc_pwrite(fd, page, 0)?
     } else {
-        client.grpc_write(fd, &page.to_vec(), page.len()).unwrap()
+        client.rpc_write(fd, page)?
     };
 
     // Length of test in files/read_test.txt
     assert!(result != -1, "{}: Write Failed", test);
 
-    let result = client.grpc_fsync(fd).unwrap();
+    let result = client.rpc_fsync(fd)?;
     assert!(result != -1, "{}: Fsync Failed", test);
 
-    let result = client.grpc_close(fd).unwrap();
+    let result = client.rpc_close(fd)?;
     assert!(result != -1, "{}: Close Failed", test);
 
-    let result = client.grpc_remove(&filename).unwrap();
+    let result = client.rpc_remove(&filename)?;
     assert!(result != -1, "{}: Remove Failed", test);
 
     Ok(())
@@ -98,20 +107,18 @@ fn pwrite_test() -> Result<(), Box<dyn std::error::Error>> {
 
 #[test]
 fn write_read_test() -> Result<(), Box<dyn std::error::Error>> {
-    let mut client = BlockingClient::connect_uds()?;
+    let mut client = client();
 
     let filename = "write_read_test.txt";
-    let fd = client
-        .grpc_open(filename, O_CREAT | O_RDWR, S_IRWXU)
-        .unwrap();
+    let fd = client.rpc_open(filename, O_CREAT | O_RDWR, S_IRWXU)?;
     assert!(fd != -1, "WriteReadTest: Open Failed");
 
     let page = "WriteReadTest".as_bytes();
-    let result = client.grpc_write(fd, &page.to_vec(), page.len()).unwrap();
+    let result = client.rpc_write(fd, page)?;
     assert!(result != -1, "WriteReadTest: Write Failed");
 
     let mut page: Vec<u8> = vec![0; PAGE_SIZE];
-    let result = client.grpc_pread(fd, &mut page, PAGE_SIZE, 0).unwrap();
+    let result = client.rpc_pread(fd, &mut page, 0)?;
     assert!(result != -1, "WriteReadTest: Read Failed");
 
     let binding = String::from_utf8(page).unwrap();
@@ -122,13 +129,13 @@ fn write_read_test() -> Result<(), Box<dyn std::error::Error>> {
         page_str
     );
 
-    let result = client.grpc_fsync(fd).unwrap();
+    let result = client.rpc_fsync(fd)?;
     assert!(result != -1, "WriteReadTest: Fsync Failed");
 
-    let result = client.grpc_close(fd).unwrap();
+    let result = client.rpc_close(fd)?;
     assert!(result != -1, "WriteReadTest: Close Failed");
 
-    let result = client.grpc_remove(filename).unwrap();
+    let result = client.rpc_remove(filename)?;
     assert!(result != -1, "WriteReadTest: Remove Failed");
 
     Ok(())
@@ -136,14 +143,28 @@ fn write_read_test() -> Result<(), Box<dyn std::error::Error>> {
 
 #[test]
 fn dir_test() -> Result<(), Box<dyn std::error::Error>> {
-    let mut client = BlockingClient::connect_uds()?;
+    let mut client = client();
 
     let dirname = "dirTest";
-    let res = client.grpc_mkdir(dirname, S_IRWXU).unwrap();
-    assert!(res != 1, "DirTest: Mkdir Failed");
+    let res = client.rpc_mkdir(dirname, S_IRWXU)?;
+    assert!(res != -1, "DirTest: Mkdir Failed");
 
-    let res = client.grpc_rmdir(dirname).unwrap();
+    let res = client.rpc_rmdir(dirname)?;
     assert!(res != -1, "DirTest: Rmdir Failed");
 
     Ok(())
 }
+
+// DRPC server over UDS with the default limits, started by the first test that needs it
+fn drpc_config() -> &'static ClientConfig {
+    static SERVER: OnceLock<ClientConfig> = OnceLock::new();
+    SERVER.get_or_init(|| {
+        let addr = ServerAddr::Uds(common::socket_path("uds-drpc"));
+        common::start_server(RPCType::DRPC, addr, BackendType::Memory, PathBuf::from("/"))
+    })
+}
+
+#[test]
+fn drpc_large_io_test() {
+    common::large_io_test(drpc_config());
+}
@@ -0,0 +1,50 @@
+mod common;
+
+use fxrpc::fxrpc::*;
+use libc::{EINVAL, O_CREAT, O_RDWR, S_IRWXU};
+
+// Segments running past the largest file offset are refused rather than wrapping
+fn overflow_test(rpc_type: RPCType) {
+    let name = format!("vectored-{}", rpc_type);
+    let addr = ServerAddr::Uds(common::socket_path(&name));
+    let root = common::scratch_dir(&name);
+    let config = common::start_server(rpc_type, addr, BackendType::Posix, root);
+    let mut client = init_client(&config).unwrap();
+
+    let filename = "vectored_overflow_test.txt";
+    let fd = client
+        .rpc_open(filename, O_CREAT | O_RDWR, S_IRWXU)
+        .unwrap();
+
+    let offset = i64::MAX - 2;
+    let res = client.rpc_pwritev(fd, &[(0, b"fits"), (offset, b"overflow")]);
+    assert!(
+        matches!(res, Err(FxRpcError::Errno(EINVAL))),
+        "{} pwritev returned {:?}",
+        rpc_type,
+        res
+    );
+
+    let mut page = [0u8; 8];
+    let res = client.rpc_preadv(fd, &mut [(offset, &mut page[..])]);
+    assert!(
+        matches!(res, Err(FxRpcError::Errno(EINVAL))),
+        "{} preadv returned {:?}",
+        rpc_type,
+        res
+    );
+    assert_eq!(client.rpc_fstat(fd).unwrap(), 0, "{} wrote data", rpc_type);
+
+    client.rpc_close(fd).unwrap();
+    client.rpc_remove(filename).unwrap();
+}
+
+#[test]
+fn grpc_overflow_test() {
+    overflow_test(RPCType::GRPC);
+}
+
+#[test]
+fn drpc_overflow_test() {
+    overflow_test(RPCType::DRPC);
+}